@@ -29,6 +29,7 @@ pub enum Item {
     Impl(ImplBlock),
     TypeAlias(TypeAlias),
     Macro(MacroDef),
+    Effect(EffectDef),
 }
 
 /// Visibility modifier
@@ -120,6 +121,7 @@ pub struct Function {
     pub body: Vec<Stmt>,
     pub span: Span,
     pub effects: Option<Vec<String>>, // Effect annotations like ["io", "async"]
+    pub effect_params: Vec<String>,   // Effect variables like ["e"]
 }
 
 /// Struct definition
@@ -131,6 +133,8 @@ pub struct StructDef {
     pub type_params: Vec<String>,     // Generic type parameters like ["T", "U"]
     pub const_params: Vec<(String, Type)>, // Const parameters like [("N", Type::U64)]
     pub fields: Vec<(String, Type)>,
+    /// Default values of fields declared as `name: Type = value`
+    pub field_defaults: Vec<(String, Expr)>,
     pub span: Span,
 }
 
@@ -174,6 +178,32 @@ pub struct TraitDef {
     pub span: Span,
 }
 
+/// User-defined effect, like `effect Log { fn log(msg: String); }`
+#[derive(Debug, Clone)]
+pub struct EffectDef {
+    pub visibility: Visibility,
+    pub name: String,
+    pub operations: Vec<EffectOperation>,
+    pub span: Span,
+}
+
+/// Operation of a user-defined effect, performed as `Effect::operation(...)`
+#[derive(Debug, Clone)]
+pub struct EffectOperation {
+    pub name: String,
+    pub params: Vec<Param>,
+    pub return_type: Option<Type>,
+    pub span: Span,
+}
+
+/// Handler of a user-defined effect: `with Effect { fn operation(...) { ... } }`
+#[derive(Debug, Clone)]
+pub struct EffectHandler {
+    pub effect: String,
+    pub operations: Vec<Function>,
+    pub span: Span,
+}
+
 /// Trait method
 #[derive(Debug, Clone)]
 pub struct TraitMethod {
@@ -219,6 +249,8 @@ pub enum Type {
     U64,
     Bool,
     String,
+    /// String slice, only used behind a reference (`ref str`)
+    Str,
     /// Unit type (void)
     Unit,
     /// Array type: element type and size
@@ -244,6 +276,13 @@ pub enum Type {
     },
     /// Tuple type (T1, T2, ...)
     Tuple(Vec<Type>),
+    /// Function pointer type, with the effects calling it may have:
+    /// fn(T) -> U ![io, e]
+    Function {
+        params: Vec<Type>,
+        return_type: Box<Type>,
+        effects: Option<Vec<String>>,
+    },
 }
 
 /// Statements
@@ -273,19 +312,28 @@ pub enum Stmt {
         then_branch: Vec<Stmt>,
         else_branch: Option<Vec<Stmt>>,
         span: Span,
+        /// Closing braces of the branches, where their locals go out of scope
+        then_end: Span,
+        else_end: Option<Span>,
     },
     /// While loop
     While {
         condition: Expr,
         body: Vec<Stmt>,
         span: Span,
+        /// Closing brace of the body
+        body_end: Span,
     },
     /// For loop
     For {
         var: String,
+        /// Second name of a `(a, b)` loop pattern, binding the other half of a pair
+        pair: Option<String>,
         iter: Expr,
         body: Vec<Stmt>,
         span: Span,
+        /// Closing brace of the body
+        body_end: Span,
     },
     /// Break statement
     Break { span: Span },
@@ -298,7 +346,29 @@ pub enum Stmt {
         span: Span,
     },
     /// Unsafe block
-    Unsafe { body: Vec<Stmt>, span: Span },
+    Unsafe {
+        body: Vec<Stmt>,
+        span: Span,
+        /// Closing brace of the body
+        body_end: Span,
+    },
+    /// `async scope { ... }`: tasks spawned in the body may borrow from the
+    /// enclosing function, and are all awaited before the scope ends
+    AsyncScope {
+        body: Vec<Stmt>,
+        span: Span,
+        /// Closing brace of the body
+        body_end: Span,
+    },
+    /// `handle { ... } with Effect { ... }`: operations of the effects
+    /// performed in the body, directly or by its callees, run the handlers
+    Handle {
+        body: Vec<Stmt>,
+        handlers: Vec<EffectHandler>,
+        span: Span,
+        /// Closing brace of the body
+        body_end: Span,
+    },
 }
 
 /// Match arm
@@ -306,6 +376,8 @@ pub enum Stmt {
 pub struct MatchArm {
     pub pattern: Pattern,
     pub body: Vec<Stmt>,
+    /// End of the arm, where the bindings of its pattern go out of scope
+    pub end: Span,
 }
 
 /// Pattern for matching
@@ -332,6 +404,15 @@ pub enum PatternData {
     Struct(Vec<(String, Pattern)>),
 }
 
+/// Fields a struct literal takes from elsewhere, written after `..`
+#[derive(Debug, Clone, PartialEq)]
+pub enum StructBase {
+    /// `..` alone: the struct's declared default values
+    Defaults,
+    /// `..base`: the remaining fields of another value of the struct
+    Expr(Box<Expr>),
+}
+
 /// Expressions
 #[derive(Debug, Clone, PartialEq)]
 pub enum Expr {
@@ -343,6 +424,12 @@ pub enum Expr {
     Bool(bool),
     /// Identifier
     Ident(String),
+    /// Path with explicit type arguments: `name::<T>`
+    Turbofish {
+        name: String,
+        type_args: Vec<Type>,
+        span: Span,
+    },
     /// Array literal
     ArrayLiteral { elements: Vec<Expr>, span: Span },
     /// Array repeat literal [value; count]
@@ -380,6 +467,8 @@ pub enum Expr {
     StructLiteral {
         name: String,
         fields: Vec<(String, Expr)>,
+        /// Source of the fields not listed, after `..`
+        base: Option<StructBase>,
         span: Span,
     },
     /// Field access
@@ -470,13 +559,66 @@ pub enum UnaryOp {
     Not,
 }
 
+/// Methods that start, adapt or consume an iterator. A chain of them is
+/// compiled into a single loop rather than into iterator values.
+pub const ITERATOR_METHODS: &[&str] = &[
+    "iter",
+    "into_iter",
+    "chars",
+    "map",
+    "filter",
+    "enumerate",
+    "zip",
+    "take",
+    "step_by",
+    "rev",
+    "sum",
+    "collect",
+];
+
 impl Expr {
+    /// Name of the function a call expression targets, with or without turbofish
+    pub fn callee_name(&self) -> Option<&str> {
+        match self {
+            Expr::Ident(name) | Expr::Turbofish { name, .. } => Some(name),
+            _ => None,
+        }
+    }
+
+    /// The receiver of a `value.clone()` call
+    pub fn clone_receiver(&self) -> Option<&Expr> {
+        match self {
+            Expr::Call { func, args, .. } if args.is_empty() => match func.as_ref() {
+                Expr::FieldAccess { object, field, .. } if field == "clone" => Some(object),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// The receiver, name and arguments of an iterator method such as the
+    /// `map` in `v.iter().map(double)`
+    pub fn iterator_call(&self) -> Option<(&Expr, &str, &[Expr])> {
+        match self {
+            Expr::Call { func, args, .. } => match func.as_ref() {
+                Expr::FieldAccess { object, field, .. }
+                    if ITERATOR_METHODS.contains(&field.as_str()) =>
+                {
+                    Some((object, field, args))
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
     pub fn span(&self) -> Span {
         match self {
             Expr::String(_) => Span::dummy(), // TODO: track spans
             Expr::Integer(_) => Span::dummy(),
             Expr::Bool(_) => Span::dummy(),
             Expr::Ident(_) => Span::dummy(),
+            Expr::Turbofish { span, .. } => *span,
             Expr::ArrayLiteral { span, .. } => *span,
             Expr::ArrayRepeat { span, .. } => *span,
             Expr::Index { span, .. } => *span,
@@ -524,6 +666,7 @@ impl std::fmt::Display for Item {
             Item::Impl(impl_block) => write!(f, "{}", impl_block),
             Item::TypeAlias(type_alias) => write!(f, "{}", type_alias),
             Item::Macro(macro_def) => write!(f, "{}", macro_def),
+            Item::Effect(effect_def) => write!(f, "{}", effect_def),
         }
     }
 }
@@ -552,6 +695,27 @@ impl std::fmt::Display for Function {
     }
 }
 
+impl std::fmt::Display for EffectDef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "effect {} {{", self.name)?;
+        for operation in &self.operations {
+            write!(f, "    fn {}(", operation.name)?;
+            for (i, param) in operation.params.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{}: {}", param.name, param.ty)?;
+            }
+            write!(f, ")")?;
+            if let Some(ret_type) = &operation.return_type {
+                write!(f, " -> {}", ret_type)?;
+            }
+            writeln!(f, ";")?;
+        }
+        write!(f, "}}")
+    }
+}
+
 impl std::fmt::Display for StructDef {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "struct {} {{", self.name)?;
@@ -559,7 +723,11 @@ impl std::fmt::Display for StructDef {
             if i == 0 {
                 writeln!(f)?;
             }
-            writeln!(f, "    {}: {},", field_name, field_type)?;
+            write!(f, "    {}: {}", field_name, field_type)?;
+            if let Some((_, default)) = self.field_defaults.iter().find(|(n, _)| n == field_name) {
+                write!(f, " = {}", default)?;
+            }
+            writeln!(f, ",")?;
         }
         write!(f, "}}")
     }
@@ -643,6 +811,7 @@ impl std::fmt::Display for Type {
             Type::U64 => write!(f, "u64"),
             Type::Bool => write!(f, "bool"),
             Type::String => write!(f, "String"),
+            Type::Str => write!(f, "str"),
             Type::Unit => write!(f, "()"),
             Type::Array(elem_type, size) => write!(f, "[{}; {}]", elem_type, size),
             Type::Custom(name) => write!(f, "{}", name),
@@ -682,6 +851,27 @@ impl std::fmt::Display for Type {
                 }
                 write!(f, ")")
             }
+            Type::Function {
+                params,
+                return_type,
+                effects,
+            } => {
+                write!(f, "fn(")?;
+                for (i, ty) in params.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", ty)?;
+                }
+                write!(f, ")")?;
+                if **return_type != Type::Unit {
+                    write!(f, " -> {}", return_type)?;
+                }
+                if let Some(effects) = effects {
+                    write!(f, " ![{}]", effects.join(", "))?;
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -901,9 +1091,16 @@ impl std::fmt::Display for Stmt {
                 write!(f, "}}")
             }
             Stmt::For {
-                var, iter, body, ..
+                var,
+                pair,
+                iter,
+                body,
+                ..
             } => {
-                write!(f, "for {} in {} {{", var, iter)?;
+                match pair {
+                    Some(pair) => write!(f, "for ({}, {}) in {} {{", var, pair, iter)?,
+                    None => write!(f, "for {} in {} {{", var, iter)?,
+                }
                 for stmt in body {
                     write!(f, " {} ", stmt)?;
                 }
@@ -942,6 +1139,28 @@ impl std::fmt::Display for Stmt {
                 }
                 write!(f, "}}")
             }
+            Stmt::AsyncScope { body, .. } => {
+                writeln!(f, "async scope {{")?;
+                for stmt in body {
+                    writeln!(f, "    {}", stmt)?;
+                }
+                write!(f, "}}")
+            }
+            Stmt::Handle { body, handlers, .. } => {
+                writeln!(f, "handle {{")?;
+                for stmt in body {
+                    writeln!(f, "    {}", stmt)?;
+                }
+                write!(f, "}}")?;
+                for handler in handlers {
+                    writeln!(f, " with {} {{", handler.effect)?;
+                    for operation in &handler.operations {
+                        writeln!(f, "    {}", operation)?;
+                    }
+                    write!(f, "}}")?;
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -953,6 +1172,18 @@ impl std::fmt::Display for Expr {
             Expr::Integer(n) => write!(f, "{}", n),
             Expr::Bool(b) => write!(f, "{}", b),
             Expr::Ident(name) => write!(f, "{}", name),
+            Expr::Turbofish {
+                name, type_args, ..
+            } => {
+                write!(f, "{}::<", name)?;
+                for (i, ty) in type_args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", ty)?;
+                }
+                write!(f, ">")
+            }
             Expr::ArrayLiteral { elements, .. } => {
                 write!(f, "[")?;
                 for (i, elem) in elements.iter().enumerate() {
@@ -987,7 +1218,9 @@ impl std::fmt::Display for Expr {
             Expr::Unary { op, operand, .. } => {
                 write!(f, "({}{})", op, operand)
             }
-            Expr::StructLiteral { name, fields, .. } => {
+            Expr::StructLiteral {
+                name, fields, base, ..
+            } => {
                 write!(f, "{} {{ ", name)?;
                 for (i, (field_name, field_expr)) in fields.iter().enumerate() {
                     if i > 0 {
@@ -995,6 +1228,15 @@ impl std::fmt::Display for Expr {
                     }
                     write!(f, "{}: {}", field_name, field_expr)?;
                 }
+                if let Some(base) = base {
+                    if !fields.is_empty() {
+                        write!(f, ", ")?;
+                    }
+                    match base {
+                        StructBase::Defaults => write!(f, "..")?,
+                        StructBase::Expr(expr) => write!(f, "..{}", expr)?,
+                    }
+                }
                 write!(f, " }}")
             }
             Expr::FieldAccess { object, field, .. } => {
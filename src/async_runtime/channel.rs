@@ -0,0 +1,1025 @@
+// Channels for the async runtime
+// Multi-producer queues, one-shot replies, broadcasts and watched values
+
+use super::{Future, Poll};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::task::Waker;
+
+/// The receiving side of a channel is gone, so `value` couldn't be sent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> std::fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "channel closed")
+    }
+}
+
+impl<T: std::fmt::Debug> std::error::Error for SendError<T> {}
+
+/// Why a value couldn't be sent without waiting
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The channel is at capacity
+    Full(T),
+    /// The receiving side is gone
+    Closed(T),
+}
+
+impl<T> std::fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TrySendError::Full(_) => write!(f, "channel full"),
+            TrySendError::Closed(_) => write!(f, "channel closed"),
+        }
+    }
+}
+
+impl<T: std::fmt::Debug> std::error::Error for TrySendError<T> {}
+
+/// Why no value could be received without waiting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// Nothing has been sent yet
+    Empty,
+    /// Nothing more will be sent: the sending side is gone
+    Closed,
+}
+
+impl std::fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TryRecvError::Empty => write!(f, "channel empty"),
+            TryRecvError::Closed => write!(f, "channel closed"),
+        }
+    }
+}
+
+impl std::error::Error for TryRecvError {}
+
+/// Wakers of the tasks waiting on one side of a channel, by waiter id
+struct Waiters {
+    next_id: u64,
+    waiting: VecDeque<(u64, Waker)>,
+}
+
+impl Waiters {
+    fn new() -> Self {
+        Self {
+            next_id: 0,
+            waiting: VecDeque::new(),
+        }
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    /// Wait as `id`, keeping its place if it's already waiting
+    fn register(&mut self, id: u64, waker: &Waker) {
+        match self.waiting.iter_mut().find(|(waiter, _)| *waiter == id) {
+            Some((_, registered)) => registered.clone_from(waker),
+            None => self.waiting.push_back((id, waker.clone())),
+        }
+    }
+
+    /// Stop waiting as `id`, returning whether it was still waiting
+    fn remove(&mut self, id: u64) -> bool {
+        let before = self.waiting.len();
+        self.waiting.retain(|(waiter, _)| *waiter != id);
+        self.waiting.len() != before
+    }
+
+    fn wake_one(&mut self) {
+        if let Some((_, waker)) = self.waiting.pop_front() {
+            waker.wake();
+        }
+    }
+
+    fn wake_all(&mut self) {
+        for (_, waker) in self.waiting.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// Multi-producer, single-consumer queues, bounded or unbounded
+pub mod mpsc {
+    use super::*;
+
+    /// The queue shared by the senders and the receiver of a channel
+    struct Chan<T> {
+        values: VecDeque<T>,
+        /// Most values queued at once, or `None` when unbounded
+        capacity: Option<usize>,
+        /// Number of live senders; none left closes the channel
+        senders: usize,
+        /// The receiver is gone or closed: sends fail from now on
+        closed: bool,
+        receiver: Option<Waker>,
+        /// Senders waiting for capacity, in arrival order
+        senders_waiting: Waiters,
+    }
+
+    impl<T> Chan<T> {
+        fn has_capacity(&self) -> bool {
+            self.capacity
+                .is_none_or(|capacity| self.values.len() < capacity)
+        }
+
+        fn push(&mut self, value: T) {
+            self.values.push_back(value);
+            if let Some(receiver) = self.receiver.take() {
+                receiver.wake();
+            }
+        }
+    }
+
+    type Shared<T> = Arc<Mutex<Chan<T>>>;
+
+    fn shared<T>(capacity: Option<usize>) -> Shared<T> {
+        Arc::new(Mutex::new(Chan {
+            values: VecDeque::new(),
+            capacity,
+            senders: 1,
+            closed: false,
+            receiver: None,
+            senders_waiting: Waiters::new(),
+        }))
+    }
+
+    /// A channel holding at most `capacity` values: sending to a full
+    /// channel waits until the receiver makes room
+    pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+        assert!(capacity > 0, "a bounded channel needs a capacity");
+        let chan = shared(Some(capacity));
+        let sender = Sender {
+            chan: Arc::clone(&chan),
+        };
+        (sender, Receiver { chan })
+    }
+
+    /// A channel that never runs out of room, so sending never waits
+    pub fn unbounded_channel<T>() -> (UnboundedSender<T>, Receiver<T>) {
+        let chan = shared(None);
+        let sender = UnboundedSender {
+            chan: Arc::clone(&chan),
+        };
+        (sender, Receiver { chan })
+    }
+
+    fn clone_sender<T>(chan: &Shared<T>) -> Shared<T> {
+        chan.lock().unwrap().senders += 1;
+        Arc::clone(chan)
+    }
+
+    fn drop_sender<T>(chan: &Shared<T>) {
+        let mut chan = chan.lock().unwrap();
+        chan.senders -= 1;
+        if chan.senders == 0 {
+            if let Some(receiver) = chan.receiver.take() {
+                receiver.wake();
+            }
+        }
+    }
+
+    /// Sending side of a bounded channel
+    pub struct Sender<T> {
+        chan: Shared<T>,
+    }
+
+    impl<T> Sender<T> {
+        /// Send `value`, waiting for room in the channel
+        pub fn send(&self, value: T) -> SendFuture<T> {
+            SendFuture {
+                sender: self.clone(),
+                value: Some(value),
+                id: None,
+            }
+        }
+
+        /// Send `value` if there's room for it right away
+        pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+            let mut chan = self.chan.lock().unwrap();
+            if chan.closed {
+                return Err(TrySendError::Closed(value));
+            }
+            // Senders already waiting go first
+            if !chan.has_capacity() || !chan.senders_waiting.waiting.is_empty() {
+                return Err(TrySendError::Full(value));
+            }
+            chan.push(value);
+            Ok(())
+        }
+
+        /// Whether the receiver is gone or closed
+        pub fn is_closed(&self) -> bool {
+            self.chan.lock().unwrap().closed
+        }
+    }
+
+    impl<T> Clone for Sender<T> {
+        fn clone(&self) -> Self {
+            Self {
+                chan: clone_sender(&self.chan),
+            }
+        }
+    }
+
+    impl<T> Drop for Sender<T> {
+        fn drop(&mut self) {
+            drop_sender(&self.chan);
+        }
+    }
+
+    /// Future of a send to a bounded channel
+    pub struct SendFuture<T> {
+        sender: Sender<T>,
+        value: Option<T>,
+        /// Id among the senders waiting for capacity, once it has waited
+        id: Option<u64>,
+    }
+
+    impl<T> Future for SendFuture<T> {
+        type Output = Result<(), SendError<T>>;
+
+        fn poll(&mut self, waker: &Waker) -> Poll<Self::Output> {
+            let mut chan = self.sender.chan.lock().unwrap();
+            let value = self
+                .value
+                .take()
+                .expect("SendFuture polled after completion");
+            if chan.closed {
+                return Poll::Ready(Err(SendError(value)));
+            }
+            let waiting = &chan.senders_waiting;
+            let in_line = self
+                .id
+                .is_some_and(|id| waiting.waiting.iter().any(|(waiter, _)| *waiter == id));
+            // A sender woken for room goes ahead of the others
+            let first = match self.id {
+                Some(id) if in_line => waiting.waiting.front().is_some_and(|(w, _)| *w == id),
+                Some(_) => true,
+                None => waiting.waiting.is_empty(),
+            };
+            if first && chan.has_capacity() {
+                if let Some(id) = self.id.take() {
+                    chan.senders_waiting.remove(id);
+                }
+                chan.push(value);
+                return Poll::Ready(Ok(()));
+            }
+            match self.id {
+                Some(id) if in_line => chan.senders_waiting.register(id, waker),
+                Some(id) => chan.senders_waiting.waiting.push_front((id, waker.clone())),
+                None => {
+                    let id = chan.senders_waiting.next_id();
+                    chan.senders_waiting.register(id, waker);
+                    self.id = Some(id);
+                }
+            }
+            self.value = Some(value);
+            Poll::Pending
+        }
+    }
+
+    impl<T> Drop for SendFuture<T> {
+        fn drop(&mut self) {
+            let Some(id) = self.id else {
+                return;
+            };
+            let mut chan = self.sender.chan.lock().unwrap();
+            // Woken for room it won't use: pass the wake on
+            if !chan.senders_waiting.remove(id) && chan.has_capacity() {
+                chan.senders_waiting.wake_one();
+            }
+        }
+    }
+
+    /// Sending side of an unbounded channel
+    pub struct UnboundedSender<T> {
+        chan: Shared<T>,
+    }
+
+    impl<T> UnboundedSender<T> {
+        /// Send `value` without waiting
+        pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+            let mut chan = self.chan.lock().unwrap();
+            if chan.closed {
+                return Err(SendError(value));
+            }
+            chan.push(value);
+            Ok(())
+        }
+
+        /// Whether the receiver is gone or closed
+        pub fn is_closed(&self) -> bool {
+            self.chan.lock().unwrap().closed
+        }
+    }
+
+    impl<T> Clone for UnboundedSender<T> {
+        fn clone(&self) -> Self {
+            Self {
+                chan: clone_sender(&self.chan),
+            }
+        }
+    }
+
+    impl<T> Drop for UnboundedSender<T> {
+        fn drop(&mut self) {
+            drop_sender(&self.chan);
+        }
+    }
+
+    /// Receiving side of a channel
+    pub struct Receiver<T> {
+        chan: Shared<T>,
+    }
+
+    impl<T> Receiver<T> {
+        /// Receive the next value, or `None` once the channel is closed and
+        /// drained
+        pub fn recv(&mut self) -> RecvFuture<'_, T> {
+            RecvFuture { receiver: self }
+        }
+
+        /// Receive the next value if one is queued
+        pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+            let mut chan = self.chan.lock().unwrap();
+            match self.take(&mut chan) {
+                Some(value) => Ok(value),
+                None if chan.senders == 0 || chan.closed => Err(TryRecvError::Closed),
+                None => Err(TryRecvError::Empty),
+            }
+        }
+
+        /// Refuse further sends; values already queued can still be received
+        pub fn close(&mut self) {
+            let mut chan = self.chan.lock().unwrap();
+            chan.closed = true;
+            chan.senders_waiting.wake_all();
+        }
+
+        fn take(&self, chan: &mut Chan<T>) -> Option<T> {
+            let value = chan.values.pop_front()?;
+            chan.senders_waiting.wake_one();
+            Some(value)
+        }
+    }
+
+    impl<T> Drop for Receiver<T> {
+        fn drop(&mut self) {
+            self.close();
+            self.chan.lock().unwrap().values.clear();
+        }
+    }
+
+    /// Future of the next value of a channel
+    pub struct RecvFuture<'a, T> {
+        receiver: &'a mut Receiver<T>,
+    }
+
+    impl<T> Future for RecvFuture<'_, T> {
+        type Output = Option<T>;
+
+        fn poll(&mut self, waker: &Waker) -> Poll<Self::Output> {
+            let mut chan = self.receiver.chan.lock().unwrap();
+            if let Some(value) = self.receiver.take(&mut chan) {
+                return Poll::Ready(Some(value));
+            }
+            if chan.senders == 0 || chan.closed {
+                return Poll::Ready(None);
+            }
+            chan.receiver = Some(waker.clone());
+            Poll::Pending
+        }
+    }
+}
+
+/// Channels carrying a single value, such as the reply to a request
+pub mod oneshot {
+    use super::*;
+
+    /// The sender was dropped without sending
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct RecvError;
+
+    impl std::fmt::Display for RecvError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "sender dropped without sending")
+        }
+    }
+
+    impl std::error::Error for RecvError {}
+
+    struct Slot<T> {
+        value: Option<T>,
+        /// The sender sent its value or was dropped
+        sender_done: bool,
+        receiver_dropped: bool,
+        receiver: Option<Waker>,
+    }
+
+    /// A channel for one value
+    pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+        let slot = Arc::new(Mutex::new(Slot {
+            value: None,
+            sender_done: false,
+            receiver_dropped: false,
+            receiver: None,
+        }));
+        let sender = Sender {
+            slot: Arc::clone(&slot),
+        };
+        (sender, Receiver { slot })
+    }
+
+    /// Sending side of a oneshot channel
+    pub struct Sender<T> {
+        slot: Arc<Mutex<Slot<T>>>,
+    }
+
+    impl<T> Sender<T> {
+        /// Send the value, handing it back if the receiver is gone
+        pub fn send(self, value: T) -> Result<(), T> {
+            let mut slot = self.slot.lock().unwrap();
+            if slot.receiver_dropped {
+                return Err(value);
+            }
+            slot.value = Some(value);
+            Ok(())
+        }
+
+        /// Whether the receiver is gone
+        pub fn is_closed(&self) -> bool {
+            self.slot.lock().unwrap().receiver_dropped
+        }
+    }
+
+    impl<T> Drop for Sender<T> {
+        fn drop(&mut self) {
+            let mut slot = self.slot.lock().unwrap();
+            slot.sender_done = true;
+            if let Some(receiver) = slot.receiver.take() {
+                receiver.wake();
+            }
+        }
+    }
+
+    /// Receiving side of a oneshot channel, awaited for the value
+    pub struct Receiver<T> {
+        slot: Arc<Mutex<Slot<T>>>,
+    }
+
+    impl<T> Receiver<T> {
+        /// Take the value if it has been sent
+        pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+            let mut slot = self.slot.lock().unwrap();
+            match slot.value.take() {
+                Some(value) => Ok(value),
+                None if slot.sender_done => Err(TryRecvError::Closed),
+                None => Err(TryRecvError::Empty),
+            }
+        }
+    }
+
+    impl<T> Future for Receiver<T> {
+        type Output = Result<T, RecvError>;
+
+        fn poll(&mut self, waker: &Waker) -> Poll<Self::Output> {
+            let mut slot = self.slot.lock().unwrap();
+            if let Some(value) = slot.value.take() {
+                return Poll::Ready(Ok(value));
+            }
+            if slot.sender_done {
+                return Poll::Ready(Err(RecvError));
+            }
+            slot.receiver = Some(waker.clone());
+            Poll::Pending
+        }
+    }
+
+    impl<T> Drop for Receiver<T> {
+        fn drop(&mut self) {
+            let mut slot = self.slot.lock().unwrap();
+            slot.receiver_dropped = true;
+            slot.receiver = None;
+        }
+    }
+}
+
+/// Channels delivering every value sent to every receiver
+pub mod broadcast {
+    use super::*;
+
+    /// Why a broadcast receive failed
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum RecvError {
+        /// Every sender is gone and every value was received
+        Closed,
+        /// The receiver fell this many values behind, which were dropped to
+        /// keep the channel within capacity; it resumes at the oldest kept
+        Lagged(u64),
+    }
+
+    impl std::fmt::Display for RecvError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                RecvError::Closed => write!(f, "channel closed"),
+                RecvError::Lagged(skipped) => write!(f, "receiver lagged by {}", skipped),
+            }
+        }
+    }
+
+    impl std::error::Error for RecvError {}
+
+    /// The most recent values sent, numbered in order
+    struct Ring<T> {
+        values: VecDeque<T>,
+        capacity: usize,
+        /// Number of the next value sent; the oldest kept is
+        /// `next - values.len()`
+        next: u64,
+        senders: usize,
+        receivers: usize,
+        receivers_waiting: Waiters,
+    }
+
+    impl<T> Ring<T> {
+        fn oldest(&self) -> u64 {
+            self.next - self.values.len() as u64
+        }
+    }
+
+    /// A channel keeping the last `capacity` values for receivers behind
+    pub fn channel<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+        assert!(capacity > 0, "a broadcast channel needs a capacity");
+        let ring = Arc::new(Mutex::new(Ring {
+            values: VecDeque::with_capacity(capacity),
+            capacity,
+            next: 0,
+            senders: 1,
+            receivers: 1,
+            receivers_waiting: Waiters::new(),
+        }));
+        let receiver = Receiver::new(Arc::clone(&ring), 0);
+        (Sender { ring }, receiver)
+    }
+
+    /// Sending side of a broadcast channel
+    pub struct Sender<T> {
+        ring: Arc<Mutex<Ring<T>>>,
+    }
+
+    impl<T: Clone> Sender<T> {
+        /// Send `value` to every receiver, returning how many there are
+        pub fn send(&self, value: T) -> Result<usize, SendError<T>> {
+            let mut ring = self.ring.lock().unwrap();
+            if ring.receivers == 0 {
+                return Err(SendError(value));
+            }
+            if ring.values.len() == ring.capacity {
+                ring.values.pop_front();
+            }
+            ring.values.push_back(value);
+            ring.next += 1;
+            ring.receivers_waiting.wake_all();
+            Ok(ring.receivers)
+        }
+
+        /// A new receiver of the values sent from now on
+        pub fn subscribe(&self) -> Receiver<T> {
+            let next = {
+                let mut ring = self.ring.lock().unwrap();
+                ring.receivers += 1;
+                ring.next
+            };
+            Receiver::new(Arc::clone(&self.ring), next)
+        }
+    }
+
+    impl<T> Clone for Sender<T> {
+        fn clone(&self) -> Self {
+            self.ring.lock().unwrap().senders += 1;
+            Self {
+                ring: Arc::clone(&self.ring),
+            }
+        }
+    }
+
+    impl<T> Drop for Sender<T> {
+        fn drop(&mut self) {
+            let mut ring = self.ring.lock().unwrap();
+            ring.senders -= 1;
+            if ring.senders == 0 {
+                ring.receivers_waiting.wake_all();
+            }
+        }
+    }
+
+    /// Receiving side of a broadcast channel
+    pub struct Receiver<T> {
+        ring: Arc<Mutex<Ring<T>>>,
+        /// Number of the next value to receive
+        next: u64,
+        id: u64,
+    }
+
+    impl<T: Clone> Receiver<T> {
+        fn new(ring: Arc<Mutex<Ring<T>>>, next: u64) -> Self {
+            let id = ring.lock().unwrap().receivers_waiting.next_id();
+            Self { ring, next, id }
+        }
+
+        /// Receive the next value
+        pub fn recv(&mut self) -> RecvFuture<'_, T> {
+            RecvFuture { receiver: self }
+        }
+
+        /// Receive the next value if one was sent, `None` when there's none
+        /// yet
+        pub fn try_recv(&mut self) -> Option<Result<T, RecvError>> {
+            let ring = Arc::clone(&self.ring);
+            let ring = ring.lock().unwrap();
+            self.take(&ring)
+        }
+
+        fn take(&mut self, ring: &Ring<T>) -> Option<Result<T, RecvError>> {
+            let oldest = ring.oldest();
+            if self.next < oldest {
+                let skipped = oldest - self.next;
+                self.next = oldest;
+                return Some(Err(RecvError::Lagged(skipped)));
+            }
+            if self.next < ring.next {
+                let value = ring.values[(self.next - oldest) as usize].clone();
+                self.next += 1;
+                return Some(Ok(value));
+            }
+            (ring.senders == 0).then_some(Err(RecvError::Closed))
+        }
+    }
+
+    impl<T> Drop for Receiver<T> {
+        fn drop(&mut self) {
+            let mut ring = self.ring.lock().unwrap();
+            ring.receivers -= 1;
+            ring.receivers_waiting.remove(self.id);
+        }
+    }
+
+    /// Future of the next value of a broadcast channel
+    pub struct RecvFuture<'a, T> {
+        receiver: &'a mut Receiver<T>,
+    }
+
+    impl<T: Clone> Future for RecvFuture<'_, T> {
+        type Output = Result<T, RecvError>;
+
+        fn poll(&mut self, waker: &Waker) -> Poll<Self::Output> {
+            let ring = Arc::clone(&self.receiver.ring);
+            let mut ring = ring.lock().unwrap();
+            if let Some(result) = self.receiver.take(&ring) {
+                return Poll::Ready(result);
+            }
+            ring.receivers_waiting.register(self.receiver.id, waker);
+            Poll::Pending
+        }
+    }
+}
+
+/// Channels holding a single value that receivers watch for changes
+pub mod watch {
+    use super::*;
+    use std::ops::Deref;
+    use std::sync::MutexGuard;
+
+    /// The sender is gone, so the value won't change again
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct RecvError;
+
+    impl std::fmt::Display for RecvError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "sender dropped")
+        }
+    }
+
+    impl std::error::Error for RecvError {}
+
+    struct Watched<T> {
+        value: T,
+        /// Bumped by every send
+        version: u64,
+        sender_dropped: bool,
+        receivers: usize,
+        receivers_waiting: Waiters,
+    }
+
+    /// A channel holding `initial` until a new value is sent
+    pub fn channel<T>(initial: T) -> (Sender<T>, Receiver<T>) {
+        let watched = Arc::new(Mutex::new(Watched {
+            value: initial,
+            version: 0,
+            sender_dropped: false,
+            receivers: 1,
+            receivers_waiting: Waiters::new(),
+        }));
+        let receiver = Receiver::new(Arc::clone(&watched), 0);
+        (Sender { watched }, receiver)
+    }
+
+    /// The current value of a watch channel, locked while borrowed
+    pub struct Ref<'a, T> {
+        guard: MutexGuard<'a, Watched<T>>,
+    }
+
+    impl<T> Deref for Ref<'_, T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            &self.guard.value
+        }
+    }
+
+    /// Sending side of a watch channel
+    pub struct Sender<T> {
+        watched: Arc<Mutex<Watched<T>>>,
+    }
+
+    impl<T> Sender<T> {
+        /// Replace the value, notifying the receivers; fails once there are
+        /// none
+        pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+            let mut watched = self.watched.lock().unwrap();
+            if watched.receivers == 0 {
+                return Err(SendError(value));
+            }
+            watched.value = value;
+            watched.version += 1;
+            watched.receivers_waiting.wake_all();
+            Ok(())
+        }
+
+        /// The current value
+        pub fn borrow(&self) -> Ref<'_, T> {
+            Ref {
+                guard: self.watched.lock().unwrap(),
+            }
+        }
+
+        /// A new receiver, which has seen the current value
+        pub fn subscribe(&self) -> Receiver<T> {
+            let version = {
+                let mut watched = self.watched.lock().unwrap();
+                watched.receivers += 1;
+                watched.version
+            };
+            Receiver::new(Arc::clone(&self.watched), version)
+        }
+    }
+
+    impl<T> Drop for Sender<T> {
+        fn drop(&mut self) {
+            let mut watched = self.watched.lock().unwrap();
+            watched.sender_dropped = true;
+            watched.receivers_waiting.wake_all();
+        }
+    }
+
+    /// Receiving side of a watch channel
+    pub struct Receiver<T> {
+        watched: Arc<Mutex<Watched<T>>>,
+        /// Version of the value last seen
+        seen: u64,
+        id: u64,
+    }
+
+    impl<T> Receiver<T> {
+        fn new(watched: Arc<Mutex<Watched<T>>>, seen: u64) -> Self {
+            let id = watched.lock().unwrap().receivers_waiting.next_id();
+            Self { watched, seen, id }
+        }
+
+        /// The current value, without marking it seen
+        pub fn borrow(&self) -> Ref<'_, T> {
+            Ref {
+                guard: self.watched.lock().unwrap(),
+            }
+        }
+
+        /// The current value, marking it seen
+        pub fn borrow_and_update(&mut self) -> Ref<'_, T> {
+            let guard = self.watched.lock().unwrap();
+            self.seen = guard.version;
+            Ref { guard }
+        }
+
+        /// Wait for a value not seen yet, marking it seen
+        pub fn changed(&mut self) -> Changed<'_, T> {
+            Changed { receiver: self }
+        }
+    }
+
+    impl<T> Clone for Receiver<T> {
+        fn clone(&self) -> Self {
+            self.watched.lock().unwrap().receivers += 1;
+            Self::new(Arc::clone(&self.watched), self.seen)
+        }
+    }
+
+    impl<T> Drop for Receiver<T> {
+        fn drop(&mut self) {
+            let mut watched = self.watched.lock().unwrap();
+            watched.receivers -= 1;
+            watched.receivers_waiting.remove(self.id);
+        }
+    }
+
+    /// Future of the next change to a watch channel
+    pub struct Changed<'a, T> {
+        receiver: &'a mut Receiver<T>,
+    }
+
+    impl<T> Future for Changed<'_, T> {
+        type Output = Result<(), RecvError>;
+
+        fn poll(&mut self, waker: &Waker) -> Poll<Self::Output> {
+            let watched = Arc::clone(&self.receiver.watched);
+            let mut watched = watched.lock().unwrap();
+            if watched.version != self.receiver.seen {
+                self.receiver.seen = watched.version;
+                return Poll::Ready(Ok(()));
+            }
+            if watched.sender_dropped {
+                return Poll::Ready(Err(RecvError));
+            }
+            watched.receivers_waiting.register(self.receiver.id, waker);
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_runtime::{block_on, AsyncRuntime};
+    use std::thread;
+
+    /// Sends each of `values` in turn, awaiting room for it
+    struct SendAll {
+        sender: mpsc::Sender<usize>,
+        values: std::ops::Range<usize>,
+        sending: Option<mpsc::SendFuture<usize>>,
+    }
+
+    impl Future for SendAll {
+        type Output = ();
+
+        fn poll(&mut self, waker: &Waker) -> Poll<Self::Output> {
+            loop {
+                let sending = match &mut self.sending {
+                    Some(sending) => sending,
+                    None => match self.values.next() {
+                        Some(value) => self.sending.insert(self.sender.send(value)),
+                        None => return Poll::Ready(()),
+                    },
+                };
+                match sending.poll(waker) {
+                    Poll::Ready(result) => result.unwrap(),
+                    Poll::Pending => return Poll::Pending,
+                }
+                self.sending = None;
+            }
+        }
+    }
+
+    #[test]
+    fn test_bounded_send_waits_for_capacity() {
+        let runtime = Arc::new(AsyncRuntime::new(2));
+        let (sender, mut receiver) = mpsc::channel::<usize>(2);
+        let producers: Vec<_> = (0..4)
+            .map(|producer| {
+                runtime.spawn(SendAll {
+                    sender: sender.clone(),
+                    values: producer * 100..producer * 100 + 50,
+                    sending: None,
+                })
+            })
+            .collect();
+        drop(sender);
+
+        let workers = {
+            let runtime = Arc::clone(&runtime);
+            thread::spawn(move || runtime.run())
+        };
+        let mut received = Vec::new();
+        while let Some(value) = block_on(receiver.recv()) {
+            received.push(value);
+        }
+        runtime.stop();
+        workers.join().unwrap();
+        for producer in producers {
+            assert_eq!(block_on(producer), Ok(()));
+        }
+
+        // Every value arrives once, each producer's in the order sent
+        assert_eq!(received.len(), 200);
+        for producer in 0..4 {
+            let values: Vec<_> = received
+                .iter()
+                .copied()
+                .filter(|value| value / 100 == producer)
+                .collect();
+            assert_eq!(
+                values,
+                (producer * 100..producer * 100 + 50).collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn test_mpsc_close_semantics() {
+        let (sender, mut receiver) = mpsc::channel::<i32>(1);
+        assert_eq!(sender.try_send(1), Ok(()));
+        assert_eq!(sender.try_send(2), Err(TrySendError::Full(2)));
+        assert_eq!(receiver.try_recv(), Ok(1));
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+
+        // Values sent before the last sender is dropped are still received
+        block_on(sender.send(3)).unwrap();
+        drop(sender);
+        assert_eq!(block_on(receiver.recv()), Some(3));
+        assert_eq!(block_on(receiver.recv()), None);
+
+        let (sender, receiver) = mpsc::unbounded_channel::<i32>();
+        drop(receiver);
+        assert!(sender.is_closed());
+        assert_eq!(sender.send(4), Err(SendError(4)));
+    }
+
+    #[test]
+    fn test_oneshot() {
+        let (sender, receiver) = oneshot::channel();
+        let replier = thread::spawn(move || sender.send("pong"));
+        assert_eq!(block_on(receiver), Ok("pong"));
+        assert_eq!(replier.join().unwrap(), Ok(()));
+
+        let (sender, receiver) = oneshot::channel::<i32>();
+        drop(sender);
+        assert_eq!(block_on(receiver), Err(oneshot::RecvError));
+
+        let (sender, receiver) = oneshot::channel();
+        drop(receiver);
+        assert_eq!(sender.send(5), Err(5));
+    }
+
+    #[test]
+    fn test_broadcast_delivers_to_every_receiver() {
+        let (sender, mut first) = broadcast::channel(2);
+        let mut second = sender.subscribe();
+        assert_eq!(sender.send(1), Ok(2));
+        assert_eq!(block_on(first.recv()), Ok(1));
+
+        // The second receiver falls behind and loses the oldest value
+        sender.send(2).unwrap();
+        sender.send(3).unwrap();
+        assert_eq!(
+            block_on(second.recv()),
+            Err(broadcast::RecvError::Lagged(1))
+        );
+        assert_eq!(block_on(second.recv()), Ok(2));
+        assert_eq!(block_on(second.recv()), Ok(3));
+
+        drop(sender);
+        assert_eq!(block_on(first.recv()), Ok(2));
+        assert_eq!(block_on(first.recv()), Ok(3));
+        assert_eq!(block_on(first.recv()), Err(broadcast::RecvError::Closed));
+        assert_eq!(second.try_recv(), Some(Err(broadcast::RecvError::Closed)));
+    }
+
+    #[test]
+    fn test_watch_sees_latest_value() {
+        let (sender, mut receiver) = watch::channel(0);
+        let watcher = thread::spawn(move || {
+            let mut seen = Vec::new();
+            while block_on(receiver.changed()).is_ok() {
+                seen.push(*receiver.borrow_and_update());
+            }
+            seen
+        });
+        for value in 1..=3 {
+            sender.send(value).unwrap();
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(*sender.borrow(), 3);
+        drop(sender);
+
+        // Changes may coalesce, but the last value is always seen
+        let seen = watcher.join().unwrap();
+        assert_eq!(seen.last(), Some(&3));
+        assert!(seen.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+}
@@ -1,88 +1,283 @@
 // Async runtime for Palladium
 // "Orchestrating concurrent legends"
 
+use std::cell::Cell;
 use std::collections::VecDeque;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Wake, Waker};
 use std::thread;
 use std::time::Duration;
 
+mod channel;
+mod scope;
+mod timer;
+
+pub use channel::{broadcast, mpsc, oneshot, watch, SendError, TryRecvError, TrySendError};
+pub use scope::Scope;
+use timer::Parker;
+pub use timer::{interval, now, sleep, sleep_until, Interval, Sleep, Tick, Timers, TimersGuard};
+
 /// Future trait for asynchronous computations
 pub trait Future {
     type Output;
 
-    /// Poll the future to check if it's ready
-    fn poll(&mut self) -> Poll<Self::Output>;
+    /// Poll the future to check if it's ready. A pending future arranges for
+    /// `waker` to be woken once polling it again can make progress
+    fn poll(&mut self, waker: &Waker) -> Poll<Self::Output>;
+}
+
+impl<F: Future + ?Sized> Future for Box<F> {
+    type Output = F::Output;
+
+    fn poll(&mut self, waker: &Waker) -> Poll<Self::Output> {
+        (**self).poll(waker)
+    }
 }
 
 /// Result of polling a future
 pub enum Poll<T> {
     /// Future is ready with a value
     Ready(T),
-    /// Future is not ready, should be polled again later
+    /// Future is not ready, and will be polled again once it's woken
     Pending,
 }
 
+/// Scheduling state of a task, kept in `Task::state`
+const IDLE: u8 = 0;
+/// Queued on a worker deque or the injector, waiting to be polled
+const SCHEDULED: u8 = 1;
+/// Being polled by a worker
+const RUNNING: u8 = 2;
+/// Woken while being polled, so it's rescheduled as soon as the poll returns
+const NOTIFIED: u8 = 3;
+/// Completed; wakes are ignored
+const DONE: u8 = 4;
+
 /// Task represents an asynchronous computation
 pub struct Task {
-    id: usize,
-    poll_fn: Box<dyn FnMut() -> Poll<()> + Send>,
+    future: Mutex<Option<Box<dyn Future<Output = ()> + Send>>>,
+    state: AtomicU8,
+    shared: Weak<Shared>,
+}
+
+impl Wake for Task {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        let mut state = self.state.load(Ordering::Acquire);
+        loop {
+            let next = match state {
+                IDLE => SCHEDULED,
+                RUNNING => NOTIFIED,
+                _ => return,
+            };
+            match self
+                .state
+                .compare_exchange(state, next, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => break,
+                Err(actual) => state = actual,
+            }
+        }
+        // A running task is rescheduled by its worker once the poll returns
+        if state == IDLE {
+            if let Some(shared) = self.shared.upgrade() {
+                shared.schedule(Arc::clone(self));
+            }
+        }
+    }
+}
+
+/// State shared by the runtime and its workers
+struct Shared {
+    /// Tasks spawned or woken outside the workers
+    injector: Mutex<VecDeque<Arc<Task>>>,
+    /// Per-worker deques: the owner pops from the front, thieves steal from the back
+    locals: Vec<Mutex<VecDeque<Arc<Task>>>>,
+    /// Number of tasks queued anywhere, checked under the parker's lock before
+    /// parking
+    queued: AtomicUsize,
+    /// Number of tasks spawned and not yet completed
+    in_flight: AtomicUsize,
+    /// Whether `stop` was called; workers exit once no task is in flight
+    stopping: AtomicBool,
+    /// Timers of the tasks, fired by the workers
+    timers: Arc<Timers>,
+    /// Idle workers park on the timers' parker, until a task is queued or the
+    /// next timer is due
+    parker: Arc<Parker>,
+}
+
+thread_local! {
+    /// The runtime (by address of its shared state) and index of the worker
+    /// running on this thread
+    static WORKER: Cell<Option<(usize, usize)>> = const { Cell::new(None) };
+}
+
+impl Shared {
+    /// Queue a task: on the current worker's own deque when woken by one of
+    /// this runtime's workers, and on the injector otherwise
+    fn schedule(self: &Arc<Self>, task: Arc<Task>) {
+        // Counted first, so a worker never takes a task it hasn't counted
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        let this = Arc::as_ptr(self) as usize;
+        match WORKER.with(Cell::get) {
+            Some((runtime, index)) if runtime == this => {
+                self.locals[index].lock().unwrap().push_back(task)
+            }
+            _ => self.injector.lock().unwrap().push_back(task),
+        }
+        let _sleep = self.parker.sleep.lock().unwrap();
+        self.parker.wakeup.notify_one();
+    }
+
+    /// The next task for worker `index`: from its own deque, then the
+    /// injector, then stolen from the back of another worker's deque
+    fn find_task(&self, index: usize) -> Option<Arc<Task>> {
+        // One lock at a time: a worker stealing never holds its own deque
+        let local = self.locals[index].lock().unwrap().pop_front();
+        let task = local
+            .or_else(|| self.injector.lock().unwrap().pop_front())
+            .or_else(|| self.steal(index));
+        if task.is_some() {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+        }
+        task
+    }
+
+    /// Steal half of the tasks of the first other worker that has any,
+    /// keeping all but one of them on worker `index`'s own deque
+    fn steal(&self, index: usize) -> Option<Arc<Task>> {
+        let count = self.locals.len();
+        for victim in (1..count).map(|offset| (index + offset) % count) {
+            let mut stolen = {
+                let mut deque = self.locals[victim].lock().unwrap();
+                let len = deque.len();
+                deque.split_off(len / 2)
+            };
+            if let Some(task) = stolen.pop_front() {
+                self.locals[index].lock().unwrap().extend(stolen);
+                return Some(task);
+            }
+        }
+        None
+    }
+
+    /// Poll a scheduled task once. Pending tasks aren't requeued; their waker
+    /// reschedules them, unless they were woken while being polled.
+    fn run_task(self: &Arc<Self>, task: Arc<Task>) {
+        task.state.store(RUNNING, Ordering::Release);
+        let waker = Waker::from(Arc::clone(&task));
+        let mut future = task.future.lock().unwrap();
+        let ready = match future.as_mut() {
+            Some(future) => matches!(future.poll(&waker), Poll::Ready(())),
+            None => true,
+        };
+        if ready {
+            *future = None;
+            drop(future);
+            task.state.store(DONE, Ordering::Release);
+            if self.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+                // The last task is done: a stopping runtime can shut down
+                let _sleep = self.parker.sleep.lock().unwrap();
+                self.parker.wakeup.notify_all();
+            }
+            return;
+        }
+        drop(future);
+        if task
+            .state
+            .compare_exchange(RUNNING, IDLE, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            task.state.store(SCHEDULED, Ordering::Release);
+            self.schedule(task);
+        }
+    }
+
+    /// Whether the workers are done: stopped, and no task is in flight
+    fn finished(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst) && self.in_flight.load(Ordering::SeqCst) == 0
+    }
 }
 
 /// Runtime for executing async tasks
 pub struct AsyncRuntime {
-    /// Queue of tasks ready to be polled
-    ready_queue: Arc<Mutex<VecDeque<Task>>>,
+    shared: Arc<Shared>,
     /// Number of worker threads
     num_workers: usize,
-    /// Whether the runtime is running
-    running: Arc<Mutex<bool>>,
 }
 
 impl AsyncRuntime {
     /// Create a new async runtime
     pub fn new(num_workers: usize) -> Self {
+        Self::with_timers(num_workers, Timers::new())
+    }
+
+    /// Create a new async runtime whose tasks sleep on `timers`, such as
+    /// virtual ones for tests
+    pub fn with_timers(num_workers: usize, timers: Arc<Timers>) -> Self {
+        let num_workers = num_workers.max(1);
         Self {
-            ready_queue: Arc::new(Mutex::new(VecDeque::new())),
+            shared: Arc::new(Shared {
+                injector: Mutex::new(VecDeque::new()),
+                locals: (0..num_workers)
+                    .map(|_| Mutex::new(VecDeque::new()))
+                    .collect(),
+                queued: AtomicUsize::new(0),
+                in_flight: AtomicUsize::new(0),
+                stopping: AtomicBool::new(false),
+                parker: Arc::clone(&timers.parker),
+                timers,
+            }),
             num_workers,
-            running: Arc::new(Mutex::new(false)),
         }
     }
 
-    /// Spawn a new async task
-    pub fn spawn<F>(&self, mut future: F) -> TaskHandle
+    /// Spawn a new async task, returning a handle that can await its output
+    pub fn spawn<F>(&self, future: F) -> TaskHandle<F::Output>
     where
-        F: Future<Output = ()> + Send + 'static,
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
     {
-        static TASK_ID_COUNTER: std::sync::atomic::AtomicUsize =
-            std::sync::atomic::AtomicUsize::new(0);
-        let id = TASK_ID_COUNTER.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-
-        let task = Task {
-            id,
-            poll_fn: Box::new(move || future.poll()),
-        };
-
-        self.ready_queue.lock().unwrap().push_back(task);
-
-        TaskHandle { id }
+        static TASK_ID_COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let id = TASK_ID_COUNTER.fetch_add(1, Ordering::SeqCst);
+
+        let join = Arc::new(JoinState {
+            cancelled: AtomicBool::new(false),
+            inner: Mutex::new(JoinInner {
+                result: None,
+                finished: false,
+                joiner: None,
+            }),
+        });
+        let task = Arc::new(Task {
+            future: Mutex::new(Some(Box::new(Spawned {
+                future,
+                join: Arc::clone(&join),
+            }))),
+            state: AtomicU8::new(SCHEDULED),
+            shared: Arc::downgrade(&self.shared),
+        });
+        let waker = Waker::from(Arc::clone(&task));
+        self.shared.in_flight.fetch_add(1, Ordering::SeqCst);
+        self.shared.schedule(task);
+
+        TaskHandle { id, join, waker }
     }
 
-    /// Run the async runtime
+    /// Run the async runtime on `num_workers` threads, until it's stopped and
+    /// every task in flight has completed
     pub fn run(&self) {
-        *self.running.lock().unwrap() = true;
-
-        let mut workers = Vec::new();
-
-        for worker_id in 0..self.num_workers {
-            let ready_queue = Arc::clone(&self.ready_queue);
-            let running = Arc::clone(&self.running);
-
-            let handle = thread::spawn(move || {
-                worker_loop(worker_id, ready_queue, running);
-            });
-
-            workers.push(handle);
-        }
+        let workers: Vec<_> = (0..self.num_workers)
+            .map(|index| {
+                let shared = Arc::clone(&self.shared);
+                thread::spawn(move || worker_loop(index, shared))
+            })
+            .collect();
 
         // Wait for all workers to finish
         for worker in workers {
@@ -90,152 +285,218 @@ impl AsyncRuntime {
         }
     }
 
-    /// Stop the runtime
+    /// Stop the runtime gracefully: tasks in flight, and any they spawn, still
+    /// run to completion before `run` returns
     pub fn stop(&self) {
-        *self.running.lock().unwrap() = false;
+        self.shared.stopping.store(true, Ordering::SeqCst);
+        let _sleep = self.shared.parker.sleep.lock().unwrap();
+        self.shared.parker.wakeup.notify_all();
     }
 }
 
 /// Worker loop for processing tasks
-fn worker_loop(
-    worker_id: usize,
-    ready_queue: Arc<Mutex<VecDeque<Task>>>,
-    running: Arc<Mutex<bool>>,
-) {
+fn worker_loop(index: usize, shared: Arc<Shared>) {
+    WORKER.with(|worker| worker.set(Some((Arc::as_ptr(&shared) as usize, index))));
+    let _timers = shared.timers.enter();
     loop {
-        // Check if we should stop
-        if !*running.lock().unwrap() {
-            break;
+        shared.timers.fire_due();
+        if let Some(task) = shared.find_task(index) {
+            shared.run_task(task);
+            continue;
         }
 
-        // Try to get a task from the queue
-        let task = {
-            let mut queue = ready_queue.lock().unwrap();
-            queue.pop_front()
-        };
-
-        if let Some(mut task) = task {
-            // Poll the task
-            match (task.poll_fn)() {
-                Poll::Ready(()) => {
-                    // Task completed
-                    println!("Worker {}: Task {} completed", worker_id, task.id);
-                }
-                Poll::Pending => {
-                    // Task not ready, put it back in the queue
-                    ready_queue.lock().unwrap().push_back(task);
-                }
+        // Park until a task is queued, the next timer is due, or the runtime
+        // has finished
+        let sleep = shared.parker.sleep.lock().unwrap();
+        if shared.finished() {
+            break;
+        }
+        if shared.queued.load(Ordering::SeqCst) == 0 {
+            match shared.timers.park_timeout() {
+                Some(timeout) => drop(shared.parker.wakeup.wait_timeout(sleep, timeout).unwrap()),
+                None => drop(shared.parker.wakeup.wait(sleep).unwrap()),
             }
-        } else {
-            // No tasks available, sleep briefly
-            thread::sleep(Duration::from_millis(10));
         }
     }
+    WORKER.with(|worker| worker.set(None));
 }
 
-/// Handle to a spawned task
-pub struct TaskHandle {
-    #[allow(dead_code)]
-    id: usize,
+/// Why a task finished without producing its output
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JoinError {
+    /// The task was aborted before it completed
+    Cancelled,
+    /// The task panicked, with this message
+    Panicked(String),
+    /// The task returned this error, failing its scope
+    Failed(String),
 }
 
-/// Simple implementation of an async sleep
-pub struct Sleep {
-    deadline: std::time::Instant,
-}
-
-impl Sleep {
-    pub fn new(duration: Duration) -> Self {
-        Self {
-            deadline: std::time::Instant::now() + duration,
+impl std::fmt::Display for JoinError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JoinError::Cancelled => write!(f, "task was cancelled"),
+            JoinError::Panicked(message) => write!(f, "task panicked: {}", message),
+            JoinError::Failed(message) => write!(f, "task failed: {}", message),
         }
     }
 }
 
-impl Future for Sleep {
-    type Output = ();
+impl std::error::Error for JoinError {}
 
-    fn poll(&mut self) -> Poll<Self::Output> {
-        if std::time::Instant::now() >= self.deadline {
-            Poll::Ready(())
-        } else {
-            Poll::Pending
-        }
-    }
+/// Outcome of a task, shared by the task and its handle
+struct JoinState<T> {
+    /// Set by `abort`; the task stops at its next poll
+    cancelled: AtomicBool,
+    inner: Mutex<JoinInner<T>>,
 }
 
-/// Channel for async communication
-pub struct Channel<T> {
-    queue: Arc<Mutex<VecDeque<T>>>,
+struct JoinInner<T> {
+    /// The outcome, until the handle takes it
+    result: Option<Result<T, JoinError>>,
+    finished: bool,
+    /// Waker of a task awaiting the handle
+    joiner: Option<Waker>,
 }
 
-impl<T> Default for Channel<T> {
-    fn default() -> Self {
-        Self {
-            queue: Arc::new(Mutex::new(VecDeque::new())),
+impl<T> JoinState<T> {
+    /// Record the outcome of the task, if it doesn't have one yet
+    fn complete(&self, result: Result<T, JoinError>) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.finished {
+            return;
+        }
+        inner.result = Some(result);
+        inner.finished = true;
+        if let Some(joiner) = inner.joiner.take() {
+            joiner.wake();
         }
     }
 }
 
-impl<T> Channel<T> {
-    pub fn new() -> Self {
-        Self::default()
-    }
+/// A spawned future: runs it, catching panics, and hands its outcome to the
+/// task's handle. Cancellation is cooperative: an aborted task stops the next
+/// time it's polled, which is at its next wake.
+struct Spawned<F: Future> {
+    future: F,
+    join: Arc<JoinState<F::Output>>,
+}
 
-    pub fn sender(&self) -> Sender<T> {
-        Sender {
-            queue: Arc::clone(&self.queue),
-        }
-    }
+impl<F: Future> Future for Spawned<F> {
+    type Output = ();
 
-    pub fn receiver(&self) -> Receiver<T> {
-        Receiver {
-            queue: Arc::clone(&self.queue),
+    fn poll(&mut self, waker: &Waker) -> Poll<Self::Output> {
+        if self.join.cancelled.load(Ordering::SeqCst) {
+            self.join.complete(Err(JoinError::Cancelled));
+            return Poll::Ready(());
+        }
+        let future = &mut self.future;
+        match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| future.poll(waker))) {
+            Ok(Poll::Ready(output)) => self.join.complete(Ok(output)),
+            Ok(Poll::Pending) => return Poll::Pending,
+            Err(payload) => {
+                let message = panic_message(payload.as_ref());
+                self.join.complete(Err(JoinError::Panicked(message)));
+            }
         }
+        Poll::Ready(())
     }
 }
 
-/// Sender end of a channel
-pub struct Sender<T> {
-    queue: Arc<Mutex<VecDeque<T>>>,
+/// The message a panic was raised with
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "task panicked".to_string())
 }
 
-impl<T> Sender<T> {
-    pub fn send(&self, value: T) {
-        self.queue.lock().unwrap().push_back(value);
-    }
+/// Handle to a spawned task. Awaiting it gives the task's output, or why it
+/// has none; dropping it detaches the task, which keeps running.
+pub struct TaskHandle<T> {
+    id: usize,
+    join: Arc<JoinState<T>>,
+    /// Waker of the task, to poll it once aborted
+    waker: Waker,
 }
 
-/// Receiver end of a channel
-pub struct Receiver<T> {
-    queue: Arc<Mutex<VecDeque<T>>>,
-}
+impl<T> TaskHandle<T> {
+    /// Identifier of the task, unique within the process
+    pub fn id(&self) -> usize {
+        self.id
+    }
 
-impl<T> Receiver<T> {
-    pub fn try_recv(&self) -> Option<T> {
-        self.queue.lock().unwrap().pop_front()
+    /// Cancel the task. It stops at its next poll, dropping its future, and
+    /// awaiting the handle gives `JoinError::Cancelled` unless it had finished.
+    pub fn abort(&self) {
+        self.join.cancelled.store(true, Ordering::SeqCst);
+        self.waker.wake_by_ref();
     }
-}
 
-/// Future for receiving from a channel
-pub struct RecvFuture<T> {
-    receiver: Receiver<T>,
+    /// Whether the task has finished, by completing, panicking or being aborted
+    pub fn is_finished(&self) -> bool {
+        self.join.inner.lock().unwrap().finished
+    }
 }
 
-impl<T> RecvFuture<T> {
-    pub fn new(receiver: Receiver<T>) -> Self {
-        Self { receiver }
+impl<T> Future for TaskHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(&mut self, waker: &Waker) -> Poll<Self::Output> {
+        let mut inner = self.join.inner.lock().unwrap();
+        if let Some(result) = inner.result.take() {
+            return Poll::Ready(result);
+        }
+        assert!(!inner.finished, "TaskHandle polled after completion");
+        inner.joiner = Some(waker.clone());
+        Poll::Pending
     }
 }
 
-impl<T> Future for RecvFuture<T> {
-    type Output = Option<T>;
+/// Run a future to completion on the current thread, parking it while the
+/// future is pending. On virtual timers, time skips ahead to the next timer
+/// whenever nothing else woke the future.
+pub fn block_on<F: Future>(mut future: F) -> F::Output {
+    struct ThreadWaker {
+        thread: thread::Thread,
+        woken: AtomicBool,
+    }
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.woken.store(true, Ordering::SeqCst);
+            self.thread.unpark();
+        }
+    }
 
-    fn poll(&mut self) -> Poll<Self::Output> {
-        if let Some(value) = self.receiver.try_recv() {
-            Poll::Ready(Some(value))
+    let thread_waker = Arc::new(ThreadWaker {
+        thread: thread::current(),
+        woken: AtomicBool::new(false),
+    });
+    let waker = Waker::from(Arc::clone(&thread_waker));
+    loop {
+        if let Poll::Ready(output) = future.poll(&waker) {
+            return output;
+        }
+        if thread_waker.woken.swap(false, Ordering::SeqCst) {
+            continue;
+        }
+        let timers = Timers::current();
+        if timers.is_virtual() {
+            if timers.advance_to_next() {
+                continue;
+            }
+            thread::park();
+        } else if Arc::ptr_eq(&timers, &Timers::global()) {
+            thread::park();
         } else {
-            Poll::Pending
+            // Timers entered on this thread have no other thread to fire them
+            match timers.park_timeout() {
+                Some(timeout) => thread::park_timeout(timeout),
+                None => thread::park(),
+            }
+            timers.fire_due();
         }
     }
 }
@@ -271,13 +532,14 @@ pub mod io {
     impl Future for ReadFile {
         type Output = io::Result<String>;
 
-        fn poll(&mut self) -> Poll<Self::Output> {
+        fn poll(&mut self, waker: &Waker) -> Poll<Self::Output> {
             match &mut self.state {
                 ReadFileState::NotStarted => {
                     // Start reading in a background thread
                     let path = self.path.clone();
                     thread::spawn(move || fs::read_to_string(path));
                     self.state = ReadFileState::Reading;
+                    waker.wake_by_ref();
                     Poll::Pending
                 }
                 ReadFileState::Reading => {
@@ -285,6 +547,7 @@ pub mod io {
                     // For now, we'll do a blocking read
                     let result = fs::read_to_string(&self.path);
                     self.state = ReadFileState::Done(result);
+                    waker.wake_by_ref();
                     Poll::Pending
                 }
                 ReadFileState::Done(result) => {
@@ -325,12 +588,13 @@ pub mod io {
     impl Future for WriteFile {
         type Output = io::Result<()>;
 
-        fn poll(&mut self) -> Poll<Self::Output> {
+        fn poll(&mut self, waker: &Waker) -> Poll<Self::Output> {
             match &mut self.state {
                 WriteFileState::NotStarted => {
                     // In a real implementation, this would be async
                     let result = fs::write(&self.path, &self.content);
                     self.state = WriteFileState::Done(result);
+                    waker.wake_by_ref();
                     Poll::Pending
                 }
                 WriteFileState::Writing => Poll::Pending,
@@ -375,8 +639,8 @@ pub mod combinators {
     {
         type Output = U;
 
-        fn poll(&mut self) -> Poll<Self::Output> {
-            match self.future.poll() {
+        fn poll(&mut self, waker: &Waker) -> Poll<Self::Output> {
+            match self.future.poll(waker) {
                 Poll::Ready(value) => {
                     let mapper = self.mapper.take().expect("Map polled after completion");
                     Poll::Ready(mapper(value))
@@ -420,11 +684,11 @@ pub mod combinators {
     {
         type Output = (O1, O2);
 
-        fn poll(&mut self) -> Poll<Self::Output> {
+        fn poll(&mut self, waker: &Waker) -> Poll<Self::Output> {
             // Poll first future if not complete
             if self.output1.is_none() {
                 if let Some(ref mut f1) = self.future1 {
-                    if let Poll::Ready(value) = f1.poll() {
+                    if let Poll::Ready(value) = f1.poll(waker) {
                         self.output1 = Some(value);
                         self.future1 = None;
                     }
@@ -434,7 +698,7 @@ pub mod combinators {
             // Poll second future if not complete
             if self.output2.is_none() {
                 if let Some(ref mut f2) = self.future2 {
-                    if let Poll::Ready(value) = f2.poll() {
+                    if let Poll::Ready(value) = f2.poll(waker) {
                         self.output2 = Some(value);
                         self.future2 = None;
                     }
@@ -442,17 +706,114 @@ pub mod combinators {
             }
 
             // Check if both are complete
-            if let (Some(v1), Some(v2)) = (self.output1.take(), self.output2.take()) {
-                Poll::Ready((v1, v2))
+            if self.output1.is_some() && self.output2.is_some() {
+                Poll::Ready((self.output1.take().unwrap(), self.output2.take().unwrap()))
             } else {
-                // Restore values if we took them
-                if let Some(v1) = self.output1.take() {
-                    self.output1 = Some(v1);
+                Poll::Pending
+            }
+        }
+    }
+
+    /// Join any number of futures, completing with all their outputs in order
+    pub struct JoinAll<F: Future> {
+        futures: Vec<Option<F>>,
+        outputs: Vec<Option<F::Output>>,
+    }
+
+    /// Await every one of `futures`
+    pub fn join_all<F: Future>(futures: Vec<F>) -> JoinAll<F> {
+        let outputs = futures.iter().map(|_| None).collect();
+        JoinAll {
+            futures: futures.into_iter().map(Some).collect(),
+            outputs,
+        }
+    }
+
+    impl<F: Future> Future for JoinAll<F> {
+        type Output = Vec<F::Output>;
+
+        fn poll(&mut self, waker: &Waker) -> Poll<Self::Output> {
+            for (slot, output) in self.futures.iter_mut().zip(&mut self.outputs) {
+                if let Some(future) = slot {
+                    if let Poll::Ready(value) = future.poll(waker) {
+                        *output = Some(value);
+                        *slot = None;
+                    }
                 }
-                if let Some(v2) = self.output2.take() {
-                    self.output2 = Some(v2);
+            }
+            if self.outputs.iter().any(Option::is_none) {
+                return Poll::Pending;
+            }
+            Poll::Ready(
+                self.outputs
+                    .iter_mut()
+                    .map(|output| output.take().unwrap())
+                    .collect(),
+            )
+        }
+    }
+
+    /// Race futures, completing with the index and output of the first one
+    /// ready; the others are dropped with it
+    pub struct Select<F> {
+        futures: Vec<F>,
+    }
+
+    /// Await whichever of `futures` is ready first. Ties go to the earliest.
+    pub fn select<F: Future>(futures: Vec<F>) -> Select<F> {
+        assert!(!futures.is_empty(), "select needs at least one future");
+        Select { futures }
+    }
+
+    impl<F: Future> Future for Select<F> {
+        type Output = (usize, F::Output);
+
+        fn poll(&mut self, waker: &Waker) -> Poll<Self::Output> {
+            for (index, future) in self.futures.iter_mut().enumerate() {
+                if let Poll::Ready(value) = future.poll(waker) {
+                    return Poll::Ready((index, value));
                 }
-                Poll::Pending
+            }
+            Poll::Pending
+        }
+    }
+
+    /// The deadline of a `timeout` passed before its future completed
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Elapsed;
+
+    impl std::fmt::Display for Elapsed {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "deadline elapsed")
+        }
+    }
+
+    impl std::error::Error for Elapsed {}
+
+    /// A future that gives up once its deadline passes
+    pub struct Timeout<F> {
+        future: F,
+        sleep: Sleep,
+    }
+
+    /// Await `future` for at most `duration`
+    pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+        Timeout {
+            future,
+            sleep: Sleep::new(duration),
+        }
+    }
+
+    impl<F: Future> Future for Timeout<F> {
+        type Output = Result<F::Output, Elapsed>;
+
+        fn poll(&mut self, waker: &Waker) -> Poll<Self::Output> {
+            if let Poll::Ready(value) = self.future.poll(waker) {
+                return Poll::Ready(Ok(value));
+            }
+            match self.sleep.poll(waker) {
+                Poll::Ready(()) => Poll::Ready(Err(Elapsed)),
+                Poll::Pending => Poll::Pending,
             }
         }
     }
@@ -467,32 +828,309 @@ mod tests {
         let mut sleep = Sleep::new(Duration::from_millis(100));
 
         // Should be pending initially
-        match sleep.poll() {
+        match sleep.poll(Waker::noop()) {
             Poll::Pending => {}
             Poll::Ready(()) => panic!("Sleep should not be ready immediately"),
         }
 
         // Wait and poll again
         thread::sleep(Duration::from_millis(150));
-        match sleep.poll() {
+        match sleep.poll(Waker::noop()) {
             Poll::Ready(()) => {}
             Poll::Pending => panic!("Sleep should be ready after deadline"),
         }
     }
 
+    /// Pending `remaining` times, waking itself each time
+    struct Yield {
+        remaining: usize,
+        polls: Arc<AtomicUsize>,
+    }
+
+    impl Future for Yield {
+        type Output = ();
+
+        fn poll(&mut self, waker: &Waker) -> Poll<Self::Output> {
+            self.polls.fetch_add(1, Ordering::SeqCst);
+            if self.remaining == 0 {
+                return Poll::Ready(());
+            }
+            self.remaining -= 1;
+            waker.wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    /// Pending until its flag is set, keeping the waker to wake then
+    struct Gate {
+        open: Arc<AtomicBool>,
+        waker: Arc<Mutex<Option<Waker>>>,
+        polls: Arc<AtomicUsize>,
+    }
+
+    impl Future for Gate {
+        type Output = ();
+
+        fn poll(&mut self, waker: &Waker) -> Poll<Self::Output> {
+            self.polls.fetch_add(1, Ordering::SeqCst);
+            if self.open.load(Ordering::SeqCst) {
+                return Poll::Ready(());
+            }
+            *self.waker.lock().unwrap() = Some(waker.clone());
+            Poll::Pending
+        }
+    }
+
+    /// Run `runtime` on a thread of its own
+    fn start(runtime: &Arc<AsyncRuntime>) -> thread::JoinHandle<()> {
+        let runtime = Arc::clone(runtime);
+        thread::spawn(move || runtime.run())
+    }
+
+    #[test]
+    fn test_runtime_runs_woken_tasks() {
+        let runtime = Arc::new(AsyncRuntime::new(4));
+        let polls = Arc::new(AtomicUsize::new(0));
+        for _ in 0..100 {
+            runtime.spawn(Yield {
+                remaining: 3,
+                polls: Arc::clone(&polls),
+            });
+        }
+
+        let workers = start(&runtime);
+        runtime.stop();
+        workers.join().unwrap();
+        assert_eq!(polls.load(Ordering::SeqCst), 400);
+    }
+
+    #[test]
+    fn test_pending_tasks_wait_for_their_waker() {
+        let runtime = Arc::new(AsyncRuntime::new(2));
+        let open = Arc::new(AtomicBool::new(false));
+        let waker = Arc::new(Mutex::new(None::<Waker>));
+        let polls = Arc::new(AtomicUsize::new(0));
+        runtime.spawn(Gate {
+            open: Arc::clone(&open),
+            waker: Arc::clone(&waker),
+            polls: Arc::clone(&polls),
+        });
+
+        let workers = start(&runtime);
+        thread::sleep(Duration::from_millis(50));
+        // Polled once, then parked rather than spinning on it
+        assert_eq!(polls.load(Ordering::SeqCst), 1);
+
+        open.store(true, Ordering::SeqCst);
+        waker.lock().unwrap().take().unwrap().wake();
+        runtime.stop();
+        workers.join().unwrap();
+        assert_eq!(polls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_sleeping_tasks_are_not_polled_until_due() {
+        /// Sleeps once, creating the sleep on the worker's timers
+        struct Nap {
+            sleep: Option<Sleep>,
+            polls: Arc<AtomicUsize>,
+        }
+
+        impl Future for Nap {
+            type Output = ();
+
+            fn poll(&mut self, waker: &Waker) -> Poll<Self::Output> {
+                self.polls.fetch_add(1, Ordering::SeqCst);
+                self.sleep
+                    .get_or_insert_with(|| sleep(Duration::from_millis(30)))
+                    .poll(waker)
+            }
+        }
+
+        let runtime = Arc::new(AsyncRuntime::new(2));
+        let polls = Arc::new(AtomicUsize::new(0));
+        for _ in 0..10 {
+            runtime.spawn(Nap {
+                sleep: None,
+                polls: Arc::clone(&polls),
+            });
+        }
+
+        let started = std::time::Instant::now();
+        let workers = start(&runtime);
+        runtime.stop();
+        workers.join().unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(30));
+        // Polled when spawned, then once more when its timer fired
+        assert_eq!(polls.load(Ordering::SeqCst), 20);
+    }
+
+    #[test]
+    fn test_runtime_on_virtual_time() {
+        let timers = Timers::new_virtual();
+        let _guard = timers.enter();
+        let runtime = Arc::new(AsyncRuntime::with_timers(2, Arc::clone(&timers)));
+        let handle = runtime.spawn(combinators::timeout(
+            Duration::from_secs(60),
+            sleep_until(now() + Duration::from_secs(3600)),
+        ));
+
+        let workers = start(&runtime);
+        thread::sleep(Duration::from_millis(20));
+        assert!(!handle.is_finished());
+        timers.advance(Duration::from_secs(60));
+        runtime.stop();
+        workers.join().unwrap();
+        assert_eq!(block_on(handle), Ok(Err(combinators::Elapsed)));
+    }
+
+    #[test]
+    fn test_stop_drains_in_flight_tasks() {
+        struct Sleepy {
+            sleep: Sleep,
+            done: Arc<AtomicBool>,
+        }
+
+        impl Future for Sleepy {
+            type Output = ();
+
+            fn poll(&mut self, waker: &Waker) -> Poll<Self::Output> {
+                match self.sleep.poll(waker) {
+                    Poll::Ready(()) => {
+                        self.done.store(true, Ordering::SeqCst);
+                        Poll::Ready(())
+                    }
+                    Poll::Pending => Poll::Pending,
+                }
+            }
+        }
+
+        let runtime = Arc::new(AsyncRuntime::new(2));
+        let done = Arc::new(AtomicBool::new(false));
+        runtime.spawn(Sleepy {
+            sleep: Sleep::new(Duration::from_millis(50)),
+            done: Arc::clone(&done),
+        });
+
+        let workers = start(&runtime);
+        runtime.stop();
+        workers.join().unwrap();
+        assert!(done.load(Ordering::SeqCst));
+    }
+
+    /// Completes with `value` after being pending `remaining` times
+    struct Later {
+        remaining: usize,
+        value: i32,
+    }
+
+    impl Future for Later {
+        type Output = i32;
+
+        fn poll(&mut self, waker: &Waker) -> Poll<Self::Output> {
+            if self.remaining == 0 {
+                assert!(self.value >= 0, "negative value");
+                return Poll::Ready(self.value);
+            }
+            self.remaining -= 1;
+            waker.wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn test_task_handles_await_outputs() {
+        let runtime = Arc::new(AsyncRuntime::new(2));
+        let ok = runtime.spawn(Later {
+            remaining: 3,
+            value: 42,
+        });
+        let panics = runtime.spawn(Later {
+            remaining: 1,
+            value: -1,
+        });
+
+        let workers = start(&runtime);
+        assert_eq!(block_on(ok), Ok(42));
+        assert_eq!(
+            block_on(panics),
+            Err(JoinError::Panicked("negative value".to_string()))
+        );
+        runtime.stop();
+        workers.join().unwrap();
+    }
+
+    #[test]
+    fn test_abort_cancels_task() {
+        let runtime = Arc::new(AsyncRuntime::new(2));
+        let polls = Arc::new(AtomicUsize::new(0));
+        let handle = runtime.spawn(Gate {
+            open: Arc::new(AtomicBool::new(false)),
+            waker: Arc::new(Mutex::new(None)),
+            polls: Arc::clone(&polls),
+        });
+
+        let workers = start(&runtime);
+        thread::sleep(Duration::from_millis(20));
+        assert!(!handle.is_finished());
+        handle.abort();
+        assert_eq!(block_on(handle), Err(JoinError::Cancelled));
+        // Stopped without polling the future again
+        assert_eq!(polls.load(Ordering::SeqCst), 1);
+        runtime.stop();
+        workers.join().unwrap();
+    }
+
+    #[test]
+    fn test_join_all_select_and_timeout() {
+        use combinators::{join_all, select, timeout, Elapsed};
+
+        let later = |remaining, value| Later { remaining, value };
+        assert_eq!(
+            block_on(join_all(vec![later(3, 1), later(0, 2), later(1, 3)])),
+            vec![1, 2, 3]
+        );
+        assert_eq!(
+            block_on(select(vec![later(3, 1), later(1, 2), later(1, 3)])),
+            (1, 2)
+        );
+
+        let sleeps = vec![
+            Sleep::new(Duration::from_secs(5)),
+            Sleep::new(Duration::from_millis(5)),
+        ];
+        assert_eq!(block_on(select(sleeps)).0, 1);
+
+        let gate = Gate {
+            open: Arc::new(AtomicBool::new(false)),
+            waker: Arc::new(Mutex::new(None)),
+            polls: Arc::new(AtomicUsize::new(0)),
+        };
+        assert_eq!(
+            block_on(timeout(Duration::from_millis(10), gate)),
+            Err(Elapsed)
+        );
+        assert_eq!(
+            block_on(timeout(Duration::from_secs(5), later(2, 7))),
+            Ok(7)
+        );
+    }
+
     #[test]
     fn test_channel() {
-        let channel = Channel::<i32>::new();
-        let sender = channel.sender();
-        let receiver = channel.receiver();
+        let (sender, mut receiver) = mpsc::unbounded_channel::<i32>();
 
         // Send some values
-        sender.send(42);
-        sender.send(100);
+        sender.send(42).unwrap();
+        sender.send(100).unwrap();
 
         // Receive values
-        assert_eq!(receiver.try_recv(), Some(42));
-        assert_eq!(receiver.try_recv(), Some(100));
-        assert_eq!(receiver.try_recv(), None);
+        assert_eq!(receiver.try_recv(), Ok(42));
+        assert_eq!(receiver.try_recv(), Ok(100));
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+
+        // Dropping the last sender closes the channel
+        drop(sender);
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Closed));
     }
 }
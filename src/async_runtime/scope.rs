@@ -0,0 +1,291 @@
+// Structured concurrency for the async runtime
+// Scoped tasks borrow from their caller, and are all done before it resumes
+
+use super::{
+    panic_message, AsyncRuntime, Future, JoinError, JoinInner, JoinState, Poll, TaskHandle,
+};
+use std::marker::PhantomData;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::Waker;
+
+/// Tasks spawned in a scope of `AsyncRuntime::scope`. They may borrow anything
+/// that outlives the scope, since the scope doesn't end until they're done.
+pub struct Scope<'scope, 'env: 'scope> {
+    runtime: &'scope AsyncRuntime,
+    state: Arc<ScopeState>,
+    /// Invariant in both lifetimes, like `std::thread::Scope`
+    _scope: PhantomData<&'scope mut &'scope ()>,
+    _env: PhantomData<&'env mut &'env ()>,
+}
+
+/// Children of a scope, shared with each of them
+#[derive(Default)]
+struct ScopeState {
+    inner: Mutex<ScopeInner>,
+    /// Notified once no child is running
+    done: Condvar,
+}
+
+#[derive(Default)]
+struct ScopeInner {
+    /// Children whose future hasn't been dropped yet
+    running: usize,
+    /// Why the first child to fail did
+    failure: Option<JoinError>,
+    children: Vec<TaskHandle<()>>,
+}
+
+impl ScopeState {
+    /// Record the failure of a child, cancelling the others
+    fn fail(&self, error: JoinError) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.failure.is_none() {
+            inner.failure = Some(error);
+            inner.children.iter().for_each(TaskHandle::abort);
+        }
+    }
+}
+
+impl AsyncRuntime {
+    /// Run `f` with a scope to spawn tasks in, and wait until every one of them
+    /// is done. The first task to fail, by returning an error or panicking,
+    /// cancels the others, and is what the scope returns.
+    ///
+    /// The calling thread blocks while the tasks run, so this must be called
+    /// from outside the runtime's workers, while the runtime runs.
+    pub fn scope<'env, F, R>(&self, f: F) -> Result<R, JoinError>
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> R,
+    {
+        let scope = Scope {
+            runtime: self,
+            state: Arc::new(ScopeState::default()),
+            _scope: PhantomData,
+            _env: PhantomData,
+        };
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| f(&scope)));
+
+        // The tasks may borrow what `f` could, so they're done before returning,
+        // even when `f` panicked
+        let mut inner = scope.state.inner.lock().unwrap();
+        if result.is_err() {
+            inner.children.iter().for_each(TaskHandle::abort);
+        }
+        while inner.running > 0 {
+            inner = scope.state.done.wait(inner).unwrap();
+        }
+        let failure = inner.failure.take();
+        inner.children.clear();
+        drop(inner);
+
+        match (result, failure) {
+            (Err(payload), _) => std::panic::resume_unwind(payload),
+            (Ok(_), Some(error)) => Err(error),
+            (Ok(value), None) => Ok(value),
+        }
+    }
+}
+
+impl<'scope> Scope<'scope, '_> {
+    /// Spawn a task in the scope, returning a handle that can await its output.
+    /// An error it returns fails the scope, as `JoinError::Failed`.
+    pub fn spawn<F, T, E>(&'scope self, future: F) -> TaskHandle<T>
+    where
+        F: Future<Output = Result<T, E>> + Send + 'scope,
+        T: Send + 'scope,
+        E: std::fmt::Display,
+    {
+        let join = Arc::new(JoinState {
+            cancelled: AtomicBool::new(false),
+            inner: Mutex::new(JoinInner {
+                result: None,
+                finished: false,
+                joiner: None,
+            }),
+        });
+        let child: Box<dyn Future<Output = ()> + Send + 'scope> = Box::new(ScopedChild {
+            future: Some(future),
+            join: Arc::clone(&join),
+            scope: Arc::clone(&self.state),
+        });
+        // SAFETY: the scope doesn't end before every child is dropped, and a
+        // child drops its future first, so nothing it borrows is used after
+        // 'scope ends even though the runtime holds it as 'static
+        let child: Box<dyn Future<Output = ()> + Send + 'static> =
+            unsafe { std::mem::transmute(child) };
+
+        self.state.inner.lock().unwrap().running += 1;
+        let task = self.runtime.spawn(child);
+        let handle = TaskHandle {
+            id: task.id,
+            join,
+            waker: task.waker.clone(),
+        };
+        let mut inner = self.state.inner.lock().unwrap();
+        if inner.failure.is_some() {
+            task.abort();
+        }
+        inner.children.push(task);
+        handle
+    }
+}
+
+/// A task spawned in a scope: runs its future, reporting an error or panic to
+/// the scope, and counts as running in the scope until it's dropped
+struct ScopedChild<F, T> {
+    future: Option<F>,
+    join: Arc<JoinState<T>>,
+    scope: Arc<ScopeState>,
+}
+
+impl<F, T, E> Future for ScopedChild<F, T>
+where
+    F: Future<Output = Result<T, E>>,
+    E: std::fmt::Display,
+{
+    type Output = ();
+
+    fn poll(&mut self, waker: &Waker) -> Poll<Self::Output> {
+        let Some(future) = self.future.as_mut() else {
+            return Poll::Ready(());
+        };
+        let error =
+            match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| future.poll(waker))) {
+                Ok(Poll::Pending) => return Poll::Pending,
+                Ok(Poll::Ready(Ok(output))) => {
+                    self.join.complete(Ok(output));
+                    return Poll::Ready(());
+                }
+                Ok(Poll::Ready(Err(error))) => JoinError::Failed(error.to_string()),
+                Err(payload) => JoinError::Panicked(panic_message(payload.as_ref())),
+            };
+        self.join.complete(Err(error.clone()));
+        self.scope.fail(error);
+        Poll::Ready(())
+    }
+}
+
+impl<F, T> Drop for ScopedChild<F, T> {
+    fn drop(&mut self) {
+        // Dropped unfinished when the task was cancelled
+        self.future = None;
+        self.join.complete(Err(JoinError::Cancelled));
+        let mut inner = self.scope.inner.lock().unwrap();
+        inner.running -= 1;
+        if inner.running == 0 {
+            self.scope.done.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_runtime::block_on;
+    use std::thread;
+
+    /// Pending once, then stores `value` in the slot it borrows
+    struct Fill<'a> {
+        slot: &'a mut u64,
+        value: u64,
+        yielded: bool,
+    }
+
+    impl Future for Fill<'_> {
+        type Output = Result<u64, String>;
+
+        fn poll(&mut self, waker: &Waker) -> Poll<Self::Output> {
+            if !self.yielded {
+                self.yielded = true;
+                waker.wake_by_ref();
+                return Poll::Pending;
+            }
+            *self.slot = self.value;
+            Poll::Ready(Ok(self.value))
+        }
+    }
+
+    /// Pending until cancelled, without arranging to be woken
+    struct Forever;
+
+    impl Future for Forever {
+        type Output = Result<(), String>;
+
+        fn poll(&mut self, _waker: &Waker) -> Poll<Self::Output> {
+            Poll::Pending
+        }
+    }
+
+    /// Fails with `message` at its first poll
+    struct Fail(&'static str);
+
+    impl Future for Fail {
+        type Output = Result<(), String>;
+
+        fn poll(&mut self, _waker: &Waker) -> Poll<Self::Output> {
+            Poll::Ready(Err(self.0.to_string()))
+        }
+    }
+
+    fn start(runtime: &Arc<AsyncRuntime>) -> thread::JoinHandle<()> {
+        let runtime = Arc::clone(runtime);
+        thread::spawn(move || runtime.run())
+    }
+
+    #[test]
+    fn test_scoped_tasks_borrow_from_the_caller() {
+        let runtime = Arc::new(AsyncRuntime::new(2));
+        let worker = start(&runtime);
+
+        let mut slots = [0u64; 8];
+        let total = runtime
+            .scope(|scope| {
+                let handles: Vec<_> = slots
+                    .iter_mut()
+                    .zip(1..)
+                    .map(|(slot, value)| {
+                        scope.spawn(Fill {
+                            slot,
+                            value,
+                            yielded: false,
+                        })
+                    })
+                    .collect();
+                handles
+            })
+            .unwrap()
+            .into_iter()
+            .map(|handle| block_on(handle).unwrap())
+            .sum::<u64>();
+
+        assert_eq!(slots, [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(total, 36);
+        runtime.stop();
+        worker.join().unwrap();
+    }
+
+    #[test]
+    fn test_failing_task_cancels_its_siblings() {
+        let runtime = Arc::new(AsyncRuntime::new(2));
+        let worker = start(&runtime);
+
+        let mut slot = 0;
+        let mut pending = None;
+        let result = runtime.scope(|scope| {
+            scope.spawn(Fill {
+                slot: &mut slot,
+                value: 7,
+                yielded: false,
+            });
+            pending = Some(scope.spawn(Forever));
+            scope.spawn(Fail("boom"));
+        });
+
+        assert_eq!(result, Err(JoinError::Failed("boom".to_string())));
+        assert_eq!(block_on(pending.unwrap()), Err(JoinError::Cancelled));
+        assert!(slot == 0 || slot == 7);
+        runtime.stop();
+        worker.join().unwrap();
+    }
+}
@@ -0,0 +1,610 @@
+// Timers for the async runtime
+// A hierarchical timer wheel, on a real or a virtual clock
+
+use super::{Future, Poll};
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::task::Waker;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Slots per level of the wheel
+const SLOTS: usize = 64;
+/// Bits of a tick addressing a slot within a level
+const SLOT_BITS: u32 = 6;
+/// Levels of the wheel: level `l` has slots of `64^l` ticks (milliseconds),
+/// so together they reach about two years ahead
+const LEVELS: usize = 6;
+/// No timer is pending
+const NEVER: u64 = u64::MAX;
+
+/// Where the time of a wheel comes from
+enum Clock {
+    /// Wall-clock time since `start`
+    Real { start: Instant },
+    /// Time since `start` that only moves when advanced, for tests
+    Virtual {
+        start: Instant,
+        elapsed: Mutex<Duration>,
+    },
+}
+
+impl Clock {
+    fn start(&self) -> Instant {
+        match self {
+            Clock::Real { start } | Clock::Virtual { start, .. } => *start,
+        }
+    }
+
+    fn elapsed(&self) -> Duration {
+        match self {
+            Clock::Real { start } => start.elapsed(),
+            Clock::Virtual { elapsed, .. } => *elapsed.lock().unwrap(),
+        }
+    }
+}
+
+/// Threads waiting for the next deadline of a wheel, or for other work: the
+/// workers of a runtime, or the thread driving the global timers
+pub(super) struct Parker {
+    pub(super) sleep: Mutex<()>,
+    pub(super) wakeup: Condvar,
+}
+
+/// A timer registered with a wheel, fired once its deadline tick is reached
+struct TimerEntry {
+    deadline: u64,
+    state: Mutex<EntryState>,
+}
+
+struct EntryState {
+    fired: bool,
+    /// Dropped before firing; the wheel discards it when it gets there
+    cancelled: bool,
+    waker: Option<Waker>,
+}
+
+/// Hierarchical timer wheel. A timer goes in the level of the highest 6-bit
+/// digit in which its deadline differs from the ticks already processed, and
+/// cascades into lower levels as time reaches its slot.
+struct Wheel {
+    /// Ticks processed so far
+    elapsed: u64,
+    /// Timers of each slot of each level
+    levels: Vec<Vec<Vec<Arc<TimerEntry>>>>,
+    /// Timers too far ahead for the top level
+    overflow: Vec<Arc<TimerEntry>>,
+}
+
+impl Wheel {
+    fn new() -> Self {
+        Self {
+            elapsed: 0,
+            levels: (0..LEVELS)
+                .map(|_| (0..SLOTS).map(|_| Vec::new()).collect())
+                .collect(),
+            overflow: Vec::new(),
+        }
+    }
+
+    /// Level of a timer due at `deadline`, after the ticks processed so far
+    fn level_for(&self, deadline: u64) -> usize {
+        let differing = (self.elapsed ^ deadline) | (SLOTS as u64 - 1);
+        ((63 - differing.leading_zeros()) / SLOT_BITS) as usize
+    }
+
+    /// Add a timer due after the ticks processed so far
+    fn insert(&mut self, entry: Arc<TimerEntry>) {
+        let level = self.level_for(entry.deadline);
+        if level >= LEVELS {
+            self.overflow.push(entry);
+            return;
+        }
+        let slot = (entry.deadline >> (SLOT_BITS * level as u32)) as usize % SLOTS;
+        self.levels[level][slot].push(entry);
+    }
+
+    /// First tick at which a slot holding timers is reached, with its level
+    /// and slot. Timers of a level above 0 aren't due yet then, only moved
+    /// down a level; level `LEVELS` stands for the overflow list, reached at
+    /// the end of the top level.
+    fn next_expiration(&self) -> Option<(u64, usize, usize)> {
+        let slot = (0..LEVELS).find_map(|level| {
+            let width = 1u64 << (SLOT_BITS * level as u32);
+            let block = self.elapsed - self.elapsed % (width * SLOTS as u64);
+            let current = (self.elapsed / width) as usize % SLOTS;
+            (current + 1..SLOTS)
+                .find(|&slot| !self.levels[level][slot].is_empty())
+                .map(|slot| (block + slot as u64 * width, level, slot))
+        });
+        slot.or_else(|| {
+            let span = 1u64 << (SLOT_BITS * LEVELS as u32);
+            let end = self.elapsed - self.elapsed % span + span;
+            (!self.overflow.is_empty()).then_some((end, LEVELS, 0))
+        })
+    }
+
+    /// Process the ticks up to `now`, collecting the wakers of timers due
+    fn advance(&mut self, now: u64, fired: &mut Vec<Waker>) {
+        while let Some((tick, level, slot)) = self.next_expiration() {
+            if tick > now {
+                break;
+            }
+            self.elapsed = tick;
+            let entries = match self.levels.get_mut(level) {
+                Some(slots) => std::mem::take(&mut slots[slot]),
+                None => std::mem::take(&mut self.overflow),
+            };
+            for entry in entries {
+                self.expire_or_insert(entry, fired);
+            }
+        }
+        self.elapsed = self.elapsed.max(now);
+    }
+
+    fn expire_or_insert(&mut self, entry: Arc<TimerEntry>, fired: &mut Vec<Waker>) {
+        if entry.deadline > self.elapsed {
+            if !entry.state.lock().unwrap().cancelled {
+                self.insert(entry);
+            }
+            return;
+        }
+        let mut state = entry.state.lock().unwrap();
+        state.fired = true;
+        fired.extend(state.waker.take());
+    }
+}
+
+/// A timer wheel and the clock it runs on. Each thread has current timers
+/// that `sleep` and `interval` register with: those of the runtime on its
+/// workers, those entered with `enter`, and otherwise global timers driven by
+/// a thread of their own.
+pub struct Timers {
+    clock: Clock,
+    wheel: Mutex<Wheel>,
+    /// Tick at which the wheel next has work, or `NEVER`
+    next: AtomicU64,
+    pub(super) parker: Arc<Parker>,
+}
+
+thread_local! {
+    /// Timers entered on this thread
+    static CURRENT: RefCell<Option<Arc<Timers>>> = const { RefCell::new(None) };
+}
+
+impl Timers {
+    /// Timers on the real clock, fired by whichever threads park on them
+    pub fn new() -> Arc<Self> {
+        Self::with_clock(Clock::Real {
+            start: Instant::now(),
+        })
+    }
+
+    /// Timers on a virtual clock, which stands still until advanced. Time
+    /// spent sleeping costs nothing, so timing logic tests deterministically.
+    pub fn new_virtual() -> Arc<Self> {
+        Self::with_clock(Clock::Virtual {
+            start: Instant::now(),
+            elapsed: Mutex::new(Duration::ZERO),
+        })
+    }
+
+    fn with_clock(clock: Clock) -> Arc<Self> {
+        Arc::new(Self {
+            clock,
+            wheel: Mutex::new(Wheel::new()),
+            next: AtomicU64::new(NEVER),
+            parker: Arc::new(Parker {
+                sleep: Mutex::new(()),
+                wakeup: Condvar::new(),
+            }),
+        })
+    }
+
+    /// The timers of threads that haven't entered any, on the real clock
+    pub fn global() -> Arc<Self> {
+        static GLOBAL: OnceLock<Arc<Timers>> = OnceLock::new();
+        let timers = GLOBAL.get_or_init(|| {
+            let timers = Timers::new();
+            let driven = Arc::clone(&timers);
+            thread::Builder::new()
+                .name("palladium-timer".to_string())
+                .spawn(move || driven.drive())
+                .expect("failed to start the timer thread");
+            timers
+        });
+        Arc::clone(timers)
+    }
+
+    /// The current timers of this thread
+    pub fn current() -> Arc<Self> {
+        CURRENT
+            .with(|current| current.borrow().clone())
+            .unwrap_or_else(Self::global)
+    }
+
+    /// Make these the current timers of this thread until the guard is dropped
+    pub fn enter(self: &Arc<Self>) -> TimersGuard {
+        let previous = CURRENT.with(|current| current.replace(Some(Arc::clone(self))));
+        TimersGuard { previous }
+    }
+
+    /// Whether the clock is virtual
+    pub fn is_virtual(&self) -> bool {
+        matches!(self.clock, Clock::Virtual { .. })
+    }
+
+    /// The current time on this clock
+    pub fn now(&self) -> Instant {
+        self.clock.start() + self.clock.elapsed()
+    }
+
+    /// Move a virtual clock forward, firing the timers due by then
+    pub fn advance(&self, duration: Duration) {
+        match &self.clock {
+            Clock::Virtual { elapsed, .. } => *elapsed.lock().unwrap() += duration,
+            Clock::Real { .. } => panic!("only virtual time can be advanced"),
+        }
+        self.fire_due();
+    }
+
+    /// Move a virtual clock to the next tick at which a timer may be due,
+    /// returning false if no timer is pending
+    pub fn advance_to_next(&self) -> bool {
+        let next = self.next.load(Ordering::SeqCst);
+        if next == NEVER {
+            return false;
+        }
+        let now = self.now_ticks();
+        self.advance(Duration::from_millis(next.saturating_sub(now)));
+        true
+    }
+
+    /// Milliseconds elapsed on the clock, rounded down
+    fn now_ticks(&self) -> u64 {
+        self.clock.elapsed().as_millis() as u64
+    }
+
+    /// Tick of `deadline`, rounded up so timers never fire early
+    fn tick_at(&self, deadline: Instant) -> u64 {
+        let since_start = deadline.saturating_duration_since(self.clock.start());
+        since_start.as_nanos().div_ceil(1_000_000) as u64
+    }
+
+    fn instant_at(&self, tick: u64) -> Instant {
+        self.clock.start() + Duration::from_millis(tick)
+    }
+
+    /// Register a timer due at `deadline`, to wake `waker`
+    fn register(&self, deadline: u64, waker: &Waker) -> Arc<TimerEntry> {
+        let entry = Arc::new(TimerEntry {
+            deadline,
+            state: Mutex::new(EntryState {
+                fired: false,
+                cancelled: false,
+                waker: Some(waker.clone()),
+            }),
+        });
+        let earlier = {
+            let mut wheel = self.wheel.lock().unwrap();
+            if deadline <= wheel.elapsed {
+                entry.state.lock().unwrap().fired = true;
+                return entry;
+            }
+            wheel.insert(Arc::clone(&entry));
+            let next = wheel.next_expiration().map_or(NEVER, |(tick, _, _)| tick);
+            next < self.next.swap(next, Ordering::SeqCst)
+        };
+        // Threads parked until a later deadline wait for this one instead
+        if earlier {
+            let _sleep = self.parker.sleep.lock().unwrap();
+            self.parker.wakeup.notify_all();
+        }
+        entry
+    }
+
+    /// Fire the timers that are due, waking their tasks
+    pub(super) fn fire_due(&self) {
+        let now = self.now_ticks();
+        if now < self.next.load(Ordering::SeqCst) {
+            return;
+        }
+        let mut fired = Vec::new();
+        {
+            let mut wheel = self.wheel.lock().unwrap();
+            wheel.advance(now, &mut fired);
+            let next = wheel.next_expiration().map_or(NEVER, |(tick, _, _)| tick);
+            self.next.store(next, Ordering::SeqCst);
+        }
+        for waker in fired {
+            waker.wake();
+        }
+    }
+
+    /// How long a thread parking on these timers may sleep before one is due:
+    /// `None` when none is pending, or the clock is virtual and only moves
+    /// when advanced
+    pub(super) fn park_timeout(&self) -> Option<Duration> {
+        let next = self.next.load(Ordering::SeqCst);
+        if next == NEVER || self.is_virtual() {
+            return None;
+        }
+        Some(Duration::from_millis(next.saturating_sub(self.now_ticks())))
+    }
+
+    /// Fire timers as they fall due, forever
+    fn drive(&self) {
+        loop {
+            self.fire_due();
+            let sleep = self.parker.sleep.lock().unwrap();
+            match self.park_timeout() {
+                Some(timeout) => drop(self.parker.wakeup.wait_timeout(sleep, timeout).unwrap()),
+                None => drop(self.parker.wakeup.wait(sleep).unwrap()),
+            }
+        }
+    }
+}
+
+/// Restores the previously current timers of a thread when dropped
+pub struct TimersGuard {
+    previous: Option<Arc<Timers>>,
+}
+
+impl Drop for TimersGuard {
+    fn drop(&mut self) {
+        CURRENT.with(|current| *current.borrow_mut() = self.previous.take());
+    }
+}
+
+/// The current time on the current timers' clock
+pub fn now() -> Instant {
+    Timers::current().now()
+}
+
+/// A future completing once a deadline passes. The task sleeping on it isn't
+/// polled until then: its timer wakes it.
+pub struct Sleep {
+    timers: Arc<Timers>,
+    deadline: u64,
+    entry: Option<Arc<TimerEntry>>,
+}
+
+impl Sleep {
+    pub fn new(duration: Duration) -> Self {
+        let timers = Timers::current();
+        let deadline = timers.tick_at(timers.now() + duration);
+        Self::at_tick(timers, deadline)
+    }
+
+    /// Sleep until `deadline`, on the current timers' clock
+    pub fn until(deadline: Instant) -> Self {
+        let timers = Timers::current();
+        let deadline = timers.tick_at(deadline);
+        Self::at_tick(timers, deadline)
+    }
+
+    fn at_tick(timers: Arc<Timers>, deadline: u64) -> Self {
+        Self {
+            timers,
+            deadline,
+            entry: None,
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(&mut self, waker: &Waker) -> Poll<Self::Output> {
+        if self.timers.now_ticks() >= self.deadline {
+            return Poll::Ready(());
+        }
+        match &self.entry {
+            Some(entry) => {
+                let mut state = entry.state.lock().unwrap();
+                if state.fired {
+                    return Poll::Ready(());
+                }
+                state.waker = Some(waker.clone());
+            }
+            None => {
+                let entry = self.timers.register(self.deadline, waker);
+                let fired = entry.state.lock().unwrap().fired;
+                self.entry = Some(entry);
+                if fired {
+                    return Poll::Ready(());
+                }
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(entry) = &self.entry {
+            let mut state = entry.state.lock().unwrap();
+            state.cancelled = true;
+            state.waker = None;
+        }
+    }
+}
+
+/// Sleep for `duration`
+pub fn sleep(duration: Duration) -> Sleep {
+    Sleep::new(duration)
+}
+
+/// Sleep until `deadline`
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep::until(deadline)
+}
+
+/// Ticks every `period`, the first time immediately. Ticks missed while the
+/// task was busy are skipped rather than delivered in a burst.
+pub struct Interval {
+    timers: Arc<Timers>,
+    period: u64,
+    next: u64,
+    sleep: Option<Sleep>,
+}
+
+/// Tick every `period`, which is rounded up to a whole millisecond
+pub fn interval(period: Duration) -> Interval {
+    let timers = Timers::current();
+    let period = (period.as_nanos().div_ceil(1_000_000) as u64).max(1);
+    let next = timers.tick_at(timers.now());
+    Interval {
+        timers,
+        period,
+        next,
+        sleep: None,
+    }
+}
+
+impl Interval {
+    /// A future of the next tick, giving the time it was due
+    pub fn tick(&mut self) -> Tick<'_> {
+        Tick { interval: self }
+    }
+
+    fn poll_tick(&mut self, waker: &Waker) -> Poll<Instant> {
+        let (timers, next) = (&self.timers, self.next);
+        let sleep = self
+            .sleep
+            .get_or_insert_with(|| Sleep::at_tick(Arc::clone(timers), next));
+        if let Poll::Pending = sleep.poll(waker) {
+            return Poll::Pending;
+        }
+        self.sleep = None;
+        let due = self.next;
+        let missed = self.timers.now_ticks().saturating_sub(due) / self.period;
+        self.next = due + (missed + 1) * self.period;
+        Poll::Ready(self.timers.instant_at(due))
+    }
+}
+
+/// The next tick of an `Interval`
+pub struct Tick<'a> {
+    interval: &'a mut Interval,
+}
+
+impl Future for Tick<'_> {
+    type Output = Instant;
+
+    fn poll(&mut self, waker: &Waker) -> Poll<Self::Output> {
+        self.interval.poll_tick(waker)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_runtime::block_on;
+
+    #[test]
+    fn test_wheel_fires_in_deadline_order() {
+        let mut wheel = Wheel::new();
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        let deadlines = [5u64, 70, 64, 4_100, 300_000, 1];
+        for &deadline in &deadlines {
+            struct Record(u64, Arc<Mutex<Vec<u64>>>);
+            impl std::task::Wake for Record {
+                fn wake(self: Arc<Self>) {
+                    self.1.lock().unwrap().push(self.0);
+                }
+            }
+            let waker = Waker::from(Arc::new(Record(deadline, Arc::clone(&fired))));
+            wheel.insert(Arc::new(TimerEntry {
+                deadline,
+                state: Mutex::new(EntryState {
+                    fired: false,
+                    cancelled: false,
+                    waker: Some(waker),
+                }),
+            }));
+        }
+
+        // Each tick only fires the timers due by then
+        for now in [0, 1, 69, 70, 5_000, 1_000_000] {
+            let mut wakers = Vec::new();
+            wheel.advance(now, &mut wakers);
+            wakers.into_iter().for_each(Waker::wake);
+            let fired = fired.lock().unwrap();
+            assert!(fired.iter().all(|&deadline| deadline <= now), "{:?}", fired);
+            let due = deadlines
+                .iter()
+                .filter(|&&deadline| deadline <= now)
+                .count();
+            assert_eq!(fired.len(), due);
+        }
+        assert_eq!(*fired.lock().unwrap(), vec![1, 5, 64, 70, 4_100, 300_000]);
+        assert!(wheel.next_expiration().is_none());
+    }
+
+    #[test]
+    fn test_virtual_time_backoff() {
+        let timers = Timers::new_virtual();
+        let _guard = timers.enter();
+        let start = now();
+        let real_start = Instant::now();
+
+        // Retry with exponential backoff: 100ms, 200ms, 400ms, 800ms
+        struct Backoff {
+            attempts: u32,
+            sleep: Option<Sleep>,
+        }
+
+        impl Future for Backoff {
+            type Output = u32;
+
+            fn poll(&mut self, waker: &Waker) -> Poll<Self::Output> {
+                loop {
+                    if let Some(sleep) = &mut self.sleep {
+                        if let Poll::Pending = sleep.poll(waker) {
+                            return Poll::Pending;
+                        }
+                    }
+                    if self.attempts == 4 {
+                        return Poll::Ready(self.attempts);
+                    }
+                    let delay = Duration::from_millis(100 << self.attempts);
+                    self.sleep = Some(sleep(delay));
+                    self.attempts += 1;
+                }
+            }
+        }
+
+        let attempts = block_on(Backoff {
+            attempts: 0,
+            sleep: None,
+        });
+        assert_eq!(attempts, 4);
+        assert_eq!(now() - start, Duration::from_millis(1_500));
+        assert!(real_start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_interval_skips_missed_ticks() {
+        let timers = Timers::new_virtual();
+        let _guard = timers.enter();
+        let start = now();
+        let mut ticks = interval(Duration::from_millis(10));
+
+        assert_eq!(block_on(ticks.tick()), start);
+        assert_eq!(block_on(ticks.tick()), start + Duration::from_millis(10));
+
+        // Busy for 35ms: the overdue tick at 20 fires at once, and those at
+        // 30 and 40 are skipped
+        timers.advance(Duration::from_millis(35));
+        assert_eq!(block_on(ticks.tick()), start + Duration::from_millis(20));
+        assert_eq!(now() - start, Duration::from_millis(45));
+        assert_eq!(block_on(ticks.tick()), start + Duration::from_millis(50));
+        assert_eq!(now() - start, Duration::from_millis(50));
+
+        let deadline = now() + Duration::from_millis(25);
+        block_on(sleep_until(deadline));
+        assert_eq!(now(), deadline);
+    }
+}
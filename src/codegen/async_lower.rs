@@ -0,0 +1,734 @@
+// Lowering of async function bodies for the C backend
+//
+// An async function compiles to a future: a struct holding its parameters,
+// its locals and the future it is waiting on, and a poll function that runs
+// the body until an awaited future is pending. Polling again jumps back to
+// that await, so this pass prepares the body for it:
+//
+// - every `.await` becomes a statement of its own, `let slot = future.await;`
+//   or `future.await;`, evaluated before the rest of its statement
+// - parameters and `let` locals are renamed to unique names, which the poll
+//   function keeps in the future so they survive a suspension
+// - a range `for` loop that awaits becomes a `while` loop over frame locals
+//
+// Match arm bindings and the variables of other loops stay C locals, so an
+// arm or such a loop body can't await.
+
+use crate::ast::*;
+use crate::errors::{CompileError, Result};
+use std::collections::HashMap;
+
+/// Prefix of the locals an async function keeps in its future
+pub const FRAME_PREFIX: &str = "__pd_local_";
+
+/// Rewrite an async function so that each `.await` is a statement of its own
+/// and the locals that live in its future have unique names
+pub fn lower_async_function(func: &Function) -> Result<Function> {
+    let mut lowering = AsyncLowering {
+        scopes: vec![HashMap::new()],
+        locals: 0,
+    };
+    let mut lowered = func.clone();
+    for param in &mut lowered.params {
+        param.name = lowering.declare(&param.name);
+    }
+    lowered.body = lowering.lower_block(&func.body)?;
+    Ok(lowered)
+}
+
+/// Whether a local holds the output of an await moved out of its statement
+pub fn is_await_slot(name: &str) -> bool {
+    name.starts_with(FRAME_PREFIX) && name.ends_with("_await")
+}
+
+struct AsyncLowering {
+    /// Names in scope, innermost last: the name a local was renamed to,
+    /// or None for a binding that stays a C local
+    scopes: Vec<HashMap<String, Option<String>>>,
+    /// Number of locals renamed so far
+    locals: usize,
+}
+
+impl AsyncLowering {
+    /// Bring `name` into scope as a local of the future
+    fn declare(&mut self, name: &str) -> String {
+        let renamed = self.fresh(name);
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.to_string(), Some(renamed.clone()));
+        }
+        renamed
+    }
+
+    /// Bring `name` into scope as a C local of a match arm or loop
+    fn declare_plain(&mut self, name: &str) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.to_string(), None);
+        }
+    }
+
+    /// A new name for a local of the future
+    fn fresh(&mut self, name: &str) -> String {
+        self.locals += 1;
+        format!("{}{}_{}", FRAME_PREFIX, self.locals, name)
+    }
+
+    /// The name a local is known by in the lowered body
+    fn resolve(&self, name: &str) -> String {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name))
+            .and_then(|renamed| renamed.clone())
+            .unwrap_or_else(|| name.to_string())
+    }
+
+    fn lower_block(&mut self, body: &[Stmt]) -> Result<Vec<Stmt>> {
+        self.scopes.push(HashMap::new());
+        let mut lowered = Vec::new();
+        for stmt in body {
+            self.lower_stmt(stmt, &mut lowered)?;
+        }
+        self.scopes.pop();
+        Ok(lowered)
+    }
+
+    /// Lower a statement, pushing it after the awaits it contains onto `out`
+    fn lower_stmt(&mut self, stmt: &Stmt, out: &mut Vec<Stmt>) -> Result<()> {
+        let lowered = match stmt {
+            Stmt::Expr(Expr::Await { expr, span }) => Stmt::Expr(Expr::Await {
+                expr: Box::new(self.lower_expr(expr, out)?),
+                span: *span,
+            }),
+            Stmt::Expr(expr) => Stmt::Expr(self.lower_expr(expr, out)?),
+            Stmt::Return(value) => Stmt::Return(
+                value
+                    .as_ref()
+                    .map(|value| self.lower_expr(value, out))
+                    .transpose()?,
+            ),
+            Stmt::Let {
+                name,
+                ty,
+                value,
+                mutable,
+                span,
+            } => {
+                let value = match value {
+                    Expr::Await { expr, span } => Expr::Await {
+                        expr: Box::new(self.lower_expr(expr, out)?),
+                        span: *span,
+                    },
+                    _ => self.lower_expr(value, out)?,
+                };
+                Stmt::Let {
+                    name: self.declare(name),
+                    ty: ty.clone(),
+                    value,
+                    mutable: *mutable,
+                    span: *span,
+                }
+            }
+            Stmt::Assign {
+                target,
+                value,
+                span,
+            } => {
+                let value = self.lower_expr(value, out)?;
+                let target = match target {
+                    AssignTarget::Ident(name) => AssignTarget::Ident(self.resolve(name)),
+                    AssignTarget::Index { array, index } => AssignTarget::Index {
+                        array: Box::new(self.lower_expr(array, out)?),
+                        index: Box::new(self.lower_expr(index, out)?),
+                    },
+                    AssignTarget::FieldAccess { object, field } => AssignTarget::FieldAccess {
+                        object: Box::new(self.lower_expr(object, out)?),
+                        field: field.clone(),
+                    },
+                    AssignTarget::Deref { expr } => AssignTarget::Deref {
+                        expr: Box::new(self.lower_expr(expr, out)?),
+                    },
+                };
+                Stmt::Assign {
+                    target,
+                    value,
+                    span: *span,
+                }
+            }
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+                span,
+                then_end,
+                else_end,
+            } => Stmt::If {
+                condition: self.lower_expr(condition, out)?,
+                then_branch: self.lower_block(then_branch)?,
+                else_branch: else_branch
+                    .as_ref()
+                    .map(|branch| self.lower_block(branch))
+                    .transpose()?,
+                span: *span,
+                then_end: *then_end,
+                else_end: *else_end,
+            },
+            Stmt::While {
+                condition,
+                body,
+                span,
+                body_end,
+            } => {
+                // A condition that awaits is evaluated at the top of every iteration
+                let mut awaits = Vec::new();
+                let condition = self.lower_expr(condition, &mut awaits)?;
+                let mut body = self.lower_block(body)?;
+                if awaits.is_empty() {
+                    Stmt::While {
+                        condition,
+                        body,
+                        span: *span,
+                        body_end: *body_end,
+                    }
+                } else {
+                    awaits.push(Stmt::If {
+                        condition: Expr::Unary {
+                            op: UnaryOp::Not,
+                            operand: Box::new(condition),
+                            span: *span,
+                        },
+                        then_branch: vec![Stmt::Break { span: *span }],
+                        else_branch: None,
+                        span: *span,
+                        then_end: *span,
+                        else_end: None,
+                    });
+                    awaits.append(&mut body);
+                    Stmt::While {
+                        condition: Expr::Bool(true),
+                        body: awaits,
+                        span: *span,
+                        body_end: *body_end,
+                    }
+                }
+            }
+            Stmt::For {
+                var,
+                pair,
+                iter,
+                body,
+                span,
+                ..
+            } if body.iter().any(stmt_awaits) => {
+                let (Expr::Range { start, end, .. }, None) = (iter, pair) else {
+                    return Err(CompileError::Generic(
+                        "'.await' inside a for loop over a collection or iterator isn't supported yet; loop over a range of indices instead".to_string(),
+                    ));
+                };
+                self.lower_range_loop(var, start, end, body, *span, out)?
+            }
+            Stmt::For {
+                var,
+                pair,
+                iter,
+                body,
+                span,
+                body_end,
+            } => {
+                let iter = self.lower_expr(iter, out)?;
+                self.scopes.push(HashMap::new());
+                self.declare_plain(var);
+                if let Some(pair) = pair {
+                    self.declare_plain(pair);
+                }
+                let body = self.lower_block(body);
+                self.scopes.pop();
+                Stmt::For {
+                    var: var.clone(),
+                    pair: pair.clone(),
+                    iter,
+                    body: body?,
+                    span: *span,
+                    body_end: *body_end,
+                }
+            }
+            Stmt::Match { expr, arms, span } => {
+                let expr = self.lower_expr(expr, out)?;
+                let mut lowered_arms = Vec::new();
+                for arm in arms {
+                    if arm.body.iter().any(stmt_awaits) {
+                        return Err(CompileError::Generic(
+                            "'.await' inside a match arm isn't supported yet; bind what the arm needs and await after the match".to_string(),
+                        ));
+                    }
+                    self.scopes.push(HashMap::new());
+                    let mut bindings = Vec::new();
+                    pattern_bindings(&arm.pattern, &mut bindings);
+                    for binding in bindings {
+                        self.declare_plain(binding);
+                    }
+                    let body = self.lower_block(&arm.body);
+                    self.scopes.pop();
+                    lowered_arms.push(MatchArm {
+                        pattern: arm.pattern.clone(),
+                        body: body?,
+                        end: arm.end,
+                    });
+                }
+                Stmt::Match {
+                    expr,
+                    arms: lowered_arms,
+                    span: *span,
+                }
+            }
+            Stmt::Unsafe {
+                body,
+                span,
+                body_end,
+            } => Stmt::Unsafe {
+                body: self.lower_block(body)?,
+                span: *span,
+                body_end: *body_end,
+            },
+            Stmt::AsyncScope {
+                body,
+                span,
+                body_end,
+            } => Stmt::AsyncScope {
+                body: self.lower_block(body)?,
+                span: *span,
+                body_end: *body_end,
+            },
+            // The handlers live in the poll function, so they can't outlast an await
+            Stmt::Handle { body, .. } if body.iter().any(stmt_awaits) => {
+                return Err(CompileError::Generic(
+                    "'.await' is not yet supported inside handle blocks".to_string(),
+                ));
+            }
+            Stmt::Handle {
+                body,
+                handlers,
+                span,
+                body_end,
+            } => Stmt::Handle {
+                body: self.lower_block(body)?,
+                handlers: handlers.clone(),
+                span: *span,
+                body_end: *body_end,
+            },
+            Stmt::Break { .. } | Stmt::Continue { .. } => stmt.clone(),
+        };
+        out.push(lowered);
+        Ok(())
+    }
+
+    /// `for var in start..end { body }` as a loop whose counter lives in the future:
+    /// `let next = start; let end = end;`
+    /// `while next < end { let var = next; next = next + 1; body }`
+    fn lower_range_loop(
+        &mut self,
+        var: &str,
+        start: &Expr,
+        end: &Expr,
+        body: &[Stmt],
+        span: crate::errors::Span,
+        out: &mut Vec<Stmt>,
+    ) -> Result<Stmt> {
+        let start = self.lower_expr(start, out)?;
+        let end = self.lower_expr(end, out)?;
+        let next = self.fresh("next");
+        let limit = self.fresh("end");
+        let ident = |name: &String| Expr::Ident(name.clone());
+        for (name, value) in [(&next, start), (&limit, end)] {
+            out.push(Stmt::Let {
+                name: name.clone(),
+                ty: Some(Type::I64),
+                value,
+                mutable: true,
+                span,
+            });
+        }
+
+        self.scopes.push(HashMap::new());
+        let mut loop_body = vec![
+            Stmt::Let {
+                name: self.declare(var),
+                ty: Some(Type::I64),
+                value: ident(&next),
+                mutable: false,
+                span,
+            },
+            Stmt::Assign {
+                target: AssignTarget::Ident(next.clone()),
+                value: Expr::Binary {
+                    left: Box::new(ident(&next)),
+                    op: BinOp::Add,
+                    right: Box::new(Expr::Integer(1)),
+                    span,
+                },
+                span,
+            },
+        ];
+        let lowered = body
+            .iter()
+            .try_for_each(|stmt| self.lower_stmt(stmt, &mut loop_body));
+        self.scopes.pop();
+        lowered?;
+
+        Ok(Stmt::While {
+            condition: Expr::Binary {
+                left: Box::new(ident(&next)),
+                op: BinOp::Lt,
+                right: Box::new(ident(&limit)),
+                span,
+            },
+            body: loop_body,
+            span,
+            body_end: span,
+        })
+    }
+
+    /// Lower an expression, moving the awaits in it onto `out` as statements
+    fn lower_expr(&mut self, expr: &Expr, out: &mut Vec<Stmt>) -> Result<Expr> {
+        let boxed = |lowering: &mut Self, expr: &Expr, out: &mut Vec<Stmt>| {
+            lowering.lower_expr(expr, out).map(Box::new)
+        };
+        Ok(match expr {
+            Expr::Ident(name) => Expr::Ident(self.resolve(name)),
+            Expr::Await { expr, span } => {
+                let future = boxed(self, expr, out)?;
+                let slot = self.fresh("await");
+                out.push(Stmt::Let {
+                    name: slot.clone(),
+                    ty: None,
+                    value: Expr::Await {
+                        expr: future,
+                        span: *span,
+                    },
+                    mutable: false,
+                    span: *span,
+                });
+                Expr::Ident(slot)
+            }
+            Expr::Binary {
+                op: BinOp::And | BinOp::Or,
+                right,
+                ..
+            } if expr_awaits(right) => {
+                return Err(CompileError::Generic(
+                    "'.await' on the right of && or || isn't supported yet; await it in an if first".to_string(),
+                ));
+            }
+            Expr::Binary {
+                left,
+                op,
+                right,
+                span,
+            } => Expr::Binary {
+                left: boxed(self, left, out)?,
+                op: *op,
+                right: boxed(self, right, out)?,
+                span: *span,
+            },
+            Expr::Unary { op, operand, span } => Expr::Unary {
+                op: *op,
+                operand: boxed(self, operand, out)?,
+                span: *span,
+            },
+            Expr::Call { func, args, span } => Expr::Call {
+                // A called name is a function, not a local
+                func: match func.as_ref() {
+                    Expr::Ident(_) => func.clone(),
+                    _ => boxed(self, func, out)?,
+                },
+                args: self.lower_exprs(args, out)?,
+                span: *span,
+            },
+            Expr::Index { array, index, span } => Expr::Index {
+                array: boxed(self, array, out)?,
+                index: boxed(self, index, out)?,
+                span: *span,
+            },
+            Expr::FieldAccess {
+                object,
+                field,
+                span,
+            } => Expr::FieldAccess {
+                object: boxed(self, object, out)?,
+                field: field.clone(),
+                span: *span,
+            },
+            Expr::StructLiteral {
+                name,
+                fields,
+                base,
+                span,
+            } => Expr::StructLiteral {
+                name: name.clone(),
+                fields: self.lower_fields(fields, out)?,
+                base: match base {
+                    Some(StructBase::Expr(base)) => Some(StructBase::Expr(boxed(self, base, out)?)),
+                    _ => base.clone(),
+                },
+                span: *span,
+            },
+            Expr::EnumConstructor {
+                enum_name,
+                variant,
+                data,
+                span,
+            } => Expr::EnumConstructor {
+                enum_name: enum_name.clone(),
+                variant: variant.clone(),
+                data: match data {
+                    Some(EnumConstructorData::Tuple(values)) => {
+                        Some(EnumConstructorData::Tuple(self.lower_exprs(values, out)?))
+                    }
+                    Some(EnumConstructorData::Struct(fields)) => {
+                        Some(EnumConstructorData::Struct(self.lower_fields(fields, out)?))
+                    }
+                    None => None,
+                },
+                span: *span,
+            },
+            Expr::ArrayLiteral { elements, span } => Expr::ArrayLiteral {
+                elements: self.lower_exprs(elements, out)?,
+                span: *span,
+            },
+            Expr::ArrayRepeat { value, count, span } => Expr::ArrayRepeat {
+                value: boxed(self, value, out)?,
+                count: boxed(self, count, out)?,
+                span: *span,
+            },
+            Expr::Range { start, end, span } => Expr::Range {
+                start: boxed(self, start, out)?,
+                end: boxed(self, end, out)?,
+                span: *span,
+            },
+            Expr::Reference {
+                mutable,
+                expr,
+                span,
+            } => Expr::Reference {
+                mutable: *mutable,
+                expr: boxed(self, expr, out)?,
+                span: *span,
+            },
+            Expr::Deref { expr, span } => Expr::Deref {
+                expr: boxed(self, expr, out)?,
+                span: *span,
+            },
+            Expr::Question { expr, span } => Expr::Question {
+                expr: boxed(self, expr, out)?,
+                span: *span,
+            },
+            Expr::String(_)
+            | Expr::Integer(_)
+            | Expr::Bool(_)
+            | Expr::Turbofish { .. }
+            | Expr::MacroInvocation { .. } => expr.clone(),
+        })
+    }
+
+    fn lower_exprs(&mut self, exprs: &[Expr], out: &mut Vec<Stmt>) -> Result<Vec<Expr>> {
+        exprs
+            .iter()
+            .map(|expr| self.lower_expr(expr, out))
+            .collect()
+    }
+
+    fn lower_fields(
+        &mut self,
+        fields: &[(String, Expr)],
+        out: &mut Vec<Stmt>,
+    ) -> Result<Vec<(String, Expr)>> {
+        fields
+            .iter()
+            .map(|(name, value)| Ok((name.clone(), self.lower_expr(value, out)?)))
+            .collect()
+    }
+}
+
+/// Names bound by a match pattern
+fn pattern_bindings<'a>(pattern: &'a Pattern, names: &mut Vec<&'a str>) {
+    match pattern {
+        Pattern::Ident(name) => names.push(name),
+        Pattern::EnumPattern {
+            data: Some(PatternData::Tuple(patterns)),
+            ..
+        } => {
+            for pattern in patterns {
+                pattern_bindings(pattern, names);
+            }
+        }
+        Pattern::EnumPattern {
+            data: Some(PatternData::Struct(fields)),
+            ..
+        } => {
+            for (_, pattern) in fields {
+                pattern_bindings(pattern, names);
+            }
+        }
+        Pattern::Wildcard | Pattern::EnumPattern { data: None, .. } => {}
+    }
+}
+
+/// Whether a statement awaits anywhere inside it
+fn stmt_awaits(stmt: &Stmt) -> bool {
+    match stmt {
+        Stmt::Expr(expr) | Stmt::Return(Some(expr)) | Stmt::Let { value: expr, .. } => {
+            expr_awaits(expr)
+        }
+        Stmt::Assign { target, value, .. } => {
+            expr_awaits(value)
+                || match target {
+                    AssignTarget::Ident(_) => false,
+                    AssignTarget::Index { array, index } => {
+                        expr_awaits(array) || expr_awaits(index)
+                    }
+                    AssignTarget::FieldAccess { object: expr, .. }
+                    | AssignTarget::Deref { expr } => expr_awaits(expr),
+                }
+        }
+        Stmt::If {
+            condition,
+            then_branch,
+            else_branch,
+            ..
+        } => {
+            expr_awaits(condition)
+                || then_branch.iter().any(stmt_awaits)
+                || else_branch.iter().flatten().any(stmt_awaits)
+        }
+        Stmt::While {
+            condition, body, ..
+        } => expr_awaits(condition) || body.iter().any(stmt_awaits),
+        Stmt::For { iter, body, .. } => expr_awaits(iter) || body.iter().any(stmt_awaits),
+        Stmt::Match { expr, arms, .. } => {
+            expr_awaits(expr) || arms.iter().flat_map(|arm| &arm.body).any(stmt_awaits)
+        }
+        Stmt::Unsafe { body, .. } | Stmt::Handle { body, .. } => body.iter().any(stmt_awaits),
+        // The scope awaits its tasks when the body ends
+        Stmt::AsyncScope { .. } => true,
+        Stmt::Return(None) | Stmt::Break { .. } | Stmt::Continue { .. } => false,
+    }
+}
+
+/// Whether an expression awaits anywhere inside it
+fn expr_awaits(expr: &Expr) -> bool {
+    match expr {
+        Expr::Await { .. } => true,
+        Expr::Binary { left, right, .. }
+        | Expr::Index {
+            array: left,
+            index: right,
+            ..
+        }
+        | Expr::ArrayRepeat {
+            value: left,
+            count: right,
+            ..
+        }
+        | Expr::Range {
+            start: left,
+            end: right,
+            ..
+        } => expr_awaits(left) || expr_awaits(right),
+        Expr::Unary { operand: expr, .. }
+        | Expr::FieldAccess { object: expr, .. }
+        | Expr::Reference { expr, .. }
+        | Expr::Deref { expr, .. }
+        | Expr::Question { expr, .. } => expr_awaits(expr),
+        Expr::Call { func, args, .. } => expr_awaits(func) || args.iter().any(expr_awaits),
+        Expr::ArrayLiteral { elements, .. } => elements.iter().any(expr_awaits),
+        Expr::StructLiteral { fields, base, .. } => {
+            fields.iter().any(|(_, value)| expr_awaits(value))
+                || matches!(base, Some(StructBase::Expr(base)) if expr_awaits(base))
+        }
+        Expr::EnumConstructor { data, .. } => match data {
+            Some(EnumConstructorData::Tuple(values)) => values.iter().any(expr_awaits),
+            Some(EnumConstructorData::Struct(fields)) => {
+                fields.iter().any(|(_, value)| expr_awaits(value))
+            }
+            None => false,
+        },
+        Expr::String(_)
+        | Expr::Integer(_)
+        | Expr::Bool(_)
+        | Expr::Ident(_)
+        | Expr::Turbofish { .. }
+        | Expr::MacroInvocation { .. } => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn lower(source: &str) -> Result<Function> {
+        let tokens = Lexer::new(source).collect_tokens()?;
+        let program = Parser::new(tokens).parse()?;
+        match program.items.first() {
+            Some(Item::Function(func)) => lower_async_function(func),
+            _ => panic!("expected a function"),
+        }
+    }
+
+    #[test]
+    fn test_awaits_become_statements() {
+        let lowered = lower(
+            r#"
+            async fn run(x: i64) -> i64 {
+                let y = step(x).await + step(x + 1).await;
+                let x = y;
+                return x;
+            }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(lowered.params[0].name, "__pd_local_1_x");
+        let body: Vec<String> = lowered.body.iter().map(|stmt| stmt.to_string()).collect();
+        assert_eq!(
+            body,
+            vec![
+                "let __pd_local_2_await = step(__pd_local_1_x).await;",
+                "let __pd_local_3_await = step((__pd_local_1_x + 1)).await;",
+                "let __pd_local_4_y = (__pd_local_2_await + __pd_local_3_await);",
+                "let __pd_local_5_x = __pd_local_4_y;",
+                "return __pd_local_5_x;",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unsupported_awaits() {
+        let arm = lower(
+            r#"
+            async fn run(v: Option<i64>) {
+                match v {
+                    Option::Some(n) => {
+                        step(n).await;
+                    }
+                    Option::None => {}
+                }
+            }
+            "#,
+        );
+        assert!(arm.unwrap_err().to_string().contains("match arm"));
+
+        let collection = lower(
+            r#"
+            async fn run(items: [i64; 2]) {
+                for n in items {
+                    step(n).await;
+                }
+            }
+            "#,
+        );
+        assert!(collection
+            .unwrap_err()
+            .to_string()
+            .contains("range of indices"));
+    }
+}
@@ -0,0 +1,953 @@
+// Built-in collections for the C backend
+//
+// Each `Vec<T>`, `HashMap<K, V>` and `HashSet<T>` used by a program is
+// monomorphized into a C struct and the functions operating on it. Maps and
+// sets are open-addressing hash tables sharing one implementation: a set is
+// a table without values.
+
+use super::CodeGenerator;
+use crate::ast::*;
+use crate::errors::{Result, Span};
+
+impl CodeGenerator {
+    /// Struct name of a built-in `Vec<T>` type
+    pub(super) fn vec_c_name(&self, ty: &Type) -> Option<String> {
+        let name = crate::typeck::prelude::mangle_type_arg(&self.type_arg_name(ty));
+        self.vec_types
+            .iter()
+            .any(|(vec_name, _)| *vec_name == name)
+            .then_some(name)
+    }
+
+    /// C type of the elements of a built-in vector of C type `c_type`
+    pub(super) fn vec_element_c(&self, c_type: &str) -> Option<String> {
+        let name = c_type.strip_prefix("struct ")?;
+        self.vec_types
+            .iter()
+            .find(|(vec_name, _)| vec_name == name)
+            .map(|(_, elem)| self.type_arg_to_c(elem))
+    }
+
+    /// Struct name of the built-in vector a `Vec::method(..)` call at `span` works on
+    pub(super) fn vec_call_name(&self, enum_name: &str, span: &Span) -> Option<String> {
+        if enum_name != "Vec" {
+            return None;
+        }
+        let elem = self.call_type_args(span)?.into_iter().next()?;
+        let name = format!("Vec_{}", crate::typeck::prelude::mangle_type_arg(&elem));
+        self.vec_types
+            .iter()
+            .any(|(vec_name, _)| *vec_name == name)
+            .then_some(name)
+    }
+
+    /// Key type, and value type for maps and their entries, of the built-in hash
+    /// table or map entry struct `name`
+    pub(super) fn table_types(&self, name: &str) -> Option<(String, Option<String>)> {
+        if let Some((_, key, value)) = self
+            .map_types
+            .iter()
+            .find(|(map, _, _)| map == name || Self::entry_name(map) == name)
+        {
+            return Some((key.clone(), Some(value.clone())));
+        }
+        self.set_types
+            .iter()
+            .find(|(set, _)| set == name)
+            .map(|(_, elem)| (elem.clone(), None))
+    }
+
+    /// Every built-in hash table: struct name, key type and, for maps, value type
+    pub(super) fn tables(&self) -> Vec<(String, String, Option<String>)> {
+        self.map_types
+            .iter()
+            .map(|(name, key, value)| (name.clone(), key.clone(), Some(value.clone())))
+            .chain(
+                self.set_types
+                    .iter()
+                    .map(|(name, elem)| (name.clone(), elem.clone(), None)),
+            )
+            .collect()
+    }
+
+    /// Struct name of the entry of the built-in map `map`
+    pub(super) fn entry_name(map: &str) -> String {
+        map.replacen("HashMap", "Entry", 1)
+    }
+
+    /// Name of the `Option<&V>` enum a map lookup returns, whose payload points
+    /// into the map
+    pub(super) fn ref_option_name(value: &str) -> String {
+        format!(
+            "Option_ref_{}",
+            crate::typeck::prelude::mangle_type_arg(value)
+        )
+    }
+
+    /// Struct name of a built-in `HashMap`, `HashSet` or `Entry` type
+    pub(super) fn table_c_name(&self, ty: &Type) -> Option<String> {
+        let name = crate::typeck::prelude::mangle_type_arg(&self.type_arg_name(ty));
+        self.table_types(&name).map(|_| name)
+    }
+
+    /// Struct name of the hash table or entry a `HashMap::method(..)`,
+    /// `HashSet::method(..)` or `Entry::method(..)` call at `span` works on
+    pub(super) fn table_call_name(&self, enum_name: &str, span: &Span) -> Option<String> {
+        if !matches!(enum_name, "HashMap" | "HashSet" | "Entry") {
+            return None;
+        }
+        let type_args = self.call_type_args(span)?;
+        let name = crate::typeck::prelude::mangle_enum_name(enum_name, &type_args);
+        self.table_types(&name).map(|_| name)
+    }
+
+    /// C type of the elements of a built-in set of C type `c_type`
+    pub(super) fn set_element_c(&self, c_type: &str) -> Option<String> {
+        let name = c_type.strip_prefix("struct ")?;
+        self.set_types
+            .iter()
+            .find(|(set_name, _)| set_name == name)
+            .map(|(_, elem)| self.type_arg_to_c(elem))
+    }
+
+    /// Generate the struct of each built-in `Vec<T>`
+    pub(super) fn generate_vec_structs(&mut self) -> Result<()> {
+        if self.vec_types.is_empty() {
+            return Ok(());
+        }
+        // The element buffer is a `T*` in C, like a `Box<T>`
+        let template = crate::typeck::GenericStruct {
+            lifetime_params: vec![],
+            type_params: vec!["T".to_string()],
+            fields: vec![
+                (
+                    "data".to_string(),
+                    Type::Generic {
+                        name: "Box".to_string(),
+                        args: vec![GenericArg::Type(Type::TypeParam("T".to_string()))],
+                    },
+                ),
+                ("len".to_string(), Type::I64),
+                ("capacity".to_string(), Type::I64),
+            ],
+        };
+
+        self.output.push_str("// Built-in vectors\n");
+        for (_, elem) in self.vec_types.clone() {
+            // Elements may not be defined yet
+            if let Some(name) = self.type_arg_to_c(&elem).strip_prefix("struct ") {
+                self.output.push_str(&format!("struct {};\n", name));
+            }
+            let concrete_struct = self.monomorphize_struct("Vec", &[elem], &template)?;
+            self.generate_struct(&concrete_struct)?;
+        }
+        Ok(())
+    }
+
+    /// Generate the operations of each built-in vector as `__pd_Vec_{T}_{method}`.
+    /// Values are moved in and out; `slice` and `clone` copy with the element's clone.
+    pub(super) fn generate_vec_functions(&mut self) {
+        if self.vec_types.is_empty() {
+            return;
+        }
+        let mut code = String::from("// Built-in vector operations\n");
+        for (name, elem) in &self.vec_types {
+            let elem_type = self.type_arg_to_c(elem);
+            let vec_type = format!("struct {}", name);
+            let prefix = format!("__pd_{}", name);
+            let reserve = |len: &str, v: &str| {
+                format!(
+                    "    __pd_vec_reserve((void**)&{}data, &{}capacity, {}, sizeof({}));\n",
+                    v, v, len, elem_type
+                )
+            };
+
+            // Methods of the element are defined with the program's functions
+            let clone = self.clone_function(&elem_type);
+            if self
+                .clone_impls
+                .contains(elem_type.trim_start_matches("struct "))
+            {
+                code.push_str(&format!(
+                    "{} {}(const {}* self);\n",
+                    elem_type, clone, elem_type
+                ));
+            }
+
+            code.push_str(&format!(
+                "static inline {} {}_new(void) {{\n",
+                vec_type, prefix
+            ));
+            code.push_str(&format!("    {} v = {{NULL, 0, 0}};\n", vec_type));
+            code.push_str("    return v;\n}\n\n");
+
+            code.push_str(&format!(
+                "static inline {} {}_with_capacity(long long capacity) {{\n",
+                vec_type, prefix
+            ));
+            code.push_str(&format!("    {} v = {{NULL, 0, 0}};\n", vec_type));
+            code.push_str(&reserve("capacity", "v."));
+            code.push_str("    return v;\n}\n\n");
+
+            code.push_str(&format!(
+                "static inline {} {}_from({}* items, long long n) {{\n",
+                vec_type, prefix, elem_type
+            ));
+            code.push_str(&format!(
+                "    {} v = {}_with_capacity(n);\n",
+                vec_type, prefix
+            ));
+            code.push_str(&format!(
+                "    if (n > 0) memcpy(v.data, items, n * sizeof({}));\n",
+                elem_type
+            ));
+            code.push_str("    v.len = n;\n");
+            code.push_str("    return v;\n}\n\n");
+
+            code.push_str(&format!(
+                "static inline void {}_push({}* v, {} value) {{\n",
+                prefix, vec_type, elem_type
+            ));
+            code.push_str(&reserve("v->len + 1", "v->"));
+            code.push_str("    v->data[v->len++] = value;\n}\n\n");
+
+            // `pop` returns an `Option`, which exists only if the program names it
+            let option =
+                crate::typeck::prelude::mangle_enum_name("Option", std::slice::from_ref(elem));
+            if self.enums.contains_key(&option) {
+                code.push_str(&format!(
+                    "static inline {} {}_pop({}* v) {{\n",
+                    option, prefix, vec_type
+                ));
+                code.push_str(&format!("    if (v->len == 0) return {}_None();\n", option));
+                code.push_str(&format!(
+                    "    return {}_Some__new(v->data[--v->len]);\n",
+                    option
+                ));
+                code.push_str("}\n\n");
+            }
+
+            code.push_str(&format!(
+                "static inline void {}_insert({}* v, long long index, {} value) {{\n",
+                prefix, vec_type, elem_type
+            ));
+            code.push_str("    if (index != v->len) __pd_vec_check(index, v->len);\n");
+            code.push_str(&reserve("v->len + 1", "v->"));
+            code.push_str(&format!(
+                "    memmove(v->data + index + 1, v->data + index, (v->len - index) * sizeof({}));\n",
+                elem_type
+            ));
+            code.push_str("    v->data[index] = value;\n");
+            code.push_str("    v->len++;\n}\n\n");
+
+            code.push_str(&format!(
+                "static inline {} {}_remove({}* v, long long index) {{\n",
+                elem_type, prefix, vec_type
+            ));
+            code.push_str("    __pd_vec_check(index, v->len);\n");
+            code.push_str(&format!("    {} value = v->data[index];\n", elem_type));
+            code.push_str(&format!(
+                "    memmove(v->data + index, v->data + index + 1, (v->len - index - 1) * sizeof({}));\n",
+                elem_type
+            ));
+            code.push_str("    v->len--;\n");
+            code.push_str("    return value;\n}\n\n");
+
+            for field in ["len", "capacity"] {
+                code.push_str(&format!(
+                    "static inline long long {}_{}(const {}* v) {{\n    return v->{};\n}}\n\n",
+                    prefix, field, vec_type, field
+                ));
+            }
+
+            code.push_str(&format!(
+                "static inline {}* {}_at(const {}* v, long long index) {{\n",
+                elem_type, prefix, vec_type
+            ));
+            code.push_str("    __pd_vec_check(index, v->len);\n");
+            code.push_str("    return &v->data[index];\n}\n\n");
+
+            code.push_str(&format!(
+                "static inline {} {}_slice(const {}* v, long long start, long long end) {{\n",
+                vec_type, prefix, vec_type
+            ));
+            code.push_str("    __pd_vec_check_range(start, end, v->len);\n");
+            code.push_str(&format!(
+                "    {} slice = {}_with_capacity(end - start);\n",
+                vec_type, prefix
+            ));
+            code.push_str(&format!(
+                "    for (long long i = start; i < end; i++) slice.data[slice.len++] = {};\n",
+                self.clone_call(&elem_type, "v->data[i]")
+            ));
+            code.push_str("    return slice;\n}\n\n");
+
+            code.push_str(&format!(
+                "static inline {} {}_clone({} v) {{\n    return {}_slice(&v, 0, v.len);\n}}\n\n",
+                vec_type, prefix, vec_type, prefix
+            ));
+        }
+        self.output.push_str(&code);
+    }
+
+    /// Generate the struct of each built-in `HashMap<K, V>` and `HashSet<T>`
+    pub(super) fn generate_table_structs(&mut self) -> Result<()> {
+        if self.map_types.is_empty() && self.set_types.is_empty() {
+            return Ok(());
+        }
+        // Keys, values and slot states are `T*` arrays in C, like a `Box<T>`
+        let array = |elem: Type| Type::Generic {
+            name: "Box".to_string(),
+            args: vec![GenericArg::Type(elem)],
+        };
+        let param = |name: &str| array(Type::TypeParam(name.to_string()));
+        let counts = [
+            ("len".to_string(), Type::I64),
+            ("used".to_string(), Type::I64),
+            ("capacity".to_string(), Type::I64),
+        ];
+        let map_template = crate::typeck::GenericStruct {
+            lifetime_params: vec![],
+            type_params: vec!["K".to_string(), "V".to_string()],
+            fields: [
+                ("keys".to_string(), param("K")),
+                ("values".to_string(), param("V")),
+                ("states".to_string(), array(Type::Bool)),
+            ]
+            .into_iter()
+            .chain(counts.clone())
+            .collect(),
+        };
+        let set_template = crate::typeck::GenericStruct {
+            lifetime_params: vec![],
+            type_params: vec!["T".to_string()],
+            fields: [
+                ("keys".to_string(), param("T")),
+                ("states".to_string(), array(Type::Bool)),
+            ]
+            .into_iter()
+            .chain(counts)
+            .collect(),
+        };
+
+        self.output.push_str("// Built-in hash tables\n");
+        let tables: Vec<(&str, Vec<String>, &crate::typeck::GenericStruct)> = self
+            .map_types
+            .iter()
+            .map(|(_, key, value)| ("HashMap", vec![key.clone(), value.clone()], &map_template))
+            .chain(
+                self.set_types
+                    .iter()
+                    .map(|(_, elem)| ("HashSet", vec![elem.clone()], &set_template)),
+            )
+            .collect();
+        for (table, type_args, template) in tables {
+            // Keys and values may not be defined yet
+            for arg in &type_args {
+                if let Some(name) = self.type_arg_to_c(arg).strip_prefix("struct ") {
+                    self.output.push_str(&format!("struct {};\n", name));
+                }
+            }
+            let concrete_struct = self.monomorphize_struct(table, &type_args, template)?;
+            self.generate_struct(&concrete_struct)?;
+        }
+        Ok(())
+    }
+
+    /// Generate the entry struct and `Option<&V>` lookup result of each built-in
+    /// map. An entry is the map, the key and the key's slot, or -1 if it's vacant.
+    pub(super) fn generate_table_entries(
+        &mut self,
+        drop_items: &mut Vec<(Item, String)>,
+    ) -> Result<()> {
+        if self.map_types.is_empty() {
+            return Ok(());
+        }
+        let template = crate::typeck::GenericStruct {
+            lifetime_params: vec![],
+            type_params: vec!["K".to_string(), "V".to_string()],
+            fields: vec![
+                (
+                    "map".to_string(),
+                    Type::Generic {
+                        name: "Box".to_string(),
+                        args: vec![GenericArg::Type(Type::Generic {
+                            name: "HashMap".to_string(),
+                            args: vec![
+                                GenericArg::Type(Type::TypeParam("K".to_string())),
+                                GenericArg::Type(Type::TypeParam("V".to_string())),
+                            ],
+                        })],
+                    },
+                ),
+                ("key".to_string(), Type::TypeParam("K".to_string())),
+                ("slot".to_string(), Type::I64),
+            ],
+        };
+
+        self.output
+            .push_str("// Built-in map entries and lookups\n");
+        for (_, key, value) in self.map_types.clone() {
+            let concrete_struct =
+                self.monomorphize_struct("Entry", &[key.clone(), value.clone()], &template)?;
+            self.generate_struct(&concrete_struct)?;
+            let name = concrete_struct.name.clone();
+            drop_items.push((Item::Struct(concrete_struct), name));
+
+            let name = Self::ref_option_name(&value);
+            if self.enums.contains_key(&name) {
+                continue;
+            }
+            // Nested instantiations are referred to by their mangled struct name
+            let concrete = if value.contains('<') && !value.starts_with("Box<") {
+                crate::typeck::prelude::mangle_type_arg(&value)
+            } else {
+                value.clone()
+            };
+            let value_type = self.substitute_type(
+                &Type::TypeParam("V".to_string()),
+                &std::collections::HashMap::from([("V".to_string(), concrete)]),
+            );
+            let option = EnumDef {
+                name: name.clone(),
+                lifetime_params: vec![],
+                type_params: vec![],
+                const_params: vec![],
+                variants: vec![
+                    EnumVariant {
+                        name: "Some".to_string(),
+                        data: EnumVariantData::Tuple(vec![Type::Reference {
+                            lifetime: None,
+                            mutable: true,
+                            inner: Box::new(value_type),
+                        }]),
+                    },
+                    EnumVariant {
+                        name: "None".to_string(),
+                        data: EnumVariantData::Unit,
+                    },
+                ],
+                span: Span {
+                    start: 0,
+                    end: 0,
+                    line: 0,
+                    column: 0,
+                },
+            };
+            self.generate_enum_with_tags(&option, "Option")?;
+            self.enums.insert(name, option);
+        }
+        self.output.push('\n');
+        Ok(())
+    }
+
+    /// Generate `__pd_hash_{T}` and `__pd_eq_{T}` for every type hashed by a hash
+    /// table: its keys and, for derived `Hash` and `Eq`, their fields in turn
+    pub(super) fn generate_key_functions(&self, code: &mut String) {
+        let mut key_types: Vec<String> = Vec::new();
+        let mut pending: Vec<String> = self
+            .map_types
+            .iter()
+            .map(|(_, key, _)| self.type_arg_to_c(key))
+            .chain(
+                self.set_types
+                    .iter()
+                    .map(|(_, elem)| self.type_arg_to_c(elem)),
+            )
+            .collect();
+        while let Some(c_type) = pending.pop() {
+            if key_types.contains(&c_type) {
+                continue;
+            }
+            pending.extend(self.hashed_fields(&c_type).into_iter().map(|(_, ty)| ty));
+            key_types.push(c_type);
+        }
+
+        code.push_str("// Hashing: keys are hashed with FNV-1a from a per-process seed\n");
+        code.push_str("static unsigned long long __pd_hash_seed_value = 0;\n");
+        code.push_str("static unsigned long long __pd_hash_seed(void) {\n");
+        code.push_str("    if (!__pd_hash_seed_value) {\n");
+        code.push_str(
+            "        __pd_hash_seed_value = ((unsigned long long)time(NULL) * 0x9e3779b97f4a7c15ULL) ^ (unsigned long long)(uintptr_t)&__pd_hash_seed_value;\n",
+        );
+        code.push_str("        __pd_hash_seed_value |= 1;\n");
+        code.push_str("    }\n");
+        code.push_str("    return __pd_hash_seed_value;\n");
+        code.push_str("}\n\n");
+        code.push_str(
+            "static unsigned long long __pd_hash_mix(unsigned long long h, unsigned long long x) {\n",
+        );
+        code.push_str("    h = (h ^ x) * 0x100000001b3ULL;\n");
+        code.push_str("    return h ^ (h >> 32);\n");
+        code.push_str("}\n\n");
+        code.push_str(
+            "static unsigned long long __pd_hash_bytes(unsigned long long h, const char* data, long long len) {\n",
+        );
+        code.push_str(
+            "    for (long long i = 0; i < len; i++) h = (h ^ (unsigned char)data[i]) * 0x100000001b3ULL;\n",
+        );
+        code.push_str("    return __pd_hash_mix(h, (unsigned long long)len);\n");
+        code.push_str("}\n\n");
+        // hash_finish: spread the state over every bit before it picks a slot
+        code.push_str("static unsigned long long __pd_hash_finish(unsigned long long h) {\n");
+        code.push_str("    h ^= h >> 30;\n");
+        code.push_str("    h *= 0xbf58476d1ce4e5b9ULL;\n");
+        code.push_str("    h ^= h >> 27;\n");
+        code.push_str("    h *= 0x94d049bb133111ebULL;\n");
+        code.push_str("    return h ^ (h >> 31);\n");
+        code.push_str("}\n\n");
+
+        // Fields may be hashed before their own functions are defined
+        for c_type in &key_types {
+            let name = Self::hashed_name(c_type);
+            code.push_str(&format!(
+                "static unsigned long long __pd_hash_{}(unsigned long long h, const {}* value);\n",
+                name, c_type
+            ));
+            code.push_str(&format!(
+                "static int __pd_eq_{}(const {}* a, const {}* b);\n",
+                name, c_type, c_type
+            ));
+        }
+        code.push('\n');
+
+        for c_type in &key_types {
+            let name = Self::hashed_name(c_type);
+            let (hash, eq) = match c_type.as_str() {
+                "long long" | "int" => (
+                    "    return __pd_hash_mix(h, (unsigned long long)*value);\n".to_string(),
+                    "    return *a == *b;\n".to_string(),
+                ),
+                "PdString" => (
+                    "    return __pd_hash_bytes(h, value->data, value->len);\n".to_string(),
+                    "    return a->len == b->len && (a->len == 0 || memcmp(a->data, b->data, a->len) == 0);\n"
+                        .to_string(),
+                ),
+                _ if self.enums.contains_key(&name) => {
+                    // Enums hash their tag, then the payload of their variant
+                    let mut hash =
+                        "    h = __pd_hash_mix(h, (unsigned long long)value->tag);\n".to_string();
+                    let mut eq = "    if (a->tag != b->tag) return 0;\n".to_string();
+                    let mut variants: Vec<(String, Vec<(String, String)>)> = Vec::new();
+                    for (member, field_type) in self.hashed_fields(c_type) {
+                        let (variant, member) = member.split_once('.').unwrap_or_default();
+                        match variants.iter_mut().find(|(v, _)| v == variant) {
+                            Some((_, members)) => members.push((member.to_string(), field_type)),
+                            None => variants
+                                .push((variant.to_string(), vec![(member.to_string(), field_type)])),
+                        }
+                    }
+                    for (variant, members) in variants {
+                        let place = format!("data.{}", variant.to_lowercase());
+                        let hashes: Vec<String> = members
+                            .iter()
+                            .map(|(member, ty)| {
+                                format!(
+                                    "h = __pd_hash_{}(h, &value->{}.{});",
+                                    Self::hashed_name(ty),
+                                    place,
+                                    member
+                                )
+                            })
+                            .collect();
+                        let eqs: Vec<String> = members
+                            .iter()
+                            .map(|(member, ty)| {
+                                format!(
+                                    "__pd_eq_{}(&a->{}.{}, &b->{}.{})",
+                                    Self::hashed_name(ty),
+                                    place,
+                                    member,
+                                    place,
+                                    member
+                                )
+                            })
+                            .collect();
+                        hash.push_str(&format!(
+                            "    if (value->tag == __{}__{}) {{ {} }}\n",
+                            name,
+                            variant,
+                            hashes.join(" ")
+                        ));
+                        eq.push_str(&format!(
+                            "    if (a->tag == __{}__{}) return {};\n",
+                            name,
+                            variant,
+                            eqs.join(" && ")
+                        ));
+                    }
+                    hash.push_str("    return h;\n");
+                    eq.push_str("    return 1;\n");
+                    (hash, eq)
+                }
+                _ => {
+                    // Structs hash and compare field by field
+                    let fields = self.hashed_fields(c_type);
+                    let mut hash = String::new();
+                    let mut eqs = vec!["1".to_string()];
+                    for (field, ty) in &fields {
+                        hash.push_str(&format!(
+                            "    h = __pd_hash_{}(h, &value->{});\n",
+                            Self::hashed_name(ty),
+                            field
+                        ));
+                        eqs.push(format!(
+                            "__pd_eq_{}(&a->{}, &b->{})",
+                            Self::hashed_name(ty),
+                            field,
+                            field
+                        ));
+                    }
+                    hash.push_str("    return h;\n");
+                    (hash, format!("    return {};\n", eqs.join(" && ")))
+                }
+            };
+            code.push_str(&format!(
+                "static unsigned long long __pd_hash_{}(unsigned long long h, const {}* value) {{\n{}}}\n\n",
+                name, c_type, hash
+            ));
+            code.push_str(&format!(
+                "static int __pd_eq_{}(const {}* a, const {}* b) {{\n{}}}\n\n",
+                name, c_type, c_type, eq
+            ));
+        }
+    }
+
+    /// Fields hashed by the derived `Hash` of a struct or enum of C type
+    /// `c_type`, with their C types. Enum payloads are named `Variant.member`.
+    pub(super) fn hashed_fields(&self, c_type: &str) -> Vec<(String, String)> {
+        let Some(name) = c_type.strip_prefix("struct ") else {
+            return vec![];
+        };
+        if let Some(enum_def) = self.enums.get(name) {
+            return enum_def
+                .variants
+                .iter()
+                .flat_map(|variant| {
+                    let members: Vec<(String, &Type)> = match &variant.data {
+                        EnumVariantData::Unit => vec![],
+                        EnumVariantData::Tuple(types) => types
+                            .iter()
+                            .enumerate()
+                            .map(|(i, ty)| (format!("field{}", i), ty))
+                            .collect(),
+                        EnumVariantData::Struct(fields) => fields
+                            .iter()
+                            .map(|(field, ty)| (field.clone(), ty))
+                            .collect(),
+                    };
+                    members
+                        .into_iter()
+                        .map(|(member, ty)| {
+                            (format!("{}.{}", variant.name, member), self.type_to_c(ty))
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .collect();
+        }
+        self.struct_field_types
+            .get(name)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Suffix of the hash and equality functions of a key of C type `c_type`
+    pub(super) fn hashed_name(c_type: &str) -> String {
+        match c_type {
+            "long long" => "i64".to_string(),
+            "int" => "bool".to_string(),
+            "PdString" => "String".to_string(),
+            _ => c_type.trim_start_matches("struct ").to_string(),
+        }
+    }
+
+    /// Generate the operations of each built-in hash table as
+    /// `__pd_{HashMap_K_V}_{method}`: open addressing with linear probing over a
+    /// power-of-two array of slots, each empty (0), full (1) or removed (2).
+    /// Keys looked up by reference are passed by value and left to their owner.
+    pub(super) fn generate_table_functions(&mut self) {
+        if self.map_types.is_empty() && self.set_types.is_empty() {
+            return;
+        }
+        let mut code = String::new();
+        self.generate_key_functions(&mut code);
+        code.push_str("// Built-in hash table operations\n");
+        for (name, key, value) in &self.tables() {
+            let key_type = self.type_arg_to_c(key);
+            let value_type = value.as_ref().map(|value| self.type_arg_to_c(value));
+            let table_type = format!("struct {}", name);
+            let prefix = format!("__pd_{}", name);
+            let hashed = Self::hashed_name(&key_type);
+            let drop_key = |place: &str| match self.drop_name(&key_type) {
+                Some(key_drop) => format!("    __pd_drop_{}(&{});\n", key_drop, place),
+                None => String::new(),
+            };
+
+            code.push_str(&format!(
+                "static inline {} {}_new(void) {{\n",
+                table_type, prefix
+            ));
+            code.push_str(&format!("    {} m = {{0}};\n", table_type));
+            code.push_str("    return m;\n}\n\n");
+
+            code.push_str(&format!(
+                "static unsigned long long {}_hash(const {}* key) {{\n",
+                prefix, key_type
+            ));
+            code.push_str(&format!(
+                "    return __pd_hash_finish(__pd_hash_{}(__pd_hash_seed(), key));\n}}\n\n",
+                hashed
+            ));
+
+            // find: the slot holding `key`, or -1
+            code.push_str(&format!(
+                "static long long {}_find(const {}* m, const {}* key) {{\n",
+                prefix, table_type, key_type
+            ));
+            code.push_str("    if (m->capacity == 0) return -1;\n");
+            code.push_str("    unsigned long long mask = m->capacity - 1;\n");
+            code.push_str(&format!(
+                "    unsigned long long i = {}_hash(key) & mask;\n",
+                prefix
+            ));
+            code.push_str("    while (m->states[i] != 0) {\n");
+            code.push_str(&format!(
+                "        if (m->states[i] == 1 && __pd_eq_{}(&m->keys[i], key)) return i;\n",
+                hashed
+            ));
+            code.push_str("        i = (i + 1) & mask;\n");
+            code.push_str("    }\n");
+            code.push_str("    return -1;\n}\n\n");
+
+            // claim: fill a free slot for a key not in the table, growing it at
+            // three quarters full; rehash: move every key into a fresh array,
+            // doubled unless most of the old slots were removed keys
+            code.push_str(&format!(
+                "static long long {}_claim({}* m, const {}* key);\n",
+                prefix, table_type, key_type
+            ));
+            code.push_str(&format!(
+                "static void {}_rehash({}* m) {{\n",
+                prefix, table_type
+            ));
+            code.push_str(&format!("    {} old = *m;\n", table_type));
+            code.push_str(
+                "    long long capacity = old.capacity == 0 ? 8 : old.len * 2 >= old.capacity ? old.capacity * 2 : old.capacity;\n",
+            );
+            code.push_str(&format!(
+                "    m->keys = malloc(capacity * sizeof({}));\n",
+                key_type
+            ));
+            if let Some(value_type) = &value_type {
+                code.push_str(&format!(
+                    "    m->values = malloc(capacity * sizeof({}));\n",
+                    value_type
+                ));
+                code.push_str("    if (!m->values) abort();\n");
+            }
+            code.push_str("    m->states = calloc(capacity, sizeof(int));\n");
+            code.push_str("    if (!m->keys || !m->states) abort();\n");
+            code.push_str("    m->len = 0;\n");
+            code.push_str("    m->used = 0;\n");
+            code.push_str("    m->capacity = capacity;\n");
+            code.push_str("    for (long long i = 0; i < old.capacity; i++) {\n");
+            code.push_str("        if (old.states[i] != 1) continue;\n");
+            code.push_str(&format!(
+                "        long long slot = {}_claim(m, &old.keys[i]);\n",
+                prefix
+            ));
+            code.push_str("        m->keys[slot] = old.keys[i];\n");
+            if value_type.is_some() {
+                code.push_str("        m->values[slot] = old.values[i];\n");
+            }
+            code.push_str("    }\n");
+            code.push_str("    free(old.keys);\n");
+            if value_type.is_some() {
+                code.push_str("    free(old.values);\n");
+            }
+            code.push_str("    free(old.states);\n}\n\n");
+
+            code.push_str(&format!(
+                "static long long {}_claim({}* m, const {}* key) {{\n",
+                prefix, table_type, key_type
+            ));
+            code.push_str(&format!(
+                "    if ((m->used + 1) * 4 > m->capacity * 3) {}_rehash(m);\n",
+                prefix
+            ));
+            code.push_str("    unsigned long long mask = m->capacity - 1;\n");
+            code.push_str(&format!(
+                "    unsigned long long i = {}_hash(key) & mask;\n",
+                prefix
+            ));
+            code.push_str("    while (m->states[i] == 1) i = (i + 1) & mask;\n");
+            code.push_str("    if (m->states[i] == 0) m->used++;\n");
+            code.push_str("    m->states[i] = 1;\n");
+            code.push_str("    m->len++;\n");
+            code.push_str("    return i;\n}\n\n");
+
+            code.push_str(&format!(
+                "static inline long long {}_len(const {}* m) {{\n    return m->len;\n}}\n\n",
+                prefix, table_type
+            ));
+
+            let Some(value_type) = value_type else {
+                // Sets: `insert` and `remove` say whether the set changed
+                code.push_str(&format!(
+                    "static inline int {}_insert({}* m, {} value) {{\n",
+                    prefix, table_type, key_type
+                ));
+                code.push_str(&format!("    if ({}_find(m, &value) >= 0) {{\n", prefix));
+                code.push_str(&drop_key("value").replacen("    ", "        ", 1));
+                code.push_str("        return 0;\n    }\n");
+                code.push_str(&format!(
+                    "    long long slot = {}_claim(m, &value);\n",
+                    prefix
+                ));
+                code.push_str("    m->keys[slot] = value;\n");
+                code.push_str("    return 1;\n}\n\n");
+
+                code.push_str(&format!(
+                    "static inline int {}_contains(const {}* m, {} value) {{\n",
+                    prefix, table_type, key_type
+                ));
+                code.push_str(&format!(
+                    "    return {}_find(m, &value) >= 0;\n}}\n\n",
+                    prefix
+                ));
+
+                code.push_str(&format!(
+                    "static inline int {}_remove({}* m, {} value) {{\n",
+                    prefix, table_type, key_type
+                ));
+                code.push_str(&format!(
+                    "    long long slot = {}_find(m, &value);\n",
+                    prefix
+                ));
+                code.push_str("    if (slot < 0) return 0;\n");
+                code.push_str("    m->states[slot] = 2;\n");
+                code.push_str("    m->len--;\n");
+                code.push_str(&drop_key("m->keys[slot]"));
+                code.push_str("    return 1;\n}\n\n");
+                continue;
+            };
+            let value = value.clone().unwrap_or_default();
+            let drop_value = |place: &str| match self.drop_name(&value_type) {
+                Some(value_drop) => format!("    __pd_drop_{}(&{});\n", value_drop, place),
+                None => String::new(),
+            };
+
+            // `insert` and `remove` return an `Option`, which exists if they're called
+            let option =
+                crate::typeck::prelude::mangle_enum_name("Option", std::slice::from_ref(&value));
+            if self.enums.contains_key(&option) {
+                code.push_str(&format!(
+                    "static inline {} {}_insert({}* m, {} key, {} value) {{\n",
+                    option, prefix, table_type, key_type, value_type
+                ));
+                code.push_str(&format!("    long long slot = {}_find(m, &key);\n", prefix));
+                code.push_str("    if (slot >= 0) {\n");
+                code.push_str(&format!("        {} old = m->values[slot];\n", value_type));
+                code.push_str("        m->values[slot] = value;\n");
+                code.push_str(&drop_key("key").replacen("    ", "        ", 1));
+                code.push_str(&format!("        return {}_Some__new(old);\n", option));
+                code.push_str("    }\n");
+                code.push_str(&format!("    slot = {}_claim(m, &key);\n", prefix));
+                code.push_str("    m->keys[slot] = key;\n");
+                code.push_str("    m->values[slot] = value;\n");
+                code.push_str(&format!("    return {}_None();\n}}\n\n", option));
+
+                code.push_str(&format!(
+                    "static inline {} {}_remove({}* m, {} key) {{\n",
+                    option, prefix, table_type, key_type
+                ));
+                code.push_str(&format!("    long long slot = {}_find(m, &key);\n", prefix));
+                code.push_str(&format!("    if (slot < 0) return {}_None();\n", option));
+                code.push_str("    m->states[slot] = 2;\n");
+                code.push_str("    m->len--;\n");
+                code.push_str(&drop_key("m->keys[slot]"));
+                code.push_str(&format!(
+                    "    return {}_Some__new(m->values[slot]);\n}}\n\n",
+                    option
+                ));
+            }
+
+            let ref_option = Self::ref_option_name(&value);
+            for method in ["get", "get_mut"] {
+                code.push_str(&format!(
+                    "static inline {} {}_{}(const {}* m, {} key) {{\n",
+                    ref_option, prefix, method, table_type, key_type
+                ));
+                code.push_str(&format!("    long long slot = {}_find(m, &key);\n", prefix));
+                code.push_str(&format!(
+                    "    if (slot < 0) return {}_None();\n",
+                    ref_option
+                ));
+                code.push_str(&format!(
+                    "    return {}_Some__new(&m->values[slot]);\n}}\n\n",
+                    ref_option
+                ));
+            }
+
+            code.push_str(&format!(
+                "static inline int {}_contains_key(const {}* m, {} key) {{\n",
+                prefix, table_type, key_type
+            ));
+            code.push_str(&format!(
+                "    return {}_find(m, &key) >= 0;\n}}\n\n",
+                prefix
+            ));
+
+            let entry = Self::entry_name(name);
+            code.push_str(&format!(
+                "static inline struct {} {}_entry({}* m, {} key) {{\n",
+                entry, prefix, table_type, key_type
+            ));
+            code.push_str(&format!(
+                "    struct {} e = {{m, key, {}_find(m, &key)}};\n",
+                entry, prefix
+            ));
+            code.push_str("    return e;\n}\n\n");
+
+            code.push_str(&format!(
+                "static inline {}* __pd_{}_or_insert(struct {} e, {} value) {{\n",
+                value_type, entry, entry, value_type
+            ));
+            code.push_str("    if (e.slot >= 0) {\n");
+            code.push_str(&drop_key("e.key").replacen("    ", "        ", 1));
+            code.push_str(&drop_value("value").replacen("    ", "        ", 1));
+            code.push_str("        return &e.map->values[e.slot];\n");
+            code.push_str("    }\n");
+            code.push_str(&format!(
+                "    long long slot = {}_claim(e.map, &e.key);\n",
+                prefix
+            ));
+            code.push_str("    e.map->keys[slot] = e.key;\n");
+            code.push_str("    e.map->values[slot] = value;\n");
+            code.push_str("    return &e.map->values[slot];\n}\n\n");
+
+            // `keys` and `values` clone into a vector, which exists if they're called
+            for (method, field, elem) in [("keys", "keys", key), ("values", "values", &value)] {
+                let vec_name = format!("Vec_{}", crate::typeck::prelude::mangle_type_arg(elem));
+                if !self.vec_types.iter().any(|(name, _)| *name == vec_name) {
+                    continue;
+                }
+                let elem_type = self.type_arg_to_c(elem);
+                code.push_str(&format!(
+                    "static inline struct {} {}_{}(const {}* m) {{\n",
+                    vec_name, prefix, method, table_type
+                ));
+                code.push_str(&format!(
+                    "    struct {} items = __pd_{}_with_capacity(m->len);\n",
+                    vec_name, vec_name
+                ));
+                code.push_str(&format!(
+                    "    for (long long i = 0; i < m->capacity; i++) if (m->states[i] == 1) items.data[items.len++] = {};\n",
+                    self.clone_call(&elem_type, &format!("m->{}[i]", field))
+                ));
+                code.push_str("    return items;\n}\n\n");
+            }
+        }
+        self.output.push_str(&code);
+    }
+}
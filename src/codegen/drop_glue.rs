@@ -0,0 +1,315 @@
+// Drop glue for the C backend
+//
+// A type needs dropping when it has an `impl Drop`, or owns a string, a box,
+// a collection or a field that needs dropping. Each such type gets a
+// `__pd_drop_{name}` function running its own `Drop::drop` and then dropping
+// what it owns. Owned locals are dropped at the end of their scope, or before
+// a `return`, `break` or `continue` leaves it, unless they were moved out.
+
+use super::CodeGenerator;
+use crate::ast::*;
+
+impl CodeGenerator {
+    /// Record the types whose values need dropping: those with an `impl Drop`
+    /// and those owning a field that needs dropping
+    pub(super) fn collect_drop_types(&mut self, items: &[(Item, String)]) {
+        self.drop_types = self.drop_impls.clone();
+        self.drop_types.insert("String".to_string());
+        self.drop_types.extend(self.box_types.keys().cloned());
+        self.drop_types
+            .extend(self.vec_types.iter().map(|(name, _)| name.clone()));
+        self.drop_types
+            .extend(self.map_types.iter().map(|(name, _, _)| name.clone()));
+        self.drop_types
+            .extend(self.set_types.iter().map(|(name, _)| name.clone()));
+        loop {
+            let known = self.drop_types.len();
+            for (item, _) in items {
+                if let Some((name, field_types)) = Self::owned_field_types(item) {
+                    if field_types.iter().any(|ty| self.needs_drop(ty)) {
+                        self.drop_types.insert(name.to_string());
+                    }
+                }
+            }
+            if self.drop_types.len() == known {
+                break;
+            }
+        }
+    }
+
+    /// Name of a non-generic struct or enum with the types of the values it owns
+    pub(super) fn owned_field_types(item: &Item) -> Option<(&str, Vec<&Type>)> {
+        match item {
+            Item::Struct(def) if def.type_params.is_empty() && def.lifetime_params.is_empty() => {
+                Some((&def.name, def.fields.iter().map(|(_, ty)| ty).collect()))
+            }
+            Item::Enum(def) if def.type_params.is_empty() && def.lifetime_params.is_empty() => {
+                let payloads = def
+                    .variants
+                    .iter()
+                    .flat_map(|variant| match &variant.data {
+                        EnumVariantData::Unit => vec![],
+                        EnumVariantData::Tuple(types) => types.iter().collect(),
+                        EnumVariantData::Struct(fields) => {
+                            fields.iter().map(|(_, ty)| ty).collect()
+                        }
+                    })
+                    .collect();
+                Some((&def.name, payloads))
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether values of a type run drop glue when they go out of scope
+    pub(super) fn needs_drop(&self, ty: &Type) -> bool {
+        self.drop_name(&self.type_to_c(ty)).is_some()
+    }
+
+    /// Whether `ty` is a `ref str` view, passed by value rather than by pointer
+    pub(super) fn is_str_ref(ty: &Type) -> bool {
+        matches!(ty, Type::Reference { inner, .. } if matches!(inner.as_ref(), Type::Str))
+    }
+
+    /// The type whose drop glue a value of `c_type` runs, if any
+    pub(super) fn drop_name(&self, c_type: &str) -> Option<String> {
+        if let Some(contents) = c_type.strip_suffix('*') {
+            return self.box_name(contents);
+        }
+        let name = match c_type {
+            "PdString" => "String",
+            _ => c_type.trim_start_matches("struct "),
+        };
+        (self.drop_types.contains(name) || self.join_handles.contains(name))
+            .then(|| name.to_string())
+    }
+
+    /// Drop glue name of a box whose contents have C type `contents`
+    pub(super) fn box_name(&self, contents: &str) -> Option<String> {
+        let contents = contents.trim_start_matches("struct ");
+        self.box_types
+            .iter()
+            .find(|(_, c_type)| c_type.trim_start_matches("struct ") == contents)
+            .map(|(name, _)| name.clone())
+    }
+
+    /// C type of the values dropped by `__pd_drop_{name}`
+    pub(super) fn drop_c_type(&self, name: &str) -> String {
+        match (name, self.box_types.get(name)) {
+            (_, Some(contents)) => format!("{}*", contents),
+            ("String", None) => "PdString".to_string(),
+            _ => format!("struct {}", name),
+        }
+    }
+
+    /// Declare the `Drop` impls and drop glue ahead of the functions using them
+    pub(super) fn generate_drop_prototypes(&mut self) {
+        // The runtime defines the drop glue of strings
+        if self.drop_types.len() == 1 {
+            return;
+        }
+        self.output.push_str("// Destructors\n");
+        for name in &self.drop_impls {
+            self.output.push_str(&format!(
+                "void __pd_{}_drop(struct {}* self);\n",
+                name, name
+            ));
+        }
+        for name in self.drop_types.iter().filter(|name| *name != "String") {
+            self.output.push_str(&format!(
+                "static void __pd_drop_{}({}* self);\n",
+                name,
+                self.drop_c_type(name)
+            ));
+        }
+        self.output.push('\n');
+    }
+
+    /// Generate the drop glue of each type: its own `Drop::drop` runs first,
+    /// then the fields it owns are dropped in declaration order
+    pub(super) fn generate_drop_glue(&mut self, items: &[(Item, String)]) {
+        for (item, tag_name) in items {
+            let Some((name, _)) = Self::owned_field_types(item) else {
+                continue;
+            };
+            if !self.drop_types.contains(name) {
+                continue;
+            }
+            self.output.push_str(&format!(
+                "static void __pd_drop_{}(struct {}* self) {{\n",
+                name, name
+            ));
+            if self.drop_impls.contains(name) {
+                self.output
+                    .push_str(&format!("    __pd_{}_drop(self);\n", name));
+            }
+            match item {
+                Item::Struct(def) => {
+                    for (field, ty) in &def.fields {
+                        if let Some(field_type) = self.drop_name(&self.type_to_c(ty)) {
+                            self.output.push_str(&format!(
+                                "    __pd_drop_{}(&self->{});\n",
+                                field_type, field
+                            ));
+                        }
+                    }
+                }
+                Item::Enum(def) => {
+                    for variant in &def.variants {
+                        let members: Vec<(String, &Type)> = match &variant.data {
+                            EnumVariantData::Unit => vec![],
+                            EnumVariantData::Tuple(types) => types
+                                .iter()
+                                .enumerate()
+                                .map(|(i, ty)| (format!("field{}", i), ty))
+                                .collect(),
+                            EnumVariantData::Struct(fields) => fields
+                                .iter()
+                                .map(|(field, ty)| (field.clone(), ty))
+                                .collect(),
+                        };
+                        for (member, ty) in members {
+                            if let Some(field_type) = self.drop_name(&self.type_to_c(ty)) {
+                                self.output.push_str(&format!(
+                                    "    if (self->tag == __{}__{}) __pd_drop_{}(&self->data.{}.{});\n",
+                                    tag_name,
+                                    variant.name,
+                                    field_type,
+                                    variant.name.to_lowercase(),
+                                    member
+                                ));
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+            self.output.push_str("}\n\n");
+        }
+
+        // A box drops its contents, then frees them. Boxes moved out of are null.
+        for (name, contents) in &self.box_types {
+            let mut glue = format!("static void __pd_drop_{}({}** self) {{\n", name, contents);
+            glue.push_str("    if (!*self) return;\n");
+            if let Some(contents_type) = self.drop_name(contents) {
+                glue.push_str(&format!("    __pd_drop_{}(*self);\n", contents_type));
+            }
+            glue.push_str("    free(*self);\n");
+            glue.push_str("}\n\n");
+            self.output.push_str(&glue);
+        }
+
+        // A vector drops its elements, then frees its buffer
+        for (name, elem) in &self.vec_types {
+            let mut glue = format!("static void __pd_drop_{}(struct {}* self) {{\n", name, name);
+            if let Some(elem_type) = self.drop_name(&self.type_arg_to_c(elem)) {
+                glue.push_str(&format!(
+                    "    for (long long i = 0; i < self->len; i++) __pd_drop_{}(&self->data[i]);\n",
+                    elem_type
+                ));
+            }
+            glue.push_str("    free(self->data);\n");
+            glue.push_str("}\n\n");
+            self.output.push_str(&glue);
+        }
+
+        // A hash table drops the keys and values in its full slots, then frees
+        // its arrays
+        for (name, key, value) in self.tables() {
+            let mut glue = format!("static void __pd_drop_{}(struct {}* self) {{\n", name, name);
+            let mut drops = Vec::new();
+            if let Some(key_type) = self.drop_name(&self.type_arg_to_c(&key)) {
+                drops.push(format!("__pd_drop_{}(&self->keys[i]);", key_type));
+            }
+            if let Some(value_type) = value
+                .as_ref()
+                .and_then(|value| self.drop_name(&self.type_arg_to_c(value)))
+            {
+                drops.push(format!("__pd_drop_{}(&self->values[i]);", value_type));
+            }
+            if !drops.is_empty() {
+                glue.push_str(&format!(
+                    "    for (long long i = 0; i < self->capacity; i++) if (self->states[i] == 1) {{ {} }}\n",
+                    drops.join(" ")
+                ));
+            }
+            glue.push_str("    free(self->keys);\n");
+            if value.is_some() {
+                glue.push_str("    free(self->values);\n");
+            }
+            glue.push_str("    free(self->states);\n");
+            glue.push_str("}\n\n");
+            self.output.push_str(&glue);
+        }
+    }
+
+    /// Open a scope whose owned locals are dropped when it closes
+    pub(super) fn push_drop_scope(&mut self) {
+        self.drop_scopes.push(Vec::new());
+    }
+
+    /// Close the innermost scope, dropping its locals unless `body` already left it
+    pub(super) fn pop_drop_scope(&mut self, body: &[Stmt]) {
+        let diverges = matches!(
+            body.last(),
+            Some(Stmt::Return(_) | Stmt::Break { .. } | Stmt::Continue { .. })
+        );
+        if !diverges {
+            let drops = self.drop_code(self.drop_scopes.len().saturating_sub(1));
+            self.output.push_str(&drops);
+        }
+        self.drop_scopes.pop();
+    }
+
+    /// Destructor calls for the owned locals of the scopes from `depth` inward,
+    /// latest declared first. Locals that may have been moved check their drop flag.
+    pub(super) fn drop_code(&self, depth: usize) -> String {
+        let mut code = String::new();
+        for scope in self.drop_scopes.iter().skip(depth).rev() {
+            for (name, type_name, flagged) in scope.iter().rev() {
+                let call = format!("__pd_drop_{}(&{})", type_name, name);
+                if *flagged {
+                    code.push_str(&format!("    if ({}) {};\n", Self::drop_flag(name), call));
+                } else {
+                    code.push_str(&format!("    {};\n", call));
+                }
+            }
+        }
+        code
+    }
+
+    /// Name of the flag recording that a local still owns its value
+    pub(super) fn drop_flag(name: &str) -> String {
+        format!("__pd_drop_flag_{}", name)
+    }
+
+    /// Make the innermost scope responsible for dropping a local of C type `c_type`.
+    /// Locals the borrow checker saw moved get a drop flag, cleared where they move.
+    pub(super) fn own_local(&mut self, name: &str, c_type: &str) {
+        let Some(type_name) = self.drop_name(c_type) else {
+            return;
+        };
+        if self.drop_scopes.is_empty() {
+            return;
+        }
+        let flagged = self
+            .current_moved
+            .as_ref()
+            .is_none_or(|moved| moved.contains(name));
+        if flagged {
+            let declaration = self.local_decl("int", &Self::drop_flag(name));
+            self.output.push_str(&format!("    {} = 1;\n", declaration));
+        }
+        if let Some(scope) = self.drop_scopes.last_mut() {
+            scope.push((name.to_string(), type_name, flagged));
+        }
+    }
+
+    /// The innermost owned local called `name`
+    pub(super) fn owned_local(&self, name: &str) -> Option<&(String, String, bool)> {
+        self.drop_scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.iter().rev().find(|(local, _, _)| local == name))
+    }
+}
@@ -0,0 +1,724 @@
+// The async runtime linked into C programs with async functions
+//
+// Spawned tasks sit in a ready queue polled by `block_on`; wakers push them
+// back onto it, and an epoll reactor wakes the tasks waiting on timers or
+// descriptors. Each async function gets a join handle type for its tasks.
+
+use super::CodeGenerator;
+use crate::errors::{CompileError, Result};
+
+impl CodeGenerator {
+    /// Generate the executor linked into programs with async functions: a ready
+    /// queue of spawned tasks, their wakers and join handles, `block_on` for
+    /// `async fn main`, and an epoll reactor behind timers and descriptor readiness
+    pub(super) fn generate_executor(&mut self) {
+        self.output.push_str("#include <errno.h>\n");
+        self.output.push_str("#include <fcntl.h>\n");
+        self.output.push_str("#include <unistd.h>\n");
+        self.output.push_str("#include <arpa/inet.h>\n");
+        self.output.push_str("#include <netinet/in.h>\n");
+        self.output.push_str("#include <sys/epoll.h>\n");
+        self.output.push_str("#include <sys/socket.h>\n");
+        self.output.push_str("#include <sys/timerfd.h>\n\n");
+
+        // Executor: spawned tasks wait on a FIFO ready queue until they're woken, and are
+        // polled on the thread running block_on. A task owns a heap copy of its future
+        self.output.push_str("typedef struct PdTask {\n");
+        self.output
+            .push_str("    int (*poll)(void* future, PdWaker* waker);\n");
+        self.output.push_str("    void* future;\n");
+        self.output.push_str("    PdWaker waker;\n");
+        self.output.push_str("    PdWaker joiner;\n");
+        self.output.push_str("    int queued;\n");
+        self.output.push_str("    int done;\n");
+        self.output.push_str("    int refs;\n");
+        self.output.push_str("    struct PdTask* next_ready;\n");
+        self.output.push_str("    struct PdScope* scope;\n");
+        self.output.push_str("    struct PdScope* scopes;\n");
+        self.output.push_str("    int (*failed)(void* future);\n");
+        self.output.push_str("    int cancelled;\n");
+        self.output.push_str("    struct PdIoWait* io_waits;\n");
+        self.output.push_str("    struct PdTask* joining;\n");
+        self.output.push_str("} PdTask;\n\n");
+
+        // Async scope: the tasks spawned in it, which are all done before it ends.
+        // A task that fails cancels the others. The scopes open in a task are
+        // chained innermost first, so cancelling it cancels their children too
+        self.output.push_str("typedef struct PdScope {\n");
+        self.output.push_str("    PdTask* task;\n");
+        self.output.push_str("    struct PdScope* outer;\n");
+        self.output.push_str("    PdTask** children;\n");
+        self.output.push_str("    int count;\n");
+        self.output.push_str("    int capacity;\n");
+        self.output.push_str("    int running;\n");
+        self.output.push_str("    int failed;\n");
+        self.output.push_str("    PdWaker waiter;\n");
+        self.output.push_str("} PdScope;\n\n");
+
+        // Reactor: a descriptor being waited on is registered with epoll, one shot, and
+        // its waker woken once the descriptor is ready or has failed. The waits of a
+        // task are chained, so cancelling it can deregister them
+        self.output.push_str("typedef struct PdIoWait {\n");
+        self.output.push_str("    int fd;\n");
+        self.output.push_str("    int ready;\n");
+        self.output.push_str("    PdWaker waker;\n");
+        self.output.push_str("    PdTask* task;\n");
+        self.output.push_str("    struct PdIoWait* next;\n");
+        self.output.push_str("} PdIoWait;\n\n");
+
+        self.output.push_str("static struct {\n");
+        self.output.push_str("    PdTask* head;\n");
+        self.output.push_str("    PdTask* tail;\n");
+        self.output.push_str("    int epoll_fd;\n");
+        self.output.push_str("    long long io_waits;\n");
+        self.output
+            .push_str("} __pd_executor = { NULL, NULL, -1, 0 };\n\n");
+
+        self.output
+            .push_str("static void __pd_task_wake(void* data) {\n");
+        self.output.push_str("    PdTask* task = (PdTask*)data;\n");
+        self.output
+            .push_str("    if (task->queued || task->done) return;\n");
+        self.output.push_str("    task->queued = 1;\n");
+        self.output.push_str("    task->next_ready = NULL;\n");
+        self.output
+            .push_str("    if (__pd_executor.tail) __pd_executor.tail->next_ready = task;\n");
+        self.output
+            .push_str("    else __pd_executor.head = task;\n");
+        self.output.push_str("    __pd_executor.tail = task;\n");
+        self.output.push_str("}\n\n");
+
+        // io_wait_free: stop waiting on a descriptor
+        self.output
+            .push_str("static void __pd_io_wait_free(PdIoWait* wait) {\n");
+        self.output
+            .push_str("    epoll_ctl(__pd_executor.epoll_fd, EPOLL_CTL_DEL, wait->fd, NULL);\n");
+        self.output
+            .push_str("    if (!wait->ready) __pd_executor.io_waits--;\n");
+        self.output.push_str("    if (wait->task) {\n");
+        self.output
+            .push_str("        PdIoWait** link = &wait->task->io_waits;\n");
+        self.output
+            .push_str("        while (*link != wait) link = &(*link)->next;\n");
+        self.output.push_str("        *link = wait->next;\n");
+        self.output.push_str("    }\n");
+        self.output.push_str("    free(wait);\n");
+        self.output.push_str("}\n\n");
+
+        // A task is freed once it's done and its join handle has taken its output
+        self.output
+            .push_str("static void __pd_task_release(PdTask* task) {\n");
+        self.output.push_str("    if (--task->refs > 0) return;\n");
+        self.output.push_str("    free(task->future);\n");
+        self.output.push_str("    free(task);\n");
+        self.output.push_str("}\n\n");
+
+        self.output.push_str(
+            "static PdTask* __pd_task_spawn(int (*poll)(void*, PdWaker*), const void* future, size_t size) {\n",
+        );
+        self.output
+            .push_str("    PdTask* task = (PdTask*)calloc(1, sizeof(PdTask));\n");
+        self.output.push_str("    if (!task) abort();\n");
+        self.output.push_str("    task->future = malloc(size);\n");
+        self.output.push_str("    if (!task->future) abort();\n");
+        self.output
+            .push_str("    memcpy(task->future, future, size);\n");
+        self.output.push_str("    task->poll = poll;\n");
+        self.output
+            .push_str("    task->waker.wake = __pd_task_wake;\n");
+        self.output.push_str("    task->waker.data = task;\n");
+        self.output.push_str("    task->refs = 2;\n");
+        self.output.push_str("    __pd_task_wake(task);\n");
+        self.output.push_str("    return task;\n");
+        self.output.push_str("}\n\n");
+
+        // task_cancel: finish a task at its next turn without polling it again. Its
+        // future is freed with it, so nothing may wake it or point into it by then:
+        // its descriptors are deregistered, the task whose handle it awaits forgets
+        // it and loses the handle, and the children of its open scopes are cancelled
+        // and let go of
+        self.output
+            .push_str("static void __pd_task_cancel(PdTask* task) {\n");
+        self.output
+            .push_str("    if (task->done || task->cancelled) return;\n");
+        self.output.push_str("    task->cancelled = 1;\n");
+        self.output
+            .push_str("    while (task->io_waits) __pd_io_wait_free(task->io_waits);\n");
+        self.output.push_str("    if (task->joining) {\n");
+        self.output.push_str(
+            "        if (task->joining->joiner.data == task) task->joining->joiner.wake = NULL;\n",
+        );
+        self.output
+            .push_str("        __pd_task_release(task->joining);\n");
+        self.output.push_str("        task->joining = NULL;\n");
+        self.output.push_str("    }\n");
+        self.output
+            .push_str("    for (PdScope* scope = task->scopes; scope; scope = scope->outer) {\n");
+        self.output
+            .push_str("        for (int i = 0; i < scope->count; i++) {\n");
+        self.output
+            .push_str("            PdTask* child = scope->children[i];\n");
+        self.output
+            .push_str("            __pd_task_cancel(child);\n");
+        self.output.push_str("            child->scope = NULL;\n");
+        self.output
+            .push_str("            __pd_task_release(child);\n");
+        self.output.push_str("        }\n");
+        self.output.push_str("        free(scope->children);\n");
+        self.output.push_str("    }\n");
+        self.output.push_str("    task->scopes = NULL;\n");
+        self.output.push_str("    __pd_task_wake(task);\n");
+        self.output.push_str("}\n\n");
+
+        // task_abort: cancel the task of a join handle that hasn't been awaited
+        self.output
+            .push_str("static void __pd_task_abort(PdTask* task) {\n");
+        self.output
+            .push_str("    if (task) __pd_task_cancel(task);\n");
+        self.output.push_str("}\n\n");
+
+        // scope_enter: open `scope` in the task being polled with `waker`
+        self.output
+            .push_str("static void __pd_scope_enter(PdScope* scope, PdWaker* waker) {\n");
+        self.output
+            .push_str("    memset(scope, 0, sizeof(PdScope));\n");
+        self.output
+            .push_str("    if (waker && waker->wake == __pd_task_wake) {\n");
+        self.output
+            .push_str("        scope->task = (PdTask*)waker->data;\n");
+        self.output
+            .push_str("        scope->outer = scope->task->scopes;\n");
+        self.output
+            .push_str("        scope->task->scopes = scope;\n");
+        self.output.push_str("    }\n");
+        self.output.push_str("}\n\n");
+
+        // scope_add: make a task spawned in `scope` one of its children
+        self.output.push_str(
+            "static void __pd_scope_add(PdScope* scope, PdTask* task, int (*failed)(void*)) {\n",
+        );
+        self.output
+            .push_str("    if (scope->count == scope->capacity) {\n");
+        self.output
+            .push_str("        scope->capacity = scope->capacity ? scope->capacity * 2 : 4;\n");
+        self.output.push_str(
+            "        scope->children = (PdTask**)realloc(scope->children, scope->capacity * sizeof(PdTask*));\n",
+        );
+        self.output
+            .push_str("        if (!scope->children) abort();\n");
+        self.output.push_str("    }\n");
+        self.output
+            .push_str("    scope->children[scope->count++] = task;\n");
+        self.output.push_str("    scope->running++;\n");
+        self.output.push_str("    task->refs++;\n");
+        self.output.push_str("    task->scope = scope;\n");
+        self.output.push_str("    task->failed = failed;\n");
+        self.output
+            .push_str("    if (scope->failed) __pd_task_cancel(task);\n");
+        self.output.push_str("}\n\n");
+
+        // scope_child_done: a child of a scope is done. The first to fail cancels the others
+        self.output
+            .push_str("static void __pd_scope_child_done(PdTask* task) {\n");
+        self.output.push_str("    PdScope* scope = task->scope;\n");
+        self.output.push_str(
+            "    if (!task->cancelled && task->failed && task->failed(task->future) && !scope->failed) {\n",
+        );
+        self.output.push_str("        scope->failed = 1;\n");
+        self.output
+            .push_str("        for (int i = 0; i < scope->count; i++) {\n");
+        self.output
+            .push_str("            __pd_task_cancel(scope->children[i]);\n");
+        self.output.push_str("        }\n");
+        self.output.push_str("    }\n");
+        self.output
+            .push_str("    if (--scope->running == 0) __pd_wake(&scope->waiter);\n");
+        self.output.push_str("}\n\n");
+
+        // scope_poll: whether every child of a scope is done, letting go of them once they are
+        self.output
+            .push_str("static int __pd_scope_poll(PdScope** scope, PdWaker* waker) {\n");
+        self.output.push_str("    if ((*scope)->running > 0) {\n");
+        self.output
+            .push_str("        if (waker) (*scope)->waiter = *waker;\n");
+        self.output.push_str("        return 0;\n");
+        self.output.push_str("    }\n");
+        self.output
+            .push_str("    for (int i = 0; i < (*scope)->count; i++) {\n");
+        self.output
+            .push_str("        __pd_task_release((*scope)->children[i]);\n");
+        self.output.push_str("    }\n");
+        self.output
+            .push_str("    if ((*scope)->task) (*scope)->task->scopes = (*scope)->outer;\n");
+        self.output.push_str("    free((*scope)->children);\n");
+        self.output
+            .push_str("    memset(*scope, 0, sizeof(PdScope));\n");
+        self.output.push_str("    return 1;\n");
+        self.output.push_str("}\n\n");
+
+        // task_join: whether a task is done. If not, `waker` is woken once it is
+        self.output
+            .push_str("static int __pd_task_join(PdTask* task, PdWaker* waker) {\n");
+        self.output.push_str(
+            "    PdTask* joiner = waker && waker->wake == __pd_task_wake ? (PdTask*)waker->data : NULL;\n",
+        );
+        self.output.push_str("    if (task->done) {\n");
+        self.output
+            .push_str("        if (joiner && joiner->joining == task) joiner->joining = NULL;\n");
+        self.output.push_str("        return 1;\n");
+        self.output.push_str("    }\n");
+        self.output
+            .push_str("    if (waker) task->joiner = *waker;\n");
+        self.output
+            .push_str("    if (joiner) joiner->joining = task;\n");
+        self.output.push_str("    return 0;\n");
+        self.output.push_str("}\n\n");
+
+        // run_ready: poll woken tasks until none is left or `until` is done
+        self.output
+            .push_str("static void __pd_run_ready(PdTask* until) {\n");
+        self.output.push_str("    PdTask* task;\n");
+        self.output
+            .push_str("    while (!until->done && (task = __pd_executor.head)) {\n");
+        self.output
+            .push_str("        __pd_executor.head = task->next_ready;\n");
+        self.output
+            .push_str("        if (!__pd_executor.head) __pd_executor.tail = NULL;\n");
+        self.output.push_str("        task->queued = 0;\n");
+        self.output.push_str("        if (task->done) {\n");
+        self.output
+            .push_str("            __pd_task_release(task);\n");
+        self.output.push_str("            continue;\n");
+        self.output.push_str("        }\n");
+        self.output.push_str(
+            "        if (!task->cancelled && !task->poll(task->future, &task->waker)) continue;\n",
+        );
+        self.output.push_str("        task->done = 1;\n");
+        self.output.push_str("        __pd_wake(&task->joiner);\n");
+        self.output
+            .push_str("        if (task->scope) __pd_scope_child_done(task);\n");
+        self.output
+            .push_str("        if (!task->queued) __pd_task_release(task);\n");
+        self.output.push_str("    }\n");
+        self.output.push_str("}\n\n");
+
+        self.output.push_str(
+            "static PdIoWait* __pd_io_wait(int fd, unsigned int events, PdWaker* waker) {\n",
+        );
+        self.output
+            .push_str("    if (__pd_executor.epoll_fd < 0) {\n");
+        self.output
+            .push_str("        __pd_executor.epoll_fd = epoll_create1(EPOLL_CLOEXEC);\n");
+        self.output
+            .push_str("        if (__pd_executor.epoll_fd < 0) abort();\n");
+        self.output.push_str("    }\n");
+        self.output
+            .push_str("    PdIoWait* wait = (PdIoWait*)calloc(1, sizeof(PdIoWait));\n");
+        self.output.push_str("    if (!wait) abort();\n");
+        self.output.push_str("    wait->fd = fd;\n");
+        self.output
+            .push_str("    if (waker) wait->waker = *waker;\n");
+        self.output
+            .push_str("    if (waker && waker->wake == __pd_task_wake) {\n");
+        self.output
+            .push_str("        wait->task = (PdTask*)waker->data;\n");
+        self.output
+            .push_str("        wait->next = wait->task->io_waits;\n");
+        self.output
+            .push_str("        wait->task->io_waits = wait;\n");
+        self.output.push_str("    }\n");
+        self.output.push_str("    struct epoll_event event;\n");
+        self.output
+            .push_str("    event.events = events | EPOLLONESHOT;\n");
+        self.output.push_str("    event.data.ptr = wait;\n");
+        self.output.push_str(
+            "    if (epoll_ctl(__pd_executor.epoll_fd, EPOLL_CTL_ADD, fd, &event) < 0) {\n",
+        );
+        self.output.push_str("        fflush(stdout);\n");
+        self.output.push_str("        if (errno == EEXIST) {\n");
+        self.output.push_str(
+            "            fprintf(stderr, \"panic: file descriptor %d is already awaited by another task\\n\", fd);\n",
+        );
+        self.output.push_str("        } else {\n");
+        self.output.push_str(
+            "            fprintf(stderr, \"panic: cannot await file descriptor %d: %s\\n\", fd, strerror(errno));\n",
+        );
+        self.output.push_str("        }\n");
+        self.output.push_str("        abort();\n");
+        self.output.push_str("    }\n");
+        self.output.push_str("    __pd_executor.io_waits++;\n");
+        self.output.push_str("    return wait;\n");
+        self.output.push_str("}\n\n");
+
+        // io_poll: register `fd` on the first poll, and return 1 once it's ready
+        self.output.push_str(
+            "static int __pd_io_poll(PdIoWait** wait, int fd, unsigned int events, PdWaker* waker) {\n",
+        );
+        self.output.push_str("    if (!*wait) {\n");
+        self.output
+            .push_str("        *wait = __pd_io_wait(fd, events, waker);\n");
+        self.output.push_str("        return 0;\n");
+        self.output.push_str("    }\n");
+        self.output.push_str("    if (!(*wait)->ready) {\n");
+        self.output
+            .push_str("        if (waker) (*wait)->waker = *waker;\n");
+        self.output.push_str("        return 0;\n");
+        self.output.push_str("    }\n");
+        self.output.push_str("    __pd_io_wait_free(*wait);\n");
+        self.output.push_str("    *wait = NULL;\n");
+        self.output.push_str("    return 1;\n");
+        self.output.push_str("}\n\n");
+
+        // reactor_turn: block until a registered descriptor is ready and wake its task.
+        // Returns 0 if none is registered, when nothing is left that could wake a task
+        self.output
+            .push_str("static int __pd_reactor_turn(void) {\n");
+        self.output
+            .push_str("    if (__pd_executor.io_waits == 0) return 0;\n");
+        self.output.push_str("    struct epoll_event events[64];\n");
+        self.output.push_str("    int count;\n");
+        self.output.push_str("    do {\n");
+        self.output
+            .push_str("        count = epoll_wait(__pd_executor.epoll_fd, events, 64, -1);\n");
+        self.output
+            .push_str("    } while (count < 0 && errno == EINTR);\n");
+        self.output.push_str("    if (count < 0) abort();\n");
+        self.output
+            .push_str("    for (int i = 0; i < count; i++) {\n");
+        self.output
+            .push_str("        PdIoWait* wait = (PdIoWait*)events[i].data.ptr;\n");
+        self.output.push_str("        wait->ready = 1;\n");
+        self.output.push_str("        __pd_executor.io_waits--;\n");
+        self.output.push_str("        __pd_wake(&wait->waker);\n");
+        self.output.push_str("    }\n");
+        self.output.push_str("    return 1;\n");
+        self.output.push_str("}\n\n");
+
+        // block_on: run tasks until `task` is done
+        self.output
+            .push_str("static void __pd_block_on(PdTask* task) {\n");
+        self.output.push_str("    for (;;) {\n");
+        self.output.push_str("        __pd_run_ready(task);\n");
+        self.output.push_str("        if (task->done) return;\n");
+        self.output
+            .push_str("        if (!__pd_reactor_turn()) {\n");
+        self.output.push_str("            fflush(stdout);\n");
+        self.output.push_str(
+            "            fprintf(stderr, \"panic: async fn main is pending, but no task or descriptor is left to wake it\\n\");\n",
+        );
+        self.output.push_str("            abort();\n");
+        self.output.push_str("        }\n");
+        self.output.push_str("    }\n");
+        self.output.push_str("}\n\n");
+
+        // sleep_ms: a timer descriptor that becomes readable after `ms` milliseconds
+        self.output
+            .push_str("typedef struct __pd_sleep_ms_Future {\n");
+        self.output.push_str("    int state;\n");
+        self.output.push_str("    long long ms;\n");
+        self.output.push_str("    int fd;\n");
+        self.output.push_str("    PdIoWait* wait;\n");
+        self.output.push_str("} __pd_sleep_ms_Future;\n\n");
+
+        self.output
+            .push_str("static __pd_sleep_ms_Future __pd_sleep_ms(long long ms) {\n");
+        self.output
+            .push_str("    __pd_sleep_ms_Future future = {0};\n");
+        self.output.push_str("    future.ms = ms;\n");
+        self.output.push_str("    return future;\n");
+        self.output.push_str("}\n\n");
+
+        self.output.push_str(
+            "static int __pd_sleep_ms_poll(__pd_sleep_ms_Future* future, PdWaker* waker) {\n",
+        );
+        self.output
+            .push_str("    if (future->state == 2) return 1;\n");
+        self.output.push_str("    if (future->state == 0) {\n");
+        self.output.push_str("        if (future->ms <= 0) {\n");
+        self.output.push_str("            future->state = 2;\n");
+        self.output.push_str("            return 1;\n");
+        self.output.push_str("        }\n");
+        self.output.push_str(
+            "        future->fd = timerfd_create(CLOCK_MONOTONIC, TFD_NONBLOCK | TFD_CLOEXEC);\n",
+        );
+        self.output
+            .push_str("        if (future->fd < 0) abort();\n");
+        self.output.push_str("        struct itimerspec when;\n");
+        self.output
+            .push_str("        memset(&when, 0, sizeof(when));\n");
+        self.output
+            .push_str("        when.it_value.tv_sec = future->ms / 1000;\n");
+        self.output
+            .push_str("        when.it_value.tv_nsec = (future->ms % 1000) * 1000000;\n");
+        self.output
+            .push_str("        timerfd_settime(future->fd, 0, &when, NULL);\n");
+        self.output.push_str("        future->state = 1;\n");
+        self.output.push_str("    }\n");
+        self.output.push_str(
+            "    if (!__pd_io_poll(&future->wait, future->fd, EPOLLIN, waker)) return 0;\n",
+        );
+        self.output.push_str("    close(future->fd);\n");
+        self.output.push_str("    future->state = 2;\n");
+        self.output.push_str("    return 1;\n");
+        self.output.push_str("}\n\n");
+
+        // readable, writable: ready once a descriptor can be read or written without blocking
+        self.output
+            .push_str("typedef struct __pd_readable_Future {\n");
+        self.output.push_str("    int state;\n");
+        self.output.push_str("    int fd;\n");
+        self.output.push_str("    PdIoWait* wait;\n");
+        self.output.push_str("} __pd_readable_Future;\n\n");
+
+        self.output
+            .push_str("static __pd_readable_Future __pd_readable(long long fd) {\n");
+        self.output
+            .push_str("    __pd_readable_Future future = {0};\n");
+        self.output.push_str("    future.fd = (int)fd;\n");
+        self.output.push_str("    return future;\n");
+        self.output.push_str("}\n\n");
+
+        self.output.push_str(
+            "static int __pd_readable_poll(__pd_readable_Future* future, PdWaker* waker) {\n",
+        );
+        self.output.push_str(
+            "    if (!future->state) future->state = __pd_io_poll(&future->wait, future->fd, EPOLLIN, waker);\n",
+        );
+        self.output.push_str("    return future->state;\n");
+        self.output.push_str("}\n\n");
+
+        self.output
+            .push_str("typedef struct __pd_writable_Future {\n");
+        self.output.push_str("    int state;\n");
+        self.output.push_str("    int fd;\n");
+        self.output.push_str("    PdIoWait* wait;\n");
+        self.output.push_str("} __pd_writable_Future;\n\n");
+
+        self.output
+            .push_str("static __pd_writable_Future __pd_writable(long long fd) {\n");
+        self.output
+            .push_str("    __pd_writable_Future future = {0};\n");
+        self.output.push_str("    future.fd = (int)fd;\n");
+        self.output.push_str("    return future;\n");
+        self.output.push_str("}\n\n");
+
+        self.output.push_str(
+            "static int __pd_writable_poll(__pd_writable_Future* future, PdWaker* waker) {\n",
+        );
+        self.output.push_str(
+            "    if (!future->state) future->state = __pd_io_poll(&future->wait, future->fd, EPOLLOUT, waker);\n",
+        );
+        self.output.push_str("    return future->state;\n");
+        self.output.push_str("}\n\n");
+
+        // tcp_listen: a non-blocking socket listening on every interface, or -1
+        self.output
+            .push_str("static long long __pd_tcp_listen(long long port) {\n");
+        self.output.push_str(
+            "    int fd = socket(AF_INET, SOCK_STREAM | SOCK_NONBLOCK | SOCK_CLOEXEC, 0);\n",
+        );
+        self.output.push_str("    if (fd < 0) return -1;\n");
+        self.output.push_str("    int on = 1;\n");
+        self.output
+            .push_str("    setsockopt(fd, SOL_SOCKET, SO_REUSEADDR, &on, sizeof(on));\n");
+        self.output.push_str("    struct sockaddr_in addr;\n");
+        self.output
+            .push_str("    memset(&addr, 0, sizeof(addr));\n");
+        self.output.push_str("    addr.sin_family = AF_INET;\n");
+        self.output
+            .push_str("    addr.sin_addr.s_addr = htonl(INADDR_ANY);\n");
+        self.output
+            .push_str("    addr.sin_port = htons((uint16_t)port);\n");
+        self.output.push_str(
+            "    if (bind(fd, (struct sockaddr*)&addr, sizeof(addr)) < 0 || listen(fd, SOMAXCONN) < 0) {\n",
+        );
+        self.output.push_str("        close(fd);\n");
+        self.output.push_str("        return -1;\n");
+        self.output.push_str("    }\n");
+        self.output.push_str("    return fd;\n");
+        self.output.push_str("}\n\n");
+
+        // tcp_accept: the next pending connection, non-blocking, or -1 if there's none yet
+        self.output
+            .push_str("static long long __pd_tcp_accept(long long listener) {\n");
+        self.output
+            .push_str("    int fd = accept((int)listener, NULL, NULL);\n");
+        self.output
+            .push_str("    if (fd >= 0) fcntl(fd, F_SETFL, fcntl(fd, F_GETFL) | O_NONBLOCK);\n");
+        self.output.push_str("    return fd;\n");
+        self.output.push_str("}\n\n");
+
+        // fd_read: up to `max` bytes; empty at end of file, on error, or if none are ready
+        self.output
+            .push_str("static PdString __pd_fd_read(long long fd, long long max) {\n");
+        self.output
+            .push_str("    if (max <= 0) return __pd_str(\"\");\n");
+        self.output
+            .push_str("    char* data = (char*)malloc(max + 1);\n");
+        self.output.push_str("    if (!data) abort();\n");
+        self.output
+            .push_str("    long long len = read((int)fd, data, max);\n");
+        self.output.push_str("    if (len <= 0) {\n");
+        self.output.push_str("        free(data);\n");
+        self.output.push_str("        return __pd_str(\"\");\n");
+        self.output.push_str("    }\n");
+        self.output.push_str("    data[len] = 0;\n");
+        self.output
+            .push_str("    return (PdString){data, len, max + 1};\n");
+        self.output.push_str("}\n\n");
+
+        // fd_write: write what's possible of `data`, returning the bytes written or -1
+        self.output
+            .push_str("static long long __pd_fd_write(long long fd, PdString data) {\n");
+        self.output
+            .push_str("    long long written = write((int)fd, data.data, data.len);\n");
+        self.output.push_str("    __pd_string_release(data);\n");
+        self.output.push_str("    return written;\n");
+        self.output.push_str("}\n\n");
+
+        self.output
+            .push_str("static void __pd_fd_close(long long fd) {\n");
+        self.output.push_str("    close((int)fd);\n");
+        self.output.push_str("}\n\n");
+
+        for name in [
+            "__pd_yield_now",
+            "__pd_sleep_ms",
+            "__pd_readable",
+            "__pd_writable",
+        ] {
+            self.generate_join_handle(name, "void", false);
+        }
+    }
+
+    /// Generate spawning a future of async fn `name` as a task, and the join
+    /// handle it returns, which is a future of the task's output. The task of
+    /// a `fallible` function, returning a Result, fails when it returns an error
+    pub(super) fn generate_join_handle(&mut self, name: &str, output_type: &str, fallible: bool) {
+        let future_name = format!("{}_Future", name);
+        let handle_name = format!("{}_JoinHandle", name);
+        self.output
+            .push_str(&format!("typedef struct {} {{\n", handle_name));
+        self.output.push_str("    PdTask* task;\n");
+        if output_type != "void" {
+            self.output
+                .push_str(&format!("    {} result;\n", output_type));
+        }
+        self.output.push_str(&format!("}} {};\n\n", handle_name));
+
+        self.output.push_str(&format!(
+            "static int {}_task_poll(void* future, PdWaker* waker) {{\n",
+            name
+        ));
+        self.output.push_str(&format!(
+            "    return {}_poll(({}*)future, waker);\n",
+            name, future_name
+        ));
+        self.output.push_str("}\n\n");
+
+        self.output.push_str(&format!(
+            "static {} {}_spawn({} future) {{\n",
+            handle_name, name, future_name
+        ));
+        self.output
+            .push_str(&format!("    {} handle = {{0}};\n", handle_name));
+        self.output.push_str(&format!(
+            "    handle.task = __pd_task_spawn({}_task_poll, &future, sizeof(future));\n",
+            name
+        ));
+        self.output.push_str("    return handle;\n");
+        self.output.push_str("}\n\n");
+
+        // Spawning in an async scope makes the task one of the scope's children
+        if fallible {
+            self.output.push_str(&format!(
+                "static int {}_task_failed(void* future) {{\n",
+                name
+            ));
+            self.output.push_str(&format!(
+                "    return (({}*)future)->result.tag == __Result__Err;\n",
+                future_name
+            ));
+            self.output.push_str("}\n\n");
+        }
+        self.output.push_str(&format!(
+            "static {} {}_spawn_in({} future, PdScope* scope) {{\n",
+            handle_name, name, future_name
+        ));
+        self.output.push_str(&format!(
+            "    {} handle = {}_spawn(future);\n",
+            handle_name, name
+        ));
+        self.output.push_str(&format!(
+            "    __pd_scope_add(scope, handle.task, {});\n",
+            if fallible {
+                format!("{}_task_failed", name)
+            } else {
+                "NULL".to_string()
+            }
+        ));
+        self.output.push_str("    return handle;\n");
+        self.output.push_str("}\n\n");
+
+        // Awaiting the handle takes the output and releases the task
+        self.output.push_str(&format!(
+            "static int {}_poll({}* handle, PdWaker* waker) {{\n",
+            handle_name, handle_name
+        ));
+        self.output.push_str("    if (!handle->task) return 1;\n");
+        self.output
+            .push_str("    if (!__pd_task_join(handle->task, waker)) return 0;\n");
+        self.output.push_str("    if (handle->task->cancelled) {\n");
+        self.output.push_str("        fflush(stdout);\n");
+        self.output
+            .push_str("        fprintf(stderr, \"panic: awaited a cancelled task\\n\");\n");
+        self.output.push_str("        abort();\n");
+        self.output.push_str("    }\n");
+        if output_type != "void" {
+            self.output.push_str(&format!(
+                "    handle->result = (({}*)handle->task->future)->result;\n",
+                future_name
+            ));
+        }
+        self.output
+            .push_str("    __pd_task_release(handle->task);\n");
+        self.output.push_str("    handle->task = NULL;\n");
+        self.output.push_str("    return 1;\n");
+        self.output.push_str("}\n\n");
+
+        // Dropping a handle detaches the task, which keeps running
+        self.output.push_str(&format!(
+            "static void __pd_drop_{}(struct {}* self) {{\n",
+            handle_name, handle_name
+        ));
+        self.output
+            .push_str("    if (self->task) __pd_task_release(self->task);\n");
+        self.output.push_str("    self->task = NULL;\n");
+        self.output.push_str("}\n\n");
+        self.join_handles.insert(handle_name);
+    }
+
+    /// Generate the C `main` of `async fn main`, which blocks on its future,
+    /// generated as `__pd_main`
+    pub(super) fn generate_async_main(&mut self, output_type: &str) -> Result<()> {
+        let exit_code = match output_type {
+            "void" => "0",
+            "int" | "long long" => "(int)handle.result",
+            _ => {
+                return Err(CompileError::Generic(
+                    "async fn main must return () or an integer".to_string(),
+                ))
+            }
+        };
+        self.output.push_str("int main(void) {\n");
+        self.output
+            .push_str("    __pd_main_JoinHandle handle = __pd_main_spawn(__pd_main());\n");
+        self.output.push_str("    __pd_block_on(handle.task);\n");
+        self.output
+            .push_str("    __pd_main_JoinHandle_poll(&handle, NULL);\n");
+        self.output
+            .push_str(&format!("    return {};\n", exit_code));
+        self.output.push_str("}\n\n");
+        Ok(())
+    }
+}
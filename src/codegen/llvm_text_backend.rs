@@ -988,6 +988,18 @@ impl LLVMTextBackend {
                     ir.push_str(&self.generate_statement(stmt)?);
                 }
             }
+
+            Stmt::AsyncScope { .. } => {
+                return Err(CompileError::Generic(
+                    "async scopes are not yet supported by the LLVM backend".to_string(),
+                ));
+            }
+
+            Stmt::Handle { .. } => {
+                return Err(CompileError::Generic(
+                    "effect handlers are not yet supported by the LLVM backend".to_string(),
+                ));
+            }
         }
 
         Ok(ir)
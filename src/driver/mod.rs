@@ -111,6 +111,17 @@ impl Driver {
             );
         }
 
+        // Get generic enum instantiations and `?` lowerings from type checker
+        let enum_instantiations = type_checker.get_enum_instantiations();
+        let enum_expr_types = type_checker.get_enum_expr_types();
+        let question_lowerings = type_checker.get_question_lowerings();
+        let format_args = type_checker.get_format_args();
+        let generic_call_types = type_checker.get_generic_call_types();
+        let box_types = type_checker.get_box_types();
+        let vec_types = type_checker.get_vec_types();
+        let map_types = type_checker.get_map_types();
+        let set_types = type_checker.get_set_types();
+
         // Get generic struct instantiations from type checker
         let struct_instantiations = type_checker.get_struct_instantiations();
         if !struct_instantiations.is_empty() {
@@ -134,16 +145,20 @@ impl Driver {
         // Phase 3.6: Effect analysis
         println!("🌊 Analyzing effects...");
         let mut effect_analyzer = crate::effects::EffectAnalyzer::new();
-        for item in &ast.items {
-            if let crate::ast::Item::Function(func) = item {
-                let effects = effect_analyzer.analyze_function(func)?;
-                if !effects.is_pure() {
-                    println!(
-                        "   Function '{}' has effects: {:?}",
-                        func.name,
-                        effects.effects()
-                    );
-                }
+        let effect_warnings = effect_analyzer.check_program(&ast)?;
+        for (name, effects) in effect_analyzer.performed_effects() {
+            if !effects.is_pure() {
+                println!(
+                    "   Function '{}' has effects: {}",
+                    name,
+                    effects.names().join(", ")
+                );
+            }
+        }
+        if !effect_warnings.is_empty() {
+            let reporter = ErrorReporter::with_source(filename.to_string(), source.to_string());
+            for warning in &effect_warnings {
+                reporter.report(&warning.to_diagnostic());
             }
         }
         println!("   Effect analysis complete!");
@@ -197,6 +212,19 @@ impl Driver {
                 codegen.set_generic_struct_instantiations(struct_instantiations);
             }
 
+            // Pass generic enum instantiations to code generator
+            codegen.set_generic_enum_instantiations(enum_instantiations);
+            codegen.set_enum_expr_types(enum_expr_types);
+            codegen.set_question_lowerings(question_lowerings);
+            codegen.set_format_args(format_args);
+            codegen.set_generic_call_types(generic_call_types);
+            codegen.set_box_types(box_types);
+            codegen.set_vec_types(vec_types);
+            codegen.set_map_types(map_types);
+            codegen.set_set_types(set_types);
+            codegen.set_moved_locals(borrow_checker.get_moved_locals());
+            codegen.set_handler_params(effect_analyzer.handler_params());
+
             codegen.compile(&ast)?;
             let output = codegen.write_output()?;
             let gen_time = gen_start.elapsed();
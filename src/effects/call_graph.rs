@@ -0,0 +1,122 @@
+// Call graph of a program, for inferring effects across functions
+// "Effects flow from callee to caller"
+
+use crate::ast::{Function, Item, Program, Type, Visibility};
+use petgraph::algo::tarjan_scc;
+use petgraph::graph::{DiGraph, NodeIndex};
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+/// A function of the program whose effects are inferred
+pub struct CallNode<'a> {
+    /// `name` for functions, `Type::name` for methods and `Trait::name` for
+    /// the default methods of traits
+    pub name: String,
+    /// The implementing type of methods, or the trait of default methods
+    pub owner: Option<String>,
+    pub func: Cow<'a, Function>,
+}
+
+/// Functions of a program, with an edge from each caller to its callees
+pub struct CallGraph<'a> {
+    pub nodes: Vec<CallNode<'a>>,
+    graph: DiGraph<(), ()>,
+}
+
+impl<'a> CallGraph<'a> {
+    /// The functions, impl methods and default trait methods of a program,
+    /// without any calls yet
+    pub fn new(program: &'a Program) -> Self {
+        let mut nodes = Vec::new();
+        for item in &program.items {
+            match item {
+                Item::Function(func) => nodes.push(CallNode {
+                    name: func.name.clone(),
+                    owner: None,
+                    func: Cow::Borrowed(func),
+                }),
+                Item::Impl(impl_block) => {
+                    let owner = type_name(&impl_block.for_type);
+                    for method in &impl_block.methods {
+                        nodes.push(CallNode {
+                            name: format!("{}::{}", owner, method.name),
+                            owner: Some(owner.clone()),
+                            func: Cow::Borrowed(method),
+                        });
+                    }
+                }
+                Item::Trait(trait_def) => {
+                    for method in &trait_def.methods {
+                        let Some(body) = &method.body else {
+                            continue;
+                        };
+                        nodes.push(CallNode {
+                            name: format!("{}::{}", trait_def.name, method.name),
+                            owner: Some(trait_def.name.clone()),
+                            func: Cow::Owned(Function {
+                                visibility: Visibility::Private,
+                                is_async: false,
+                                name: method.name.clone(),
+                                lifetime_params: method.lifetime_params.clone(),
+                                type_params: method.type_params.clone(),
+                                const_params: Vec::new(),
+                                params: method.params.clone(),
+                                return_type: method.return_type.clone(),
+                                body: body.clone(),
+                                span: method.span,
+                                effects: None,
+                                effect_params: Vec::new(),
+                            }),
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let mut graph = DiGraph::with_capacity(nodes.len(), 0);
+        for _ in &nodes {
+            graph.add_node(());
+        }
+        Self { nodes, graph }
+    }
+
+    /// The nodes of every function, keyed by name. Several functions can share
+    /// a name, like the inherent and trait methods of a type.
+    pub fn names(&self) -> HashMap<String, Vec<usize>> {
+        let mut names: HashMap<String, Vec<usize>> = HashMap::new();
+        for (index, node) in self.nodes.iter().enumerate() {
+            names.entry(node.name.clone()).or_default().push(index);
+        }
+        names
+    }
+
+    /// Record that `caller` calls `callee`
+    pub fn add_call(&mut self, caller: usize, callee: usize) {
+        self.graph
+            .update_edge(NodeIndex::new(caller), NodeIndex::new(callee), ());
+    }
+
+    /// Whether `node` calls itself
+    pub fn is_recursive(&self, node: usize) -> bool {
+        let node = NodeIndex::new(node);
+        self.graph.contains_edge(node, node)
+    }
+
+    /// Strongly connected components of the graph, callees before callers:
+    /// the functions in each component call each other, directly or not
+    pub fn components(&self) -> Vec<Vec<usize>> {
+        tarjan_scc(&self.graph)
+            .into_iter()
+            .map(|component| component.into_iter().map(NodeIndex::index).collect())
+            .collect()
+    }
+}
+
+/// The name of an implementing type, without its type arguments
+pub fn type_name(ty: &Type) -> String {
+    match ty {
+        Type::Custom(name) | Type::Generic { name, .. } => name.clone(),
+        ty => ty.to_string(),
+    }
+}
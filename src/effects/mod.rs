@@ -1,9 +1,16 @@
 // Effect system for Palladium
 // "Tracking the ripples of computation"
 
-use crate::ast::{Expr, Function, Stmt};
-use crate::errors::Result;
+mod call_graph;
+
+use crate::ast::{
+    AssignTarget, BinOp, EnumConstructorData, Expr, Function, Item, Program, Stmt, StructBase, Type,
+};
+use crate::errors::{CompileError, Diagnostic, Result, Span};
+pub use call_graph::type_name;
+use call_graph::{CallGraph, CallNode};
 use std::collections::HashSet;
+use std::fmt;
 
 /// Represents different kinds of effects a function can have
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -20,6 +27,49 @@ pub enum Effect {
     Unsafe,
     /// Pure - no side effects
     Pure,
+    /// An effect variable of a polymorphic function, standing for the effects
+    /// of the functions passed to it
+    Var(String),
+    /// A user-defined effect, declared with `effect Name { ... }`
+    User(String),
+}
+
+impl Effect {
+    /// The effects a function can declare, in `![...]` after its signature
+    pub const DECLARABLE: [Effect; 5] = [
+        Effect::IO,
+        Effect::Memory,
+        Effect::Panic,
+        Effect::Async,
+        Effect::Unsafe,
+    ];
+
+    /// Look up a declarable effect by its name
+    pub fn from_name(name: &str) -> Option<Effect> {
+        Self::DECLARABLE
+            .iter()
+            .find(|effect| effect.name() == name)
+            .cloned()
+    }
+
+    /// The name of the effect in declarations
+    pub fn name(&self) -> &str {
+        match self {
+            Effect::IO => "io",
+            Effect::Memory => "memory",
+            Effect::Panic => "panic",
+            Effect::Async => "async",
+            Effect::Unsafe => "unsafe",
+            Effect::Pure => "pure",
+            Effect::Var(name) | Effect::User(name) => name,
+        }
+    }
+}
+
+impl fmt::Display for Effect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
 }
 
 /// Effect set for a function or expression
@@ -57,6 +107,11 @@ impl EffectSet {
         }
     }
 
+    /// Remove an effect from the set
+    pub fn remove(&mut self, effect: &Effect) {
+        self.effects.remove(effect);
+    }
+
     /// Check if the effect set is pure
     pub fn is_pure(&self) -> bool {
         self.effects.is_empty() || self.effects.contains(&Effect::Pure)
@@ -71,6 +126,55 @@ impl EffectSet {
     pub fn effects(&self) -> &HashSet<Effect> {
         &self.effects
     }
+
+    /// The names of the effects, sorted
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<_> = self
+            .effects
+            .iter()
+            .filter(|effect| **effect != Effect::Pure)
+            .map(Effect::name)
+            .collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// The names of the user-defined effects, sorted
+    pub fn user_effects(&self) -> Vec<&str> {
+        let mut names: Vec<_> = self
+            .effects
+            .iter()
+            .filter(|effect| matches!(effect, Effect::User(_)))
+            .map(Effect::name)
+            .collect();
+        names.sort_unstable();
+        names
+    }
+}
+
+/// An effect a function declares but never performs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EffectWarning {
+    pub function: String,
+    pub effect: Effect,
+    pub span: Span,
+}
+
+impl EffectWarning {
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        Diagnostic::warning(format!(
+            "function '{}' declares effect '{}' but never performs it",
+            self.function, self.effect
+        ))
+        .with_span(self.span)
+        .with_suggestion(
+            format!(
+                "Remove '{}' from the effects of '{}'",
+                self.effect, self.function
+            ),
+            None,
+        )
+    }
 }
 
 /// Effect analyzer for tracking effects in code
@@ -79,6 +183,24 @@ pub struct EffectAnalyzer {
     function_effects: std::collections::HashMap<String, EffectSet>,
     /// Built-in functions and their effects
     builtin_effects: std::collections::HashMap<String, EffectSet>,
+    /// Functions of the program in source order, with `Type::name` for methods
+    functions: Vec<String>,
+    /// Qualified names of the methods of each name, as `value.name()` may call
+    methods: std::collections::HashMap<String, Vec<String>>,
+    /// Functions of the program the function being analyzed calls
+    calls: HashSet<String>,
+    /// Where the function being analyzed first performs each of its effects
+    origins: std::collections::HashMap<Effect, (Span, String)>,
+    /// The effects each parameter of a function type allows, per parameter of
+    /// every function of the program, for instantiating effect variables
+    signatures: std::collections::HashMap<String, Vec<Option<(String, EffectSet)>>>,
+    /// Parameters of a function type of the function being analyzed
+    fn_params: std::collections::HashMap<String, EffectSet>,
+    /// User-defined effects with the names of their operations
+    user_effects: std::collections::HashMap<String, Vec<String>>,
+    /// The effects each function of the program performs, whether or not it
+    /// declares them
+    performed_effects: std::collections::HashMap<String, EffectSet>,
 }
 
 impl Default for EffectAnalyzer {
@@ -88,6 +210,14 @@ impl Default for EffectAnalyzer {
         // IO functions
         builtin_effects.insert("print".to_string(), EffectSet::singleton(Effect::IO));
         builtin_effects.insert("print_int".to_string(), EffectSet::singleton(Effect::IO));
+        builtin_effects.insert(
+            "__write_stdout".to_string(),
+            EffectSet::singleton(Effect::IO),
+        );
+        builtin_effects.insert(
+            "__write_stderr".to_string(),
+            EffectSet::singleton(Effect::IO),
+        );
         builtin_effects.insert("file_open".to_string(), EffectSet::singleton(Effect::IO));
         builtin_effects.insert(
             "file_read_all".to_string(),
@@ -100,6 +230,20 @@ impl Default for EffectAnalyzer {
         builtin_effects.insert("file_write".to_string(), EffectSet::singleton(Effect::IO));
         builtin_effects.insert("file_close".to_string(), EffectSet::singleton(Effect::IO));
         builtin_effects.insert("file_exists".to_string(), EffectSet::singleton(Effect::IO));
+        for name in [
+            "tcp_listen",
+            "tcp_accept",
+            "fd_read",
+            "fd_write",
+            "fd_close",
+        ] {
+            builtin_effects.insert(name.to_string(), EffectSet::singleton(Effect::IO));
+        }
+
+        // Futures and tasks of the executor
+        for name in ["yield_now", "sleep_ms", "readable", "writable", "spawn"] {
+            builtin_effects.insert(name.to_string(), EffectSet::singleton(Effect::Async));
+        }
 
         // Memory functions
         // For now, we don't have explicit allocation functions
@@ -107,6 +251,7 @@ impl Default for EffectAnalyzer {
         // Pure functions
         builtin_effects.insert("string_len".to_string(), EffectSet::new());
         builtin_effects.insert("string_concat".to_string(), EffectSet::new());
+        builtin_effects.insert("string_push_str".to_string(), EffectSet::new());
         builtin_effects.insert("string_eq".to_string(), EffectSet::new());
         builtin_effects.insert("string_char_at".to_string(), EffectSet::new());
         builtin_effects.insert("string_substring".to_string(), EffectSet::new());
@@ -116,10 +261,21 @@ impl Default for EffectAnalyzer {
         builtin_effects.insert("char_is_whitespace".to_string(), EffectSet::new());
         builtin_effects.insert("string_to_int".to_string(), EffectSet::new());
         builtin_effects.insert("int_to_string".to_string(), EffectSet::new());
+        for kind in crate::macros::format::FormatKind::ALL {
+            builtin_effects.insert(kind.intrinsic().to_string(), EffectSet::new());
+        }
 
         Self {
             function_effects: std::collections::HashMap::new(),
             builtin_effects,
+            functions: Vec::new(),
+            methods: std::collections::HashMap::new(),
+            calls: HashSet::new(),
+            origins: std::collections::HashMap::new(),
+            signatures: std::collections::HashMap::new(),
+            fn_params: std::collections::HashMap::new(),
+            user_effects: std::collections::HashMap::new(),
+            performed_effects: std::collections::HashMap::new(),
         }
     }
 }
@@ -129,9 +285,442 @@ impl EffectAnalyzer {
         Self::default()
     }
 
+    /// Infer the effects of every function, impl method and default trait
+    /// method of a program, holding those that declare their effects to them:
+    /// performing an undeclared effect is an error, and declaring one that's
+    /// never performed gets a warning.
+    ///
+    /// Effects flow from callees to callers over the call graph, so functions
+    /// may call others defined after them, or each other recursively. A call
+    /// `value.name()` may reach any method named `name`, since the type of
+    /// `value`, maybe a generic one, isn't known here.
+    ///
+    /// Parameters of a function type allow the effects their type names, or
+    /// else have an effect variable of their own. Each call instantiates the
+    /// effect variables of its callee with the effects of the functions
+    /// passed for them.
+    ///
+    /// Operations of user-defined effects perform their effect, until a
+    /// `handle` block handles it; `main` has to handle every one.
+    pub fn check_program(&mut self, program: &Program) -> Result<Vec<EffectWarning>> {
+        for item in &program.items {
+            if let Item::Effect(effect_def) = item {
+                let operations = effect_def
+                    .operations
+                    .iter()
+                    .map(|operation| operation.name.clone())
+                    .collect();
+                self.user_effects
+                    .insert(effect_def.name.clone(), operations);
+            }
+        }
+
+        let mut graph = CallGraph::new(program);
+        let names = graph.names();
+
+        // Functions start out pure, except those declaring their effects,
+        // which callers see instead of the inferred ones
+        let mut declared = std::collections::HashMap::new();
+        let mut effects = Vec::with_capacity(graph.nodes.len());
+        for (index, node) in graph.nodes.iter().enumerate() {
+            let declared_effects = self.declared_effects(&node.name, &node.func)?;
+            let signature = self.param_effects(&node.name, &node.func)?;
+            self.signatures
+                .entry(node.name.clone())
+                .or_insert(signature);
+            effects.push(declared_effects.clone().unwrap_or_default());
+            if let Some(declared_effects) = declared_effects {
+                declared.insert(index, declared_effects);
+            }
+            if node.owner.is_some() {
+                self.methods
+                    .entry(node.func.name.clone())
+                    .or_default()
+                    .push(node.name.clone());
+            }
+            if !self.functions.contains(&node.name) {
+                self.functions.push(node.name.clone());
+            }
+        }
+        for (name, indices) in &names {
+            self.publish(name, indices, &effects);
+        }
+
+        // Find what each function calls
+        for index in 0..graph.nodes.len() {
+            self.infer_function(&graph.nodes[index].func)?;
+            for callee in std::mem::take(&mut self.calls) {
+                for &callee in names.get(callee.as_str()).into_iter().flatten() {
+                    graph.add_call(index, callee);
+                }
+            }
+        }
+
+        // Infer callees before their callers, and functions calling each
+        // other until their effects stop growing
+        for component in graph.components() {
+            let cyclic = component.len() > 1 || graph.is_recursive(component[0]);
+            loop {
+                let mut changed = false;
+                for &index in &component {
+                    if declared.contains_key(&index) {
+                        continue;
+                    }
+                    let node = &graph.nodes[index];
+                    let inferred = self.infer_function(&node.func)?;
+                    if inferred != effects[index] {
+                        effects[index] = inferred;
+                        self.publish(&node.name, &names[node.name.as_str()], &effects);
+                        changed = true;
+                    }
+                }
+                if !changed || !cyclic {
+                    break;
+                }
+            }
+        }
+
+        let mut warnings = Vec::new();
+        for (index, node) in graph.nodes.iter().enumerate() {
+            let performed = match declared.get(&index) {
+                Some(declared) => {
+                    let inferred = self.infer_function(&node.func)?;
+                    warnings
+                        .extend(self.check_declared(&node.name, &node.func, declared, &inferred)?);
+                    inferred
+                }
+                None => effects[index].clone(),
+            };
+            self.performed_effects
+                .entry(node.name.clone())
+                .or_default()
+                .union(&performed);
+        }
+
+        for (index, node) in graph.nodes.iter().enumerate() {
+            if let Some(effect) = effects[index].user_effects().first() {
+                self.check_handled(node, effect)?;
+            }
+        }
+
+        Ok(warnings)
+    }
+
+    /// Make the effects of the functions named `name` those callers see
+    fn publish(&mut self, name: &str, indices: &[usize], effects: &[EffectSet]) {
+        let mut union = EffectSet::new();
+        for &index in indices {
+            union.union(&effects[index]);
+        }
+        self.function_effects.insert(name.to_string(), union);
+    }
+
+    /// The effects a function declares, if it does, with the implicit async
+    /// effect of an async fn and the effect variables of its parameters of a
+    /// function type that don't name their effects
+    fn declared_effects(&self, name: &str, func: &Function) -> Result<Option<EffectSet>> {
+        let Some(names) = &func.effects else {
+            return Ok(None);
+        };
+
+        let mut effects = EffectSet::new();
+        if func.is_async {
+            effects.add(Effect::Async);
+        }
+        for effect_name in names {
+            effects.add(self.resolve_effect(effect_name, name, func)?);
+        }
+        for param in &func.params {
+            if let Type::Function { effects: None, .. } = param.ty {
+                effects.add(Effect::Var(param.name.clone()));
+            }
+        }
+
+        Ok(Some(effects))
+    }
+
+    /// Look up an effect named in the signature of `func`: one of its effect
+    /// variables, a declarable effect or a user-defined one
+    fn resolve_effect(&self, effect_name: &str, name: &str, func: &Function) -> Result<Effect> {
+        if func.effect_params.iter().any(|param| param == effect_name) {
+            return Ok(Effect::Var(effect_name.to_string()));
+        }
+        if self.user_effects.contains_key(effect_name) {
+            return Ok(Effect::User(effect_name.to_string()));
+        }
+        Effect::from_name(effect_name).ok_or_else(|| {
+            let mut expected: Vec<_> = Effect::DECLARABLE.iter().map(Effect::name).collect();
+            let mut user_effects: Vec<_> = self.user_effects.keys().map(String::as_str).collect();
+            user_effects.sort_unstable();
+            expected.extend(user_effects);
+            CompileError::SyntaxError {
+                message: format!(
+                    "unknown effect '{}' declared by '{}'; expected one of {}",
+                    effect_name,
+                    name,
+                    expected.join(", ")
+                ),
+                span: Some(func.span),
+            }
+        })
+    }
+
+    /// The effects each parameter of a function type allows, `None` for the
+    /// other parameters
+    fn param_effects(
+        &self,
+        name: &str,
+        func: &Function,
+    ) -> Result<Vec<Option<(String, EffectSet)>>> {
+        func.params
+            .iter()
+            .map(|param| {
+                let Type::Function { effects, .. } = &param.ty else {
+                    return Ok(None);
+                };
+                let mut allowed = EffectSet::new();
+                match effects {
+                    Some(names) => {
+                        for effect_name in names {
+                            // Function pointers can't take the handlers of
+                            // user-defined effects
+                            match self.resolve_effect(effect_name, name, func)? {
+                                Effect::User(effect) => {
+                                    return Err(CompileError::UserEffectMisuse {
+                                        effect,
+                                        message: format!(
+                                            "can't be named in the function type of parameter '{}' of '{}'",
+                                            param.name, name
+                                        ),
+                                        span: Some(func.span),
+                                    });
+                                }
+                                effect => allowed.add(effect),
+                            }
+                        }
+                    }
+                    None => allowed.add(Effect::Var(param.name.clone())),
+                }
+                Ok(Some((param.name.clone(), allowed)))
+            })
+            .collect()
+    }
+
+    /// Check the effects inferred for a function against those it declares
+    fn check_declared(
+        &self,
+        name: &str,
+        func: &Function,
+        declared: &EffectSet,
+        inferred: &EffectSet,
+    ) -> Result<Vec<EffectWarning>> {
+        // Report the undeclared effect that's performed first
+        let undeclared = inferred
+            .effects()
+            .iter()
+            .filter(|effect| !declared.contains(effect))
+            .map(|effect| match self.origins.get(effect) {
+                Some((span, cause)) => (effect, *span, cause.clone()),
+                None => (effect, func.span, "its body".to_string()),
+            })
+            .min_by_key(|(_, span, _)| span.start);
+        if let Some((effect, span, cause)) = undeclared {
+            return Err(CompileError::UndeclaredEffect {
+                function: name.to_string(),
+                effect: effect.to_string(),
+                cause,
+                span: Some(span),
+            });
+        }
+
+        let effect_vars = func.effect_params.iter().cloned().map(Effect::Var);
+        Ok(Effect::DECLARABLE
+            .into_iter()
+            .chain(effect_vars)
+            .filter(|effect| declared.contains(effect) && !inferred.contains(effect))
+            .map(|effect| EffectWarning {
+                function: name.to_string(),
+                effect,
+                span: func.span,
+            })
+            .collect())
+    }
+
+    /// Check that the handlers of a user-defined effect `node` performs can
+    /// reach it: `main` has to handle every effect, and only functions that
+    /// aren't async take handlers
+    fn check_handled(&mut self, node: &CallNode, effect: &str) -> Result<()> {
+        self.infer_function(&node.func)?;
+        let (span, cause) = match self.origins.get(&Effect::User(effect.to_string())) {
+            Some((span, cause)) => (*span, cause.clone()),
+            None => (node.func.span, "declaring it".to_string()),
+        };
+
+        let message = if node.owner.is_some() {
+            format!(
+                "is performed by method '{}', but only functions can perform user-defined effects",
+                node.name
+            )
+        } else if node.func.is_async {
+            format!(
+                "is performed by async fn '{}', but only functions that aren't async can perform user-defined effects",
+                node.name
+            )
+        } else if node.name == "main" {
+            return Err(CompileError::UnhandledEffect {
+                function: node.name.clone(),
+                effect: effect.to_string(),
+                cause,
+                span: Some(span),
+            });
+        } else {
+            return Ok(());
+        };
+        Err(CompileError::UserEffectMisuse {
+            effect: effect.to_string(),
+            message,
+            span: Some(span),
+        })
+    }
+
+    /// Effects of an operation of a handler, which runs where its effect is
+    /// performed and can only perform built-in effects
+    fn analyze_handler(&mut self, effect: &str, operation: &Function) -> Result<EffectSet> {
+        let params = self
+            .param_effects(&operation.name, operation)?
+            .into_iter()
+            .flatten()
+            .collect();
+        let outer_params = std::mem::replace(&mut self.fn_params, params);
+        let mut effects = EffectSet::new();
+        for stmt in &operation.body {
+            let stmt_effects = self.analyze_statement(stmt)?;
+            effects.union(&stmt_effects);
+        }
+        self.fn_params = outer_params;
+
+        if let Some(performed) = effects.user_effects().first() {
+            return Err(CompileError::UserEffectMisuse {
+                effect: performed.to_string(),
+                message: format!(
+                    "is performed by the handler of '{}::{}', but handlers can only perform built-in effects",
+                    effect, operation.name
+                ),
+                span: Some(operation.span),
+            });
+        }
+        Ok(effects)
+    }
+
+    /// Remember `span` as where the function being analyzed performs
+    /// `effects`, unless it already did
+    fn note_origin(&mut self, effects: &EffectSet, span: Span, cause: impl Fn() -> String) {
+        for effect in effects.effects() {
+            self.origins
+                .entry(effect.clone())
+                .or_insert_with(|| (span, cause()));
+        }
+    }
+
+    /// Add the panic of an operation that can fail at runtime, caused by `cause`
+    fn note_panic(&mut self, effects: &mut EffectSet, span: Span, cause: &str) {
+        effects.add(Effect::Panic);
+        self.note_origin(&EffectSet::singleton(Effect::Panic), span, || {
+            cause.to_string()
+        });
+    }
+
+    /// Effects of calling `name`, a builtin or a function of the program
+    fn call_effects(&mut self, name: &str) -> Option<EffectSet> {
+        if let Some(effects) = self.builtin_effects.get(name) {
+            return Some(effects.clone());
+        }
+        let effects = self.function_effects.get(name)?.clone();
+        self.calls.insert(name.to_string());
+        Some(effects)
+    }
+
+    /// Effects of calling the function named `name` refers to, if it names a
+    /// parameter of a function type, a builtin or a function of the program.
+    /// Functions performing user-defined effects can only be called by name,
+    /// as they take the handlers of those effects.
+    fn function_value_effects(
+        &mut self,
+        name: &str,
+        span: Option<Span>,
+    ) -> Result<Option<EffectSet>> {
+        if let Some(effects) = self.fn_params.get(name) {
+            return Ok(Some(effects.clone()));
+        }
+        let Some(effects) = self.call_effects(name) else {
+            return Ok(None);
+        };
+        if let Some(effect) = effects.user_effects().first() {
+            return Err(CompileError::UserEffectMisuse {
+                effect: effect.to_string(),
+                message: format!(
+                    "is performed by '{}', which can only be called by name, not used as a value",
+                    name
+                ),
+                span,
+            });
+        }
+        Ok(Some(effects))
+    }
+
+    /// The effects of a callee with its effect variables replaced by the
+    /// effects bound to them, those left unbound dropping out
+    fn instantiate(
+        effects: &EffectSet,
+        bindings: &std::collections::HashMap<String, EffectSet>,
+    ) -> EffectSet {
+        let mut instantiated = EffectSet::new();
+        for effect in effects.effects() {
+            match effect {
+                Effect::Var(var) => {
+                    if let Some(bound) = bindings.get(var) {
+                        instantiated.union(bound);
+                    }
+                }
+                effect => instantiated.add(effect.clone()),
+            }
+        }
+        instantiated
+    }
+
+    /// Effects of calling a method: those of every method with its name
+    fn method_call_effects(&mut self, method: &str) -> Option<EffectSet> {
+        let names = self.methods.get(method)?.clone();
+        let mut effects = EffectSet::new();
+        for name in names {
+            if let Some(method_effects) = self.call_effects(&name) {
+                effects.union(&method_effects);
+            }
+        }
+        Some(effects)
+    }
+
     /// Analyze effects for a function
     pub fn analyze_function(&mut self, func: &Function) -> Result<EffectSet> {
+        let effects = self.infer_function(func)?;
+
+        // Store the function's effects
+        self.function_effects
+            .insert(func.name.clone(), effects.clone());
+
+        Ok(effects)
+    }
+
+    /// Infer the effects of a function from its body and what it calls
+    fn infer_function(&mut self, func: &Function) -> Result<EffectSet> {
         let mut effects = EffectSet::new();
+        self.calls.clear();
+        self.origins.clear();
+        self.fn_params = self
+            .param_effects(&func.name, func)?
+            .into_iter()
+            .flatten()
+            .collect();
 
         // Async functions have async effect
         if func.is_async {
@@ -144,10 +733,6 @@ impl EffectAnalyzer {
             effects.union(&stmt_effects);
         }
 
-        // Store the function's effects
-        self.function_effects
-            .insert(func.name.clone(), effects.clone());
-
         Ok(effects)
     }
 
@@ -219,8 +804,41 @@ impl EffectAnalyzer {
                 Ok(effects)
             }
             Stmt::Break { .. } | Stmt::Continue { .. } => Ok(EffectSet::new()),
-            Stmt::Unsafe { body, .. } => {
+            Stmt::AsyncScope { body, span, .. } => {
+                // The scope awaits its tasks when it ends
+                let mut effects = EffectSet::singleton(Effect::Async);
+                self.note_origin(&effects, *span, || "opening an async scope".to_string());
+
+                for stmt in body {
+                    let stmt_effects = self.analyze_statement(stmt)?;
+                    effects.union(&stmt_effects);
+                }
+
+                Ok(effects)
+            }
+            Stmt::Handle { body, handlers, .. } => {
+                let mut effects = EffectSet::new();
+                for stmt in body {
+                    let stmt_effects = self.analyze_statement(stmt)?;
+                    effects.union(&stmt_effects);
+                }
+
+                // The handled effects end here, and their handlers run instead
+                for handler in handlers {
+                    effects.remove(&Effect::User(handler.effect.clone()));
+                }
+                for handler in handlers {
+                    for operation in &handler.operations {
+                        let handler_effects = self.analyze_handler(&handler.effect, operation)?;
+                        effects.union(&handler_effects);
+                    }
+                }
+
+                Ok(effects)
+            }
+            Stmt::Unsafe { body, span, .. } => {
                 let mut effects = EffectSet::singleton(Effect::Unsafe);
+                self.note_origin(&effects, *span, || "entering an unsafe block".to_string());
 
                 for stmt in body {
                     let stmt_effects = self.analyze_statement(stmt)?;
@@ -229,6 +847,17 @@ impl EffectAnalyzer {
 
                 Ok(effects)
             }
+            Stmt::Assign {
+                target: AssignTarget::Index { array, index },
+                value,
+                span,
+            } => {
+                let mut effects = self.analyze_expression(value)?;
+                effects.union(&self.analyze_expression(array)?);
+                effects.union(&self.analyze_expression(index)?);
+                self.note_panic(&mut effects, *span, "indexing");
+                Ok(effects)
+            }
             Stmt::Assign { value, .. } => self.analyze_expression(value),
         }
     }
@@ -237,42 +866,135 @@ impl EffectAnalyzer {
     fn analyze_expression(&mut self, expr: &Expr) -> Result<EffectSet> {
         match expr {
             // Literals are pure
-            Expr::Integer(_) | Expr::String(_) | Expr::Bool(_) | Expr::Ident(_) => {
+            Expr::Integer(_) | Expr::String(_) | Expr::Bool(_) | Expr::Turbofish { .. } => {
                 Ok(EffectSet::new())
             }
 
+            // A function used as a value may be called wherever it ends up
+            Expr::Ident(name) => Ok(self.function_value_effects(name, None)?.unwrap_or_default()),
+
             // Function calls may have effects
-            Expr::Call { func, args, .. } => {
+            Expr::Call { func, args, span } => {
                 let mut effects = EffectSet::new();
 
-                // Analyze function expression (in case it's not just an identifier)
-                let func_effects = self.analyze_expression(func)?;
-                effects.union(&func_effects);
+                // Analyze function expression (in case it's not just a name)
+                if func.callee_name().is_none() {
+                    let func_effects = self.analyze_expression(func)?;
+                    effects.union(&func_effects);
+                }
 
-                // Analyze arguments
+                // Analyze arguments, keeping the effects of functions passed
+                // for the callee's parameters to instantiate
+                let mut passed = Vec::with_capacity(args.len());
                 for arg in args {
-                    let arg_effects = self.analyze_expression(arg)?;
-                    effects.union(&arg_effects);
+                    match arg {
+                        Expr::Ident(name) => passed.push(
+                            self.function_value_effects(name, Some(*span))?
+                                .map(|arg_effects| (name.as_str(), arg_effects)),
+                        ),
+                        arg => {
+                            let arg_effects = self.analyze_expression(arg)?;
+                            effects.union(&arg_effects);
+                            passed.push(None);
+                        }
+                    }
                 }
 
                 // Add function's own effects
-                if let Expr::Ident(func_name) = func.as_ref() {
-                    if let Some(builtin_effects) = self.builtin_effects.get(func_name) {
-                        effects.union(builtin_effects);
-                    } else if let Some(func_effects) = self.function_effects.get(func_name) {
-                        effects.union(func_effects);
+                let callee = match func.as_ref() {
+                    Expr::FieldAccess { field, .. } => self
+                        .method_call_effects(field)
+                        .map(|effects| (effects, None, format!("calling method '{}'", field))),
+                    func => func.callee_name().and_then(|name| {
+                        if let Some(param_effects) = self.fn_params.get(name) {
+                            return Some((
+                                param_effects.clone(),
+                                None,
+                                format!("calling '{}'", name),
+                            ));
+                        }
+                        self.call_effects(name)
+                            .map(|effects| (effects, Some(name), format!("calling '{}'", name)))
+                    }),
+                };
+                let callee_name = callee.as_ref().and_then(|(_, name, _)| *name);
+                let signature = callee_name
+                    .and_then(|name| self.signatures.get(name))
+                    .cloned()
+                    .unwrap_or_default();
+
+                // Bind the callee's effect variables to the effects of the
+                // functions passed for them. Functions passed elsewhere may
+                // be called anyhow.
+                let mut bindings: std::collections::HashMap<String, EffectSet> =
+                    std::collections::HashMap::new();
+                for (index, arg) in passed.into_iter().enumerate() {
+                    let Some((arg_name, arg_effects)) = arg else {
+                        continue;
+                    };
+                    let Some(Some((param, allowed))) = signature.get(index) else {
+                        self.note_origin(&arg_effects, *span, || {
+                            format!("referring to '{}'", arg_name)
+                        });
+                        effects.union(&arg_effects);
+                        continue;
+                    };
+                    let vars: Vec<_> = allowed
+                        .effects()
+                        .iter()
+                        .filter(|effect| matches!(effect, Effect::Var(_)))
+                        .collect();
+                    let mut unbound = EffectSet::new();
+                    for effect in arg_effects.effects() {
+                        if !allowed.contains(effect) {
+                            unbound.add(effect.clone());
+                        }
+                    }
+                    if vars.is_empty() {
+                        if let Some(effect) = unbound.names().first() {
+                            return Err(CompileError::EffectMismatch {
+                                function: callee_name.unwrap_or_default().to_string(),
+                                param: param.clone(),
+                                effect: effect.to_string(),
+                                span: Some(*span),
+                            });
+                        }
+                    }
+                    for var in vars {
+                        bindings
+                            .entry(var.name().to_string())
+                            .or_default()
+                            .union(&unbound);
                     }
-                    // If function is unknown, we conservatively assume it's pure
+                }
+
+                // If function is unknown, we conservatively assume it's pure
+                if let Some((callee_effects, callee_name, cause)) = callee {
+                    let callee_effects = match callee_name {
+                        Some(_) => Self::instantiate(&callee_effects, &bindings),
+                        None => callee_effects,
+                    };
+                    self.note_origin(&callee_effects, *span, || cause.clone());
+                    effects.union(&callee_effects);
                 }
 
                 Ok(effects)
             }
 
-            // Binary operations are usually pure
-            Expr::Binary { left, right, .. } => {
+            // Binary operations are pure, except that dividing by zero panics
+            Expr::Binary {
+                left,
+                op,
+                right,
+                span,
+            } => {
                 let mut effects = self.analyze_expression(left)?;
                 let right_effects = self.analyze_expression(right)?;
                 effects.union(&right_effects);
+                let divides = matches!(op, BinOp::Div | BinOp::Mod);
+                if divides && !matches!(right.as_ref(), Expr::Integer(n) if *n != 0) {
+                    self.note_panic(&mut effects, *span, "dividing");
+                }
                 Ok(effects)
             }
 
@@ -296,37 +1018,47 @@ impl EffectAnalyzer {
                 Ok(effects)
             }
 
-            Expr::Index { array, index, .. } => {
+            // Indexing out of bounds panics
+            Expr::Index { array, index, span } => {
                 let mut effects = self.analyze_expression(array)?;
                 let index_effects = self.analyze_expression(index)?;
                 effects.union(&index_effects);
+                self.note_panic(&mut effects, *span, "indexing");
                 Ok(effects)
             }
 
             // Struct operations are pure
-            Expr::StructLiteral { fields, .. } => {
+            Expr::StructLiteral { fields, base, .. } => {
                 let mut effects = EffectSet::new();
                 for (_, field_expr) in fields {
                     let field_effects = self.analyze_expression(field_expr)?;
                     effects.union(&field_effects);
                 }
+                if let Some(StructBase::Expr(base)) = base {
+                    effects.union(&self.analyze_expression(base)?);
+                }
                 Ok(effects)
             }
 
             Expr::FieldAccess { object, .. } => self.analyze_expression(object),
 
-            // Enum operations are pure
-            Expr::EnumConstructor { data, .. } => {
+            // Enum operations are pure, but `Type::name(..)` calls functions
+            Expr::EnumConstructor {
+                enum_name,
+                variant,
+                data,
+                span,
+            } => {
                 let mut effects = EffectSet::new();
                 if let Some(constructor_data) = data {
                     match constructor_data {
-                        crate::ast::EnumConstructorData::Tuple(exprs) => {
+                        EnumConstructorData::Tuple(exprs) => {
                             for expr in exprs {
                                 let expr_effects = self.analyze_expression(expr)?;
                                 effects.union(&expr_effects);
                             }
                         }
-                        crate::ast::EnumConstructorData::Struct(fields) => {
+                        EnumConstructorData::Struct(fields) => {
                             for (_, expr) in fields {
                                 let expr_effects = self.analyze_expression(expr)?;
                                 effects.union(&expr_effects);
@@ -334,6 +1066,24 @@ impl EffectAnalyzer {
                         }
                     }
                 }
+                // `Effect::operation(..)` performs a user-defined effect
+                let performs = self
+                    .user_effects
+                    .get(enum_name)
+                    .is_some_and(|operations| operations.contains(variant));
+                if performs {
+                    let performed = EffectSet::singleton(Effect::User(enum_name.clone()));
+                    self.note_origin(&performed, *span, || {
+                        format!("performing '{}::{}'", enum_name, variant)
+                    });
+                    effects.union(&performed);
+                } else if let Some(EnumConstructorData::Tuple(_)) = data {
+                    let name = format!("{}::{}", enum_name, variant);
+                    if let Some(callee_effects) = self.call_effects(&name) {
+                        self.note_origin(&callee_effects, *span, || format!("calling '{}'", name));
+                        effects.union(&callee_effects);
+                    }
+                }
                 Ok(effects)
             }
 
@@ -351,17 +1101,16 @@ impl EffectAnalyzer {
             Expr::Reference { expr, .. } => self.analyze_expression(expr),
             Expr::Deref { expr, .. } => self.analyze_expression(expr),
 
-            // Question mark operator can panic
-            Expr::Question { expr, .. } => {
-                let mut effects = self.analyze_expression(expr)?;
-                effects.add(Effect::Panic);
-                Ok(effects)
-            }
+            // Question mark operator returns the error to the caller
+            Expr::Question { expr, .. } => self.analyze_expression(expr),
 
             // Await has async effect
-            Expr::Await { expr, .. } => {
+            Expr::Await { expr, span } => {
                 let mut effects = self.analyze_expression(expr)?;
                 effects.add(Effect::Async);
+                self.note_origin(&EffectSet::singleton(Effect::Async), *span, || {
+                    "awaiting".to_string()
+                });
                 Ok(effects)
             }
 
@@ -379,6 +1128,39 @@ impl EffectAnalyzer {
         self.function_effects.get(func_name)
     }
 
+    /// The effects of every function of the program `check_program` checked,
+    /// in source order, with `Type::name` for methods: the declared effects of
+    /// functions that declare them, the inferred ones of the others
+    pub fn program_effects(&self) -> impl Iterator<Item = (&str, &EffectSet)> {
+        self.functions
+            .iter()
+            .filter_map(|name| Some((name.as_str(), self.function_effects.get(name)?)))
+    }
+
+    /// The effects every function of the program performs, in source order,
+    /// whether or not it declares them
+    pub fn performed_effects(&self) -> impl Iterator<Item = (&str, &EffectSet)> {
+        self.functions
+            .iter()
+            .filter_map(|name| Some((name.as_str(), self.performed_effects.get(name)?)))
+    }
+
+    /// The user-defined effects each function of the program performs, whose
+    /// handlers it takes after its parameters, sorted by name
+    pub fn handler_params(&self) -> std::collections::HashMap<String, Vec<String>> {
+        self.program_effects()
+            .filter(|(_, effects)| !effects.user_effects().is_empty())
+            .map(|(name, effects)| {
+                let handlers = effects
+                    .user_effects()
+                    .into_iter()
+                    .map(String::from)
+                    .collect();
+                (name.to_string(), handlers)
+            })
+            .collect()
+    }
+
     /// Check if a function is pure
     pub fn is_function_pure(&self, func_name: &str) -> bool {
         self.function_effects
@@ -391,6 +1173,14 @@ impl EffectAnalyzer {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn check(source: &str) -> Result<Vec<EffectWarning>> {
+        let tokens = Lexer::new(source).collect_tokens().unwrap();
+        let program = Parser::new(tokens).parse().unwrap();
+        EffectAnalyzer::new().check_program(&program)
+    }
 
     #[test]
     fn test_effect_set() {
@@ -406,4 +1196,408 @@ mod tests {
         assert!(effects.contains(&Effect::IO));
         assert!(effects.contains(&Effect::Async));
     }
+
+    #[test]
+    fn test_declared_effects_are_a_contract() {
+        // Declared functions are seen by callers ahead of their definition
+        let warnings = check(
+            r#"
+            fn log(n: i64) ![io] {
+                print_int(n);
+            }
+            fn add(a: i64, b: i64) -> i64 ![] {
+                return a + b;
+            }
+            fn main() ![io] {
+                report(add(1, 2));
+            }
+            fn report(n: i64) ![io] {
+                log(n);
+            }
+            "#,
+        )
+        .unwrap();
+        assert!(warnings.is_empty());
+
+        let err = check(
+            r#"
+            fn greet() ![] {
+                let n = 1;
+                print("hi");
+            }
+            "#,
+        )
+        .unwrap_err();
+        match err {
+            CompileError::UndeclaredEffect {
+                function,
+                effect,
+                cause,
+                span,
+            } => {
+                assert_eq!(function, "greet");
+                assert_eq!(effect, "io");
+                assert_eq!(cause, "calling 'print'");
+                assert_eq!(span.unwrap().line, 4);
+            }
+            other => panic!("Expected UndeclaredEffect, got {:?}", other),
+        }
+
+        // An unannotated callee has its effects inferred
+        let err = check(
+            r#"
+            fn helper() {
+                print("hi");
+            }
+            fn pure_looking() -> i64 ![panic] {
+                helper();
+                return 1;
+            }
+            "#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("by calling 'helper'"));
+    }
+
+    #[test]
+    fn test_over_declared_effects_warn() {
+        let warnings = check(
+            r#"
+            async fn tick() ![io, panic] {
+                print("tick");
+            }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].function, "tick");
+        assert_eq!(warnings[0].effect, Effect::Panic);
+
+        let err = check(
+            r#"
+            fn main() ![io, network] {
+                print("hi");
+            }
+            "#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("unknown effect 'network'"));
+    }
+
+    #[test]
+    fn test_performed_effects_ignore_declarations() {
+        let tokens = Lexer::new(
+            r#"
+            fn over() -> i64 ![io] {
+                return 1;
+            }
+            fn main() ![io] {
+                print_int(over());
+            }
+            "#,
+        )
+        .collect_tokens()
+        .unwrap();
+        let program = Parser::new(tokens).parse().unwrap();
+        let mut analyzer = EffectAnalyzer::new();
+        analyzer.check_program(&program).unwrap();
+
+        // Callers see what `over` declares, but it performs nothing
+        let performed: std::collections::HashMap<_, _> = analyzer
+            .performed_effects()
+            .map(|(name, effects)| (name, effects.names()))
+            .collect();
+        assert_eq!(performed["over"], Vec::<&str>::new());
+        assert_eq!(performed["main"], vec!["io"]);
+        assert_eq!(
+            analyzer.get_function_effects("over").unwrap().names(),
+            vec!["io"]
+        );
+    }
+
+    fn program_effects(source: &str) -> std::collections::HashMap<String, Vec<String>> {
+        let tokens = Lexer::new(source).collect_tokens().unwrap();
+        let program = Parser::new(tokens).parse().unwrap();
+        let mut analyzer = EffectAnalyzer::new();
+        analyzer.check_program(&program).unwrap();
+        analyzer
+            .program_effects()
+            .map(|(name, effects)| {
+                let names = effects.names().into_iter().map(String::from).collect();
+                (name.to_string(), names)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_effects_flow_through_the_call_graph() {
+        // Callees defined later and mutually recursive functions
+        let effects = program_effects(
+            r#"
+            fn main() {
+                ping(3);
+            }
+            fn ping(n: i64) {
+                if n > 0 {
+                    pong(n - 1);
+                }
+            }
+            fn pong(n: i64) {
+                if n == 0 {
+                    print("done");
+                } else {
+                    ping(n - 1);
+                }
+            }
+            fn count(n: i64) -> i64 {
+                if n == 0 {
+                    return 0;
+                }
+                return count(n - 1) + 1;
+            }
+            "#,
+        );
+        assert_eq!(effects["main"], ["io"]);
+        assert_eq!(effects["ping"], ["io"]);
+        assert_eq!(effects["pong"], ["io"]);
+        assert!(effects["count"].is_empty());
+    }
+
+    #[test]
+    fn test_operations_that_panic() {
+        // Dividing and indexing can fail at runtime; `?` returns the error
+        let effects = program_effects(
+            r#"
+            fn halve(n: i64) -> i64 {
+                return n / 2;
+            }
+            fn ratio(a: i64, b: i64) -> i64 {
+                return a / b;
+            }
+            fn remainder(a: i64, b: i64) -> i64 {
+                return a % b;
+            }
+            fn first(xs: [i64; 3]) -> i64 {
+                return xs[0];
+            }
+            fn reset(xs: [i64; 3]) {
+                xs[1] = 0;
+            }
+            fn forward(r: Result<i64, String>) -> Result<i64, String> {
+                let n = r?;
+                return Ok(n);
+            }
+            "#,
+        );
+        assert!(effects["halve"].is_empty());
+        assert_eq!(effects["ratio"], ["panic"]);
+        assert_eq!(effects["remainder"], ["panic"]);
+        assert_eq!(effects["first"], ["panic"]);
+        assert_eq!(effects["reset"], ["panic"]);
+        assert!(effects["forward"].is_empty());
+
+        let err = check(
+            r#"
+            fn ratio(a: i64, b: i64) -> i64 ![io] {
+                return a / b;
+            }
+            "#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("by dividing"));
+    }
+
+    #[test]
+    fn test_effects_of_methods() {
+        let source = r#"
+            trait Shape {
+                fn area(self) -> i64;
+                fn describe(self) {
+                    print_int(self.area());
+                }
+            }
+            struct Square {
+                side: i64,
+            }
+            impl Square {
+                fn new(side: i64) -> Square {
+                    return Square { side: side };
+                }
+            }
+            impl Shape for Square {
+                fn area(self) -> i64 {
+                    unsafe {
+                        let n = 1;
+                    }
+                    return self.side * self.side;
+                }
+            }
+            fn largest<T>(shape: T) -> i64 {
+                return shape.area();
+            }
+            fn main() {
+                let square = Square::new(2);
+                square.describe();
+            }
+        "#;
+        let effects = program_effects(source);
+        assert!(effects["Square::new"].is_empty());
+        assert_eq!(effects["Square::area"], ["unsafe"]);
+        assert_eq!(effects["Shape::describe"], ["io", "unsafe"]);
+        // Calls through a generic reach every implementation
+        assert_eq!(effects["largest"], ["unsafe"]);
+        assert_eq!(effects["main"], ["io", "unsafe"]);
+
+        let err = check(&source.replace(
+            "fn new(side: i64) -> Square {",
+            "fn new(side: i64) -> Square ![] {\n print(\"new\");",
+        ))
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("function 'Square::new' performs effect 'io'"));
+    }
+
+    #[test]
+    fn test_effect_polymorphism() {
+        let source = r#"
+            fn apply_twice<T, e>(f: fn(T) -> T ! e, x: T) -> T ! e {
+                return f(f(x));
+            }
+            fn apply(f: fn(i64) -> i64, x: i64) -> i64 {
+                return f(x);
+            }
+            fn each_pure(f: fn(i64) ![], x: i64) {
+                f(x);
+            }
+            fn twice(n: i64) -> i64 ![] {
+                return n * 2;
+            }
+            fn shout(n: i64) -> i64 ![io] {
+                print_int(n);
+                return n;
+            }
+            fn quiet() -> i64 ![] {
+                return apply_twice(twice, apply(twice, 5));
+            }
+            fn loud() -> i64 {
+                return apply_twice(shout, 5);
+            }
+            fn forward<e>(g: fn(i64) -> i64 ! e) -> i64 ! e {
+                return apply(g, 1);
+            }
+            fn main() {
+                let n = forward(shout);
+            }
+        "#;
+        let effects = program_effects(source);
+        assert_eq!(effects["apply_twice"], ["e"]);
+        // Unannotated function types have an effect variable of their own
+        assert_eq!(effects["apply"], ["f"]);
+        assert!(effects["quiet"].is_empty());
+        assert_eq!(effects["loud"], ["io"]);
+        assert_eq!(effects["forward"], ["e"]);
+        assert_eq!(effects["main"], ["io"]);
+        assert!(check(source).unwrap().is_empty());
+
+        // Instantiated effects are checked like any other
+        let err =
+            check(&source.replace("fn loud() -> i64 {", "fn loud() -> i64 ![] {")).unwrap_err();
+        assert!(err.to_string().contains(
+            "function 'loud' performs effect 'io' without declaring it, by calling 'apply_twice'"
+        ));
+
+        // Parameters naming their effects only accept functions within them
+        let err = check(&source.replace("fn main() {", "fn main() {\n each_pure(shout, 1);"))
+            .unwrap_err();
+        match err {
+            CompileError::EffectMismatch {
+                function,
+                param,
+                effect,
+                span,
+            } => {
+                assert_eq!(function, "each_pure");
+                assert_eq!(param, "f");
+                assert_eq!(span.unwrap().line, 28);
+                assert_eq!(effect, "io");
+            }
+            other => panic!("Expected EffectMismatch, got {:?}", other),
+        }
+
+        // Declared effect variables that are never performed warn
+        let warnings = check(&source.replace("return f(f(x));", "return x;")).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].effect, Effect::Var("e".to_string()));
+    }
+
+    #[test]
+    fn test_user_defined_effects() {
+        let source = r#"
+            effect Log {
+                fn log(msg: String);
+            }
+            effect Random {
+                fn next() -> i64;
+            }
+            fn greet(name: String) ![Log] {
+                Log::log(name);
+            }
+            fn roll() -> i64 {
+                return Random::next() + Random::next();
+            }
+            fn report() {
+                greet("world");
+                print_int(roll());
+            }
+            fn main() {
+                handle {
+                    report();
+                } with Log {
+                    fn log(msg: String) {
+                        print(msg);
+                    }
+                } with Random {
+                    fn next() -> i64 {
+                        return 4;
+                    }
+                }
+            }
+        "#;
+        let effects = program_effects(source);
+        assert_eq!(effects["greet"], ["Log"]);
+        assert_eq!(effects["roll"], ["Random"]);
+        assert_eq!(effects["report"], ["Log", "Random", "io"]);
+        // Handling removes the effects; the handlers' own effects remain
+        assert_eq!(effects["main"], ["io"]);
+        assert!(check(source).unwrap().is_empty());
+
+        // User effects are checked against annotations like built-in ones
+        let err =
+            check(&source.replace("fn roll() -> i64 {", "fn roll() -> i64 ![io] {")).unwrap_err();
+        assert!(err.to_string().contains(
+            "function 'roll' performs effect 'Random' without declaring it, by performing 'Random::next'"
+        ));
+
+        // An effect nobody handles reaches main
+        let err =
+            check(&source.replace("fn main() {", "fn main() {\n print_int(roll());")).unwrap_err();
+        match err {
+            CompileError::UnhandledEffect {
+                function, effect, ..
+            } => {
+                assert_eq!(function, "main");
+                assert_eq!(effect, "Random");
+            }
+            other => panic!("Expected UnhandledEffect, got {:?}", other),
+        }
+
+        // Handlers can't perform user effects themselves
+        let err = check(&source.replace("print(msg);", "Log::log(msg);")).unwrap_err();
+        assert!(matches!(err, CompileError::UserEffectMisuse { .. }));
+
+        // Functions performing user effects can't be passed around as values
+        let err = check(&source.replace("report();\n", "report();\n let f = roll;\n")).unwrap_err();
+        assert!(matches!(err, CompileError::UserEffectMisuse { .. }));
+    }
 }
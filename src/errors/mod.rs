@@ -81,7 +81,26 @@ pub enum CompileError {
     BorrowChecker { message: String, span: Option<Span> },
 
     #[error("Use of moved value: {name}")]
-    UseOfMovedValue { name: String, span: Option<Span> },
+    UseOfMovedValue {
+        name: String,
+        moved_at: Option<Span>,
+        span: Option<Span>,
+    },
+
+    #[error("Use of possibly moved value: {name} (value moved {reason})")]
+    UseOfPossiblyMovedValue {
+        name: String,
+        reason: &'static str,
+        moved_at: Option<Span>,
+        span: Option<Span>,
+    },
+
+    #[error("Use of partially moved value: {name} ({moved} was moved)")]
+    UseOfPartiallyMovedValue {
+        name: String,
+        moved: String,
+        span: Option<Span>,
+    },
 
     #[error("Use of uninitialized value: {name}")]
     UseOfUninitializedValue { name: String, span: Option<Span> },
@@ -94,11 +113,30 @@ pub enum CompileError {
     UnsafeOperation { operation: String, span: Span },
 
     #[error("Conflicting borrows: {message}")]
-    ConflictingBorrows { message: String, span: Option<Span> },
+    ConflictingBorrows {
+        message: String,
+        borrowed_at: Option<Span>,
+        span: Option<Span>,
+    },
 
     #[error("Lifetime error: {message}")]
     LifetimeError { message: String, span: Option<Span> },
 
+    #[error("`{name}` does not live long enough: a reference to it is {escapes}")]
+    DanglingReference {
+        name: String,
+        function: String,
+        escapes: &'static str,
+        span: Option<Span>,
+    },
+
+    #[error("`{name}` does not live long enough: it is dropped while still borrowed")]
+    DroppedWhileBorrowed {
+        name: String,
+        borrowed_at: Option<Span>,
+        span: Option<Span>,
+    },
+
     // Pattern matching errors
     #[error("Non-exhaustive match: missing patterns {}", missing_patterns.join(", "))]
     NonExhaustiveMatch {
@@ -111,10 +149,70 @@ pub enum CompileError {
         patterns: Vec<String>,
         span: Option<Span>,
     },
+
+    // Error propagation errors
+    #[error("Invalid use of '?': {message}")]
+    InvalidTryOperator {
+        message: String,
+        help: String,
+        span: Option<Span>,
+    },
+
+    // Struct literal errors
+    #[error("missing fields: {} in initializer of '{struct_name}'", .fields.join(", "))]
+    MissingStructFields {
+        struct_name: String,
+        fields: Vec<String>,
+        span: Option<Span>,
+    },
+
+    // Formatting macro errors
+    #[error("Invalid format: {message}")]
+    FormatError { message: String, span: Option<Span> },
+
+    // Effect errors
+    #[error("function '{function}' performs effect '{effect}' without declaring it, by {cause}")]
+    UndeclaredEffect {
+        function: String,
+        effect: String,
+        cause: String,
+        span: Option<Span>,
+    },
+
+    #[error("the function passed for parameter '{param}' of '{function}' performs effect '{effect}', which the parameter doesn't allow")]
+    EffectMismatch {
+        function: String,
+        param: String,
+        effect: String,
+        span: Option<Span>,
+    },
+
+    #[error("effect '{effect}' is never handled: '{function}' performs it by {cause}")]
+    UnhandledEffect {
+        function: String,
+        effect: String,
+        cause: String,
+        span: Option<Span>,
+    },
+
+    #[error("user-defined effect '{effect}' {message}")]
+    UserEffectMisuse {
+        effect: String,
+        message: String,
+        span: Option<Span>,
+    },
+
+    // Type inference errors
+    #[error("type annotations needed: cannot infer type parameter '{param}' of '{name}'")]
+    TypeAnnotationsNeeded {
+        name: String,
+        param: String,
+        span: Option<Span>,
+    },
 }
 
 /// Source location information
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Span {
     pub start: usize,
     pub end: usize,
@@ -374,6 +472,178 @@ impl CompileError {
             )
             .with_suggestion("Remove this pattern or reorder the patterns", None),
 
+            CompileError::InvalidTryOperator {
+                message,
+                help,
+                span,
+            } => Diagnostic::error(format!("Invalid use of '?': {}", message))
+                .with_span(span.unwrap_or(Span::dummy()))
+                .with_note("'?' returns early with the error (or None) from the enclosing function")
+                .with_suggestion(help.clone(), None),
+
+            CompileError::MissingStructFields {
+                struct_name,
+                fields,
+                span,
+            } => Diagnostic::error(self.to_string())
+                .with_span(span.unwrap_or(Span::dummy()))
+                .with_suggestion(
+                    format!(
+                        "Provide {}, or end the literal with `..base` to take the rest from another {}",
+                        fields.join(", "),
+                        struct_name
+                    ),
+                    None,
+                ),
+
+            CompileError::FormatError { message, span } => {
+                Diagnostic::error(format!("Invalid format: {}", message))
+                    .with_span(span.unwrap_or(Span::dummy()))
+                    .with_note(
+                        "Use {} for Display, {:?} for Debug, {:x}/{:b} for integers and {{ }} for literal braces",
+                    )
+            }
+
+            CompileError::UndeclaredEffect {
+                function,
+                effect,
+                span,
+                ..
+            } => Diagnostic::error(self.to_string())
+                .with_span(span.unwrap_or(Span::dummy()))
+                .with_note(format!(
+                    "the effects '{}' declares are all it may perform, including through its callees",
+                    function
+                ))
+                .with_suggestion(
+                    format!("Add '{}' to the effects of '{}'", effect, function),
+                    None,
+                ),
+
+            CompileError::UnhandledEffect { effect, span, .. } => {
+                Diagnostic::error(self.to_string())
+                    .with_span(span.unwrap_or(Span::dummy()))
+                    .with_suggestion(
+                        format!(
+                            "Perform it inside handle {{ ... }} with {} {{ ... }}",
+                            effect
+                        ),
+                        None,
+                    )
+            }
+
+            CompileError::UserEffectMisuse { span, .. } => Diagnostic::error(self.to_string())
+                .with_span(span.unwrap_or(Span::dummy()))
+                .with_note("handlers are passed to the functions performing their effects as hidden arguments"),
+
+            CompileError::EffectMismatch {
+                function,
+                param,
+                span,
+                ..
+            } => Diagnostic::error(self.to_string())
+                .with_span(span.unwrap_or(Span::dummy()))
+                .with_suggestion(
+                    format!(
+                        "Give parameter '{}' of '{}' an effect variable, as in `fn(T) -> U ! e`",
+                        param, function
+                    ),
+                    None,
+                ),
+
+            CompileError::TypeAnnotationsNeeded { name, param, span } => {
+                Diagnostic::error(self.to_string())
+                    .with_span(span.unwrap_or(Span::dummy()))
+                    .with_suggestion(
+                        format!(
+                            "Annotate the binding's type or call it as `{}::<...>` to specify '{}'",
+                            name, param
+                        ),
+                        None,
+                    )
+            }
+
+            CompileError::UseOfMovedValue { moved_at, span, .. } => {
+                let mut diag =
+                    Diagnostic::error(self.to_string()).with_span(span.unwrap_or(Span::dummy()));
+                if let Some(moved_at) = moved_at {
+                    diag = diag.with_note(format!(
+                        "value moved here (line {}, column {})",
+                        moved_at.line, moved_at.column
+                    ));
+                }
+                diag.with_suggestion("Clone the value before moving it", None)
+            }
+
+            CompileError::ConflictingBorrows {
+                borrowed_at, span, ..
+            } => {
+                let mut diag =
+                    Diagnostic::error(self.to_string()).with_span(span.unwrap_or(Span::dummy()));
+                if let Some(borrowed_at) = borrowed_at {
+                    diag = diag.with_note(format!(
+                        "borrowed here (line {}, column {}), and the borrow is used later",
+                        borrowed_at.line, borrowed_at.column
+                    ));
+                }
+                diag
+            }
+
+            CompileError::UseOfPossiblyMovedValue { moved_at, span, .. } => {
+                let mut diag =
+                    Diagnostic::error(self.to_string()).with_span(span.unwrap_or(Span::dummy()));
+                if let Some(moved_at) = moved_at {
+                    diag = diag.with_note(format!(
+                        "value moved here (line {}, column {})",
+                        moved_at.line, moved_at.column
+                    ));
+                }
+                diag.with_suggestion(
+                    "Clone the value before moving it, or move it on every path",
+                    None,
+                )
+            }
+
+            CompileError::UseOfPartiallyMovedValue { name, moved, span } => {
+                Diagnostic::error(self.to_string())
+                    .with_span(span.unwrap_or(Span::dummy()))
+                    .with_note(format!("the other fields of `{}` can still be used", name))
+                    .with_suggestion(
+                        format!("Assign a new value to `{}` before using `{}`", moved, name),
+                        None,
+                    )
+            }
+
+            CompileError::DanglingReference {
+                name,
+                function,
+                span,
+                ..
+            } => Diagnostic::error(self.to_string())
+                .with_span(span.unwrap_or(Span::dummy()))
+                .with_note(format!(
+                    "`{}` is dropped at the end of `{}` while still borrowed",
+                    name, function
+                ))
+                .with_suggestion("Return or store an owned value instead of a reference", None),
+
+            CompileError::DroppedWhileBorrowed {
+                name,
+                borrowed_at,
+                span,
+            } => {
+                let mut diag = Diagnostic::error(self.to_string())
+                    .with_span(span.unwrap_or(Span::dummy()))
+                    .with_note(format!("`{}` goes out of scope here", name));
+                if let Some(borrowed_at) = borrowed_at {
+                    diag = diag.with_note(format!(
+                        "borrowed here (line {}, column {}), and the borrow is used later",
+                        borrowed_at.line, borrowed_at.column
+                    ));
+                }
+                diag
+            }
+
             _ => {
                 // Default diagnostic for other errors
                 Diagnostic::error(self.to_string())
@@ -421,6 +691,13 @@ impl Diagnostic {
         }
     }
 
+    pub fn warning(message: impl Into<String>) -> Self {
+        Self {
+            level: DiagnosticLevel::Warning,
+            ..Self::error(message)
+        }
+    }
+
     pub fn with_span(mut self, span: Span) -> Self {
         self.span = Some(span);
         self
@@ -685,9 +962,28 @@ mod tests {
     fn test_borrow_checker_errors() {
         let err = CompileError::UseOfMovedValue {
             name: "x".to_string(),
+            moved_at: Some(Span::new(0, 1, 3, 9)),
             span: Some(Span::new(10, 11, 5, 5)),
         };
         assert_eq!(err.to_string(), "Use of moved value: x");
+        let diag = err.to_diagnostic();
+        assert_eq!(diag.span.map(|span| span.line), Some(5));
+        assert!(diag
+            .notes
+            .iter()
+            .any(|note| note.contains("line 3, column 9")));
+
+        let err = CompileError::ConflictingBorrows {
+            message: "cannot assign to `x` because it is borrowed".to_string(),
+            borrowed_at: Some(Span::new(0, 2, 6, 13)),
+            span: Some(Span::new(20, 25, 7, 5)),
+        };
+        let diag = err.to_diagnostic();
+        assert_eq!(diag.span.map(|span| span.line), Some(7));
+        assert!(diag
+            .notes
+            .iter()
+            .any(|note| note.contains("line 6, column 13")));
 
         let err = CompileError::UseOfUninitializedValue {
             name: "y".to_string(),
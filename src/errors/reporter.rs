@@ -19,6 +19,14 @@ impl ErrorReporter {
         })
     }
 
+    /// Report against source already in memory, as it was compiled
+    pub fn with_source(source_file: String, source_content: String) -> Self {
+        Self {
+            source_file,
+            source_content,
+        }
+    }
+
     pub fn report(&self, diagnostic: &Diagnostic) {
         // Print header with error level and message
         let (level_color, level_text) = match diagnostic.level {
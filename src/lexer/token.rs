@@ -12,14 +12,7 @@ pub enum Token {
     #[regex(r#""([^"\\]|\\.)*""#, |lex| {
         let s = lex.slice();
         // Remove quotes and handle escape sequences
-        let content = &s[1..s.len()-1];
-        let unescaped = content
-            .replace("\\n", "\n")
-            .replace("\\t", "\t")
-            .replace("\\r", "\r")
-            .replace("\\\"", "\"")
-            .replace("\\\\", "\\");
-        Some(unescaped)
+        Some(unescape(&s[1..s.len()-1]))
     })]
     String(String),
 
@@ -261,6 +254,57 @@ impl Token {
     }
 }
 
+/// Resolve the escape sequences of a string literal's contents; unknown
+/// escapes are kept as written
+fn unescape(content: &str) -> String {
+    let mut result = String::with_capacity(content.len());
+    let mut chars = content.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some('r') => result.push('\r'),
+            Some('0') => result.push('\0'),
+            Some('"') => result.push('"'),
+            Some('\\') => result.push('\\'),
+            Some(other) => {
+                result.push('\\');
+                result.push(other);
+            }
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+impl Token {
+    /// The token as it would be written in source code
+    pub fn source_text(&self) -> String {
+        match self {
+            Token::String(s) => {
+                let escaped = s
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace('\n', "\\n")
+                    .replace('\t', "\\t")
+                    .replace('\r', "\\r")
+                    .replace('\0', "\\0");
+                format!("\"{}\"", escaped)
+            }
+            Token::Integer(n) => n.to_string(),
+            Token::Identifier(name) => name.clone(),
+            Token::SingleQuote => "'".to_string(),
+            Token::Eof => String::new(),
+            // Keywords and punctuation display as their quoted spelling
+            _ => self.to_string().trim_matches('\'').to_string(),
+        }
+    }
+}
+
 impl std::fmt::Display for Token {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -340,6 +384,15 @@ impl std::fmt::Display for Token {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_source_text_round_trips() {
+        let source = r#"if a == b { print("say \"hi\"\n"); }"#;
+        let tokens: Vec<Token> = Token::lexer(source).map(|t| t.unwrap()).collect();
+        let text: Vec<String> = tokens.iter().map(Token::source_text).collect();
+        let relexed: Vec<Token> = Token::lexer(&text.join(" ")).map(|t| t.unwrap()).collect();
+        assert_eq!(tokens, relexed);
+    }
+
     #[test]
     fn test_string_lexing() {
         let mut lex = Token::lexer(r#""Hello, World!""#);
@@ -358,6 +411,12 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_escaped_nul_and_backslash() {
+        let mut lex = Token::lexer(r#""a\0b\\n""#);
+        assert_eq!(lex.next(), Some(Ok(Token::String("a\0b\\n".to_string()))));
+    }
+
     #[test]
     fn test_integer() {
         let mut lex = Token::lexer("42 -17");
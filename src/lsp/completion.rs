@@ -300,41 +300,26 @@ impl LanguageServer {
         }
 
         // Built-in functions
-        let builtins = vec![
-            ("print", "fn print(s: String)", "Print a string to stdout"),
+        let builtins: Vec<(&str, &[&str], &str)> = vec![
+            ("print", &["s"], "Print a string to stdout"),
+            ("print_int", &["n"], "Print an integer to stdout"),
+            ("string_len", &["s"], "Get string length"),
+            ("string_concat", &["a", "b"], "Concatenate strings"),
             (
-                "print_int",
-                "fn print_int(n: i64)",
-                "Print an integer to stdout",
-            ),
-            (
-                "string_len",
-                "fn string_len(s: String) -> i64",
-                "Get string length",
-            ),
-            (
-                "string_concat",
-                "fn string_concat(a: String, b: String) -> String",
-                "Concatenate strings",
-            ),
-            (
-                "int_to_string",
-                "fn int_to_string(n: i64) -> String",
-                "Convert integer to string",
-            ),
-            (
-                "string_to_int",
-                "fn string_to_int(s: String) -> Option<i64>",
-                "Parse integer from string",
+                "string_push_str",
+                &["s", "t"],
+                "Append to a string in place, growing its buffer",
             ),
+            ("int_to_string", &["n"], "Convert integer to string"),
+            ("string_to_int", &["s"], "Parse integer from string"),
         ];
 
-        for (name, signature, doc) in builtins {
+        for (name, params, doc) in builtins {
             if name.starts_with(&context.word) {
                 completions.push(CompletionItem {
                     label: name.to_string(),
                     kind: Some(CompletionItemKind::Function),
-                    detail: Some(signature.to_string()),
+                    detail: self.builtin_signature(name, params),
                     documentation: Some(doc.to_string()),
                     insert_text: Some(format!("{}(", name)),
                     insert_text_format: Some(InsertTextFormat::PlainText),
@@ -587,6 +572,7 @@ impl LanguageServer {
             Type::U64 => "u64".to_string(),
             Type::Bool => "bool".to_string(),
             Type::String => "String".to_string(),
+            Type::Str => "str".to_string(),
             Type::Unit => "()".to_string(),
             Type::Custom(name) => name.clone(),
             Type::Array(elem, size) => format!("[{}; {}]", self.type_to_string(elem), size),
@@ -611,6 +597,7 @@ impl LanguageServer {
                 let type_strs: Vec<String> = types.iter().map(|t| self.type_to_string(t)).collect();
                 format!("({})", type_strs.join(", "))
             }
+            Type::Function { .. } => ty.to_string(),
         }
     }
 }
@@ -219,6 +219,7 @@ mod tests {
                         ("y".to_string(), Type::I32),
                     ],
                     visibility: Visibility::Private,
+                    field_defaults: vec![],
                     span: Span::new(0, 0, 0, 0),
                 }),
             ],
@@ -350,6 +351,7 @@ mod tests {
                         ("y".to_string(), Type::I32),
                     ],
                     visibility: Visibility::Private,
+                    field_defaults: vec![],
                     span: Span::new(0, 0, 0, 0),
                 }),
             ],
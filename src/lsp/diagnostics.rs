@@ -393,6 +393,7 @@ mod tests {
                     visibility: Visibility::Private,
                     is_async: false,
                     effects: None,
+                    effect_params: vec![],
                     span: Span::new(0, 10, 0, 0),
                 }),
             ],
@@ -420,6 +421,7 @@ mod tests {
                     const_params: vec![],
                     fields: vec![],
                     visibility: Visibility::Private,
+                    field_defaults: vec![],
                     span: Span::new(0, 10, 0, 0),
                 }),
             ],
@@ -500,6 +502,7 @@ mod tests {
                     visibility: Visibility::Private,
                     is_async: false,
                     effects: None,
+                    effect_params: vec![],
                     span: Span::new(0, 10, 0, 0),
                 }),
                 Item::Struct(StructDef {
@@ -509,6 +512,7 @@ mod tests {
                     const_params: vec![],
                     fields: vec![],
                     visibility: Visibility::Private,
+                    field_defaults: vec![],
                     span: Span::new(0, 10, 0, 0),
                 }),
                 Item::Enum(EnumDef {
@@ -635,6 +639,7 @@ mod tests {
                     visibility: Visibility::Private,
                     is_async: false,
                     effects: None,
+                    effect_params: vec![],
                     span: Span::new(0, 10, 0, 0),
                 }),
                 Item::Struct(StructDef {
@@ -644,6 +649,7 @@ mod tests {
                     const_params: vec![],
                     fields: vec![],
                     visibility: Visibility::Private,
+                    field_defaults: vec![],
                     span: Span::new(20, 30, 0, 0),
                 }),
             ],
@@ -114,37 +114,23 @@ impl LanguageServer {
         }
 
         // Check built-in functions
-        let builtins = vec![
-            ("print", "fn print(s: String)", "Print a string to stdout"),
-            (
-                "print_int",
-                "fn print_int(n: i64)",
-                "Print an integer to stdout",
-            ),
-            (
-                "string_len",
-                "fn string_len(s: String) -> i64",
-                "Get the length of a string",
-            ),
-            (
-                "string_concat",
-                "fn string_concat(a: String, b: String) -> String",
-                "Concatenate two strings",
-            ),
-            (
-                "int_to_string",
-                "fn int_to_string(n: i64) -> String",
-                "Convert an integer to a string",
-            ),
+        let builtins: Vec<(&str, &[&str], &str)> = vec![
+            ("print", &["s"], "Print a string to stdout"),
+            ("print_int", &["n"], "Print an integer to stdout"),
+            ("string_len", &["s"], "Get the length of a string"),
+            ("string_concat", &["a", "b"], "Concatenate two strings"),
             (
-                "string_to_int",
-                "fn string_to_int(s: String) -> Option<i64>",
-                "Parse an integer from a string",
+                "string_push_str",
+                &["s", "t"],
+                "Append to a string in place, growing its buffer",
             ),
+            ("int_to_string", &["n"], "Convert an integer to a string"),
+            ("string_to_int", &["s"], "Parse an integer from a string"),
         ];
 
-        for (name, sig, doc) in builtins {
+        for (name, params, doc) in builtins {
             if name == symbol {
+                let sig = self.builtin_signature(name, params)?;
                 return Some(Hover {
                     contents: MarkupContent {
                         kind: MarkupKind::Markdown,
@@ -366,6 +352,21 @@ mod tests {
         assert!(hover.contents.value.contains("*Async function*"));
     }
 
+    #[test]
+    fn test_hover_shows_inferred_effects() {
+        let mut server = create_test_server();
+        let content = "fn main() {\n    greet();\n}\nfn greet() {\n    print(\"hi\");\n}\n";
+        server
+            .open_document("file:///test.pd".to_string(), 1, content.to_string())
+            .unwrap();
+
+        // `main` gets the effects of `greet`, defined after it
+        let hover = server
+            .get_hover("file:///test.pd", create_position(0, 4))
+            .unwrap();
+        assert!(hover.contents.value.contains("**Effects**: io"));
+    }
+
     #[test]
     fn test_hover_function_with_effects() {
         let mut server = create_test_server();
@@ -461,7 +462,7 @@ mod tests {
         assert!(hover.is_some());
 
         let hover = hover.unwrap();
-        assert!(hover.contents.value.contains("fn print(s: String)"));
+        assert!(hover.contents.value.contains("fn print(s: ref str)"));
         assert!(hover.contents.value.contains("Print a string to stdout"));
     }
 
@@ -497,10 +498,11 @@ mod tests {
         // Test each builtin function
         let builtins = vec![
                 ("print_int", "fn print_int(n: i64)"),
-                ("string_len", "fn string_len(s: String) -> i64"),
-                ("string_concat", "fn string_concat(a: String, b: String) -> String"),
+                ("string_len", "fn string_len(s: ref str) -> i64"),
+                ("string_concat", "fn string_concat(a: ref str, b: ref str) -> String"),
+                ("string_push_str", "fn string_push_str(s: String, t: ref str)"),
                 ("int_to_string", "fn int_to_string(n: i64) -> String"),
-            ("string_to_int", "fn string_to_int(s: String) -> Option<i64>"),
+            ("string_to_int", "fn string_to_int(s: ref str) -> i64"),
         ];
 
         for (name, expected_sig) in builtins {
@@ -333,11 +333,47 @@ impl LanguageServer {
         let mut type_checker = crate::typeck::TypeChecker::new();
         type_checker.check(ast)?;
 
+        let mut effect_analyzer = crate::effects::EffectAnalyzer::new();
+        effect_analyzer.check_program(ast)?;
+
         // Extract type information
-        // TODO: Implement type info extraction
+        // TODO: Implement type info extraction for variables
+        let mut functions = HashMap::new();
+        let mut add_function = |name: String, func: &crate::ast::Function| {
+            let effects = effect_analyzer
+                .get_function_effects(&name)
+                .map(|effects| effects.names().into_iter().map(String::from).collect())
+                .unwrap_or_default();
+            functions.insert(
+                name,
+                FunctionSignature {
+                    params: func
+                        .params
+                        .iter()
+                        .map(|param| (param.name.clone(), param.ty.clone()))
+                        .collect(),
+                    return_type: func.return_type.clone(),
+                    is_async: func.is_async,
+                    effects,
+                },
+            );
+        };
+        for item in &ast.items {
+            match item {
+                crate::ast::Item::Function(func) => add_function(func.name.clone(), func),
+                crate::ast::Item::Impl(impl_block) => {
+                    let type_name = crate::effects::type_name(&impl_block.for_type);
+                    for method in &impl_block.methods {
+                        add_function(format!("{}::{}", type_name, method.name), method);
+                    }
+                }
+                _ => {}
+            }
+        }
+
         Ok(TypeInfo {
             variables: HashMap::new(),
-            functions: HashMap::new(),
+            functions,
             type_aliases: HashMap::new(),
         })
     }
@@ -462,6 +498,23 @@ impl LanguageServer {
         }
     }
     
+    /// Signature of a built-in function with its parameters named, typed the
+    /// way the type checker declares it
+    fn builtin_signature(&self, name: &str, param_names: &[&str]) -> Option<String> {
+        let (param_types, return_type) =
+            crate::typeck::TypeChecker::new().builtin_signature(name)?;
+        let params: Vec<String> = param_names
+            .iter()
+            .zip(&param_types)
+            .map(|(param, ty)| format!("{}: {}", param, ty))
+            .collect();
+        let mut signature = format!("fn {}({})", name, params.join(", "));
+        if let Some(return_type) = return_type {
+            signature.push_str(&format!(" -> {}", return_type));
+        }
+        Some(signature)
+    }
+    
     /// Find symbol at position
     pub fn find_symbol_at_position(&self, content: &str, position: Position) -> Option<String> {
         let lines: Vec<&str> = content.lines().collect();
@@ -2,7 +2,7 @@
 // "Find all the places your code lives"
 
 use super::{LanguageServer, Location, Position, Range};
-use crate::ast::{Expr, Item, Program, Stmt};
+use crate::ast::{Expr, Item, Program, Stmt, StructBase};
 
 impl LanguageServer {
     /// Find definition of symbol at position
@@ -265,13 +265,18 @@ impl ReferenceFinder {
                 self.find_in_expression(array);
                 self.find_in_expression(index);
             }
-            Expr::StructLiteral { name, fields, .. } => {
+            Expr::StructLiteral {
+                name, fields, base, ..
+            } => {
                 if name == &self.symbol {
                     // TODO: Add reference to struct name
                 }
                 for (_, expr) in fields {
                     self.find_in_expression(expr);
                 }
+                if let Some(StructBase::Expr(base)) = base {
+                    self.find_in_expression(base);
+                }
             }
             Expr::ArrayLiteral { elements, .. } => {
                 for elem in elements {
@@ -433,6 +438,7 @@ mod tests {
                     visibility: Visibility::Private,
                     is_async: false,
                     effects: None,
+                    effect_params: vec![],
                     span: Span::new(0, 11, 0, 0),
                 }),
             ],
@@ -474,6 +480,7 @@ mod tests {
                     visibility: Visibility::Private,
                     is_async: false,
                     effects: None,
+                    effect_params: vec![],
                     span: Span::new(0, 11, 0, 0),
                 }),
             ],
@@ -551,6 +558,7 @@ mod tests {
                     visibility: Visibility::Private,
                     is_async: false,
                     effects: None,
+                    effect_params: vec![],
                     span: Span::new(0, 11, 0, 0),
                 }),
             ],
@@ -591,6 +599,7 @@ mod tests {
                     const_params: vec![],
                     fields: vec![],
                     visibility: Visibility::Private,
+                    field_defaults: vec![],
                     span: Span::new(0, 20, 0, 0),
                 }),
             ],
@@ -686,6 +695,7 @@ mod tests {
                     visibility: Visibility::Private,
                     is_async: false,
                     effects: None,
+                    effect_params: vec![],
                     span: Span::new(0, 30, 0, 0),
                 }),
             ],
@@ -763,6 +773,8 @@ mod tests {
                 Stmt::Expr(Expr::Ident("cond".to_string())),
             ]),
             span: Span::new(0, 20, 0, 0),
+            then_end: Span::new(0, 20, 0, 0),
+            else_end: Some(Span::new(0, 20, 0, 0)),
         };
 
         finder.find_in_statement(&if_stmt);
@@ -776,6 +788,7 @@ mod tests {
                 Stmt::Expr(Expr::Ident("cond".to_string())),
             ],
             span: Span::new(0, 20, 0, 0),
+            body_end: Span::new(0, 20, 0, 0),
         };
 
         finder.find_in_statement(&while_stmt);
@@ -796,6 +809,7 @@ mod tests {
                 ("x".to_string(), Expr::Integer(10)),
                 ("y".to_string(), Expr::Integer(20)),
             ],
+            base: None,
             span: Span::new(0, 30, 0, 0),
         };
         finder.find_in_expression(&expr);
@@ -868,6 +882,7 @@ mod tests {
                             visibility: Visibility::Private,
                             is_async: false,
                             effects: None,
+                            effect_params: vec![],
                             span: Span::new(0, 20, 0, 0),
                         },
                     ],
@@ -374,6 +374,7 @@ mod tests {
                     visibility: Visibility::Private,
                     is_async: false,
                     effects: None,
+                    effect_params: vec![],
                     span: Span::new(0, 40, 0, 0),
                 }),
             ],
@@ -419,6 +420,7 @@ mod tests {
                         ("y".to_string(), Type::I32),
                     ],
                     visibility: Visibility::Private,
+                    field_defaults: vec![],
                     span: Span::new(0, 31, 0, 0),
                 }),
             ],
@@ -640,6 +642,7 @@ mod tests {
                     visibility: Visibility::Private,
                     is_async: false,
                     effects: None,
+                    effect_params: vec![],
                     span: Span::new(0, 20, 0, 0),
                 }),
                 Item::Struct(StructDef {
@@ -649,6 +652,7 @@ mod tests {
                     const_params: vec![],
                     fields: vec![("value".to_string(), Type::I32)],
                     visibility: Visibility::Private,
+                    field_defaults: vec![],
                     span: Span::new(22, 40, 0, 0),
                 }),
                 Item::Enum(EnumDef {
@@ -803,6 +807,7 @@ mod tests {
             visibility: Visibility::Private,
             is_async: false,
             effects: None,
+            effect_params: vec![],
             span: Span::new(0, 10, 0, 0),
         };
 
@@ -836,6 +841,7 @@ mod tests {
             visibility: Visibility::Private,
             is_async: false,
             effects: None,
+            effect_params: vec![],
             span: Span::new(0, 20, 0, 0),
         };
 
@@ -3,7 +3,7 @@
 
 use super::parser::{CaptureKind, PatternElement, RepetitionKind};
 use crate::ast::{Expr, Stmt};
-use crate::errors::{CompileError, Result};
+use crate::errors::{CompileError, Result, Span};
 use crate::lexer::{Lexer, Token};
 use crate::parser::Parser;
 use std::collections::HashMap;
@@ -404,23 +404,62 @@ pub fn substitute_template(template: &[Token], context: &MatchContext) -> Result
     Ok(result)
 }
 
+/// Where expanded code is attributed in the source
+pub struct ExpansionSite {
+    /// Span of the macro invocation
+    pub origin: Span,
+    /// Next unused offset; keeps the spans of expanded code distinct from each other
+    pub offset: usize,
+}
+
+impl ExpansionSite {
+    /// A new span at the invocation, distinct from every other expanded span
+    pub fn fresh_span(&mut self) -> Span {
+        let len = self.origin.end.saturating_sub(self.origin.start).max(1);
+        let span = Span::new(
+            self.offset,
+            self.offset + len,
+            self.origin.line,
+            self.origin.column,
+        );
+        self.offset += len;
+        span
+    }
+
+    /// Lex expanded source, placing its tokens at the invocation
+    fn lex(&mut self, source: &str) -> Result<Vec<(Token, Span)>> {
+        let mut lexer = Lexer::new(source);
+        let base = self.offset;
+        self.offset += source.len() + 1;
+        Ok(lexer
+            .collect_tokens()?
+            .into_iter()
+            .map(|(token, span)| {
+                let span = Span::new(
+                    base + span.start,
+                    base + span.end,
+                    self.origin.line,
+                    self.origin.column,
+                );
+                (token, span)
+            })
+            .collect())
+    }
+}
+
 /// Expand a macro invocation to an expression
-pub fn expand_to_expr(tokens: Vec<Token>) -> Result<Expr> {
+pub fn expand_to_expr(tokens: Vec<Token>, site: &mut ExpansionSite) -> Result<Expr> {
     // Convert tokens back to string and parse
     let source = tokens_to_string(&tokens);
-    let mut lexer = Lexer::new(&source);
-    let tokens = lexer.collect_tokens()?;
-    let mut parser = Parser::new(tokens);
+    let mut parser = Parser::new(site.lex(&source)?);
     parser.parse_expression()
 }
 
 /// Expand a macro invocation to statements
-pub fn expand_to_stmts(tokens: Vec<Token>) -> Result<Vec<Stmt>> {
+pub fn expand_to_stmts(tokens: Vec<Token>, site: &mut ExpansionSite) -> Result<Vec<Stmt>> {
     // Convert tokens back to string and parse
     let source = tokens_to_string(&tokens);
-    let mut lexer = Lexer::new(&source);
-    let tokens = lexer.collect_tokens()?;
-    let mut parser = Parser::new(tokens);
+    let mut parser = Parser::new(site.lex(&source)?);
 
     let mut stmts = Vec::new();
     while parser.current_token() != &Token::Eof {
@@ -432,57 +471,9 @@ pub fn expand_to_stmts(tokens: Vec<Token>) -> Result<Vec<Stmt>> {
 
 /// Convert tokens to string (for re-parsing)
 fn tokens_to_string(tokens: &[Token]) -> String {
-    let mut result = String::new();
-
-    for (i, token) in tokens.iter().enumerate() {
-        if i > 0 {
-            result.push(' ');
-        }
-
-        match token {
-            Token::Identifier(s) => result.push_str(s),
-            Token::Integer(n) => result.push_str(&n.to_string()),
-            Token::String(s) => {
-                result.push('"');
-                result.push_str(s);
-                result.push('"');
-            }
-            Token::True => result.push_str("true"),
-            Token::False => result.push_str("false"),
-            Token::LeftParen => result.push('('),
-            Token::RightParen => result.push(')'),
-            Token::LeftBrace => result.push('{'),
-            Token::RightBrace => result.push('}'),
-            Token::LeftBracket => result.push('['),
-            Token::RightBracket => result.push(']'),
-            Token::Comma => result.push(','),
-            Token::Semicolon => result.push(';'),
-            Token::Colon => result.push(':'),
-            Token::Arrow => result.push_str("->"),
-            Token::Plus => result.push('+'),
-            Token::Minus => result.push('-'),
-            Token::Star => result.push('*'),
-            Token::Slash => result.push('/'),
-            Token::Percent => result.push('%'),
-            Token::Eq => result.push('='),
-            Token::EqEq => result.push_str("=="),
-            Token::Ne => result.push_str("!="),
-            Token::Lt => result.push('<'),
-            Token::Gt => result.push('>'),
-            Token::Le => result.push_str("<="),
-            Token::Ge => result.push_str(">="),
-            Token::AndAnd => result.push_str("&&"),
-            Token::OrOr => result.push_str("||"),
-            Token::Not => result.push('!'),
-            Token::Dot => result.push('.'),
-            Token::DotDot => result.push_str(".."),
-            Token::Ampersand => result.push('&'),
-            Token::Pipe => result.push('|'),
-            Token::Question => result.push('?'),
-            Token::Dollar => result.push('$'),
-            _ => result.push_str(&format!("{:?}", token)),
-        }
-    }
-
-    result
+    tokens
+        .iter()
+        .map(Token::source_text)
+        .collect::<Vec<_>>()
+        .join(" ")
 }
@@ -0,0 +1,454 @@
+// Format strings for Palladium
+// "Every legend deserves a proper telling"
+
+use crate::ast::Expr;
+use crate::errors::{CompileError, Result, Span};
+
+/// How a placeholder renders its argument
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FormatKind {
+    /// `{}` through the `Display` trait
+    Display,
+    /// `{:?}` through the `Debug` trait
+    Debug,
+    /// `{:x}`
+    LowerHex,
+    /// `{:X}`
+    UpperHex,
+    /// `{:b}`
+    Binary,
+    /// `{:o}`
+    Octal,
+}
+
+impl FormatKind {
+    pub const ALL: [FormatKind; 6] = [
+        FormatKind::Display,
+        FormatKind::Debug,
+        FormatKind::LowerHex,
+        FormatKind::UpperHex,
+        FormatKind::Binary,
+        FormatKind::Octal,
+    ];
+
+    /// Compiler intrinsic that formats one argument of this kind
+    pub fn intrinsic(&self) -> &'static str {
+        match self {
+            FormatKind::Display => "__fmt_display",
+            FormatKind::Debug => "__fmt_debug",
+            FormatKind::LowerHex => "__fmt_lower_hex",
+            FormatKind::UpperHex => "__fmt_upper_hex",
+            FormatKind::Binary => "__fmt_binary",
+            FormatKind::Octal => "__fmt_octal",
+        }
+    }
+
+    /// Look up the kind formatted by an intrinsic
+    pub fn from_intrinsic(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.intrinsic() == name)
+    }
+
+    /// The placeholder as written in a format string
+    pub fn placeholder(&self) -> &'static str {
+        match self {
+            FormatKind::Display => "{}",
+            FormatKind::Debug => "{:?}",
+            FormatKind::LowerHex => "{:x}",
+            FormatKind::UpperHex => "{:X}",
+            FormatKind::Binary => "{:b}",
+            FormatKind::Octal => "{:o}",
+        }
+    }
+
+    /// Whether the kind only accepts integers
+    pub fn is_integer_only(&self) -> bool {
+        !matches!(self, FormatKind::Display | FormatKind::Debug)
+    }
+
+    /// Radix of integer-only kinds
+    pub fn radix(&self) -> u32 {
+        match self {
+            FormatKind::LowerHex | FormatKind::UpperHex => 16,
+            FormatKind::Binary => 2,
+            FormatKind::Octal => 8,
+            FormatKind::Display | FormatKind::Debug => 10,
+        }
+    }
+}
+
+/// Alignment of padded output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+/// Fill, alignment, width and precision of a placeholder, e.g. `*^#010.3`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormatSpec {
+    pub fill: char,
+    pub align: Option<Align>,
+    pub alternate: bool,
+    pub zero_pad: bool,
+    pub width: Option<usize>,
+    pub precision: Option<usize>,
+}
+
+impl Default for FormatSpec {
+    fn default() -> Self {
+        Self {
+            fill: ' ',
+            align: None,
+            alternate: false,
+            zero_pad: false,
+            width: None,
+            precision: None,
+        }
+    }
+}
+
+impl FormatSpec {
+    /// Parse a spec without its trailing type, e.g. `>8` or `#010`
+    pub fn parse(text: &str) -> std::result::Result<Self, String> {
+        let chars: Vec<char> = text.chars().collect();
+        let mut spec = FormatSpec::default();
+        let mut pos = 0;
+
+        let align_of = |c: char| match c {
+            '<' => Some(Align::Left),
+            '^' => Some(Align::Center),
+            '>' => Some(Align::Right),
+            _ => None,
+        };
+
+        if let Some(align) = chars.get(1).and_then(|&c| align_of(c)) {
+            if !chars[0].is_ascii() {
+                return Err(format!("fill character '{}' must be ASCII", chars[0]));
+            }
+            spec.fill = chars[0];
+            spec.align = Some(align);
+            pos = 2;
+        } else if let Some(align) = chars.first().and_then(|&c| align_of(c)) {
+            spec.align = Some(align);
+            pos = 1;
+        }
+
+        if chars.get(pos) == Some(&'#') {
+            spec.alternate = true;
+            pos += 1;
+        }
+        if chars.get(pos) == Some(&'0') {
+            spec.zero_pad = true;
+            pos += 1;
+        }
+
+        let (width, next) = parse_count(&chars, pos);
+        spec.width = width;
+        pos = next;
+
+        if chars.get(pos) == Some(&'.') {
+            let (precision, next) = parse_count(&chars, pos + 1);
+            if precision.is_none() {
+                return Err("expected a precision after '.'".to_string());
+            }
+            spec.precision = precision;
+            pos = next;
+        }
+
+        if pos < chars.len() {
+            let rest: String = chars[pos..].iter().collect();
+            return Err(format!("unknown format spec '{}'", rest));
+        }
+
+        Ok(spec)
+    }
+
+    /// Whether the spec changes nothing about the formatted text
+    pub fn is_default(&self) -> bool {
+        self.width.is_none() && self.precision.is_none()
+    }
+}
+
+/// Parse a decimal count starting at `pos`
+fn parse_count(chars: &[char], pos: usize) -> (Option<usize>, usize) {
+    let digits: String = chars[pos.min(chars.len())..]
+        .iter()
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+    if digits.is_empty() {
+        (None, pos)
+    } else {
+        (digits.parse().ok(), pos + digits.len())
+    }
+}
+
+/// Which argument a placeholder refers to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArgRef {
+    /// `{}`: the next positional argument
+    Next,
+    /// `{0}`
+    Index(usize),
+    /// `{name}`: a named argument or a variable in scope
+    Name(String),
+}
+
+/// A parsed piece of a format string
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Piece {
+    Literal(String),
+    Placeholder {
+        arg: ArgRef,
+        kind: FormatKind,
+        /// Spec text without the type, passed on to code generation
+        spec: String,
+    },
+}
+
+/// Split a format string into literal text and placeholders
+pub fn parse_format_string(template: &str) -> std::result::Result<Vec<Piece>, String> {
+    let mut pieces = Vec::new();
+    let mut literal = String::new();
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                literal.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                literal.push('}');
+            }
+            '}' => {
+                return Err(
+                    "unmatched '}' in format string; use '}}' for a literal brace".to_string(),
+                )
+            }
+            '{' => {
+                let mut contents = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => contents.push(c),
+                        None => {
+                            return Err("unterminated placeholder; use '{{' for a literal brace"
+                                .to_string())
+                        }
+                    }
+                }
+
+                if !literal.is_empty() {
+                    pieces.push(Piece::Literal(std::mem::take(&mut literal)));
+                }
+                pieces.push(parse_placeholder(&contents)?);
+            }
+            _ => literal.push(c),
+        }
+    }
+
+    if !literal.is_empty() {
+        pieces.push(Piece::Literal(literal));
+    }
+    Ok(pieces)
+}
+
+/// Parse the inside of `{...}`
+fn parse_placeholder(contents: &str) -> std::result::Result<Piece, String> {
+    let (arg_text, spec_text) = contents.split_once(':').unwrap_or((contents, ""));
+    let arg_text = arg_text.trim();
+
+    let arg = if arg_text.is_empty() {
+        ArgRef::Next
+    } else if let Ok(index) = arg_text.parse::<usize>() {
+        ArgRef::Index(index)
+    } else if arg_text.starts_with(|c: char| c.is_alphabetic() || c == '_')
+        && arg_text.chars().all(|c| c.is_alphanumeric() || c == '_')
+    {
+        ArgRef::Name(arg_text.to_string())
+    } else {
+        return Err(format!("invalid argument '{}' in placeholder", arg_text));
+    };
+
+    let (kind, spec) = match spec_text.chars().last() {
+        Some('?') => (FormatKind::Debug, &spec_text[..spec_text.len() - 1]),
+        Some('x') => (FormatKind::LowerHex, &spec_text[..spec_text.len() - 1]),
+        Some('X') => (FormatKind::UpperHex, &spec_text[..spec_text.len() - 1]),
+        Some('b') => (FormatKind::Binary, &spec_text[..spec_text.len() - 1]),
+        Some('o') => (FormatKind::Octal, &spec_text[..spec_text.len() - 1]),
+        _ => (FormatKind::Display, spec_text),
+    };
+
+    // Validate now so code generation can rely on the spec
+    FormatSpec::parse(spec)?;
+
+    Ok(Piece::Placeholder {
+        arg,
+        kind,
+        spec: spec.to_string(),
+    })
+}
+
+/// Lower a format string and its arguments to concatenated formatting intrinsics
+pub fn lower_format(
+    template: &str,
+    positional: Vec<Expr>,
+    named: Vec<(String, Expr)>,
+    span: Span,
+    mut fresh_span: impl FnMut() -> Span,
+) -> Result<Expr> {
+    let format_error = |message: String| CompileError::FormatError {
+        message,
+        span: Some(span),
+    };
+
+    let pieces = parse_format_string(template).map_err(format_error)?;
+
+    let mut next_positional = 0;
+    let mut used_positional = vec![false; positional.len()];
+    let mut used_named = vec![false; named.len()];
+    let mut parts = Vec::new();
+
+    for piece in pieces {
+        let (arg, kind, spec) = match piece {
+            Piece::Literal(text) => {
+                parts.push(Expr::String(text));
+                continue;
+            }
+            Piece::Placeholder { arg, kind, spec } => (arg, kind, spec),
+        };
+
+        let value =
+            match arg {
+                ArgRef::Next | ArgRef::Index(_) => {
+                    let index = match arg {
+                        ArgRef::Index(index) => index,
+                        _ => {
+                            next_positional += 1;
+                            next_positional - 1
+                        }
+                    };
+                    match positional.get(index) {
+                        Some(value) => {
+                            used_positional[index] = true;
+                            value.clone()
+                        }
+                        None => {
+                            return Err(format_error(format!(
+                            "format string refers to argument {}, but only {} argument{} supplied",
+                            index,
+                            positional.len(),
+                            if positional.len() == 1 { " was" } else { "s were" }
+                        )))
+                        }
+                    }
+                }
+                ArgRef::Name(name) => {
+                    match named.iter().position(|(arg_name, _)| *arg_name == name) {
+                        Some(index) => {
+                            used_named[index] = true;
+                            named[index].1.clone()
+                        }
+                        // Otherwise capture the variable of that name
+                        None => Expr::Ident(name),
+                    }
+                }
+            };
+
+        parts.push(Expr::Call {
+            func: Box::new(Expr::Ident(kind.intrinsic().to_string())),
+            args: vec![value, Expr::String(spec)],
+            span: fresh_span(),
+        });
+    }
+
+    if let Some(index) = used_positional.iter().position(|used| !used) {
+        return Err(format_error(format!(
+            "argument {} is never used by the format string",
+            index
+        )));
+    }
+    if let Some(index) = used_named.iter().position(|used| !used) {
+        return Err(format_error(format!(
+            "named argument '{}' is never used by the format string",
+            named[index].0
+        )));
+    }
+
+    let mut parts = parts.into_iter();
+    let first = parts.next().unwrap_or_else(|| Expr::String(String::new()));
+    Ok(parts.fold(first, |acc, part| Expr::Call {
+        func: Box::new(Expr::Ident("string_concat".to_string())),
+        args: vec![acc, part],
+        span: fresh_span(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_placeholders() {
+        let pieces = parse_format_string("{{x}} = {:>8} {name:?} {1:#010x}").unwrap();
+        assert_eq!(pieces[0], Piece::Literal("{x} = ".to_string()));
+        assert_eq!(
+            pieces[1],
+            Piece::Placeholder {
+                arg: ArgRef::Next,
+                kind: FormatKind::Display,
+                spec: ">8".to_string(),
+            }
+        );
+        assert_eq!(
+            pieces[3],
+            Piece::Placeholder {
+                arg: ArgRef::Name("name".to_string()),
+                kind: FormatKind::Debug,
+                spec: String::new(),
+            }
+        );
+        assert_eq!(
+            pieces[5],
+            Piece::Placeholder {
+                arg: ArgRef::Index(1),
+                kind: FormatKind::LowerHex,
+                spec: "#010".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_spec() {
+        let spec = FormatSpec::parse("*^12.3").unwrap();
+        assert_eq!(spec.fill, '*');
+        assert_eq!(spec.align, Some(Align::Center));
+        assert_eq!(spec.width, Some(12));
+        assert_eq!(spec.precision, Some(3));
+        assert!(FormatSpec::parse("5q").is_err());
+    }
+
+    #[test]
+    fn test_argument_count_is_checked() {
+        let span = Span::dummy();
+        let result = lower_format("{} {}", vec![Expr::Integer(1)], vec![], span, Span::dummy);
+        assert!(matches!(result, Err(CompileError::FormatError { .. })));
+
+        let result = lower_format(
+            "{}",
+            vec![Expr::Integer(1), Expr::Integer(2)],
+            vec![],
+            span,
+            Span::dummy,
+        );
+        assert!(matches!(result, Err(CompileError::FormatError { .. })));
+    }
+
+    #[test]
+    fn test_unbalanced_braces() {
+        assert!(parse_format_string("{").is_err());
+        assert!(parse_format_string("}").is_err());
+    }
+}
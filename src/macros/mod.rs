@@ -2,15 +2,26 @@
 // "Expanding the code's possibilities"
 
 pub mod expander;
+pub mod format;
 pub mod parser;
 
-use crate::ast::{Expr, Item, MacroDef, Program, Stmt};
-use crate::errors::{CompileError, Result};
+use crate::ast::{AssignTarget, Expr, Item, MacroDef, Program, Stmt};
+use crate::errors::{CompileError, Result, Span};
 use crate::lexer::{Lexer, Token};
-use expander::{expand_to_expr, expand_to_stmts, match_pattern, substitute_template};
+use expander::{
+    expand_to_expr, expand_to_stmts, match_pattern, substitute_template, ExpansionSite,
+};
 use parser::PatternElement;
 use std::collections::HashMap;
 
+/// Built-in formatting macros, expanded directly instead of through patterns
+const FORMAT_MACROS: &[&str] = &[
+    "format", "print", "println", "eprint", "eprintln", "write", "writeln",
+];
+
+/// Offset of the first expanded span, beyond any real source position
+const EXPANSION_OFFSET_BASE: usize = usize::MAX / 2;
+
 /// Parsed macro definition
 #[derive(Clone)]
 struct ParsedMacro {
@@ -23,12 +34,15 @@ struct ParsedMacro {
 pub struct MacroExpander {
     /// Registered macros (name -> definition)
     macros: HashMap<String, ParsedMacro>,
+    /// Next offset for spans of expanded code
+    expansion_offset: usize,
 }
 
 impl MacroExpander {
     pub fn new() -> Self {
         let mut expander = Self {
             macros: HashMap::new(),
+            expansion_offset: EXPANSION_OFFSET_BASE,
         };
 
         // Register built-in macros
@@ -38,55 +52,16 @@ impl MacroExpander {
     }
 
     /// Register built-in macros
+    /// Formatting macros (format!, println!, ...) are handled by `expand_format`,
+    /// and `vec!` by `expand_vec`.
     fn register_builtin_macros(&mut self) {
-        // println! macro
-        self.register_builtin_println();
-
         // assert! macro
         self.register_builtin_assert();
 
-        // vec! macro
-        self.register_builtin_vec();
-
         // dbg! macro
         self.register_builtin_dbg();
     }
 
-    /// Register println! macro
-    fn register_builtin_println(&mut self) {
-        // println!() -> print("\n")
-        // println!($expr:expr) -> print($expr); print("\n")
-        // println!($expr:expr, $($args:expr),*) -> print($expr); $(print(" "); print($args);)* print("\n")
-
-        // For now, simplified version
-        let pattern = vec![PatternElement::Variable {
-            name: "msg".to_string(),
-            kind: parser::CaptureKind::Expr,
-        }];
-
-        let template = vec![
-            Token::Identifier("print".to_string()),
-            Token::LeftParen,
-            Token::Dollar,
-            Token::Identifier("msg".to_string()),
-            Token::RightParen,
-            Token::Semicolon,
-            Token::Identifier("print".to_string()),
-            Token::LeftParen,
-            Token::String("\\n".to_string()),
-            Token::RightParen,
-        ];
-
-        self.macros.insert(
-            "println".to_string(),
-            ParsedMacro {
-                name: "println".to_string(),
-                pattern,
-                template,
-            },
-        );
-    }
-
     /// Register assert! macro
     fn register_builtin_assert(&mut self) {
         // assert!($cond:expr) -> if (!$cond) { panic("Assertion failed"); }
@@ -123,32 +98,6 @@ impl MacroExpander {
         );
     }
 
-    /// Register vec! macro
-    fn register_builtin_vec(&mut self) {
-        // vec![$($elem:expr),*] -> { let mut v = Vec::new(); $(v.push($elem);)* v }
-        // Simplified: vec![$elem:expr] -> [$elem]
-        let pattern = vec![PatternElement::Variable {
-            name: "elem".to_string(),
-            kind: parser::CaptureKind::Expr,
-        }];
-
-        let template = vec![
-            Token::LeftBracket,
-            Token::Dollar,
-            Token::Identifier("elem".to_string()),
-            Token::RightBracket,
-        ];
-
-        self.macros.insert(
-            "vec".to_string(),
-            ParsedMacro {
-                name: "vec".to_string(),
-                pattern,
-                template,
-            },
-        );
-    }
-
     /// Register dbg! macro
     fn register_builtin_dbg(&mut self) {
         // dbg!($expr:expr) -> { print("DEBUG: "); print($expr); print("\n"); $expr }
@@ -172,7 +121,7 @@ impl MacroExpander {
             Token::Semicolon,
             Token::Identifier("print".to_string()),
             Token::LeftParen,
-            Token::String("\\n".to_string()),
+            Token::String("\n".to_string()),
             Token::RightParen,
             Token::Semicolon,
             Token::Dollar,
@@ -221,7 +170,10 @@ impl MacroExpander {
 
     /// Register a macro definition
     fn register_macro(&mut self, macro_def: &MacroDef) -> Result<()> {
-        if self.macros.contains_key(&macro_def.name) {
+        if self.macros.contains_key(&macro_def.name)
+            || FORMAT_MACROS.contains(&macro_def.name.as_str())
+            || macro_def.name == "vec"
+        {
             return Err(CompileError::Generic(format!(
                 "Macro '{}' is already defined",
                 macro_def.name
@@ -290,9 +242,20 @@ impl MacroExpander {
             match ast_token {
                 crate::ast::Token::Ident(s) => tokens.push(Token::Identifier(s.clone())),
                 crate::ast::Token::Literal(s) => {
-                    // Try to parse as integer or string
+                    // Try to parse as integer, string or boolean
                     if let Ok(n) = s.parse::<i64>() {
                         tokens.push(Token::Integer(n));
+                    } else if s.starts_with('"') {
+                        // String literals keep their quotes and escapes
+                        let lexed = Lexer::new(s).collect_tokens()?;
+                        match lexed.into_iter().next() {
+                            Some((token @ Token::String(_), _)) => tokens.push(token),
+                            _ => tokens.push(Token::String(s.clone())),
+                        }
+                    } else if s == "true" {
+                        tokens.push(Token::True);
+                    } else if s == "false" {
+                        tokens.push(Token::False);
                     } else {
                         tokens.push(Token::String(s.clone()));
                     }
@@ -358,6 +321,12 @@ impl MacroExpander {
                 }
                 func.body = new_body;
             }
+            Item::Impl(impl_block) => {
+                // Expand macros in method bodies
+                for method in &mut impl_block.methods {
+                    method.body = self.expand_stmts(&method.body)?;
+                }
+            }
             // TODO: Handle other item types that might contain expressions
             _ => {}
         }
@@ -428,7 +397,14 @@ impl MacroExpander {
 
     /// Expand macro invocation to statements
     fn expand_macro_to_stmts(&mut self, expr: &Expr) -> Result<Vec<Stmt>> {
-        if let Expr::MacroInvocation { name, args, .. } = expr {
+        if let Expr::MacroInvocation { name, args, span } = expr {
+            if FORMAT_MACROS.contains(&name.as_str()) {
+                return self.expand_format(name, args, *span);
+            }
+            if name == "vec" {
+                return Ok(vec![Stmt::Expr(self.expand_vec(args, *span)?)]);
+            }
+
             // Look up the macro
             let parsed_macro = self
                 .macros
@@ -445,7 +421,7 @@ impl MacroExpander {
                 let expanded_tokens = substitute_template(&parsed_macro.template, &context)?;
 
                 // Parse as statements
-                return expand_to_stmts(expanded_tokens);
+                return self.with_site(*span, |site| expand_to_stmts(expanded_tokens, site));
             } else {
                 return Err(CompileError::Generic(format!(
                     "Macro '{}' arguments don't match pattern",
@@ -460,7 +436,28 @@ impl MacroExpander {
     /// Expand macros in an expression
     fn expand_expr(&mut self, expr: &mut Expr) -> Result<()> {
         match expr {
-            Expr::MacroInvocation { name, args, .. } => {
+            Expr::MacroInvocation { name, args, span } => {
+                if FORMAT_MACROS.contains(&name.as_str()) {
+                    let span = *span;
+                    *expr = match self
+                        .expand_format(&name.clone(), &args.clone(), span)?
+                        .pop()
+                    {
+                        Some(Stmt::Expr(expanded)) => expanded,
+                        _ => {
+                            return Err(CompileError::FormatError {
+                                message: format!("'{}!' can only be used as a statement", name),
+                                span: Some(span),
+                            })
+                        }
+                    };
+                    return Ok(());
+                }
+                if name == "vec" {
+                    *expr = self.expand_vec(&args.clone(), *span)?;
+                    return Ok(());
+                }
+
                 // Look up the macro
                 let parsed_macro = self
                     .macros
@@ -477,7 +474,8 @@ impl MacroExpander {
                     let expanded_tokens = substitute_template(&parsed_macro.template, &context)?;
 
                     // Parse as expression
-                    *expr = expand_to_expr(expanded_tokens)?;
+                    let span = *span;
+                    *expr = self.with_site(span, |site| expand_to_expr(expanded_tokens, site))?;
                 } else {
                     return Err(CompileError::Generic(format!(
                         "Macro '{}' arguments don't match pattern",
@@ -513,10 +511,13 @@ impl MacroExpander {
                 self.expand_expr(array)?;
                 self.expand_expr(index)?;
             }
-            Expr::StructLiteral { fields, .. } => {
+            Expr::StructLiteral { fields, base, .. } => {
                 for (_, field_expr) in fields {
                     self.expand_expr(field_expr)?;
                 }
+                if let Some(crate::ast::StructBase::Expr(base)) = base {
+                    self.expand_expr(base)?;
+                }
             }
             Expr::FieldAccess { object, .. } => {
                 self.expand_expr(object)?;
@@ -544,6 +545,156 @@ impl MacroExpander {
         Ok(())
     }
 
+    /// Run `f` with an expansion site for the invocation at `origin`
+    fn with_site<T>(
+        &mut self,
+        origin: Span,
+        f: impl FnOnce(&mut ExpansionSite) -> Result<T>,
+    ) -> Result<T> {
+        let mut site = ExpansionSite {
+            origin,
+            offset: self.expansion_offset,
+        };
+        let result = f(&mut site);
+        self.expansion_offset = site.offset;
+        result
+    }
+
+    /// Expand `vec![a, b, c]` or `vec![x; n]` into `Vec::from` of that array,
+    /// and `vec![]` into `Vec::new()`
+    fn expand_vec(&mut self, args: &[crate::ast::Token], span: Span) -> Result<Expr> {
+        let mut tokens = vec![
+            Token::Identifier("Vec".to_string()),
+            Token::DoubleColon,
+        ];
+        if args.is_empty() {
+            tokens.extend([
+                Token::Identifier("new".to_string()),
+                Token::LeftParen,
+                Token::RightParen,
+            ]);
+        } else {
+            tokens.extend([
+                Token::Identifier("from".to_string()),
+                Token::LeftParen,
+                Token::LeftBracket,
+            ]);
+            tokens.extend(self.convert_ast_tokens_to_lexer_tokens(args)?);
+            tokens.extend([Token::RightBracket, Token::RightParen]);
+        }
+        self.with_site(span, |site| expand_to_expr(tokens, site))
+    }
+
+    /// Expand a formatting macro into a single statement
+    fn expand_format(
+        &mut self,
+        name: &str,
+        args: &[crate::ast::Token],
+        span: Span,
+    ) -> Result<Vec<Stmt>> {
+        let format_error = |message: String| CompileError::FormatError {
+            message,
+            span: Some(span),
+        };
+
+        let mut args = split_macro_args(args).into_iter();
+
+        // write!(dest, ...) appends to a string variable
+        let destination = if name.starts_with("write") {
+            match args.next().as_deref() {
+                Some([crate::ast::Token::Ident(dest)]) => Some(dest.clone()),
+                _ => {
+                    return Err(format_error(format!(
+                        "'{}!' expects a string variable as its first argument",
+                        name
+                    )))
+                }
+            }
+        } else {
+            None
+        };
+
+        let mut template = match args.next().as_deref() {
+            None if name.ends_with("ln") => String::new(),
+            Some([crate::ast::Token::Literal(literal)]) if literal.starts_with('"') => {
+                match Lexer::new(literal).collect_tokens()?.into_iter().next() {
+                    Some((Token::String(template), _)) => template,
+                    _ => return Err(format_error("malformed format string".to_string())),
+                }
+            }
+            _ => {
+                return Err(format_error(format!(
+                    "'{}!' expects a string literal as its format string",
+                    name
+                )))
+            }
+        };
+        if name.ends_with("ln") {
+            template.push('\n');
+        }
+
+        // Parse the arguments, named ones written as `name = expr`
+        let mut positional = Vec::new();
+        let mut named: Vec<(String, Expr)> = Vec::new();
+        for arg in args {
+            let (arg_name, tokens) = match arg.as_slice() {
+                [crate::ast::Token::Ident(arg_name), crate::ast::Token::Punct('='), rest @ ..]
+                    if !rest.is_empty() =>
+                {
+                    (Some(arg_name.clone()), rest.to_vec())
+                }
+                _ => (None, arg),
+            };
+            if tokens.is_empty() {
+                return Err(format_error("expected an argument after ','".to_string()));
+            }
+
+            let tokens = self.convert_ast_tokens_to_lexer_tokens(&tokens)?;
+            let mut value = self.with_site(span, |site| expand_to_expr(tokens, site))?;
+            self.expand_expr(&mut value)?;
+
+            match arg_name {
+                Some(arg_name) if named.iter().any(|(existing, _)| *existing == arg_name) => {
+                    return Err(format_error(format!(
+                        "duplicate named argument '{}'",
+                        arg_name
+                    )))
+                }
+                Some(arg_name) => named.push((arg_name, value)),
+                None if !named.is_empty() => {
+                    return Err(format_error(
+                        "positional arguments must come before named arguments".to_string(),
+                    ))
+                }
+                None => positional.push(value),
+            }
+        }
+
+        let formatted = self.with_site(span, |site| {
+            format::lower_format(&template, positional, named, span, || site.fresh_span())
+        })?;
+
+        let call = |func: &str, args: Vec<Expr>| Expr::Call {
+            func: Box::new(Expr::Ident(func.to_string())),
+            args,
+            span,
+        };
+
+        let stmt = match (name, destination) {
+            ("format", _) => Stmt::Expr(formatted),
+            ("print" | "println", _) => Stmt::Expr(call("__write_stdout", vec![formatted])),
+            ("eprint" | "eprintln", _) => Stmt::Expr(call("__write_stderr", vec![formatted])),
+            (_, Some(dest)) => Stmt::Assign {
+                target: AssignTarget::Ident(dest.clone()),
+                value: call("string_concat", vec![Expr::Ident(dest), formatted]),
+                span,
+            },
+            _ => unreachable!("write! always has a destination"),
+        };
+
+        Ok(vec![stmt])
+    }
+
     /// Helper method to expand enum constructor data
     fn expand_enum_constructor_data(&mut self, data: &mut crate::ast::EnumConstructorData) -> Result<()> {
         match data {
@@ -567,3 +718,116 @@ impl Default for MacroExpander {
         Self::new()
     }
 }
+
+/// Split macro arguments at top-level commas
+fn split_macro_args(tokens: &[crate::ast::Token]) -> Vec<Vec<crate::ast::Token>> {
+    let mut args = Vec::new();
+    let mut current = Vec::new();
+    let mut depth = 0;
+
+    for token in tokens {
+        match token {
+            crate::ast::Token::Punct('(' | '[' | '{') => depth += 1,
+            crate::ast::Token::Punct(')' | ']' | '}') => depth -= 1,
+            crate::ast::Token::Punct(',') if depth == 0 => {
+                args.push(std::mem::take(&mut current));
+                continue;
+            }
+            _ => {}
+        }
+        current.push(token.clone());
+    }
+
+    // Allow a trailing comma
+    if !current.is_empty() {
+        args.push(current);
+    }
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn expand(source: &str) -> Result<Program> {
+        let tokens = Lexer::new(source).collect_tokens()?;
+        let mut program = Parser::new(tokens).parse()?;
+        MacroExpander::new().expand_program(&mut program)?;
+        Ok(program)
+    }
+
+    #[test]
+    fn test_println_expands_to_write() {
+        let program = expand(r#"fn main() { println!("x = {}", 1 + 2); }"#).unwrap();
+        let Item::Function(main) = &program.items[0] else {
+            panic!("expected main");
+        };
+        match &main.body[0] {
+            Stmt::Expr(Expr::Call { func, args, .. }) => {
+                assert!(matches!(func.as_ref(), Expr::Ident(name) if name == "__write_stdout"));
+                assert_eq!(args.len(), 1);
+            }
+            other => panic!("unexpected expansion: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_vec_expands_to_constructor() {
+        let program = expand("fn main() { let a = vec![1, 2, 3]; let b = vec![]; }").unwrap();
+        let Item::Function(main) = &program.items[0] else {
+            panic!("expected main");
+        };
+        match &main.body[0] {
+            Stmt::Let {
+                value: Expr::EnumConstructor { enum_name, variant, data, .. },
+                ..
+            } => {
+                assert_eq!((enum_name.as_str(), variant.as_str()), ("Vec", "from"));
+                assert!(matches!(
+                    data,
+                    Some(crate::ast::EnumConstructorData::Tuple(args))
+                        if matches!(
+                            &args[0],
+                            Expr::ArrayLiteral { elements, .. } if elements.len() == 3
+                        )
+                ));
+            }
+            other => panic!("unexpected expansion: {:?}", other),
+        }
+        assert!(matches!(
+            &main.body[1],
+            Stmt::Let { value: Expr::EnumConstructor { variant, .. }, .. } if variant == "new"
+        ));
+    }
+
+    #[test]
+    fn test_format_arguments_are_checked() {
+        let err = expand(r#"fn main() { let s = format!("{} {}", 1); }"#).unwrap_err();
+        assert!(matches!(err, CompileError::FormatError { .. }));
+
+        let err = expand(r#"fn main() { println!("{}", 1, 2); }"#).unwrap_err();
+        assert!(err.to_string().contains("never used"));
+    }
+
+    #[test]
+    fn test_expanded_intrinsics_get_distinct_spans() {
+        let program = expand(r#"fn main() { println!("{} {}", 1, 2); }"#).unwrap();
+        let Item::Function(main) = &program.items[0] else {
+            panic!("expected main");
+        };
+        let Stmt::Expr(Expr::Call { args, .. }) = &main.body[0] else {
+            panic!("expected a call");
+        };
+
+        // string_concat(string_concat(string_concat(fmt(1), " "), fmt(2)), "\n")
+        let mut spans = Vec::new();
+        let mut pending = vec![&args[0]];
+        while let Some(Expr::Call { args, span, .. }) = pending.pop() {
+            spans.push(*span);
+            pending.extend(args.iter());
+        }
+        let unique: std::collections::HashSet<_> = spans.iter().collect();
+        assert_eq!(unique.len(), spans.len());
+    }
+}
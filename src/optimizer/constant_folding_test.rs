@@ -18,6 +18,7 @@ mod tests {
                 body,
                 span: Span::dummy(),
                 effects: None,
+                effect_params: vec![],
             })],
         }
     }
@@ -380,6 +381,8 @@ mod tests {
             then_branch: vec![],
             else_branch: None,
             span: Span::dummy(),
+            then_end: Span::dummy(),
+            else_end: None,
         };
         
         pass.optimize_statement(&mut stmt).unwrap();
@@ -650,6 +653,7 @@ mod tests {
             ),
             body: vec![Stmt::Expr(Expr::Integer(1))],
             span: Span::dummy(),
+            body_end: Span::dummy(),
         };
         
         pass.optimize_statement(&mut stmt).unwrap();
@@ -835,6 +839,8 @@ mod tests {
                     then_branch: vec![],
                     else_branch: None,
                     span: Span::dummy(),
+                    then_end: Span::dummy(),
+                    else_end: None,
                 },
             ],
             else_branch: Some(vec![
@@ -845,6 +851,8 @@ mod tests {
                 )),
             ]),
             span: Span::dummy(),
+            then_end: Span::dummy(),
+            else_end: Some(Span::dummy()),
         };
         
         pass.optimize_statement(&mut stmt).unwrap();
@@ -875,6 +883,7 @@ mod tests {
         
         let mut stmt = Stmt::For {
             var: "i".to_string(),
+            pair: None,
             iter: create_binary_expr(
                 Expr::Integer(1),
                 BinOp::Add,
@@ -882,6 +891,7 @@ mod tests {
             ),
             body: vec![],
             span: Span::dummy(),
+            body_end: Span::dummy(),
         };
         
         pass.optimize_statement(&mut stmt).unwrap();
@@ -18,6 +18,7 @@ mod tests {
                 body,
                 span: Span::dummy(),
                 effects: None,
+                effect_params: vec![],
             })],
         }
     }
@@ -132,6 +133,8 @@ mod tests {
                 Stmt::Expr(Expr::Integer(4)), // Dead code
             ]),
             span: Span::dummy(),
+            then_end: Span::dummy(),
+            else_end: Some(Span::dummy()),
         };
         
         pass.optimize_statement(&mut stmt).unwrap();
@@ -157,6 +160,7 @@ mod tests {
                 Stmt::Expr(Expr::Integer(2)), // Dead code after break
             ],
             span: Span::dummy(),
+            body_end: Span::dummy(),
         };
         
         pass.optimize_statement(&mut stmt).unwrap();
@@ -186,12 +190,16 @@ mod tests {
                         ],
                         else_branch: None,
                         span: Span::dummy(),
+                        then_end: Span::dummy(),
+                        else_end: None,
                     },
                     Stmt::Return(None),
                     Stmt::Expr(Expr::Integer(2)), // Dead
                 ],
                 else_branch: None,
                 span: Span::dummy(),
+                then_end: Span::dummy(),
+                else_end: None,
             },
             Stmt::Expr(Expr::Integer(3)), // Dead
         ]);
@@ -251,6 +259,8 @@ mod tests {
                     Stmt::Expr(Expr::Integer(3)),
                 ]),
                 span: Span::dummy(),
+                then_end: Span::dummy(),
+                else_end: Some(Span::dummy()),
             },
             Stmt::Expr(Expr::Binary {
                 left: Box::new(Expr::Integer(10)),
@@ -289,6 +299,8 @@ mod tests {
             then_branch: vec![],
             else_branch: Some(vec![]),
             span: Span::dummy(),
+            then_end: Span::dummy(),
+            else_end: Some(Span::dummy()),
         };
         pass.optimize_statement(&mut stmt).unwrap();
         
@@ -297,6 +309,7 @@ mod tests {
             condition: Expr::Bool(false),
             body: vec![],
             span: Span::dummy(),
+            body_end: Span::dummy(),
         };
         pass.optimize_statement(&mut stmt).unwrap();
     }
@@ -330,10 +343,13 @@ mod tests {
                             Stmt::Expr(Expr::Integer(2)), // Dead
                         ]),
                         span: Span::dummy(),
+                        then_end: Span::dummy(),
+                        else_end: Some(Span::dummy()),
                     },
                     Stmt::Expr(Expr::Integer(3)), // Reachable (if condition is false and no else)
                 ],
                 span: Span::dummy(),
+                body_end: Span::dummy(),
             },
         ]);
         
@@ -372,6 +388,7 @@ mod tests {
                         Stmt::Return(Some(Expr::Integer(1))),
                         Stmt::Expr(Expr::Integer(2)), // Dead code
                     ],
+                    end: Span::dummy(),
                 },
                 MatchArm {
                     pattern: Pattern::Wildcard,
@@ -379,6 +396,7 @@ mod tests {
                         Stmt::Break { span: Span::dummy() },
                         Stmt::Expr(Expr::Integer(3)), // Dead code
                     ],
+                    end: Span::dummy(),
                 },
             ],
             span: Span::dummy(),
@@ -395,6 +413,7 @@ mod tests {
         
         let mut stmt = Stmt::For {
             var: "i".to_string(),
+            pair: None,
             iter: Expr::Ident("items".to_string()),
             body: vec![
                 Stmt::If {
@@ -405,10 +424,13 @@ mod tests {
                     ],
                     else_branch: None,
                     span: Span::dummy(),
+                    then_end: Span::dummy(),
+                    else_end: None,
                 },
                 Stmt::Expr(Expr::Integer(2)), // Reachable
             ],
             span: Span::dummy(),
+            body_end: Span::dummy(),
         };
         
         // Note: current implementation doesn't optimize inside for loops
@@ -438,14 +460,19 @@ mod tests {
                                     Stmt::Expr(Expr::Integer(2)), // Dead
                                 ]),
                                 span: Span::dummy(),
+                                then_end: Span::dummy(),
+                                else_end: Some(Span::dummy()),
                             },
                             Stmt::Expr(Expr::Integer(3)), // Reachable if inner if takes neither branch
                         ],
                         else_branch: None,
                         span: Span::dummy(),
+                        then_end: Span::dummy(),
+                        else_end: None,
                     },
                 ],
                 span: Span::dummy(),
+                body_end: Span::dummy(),
             },
             Stmt::Expr(Expr::Integer(4)), // Reachable only if while loop breaks
         ]);
@@ -541,11 +568,14 @@ mod tests {
             then_branch: vec![],
             else_branch: None,
             span: Span::dummy(),
+            then_end: Span::dummy(),
+            else_end: None,
         }));
         assert!(helpers::has_side_effects(&Stmt::While {
             condition: Expr::Bool(true),
             body: vec![],
             span: Span::dummy(),
+            body_end: Span::dummy(),
         }));
         
         // Assignments have side effects
@@ -573,6 +603,8 @@ mod tests {
             then_branch: vec![],
             else_branch: Some(vec![]),
             span: Span::dummy(),
+            then_end: Span::dummy(),
+            else_end: Some(Span::dummy()),
         };
         
         // Empty branches should be handled correctly
@@ -583,6 +615,7 @@ mod tests {
             condition: Expr::Bool(true),
             body: vec![],
             span: Span::dummy(),
+            body_end: Span::dummy(),
         };
         
         pass.optimize_statement(&mut stmt).unwrap();
@@ -609,15 +642,20 @@ mod tests {
                                 ],
                                 else_branch: None,
                                 span: Span::dummy(),
+                                then_end: Span::dummy(),
+                                else_end: None,
                             },
                             Stmt::Expr(Expr::Integer(4)), // Dead (unreachable after return)
                         ],
                         span: Span::dummy(),
+                        body_end: Span::dummy(),
                     },
                     Stmt::Expr(Expr::Integer(5)), // Dead (while loop never terminates normally)
                 ],
                 else_branch: None,
                 span: Span::dummy(),
+                then_end: Span::dummy(),
+                else_end: None,
             },
         ]);
         
@@ -676,6 +714,7 @@ mod tests {
                 Stmt::Expr(Expr::Integer(2)), // Dead
             ],
             span: Span::dummy(),
+            body_end: Span::dummy(),
         };
         
         // Note: current implementation doesn't optimize inside unsafe blocks
@@ -18,6 +18,7 @@ mod tests {
                 body,
                 span: Span::dummy(),
                 effects: None,
+                effect_params: vec![],
             })],
         }
     }
@@ -256,6 +257,8 @@ mod tests {
             then_branch: vec![],
             else_branch: None,
             span: Span::dummy(),
+            then_end: Span::dummy(),
+            else_end: None,
         };
         
         pass.optimize_statement(&mut stmt).unwrap();
@@ -280,6 +283,7 @@ mod tests {
             ),
             body: vec![],
             span: Span::dummy(),
+            body_end: Span::dummy(),
         };
         
         pass.optimize_statement(&mut stmt).unwrap();
@@ -470,6 +474,8 @@ mod tests {
                 ],
                 else_branch: None,
                 span: Span::dummy(),
+                then_end: Span::dummy(),
+                else_end: None,
             },
         ]);
         
@@ -635,6 +641,7 @@ mod tests {
                             Expr::Bool(true),
                         )),
                     ],
+                    end: Span::dummy(),
                 },
             ],
             span: Span::dummy(),
@@ -650,6 +657,7 @@ mod tests {
         
         let mut stmt = Stmt::For {
             var: "i".to_string(),
+            pair: None,
             iter: Expr::Ident("items".to_string()),
             body: vec![
                 Stmt::Expr(create_unary_expr(
@@ -661,6 +669,7 @@ mod tests {
                 )),
             ],
             span: Span::dummy(),
+            body_end: Span::dummy(),
         };
         
         // Note: current implementation doesn't optimize inside for loops
@@ -729,6 +738,7 @@ mod tests {
                     ),
                 )),
             ],
+            base: None,
             span: Span::dummy(),
         };
         
@@ -836,6 +846,7 @@ mod tests {
                 )),
             ],
             span: Span::dummy(),
+            body_end: Span::dummy(),
         };
         
         // Note: current implementation doesn't optimize inside unsafe blocks
@@ -1,23 +1,84 @@
 // Borrow checker for Palladium
 // "Ensuring memory safety through static analysis"
 
-use crate::ast::{AssignTarget, Expr, Function, Item, Pattern, Program, Stmt, Type};
-use crate::errors::{CompileError, Result};
-use crate::ownership::{expr_to_place, Lifetime, OwnershipContext, Place, RefKind};
-use std::collections::HashMap;
+use crate::ast::{
+    ArraySize, AssignTarget, Expr, Function, GenericArg, Item, Param, Pattern, Program, Stmt,
+    StructBase, Type,
+};
+use crate::errors::{CompileError, Result, Span};
+use crate::ownership::cfg::{
+    init_state, Analysis, BlockId, Cfg, Event, HeldLoans, InitFlow, InitMap, LoanFlow, LoanId,
+    Point,
+};
+use crate::ownership::{expr_to_place, local_name, Lifetime, Place, RefKind};
+use crate::typeck::TraitResolver;
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+/// The place a returned value is written to
+const RETURN_SLOT: &str = "return";
+
+/// The place a future spawned as a detached task is written to
+const SPAWN_SLOT: &str = "spawn";
+
+/// Prefix of the local holding the loans of the tasks spawned in an async scope
+const SCOPE_SLOT: &str = "async scope ";
 
 /// The borrow checker analyzes the program to ensure memory safety
 pub struct BorrowChecker {
-    /// Current ownership context
-    context: OwnershipContext,
+    /// Control-flow graph of the function being checked
+    cfg: Cfg,
+    /// Block that lowering currently appends to
+    current: BlockId,
+    /// Block every return leads to
+    exit: BlockId,
+    /// Continue and break targets of the enclosing loops, with the number of
+    /// blocks open outside each loop's body
+    loops: Vec<(BlockId, BlockId, usize)>,
+    /// Locals declared in each open block, the innermost last, with the names
+    /// they were declared with
+    blocks: Vec<Vec<(String, String)>>,
+    /// How many locals of each name the current function has declared
+    declarations: HashMap<String, usize>,
     /// Function signatures for ownership analysis
     functions: HashMap<String, FunctionSig>,
+    /// Declared return types, for the types of call results
+    return_types: HashMap<String, Type>,
+    /// Parameters of each function that are `&mut` references to values able
+    /// to hold references, into which a call may store its other arguments
+    storing_params: HashMap<String, Vec<usize>>,
     /// Current function being analyzed
     current_function: Option<String>,
     /// Local variable types for Copy checking
     local_types: HashMap<String, Type>,
+    /// Locals bound without `mut`, which can't be borrowed mutably
+    immutable_locals: HashSet<String>,
+    /// Struct field types, for the fields a struct update takes from its base
+    structs: HashMap<String, Vec<(String, Type)>>,
+    /// Non-generic enums, for the types of their constructors
+    enums: HashSet<String>,
+    /// Trait impls of the program, deciding which types are `Copy`
+    traits: TraitResolver,
+    /// Loans standing for what each reference parameter points to, with its lifetime
+    param_loans: HashMap<LoanId, Lifetime>,
+    /// Loans of places reached through a reference; they point where that reference does
+    reborrows: HashSet<LoanId>,
+    /// Lifetime of the reference the current function returns
+    return_lifetime: Option<Lifetime>,
+    /// Lifetimes declared by the enclosing impl block
+    impl_lifetimes: Vec<String>,
+    /// Type named by `Self` in the enclosing impl block
+    impl_type: Option<Type>,
     /// Track if we're in an unsafe context
     unsafe_depth: usize,
+    /// Locals each function moves out of, keyed by the function's span
+    moved_locals: HashMap<Span, HashSet<String>>,
+    /// Locals holding the loans of the tasks spawned in the enclosing async scopes
+    async_scopes: Vec<String>,
+    /// How many async scopes the current function has opened
+    opened_scopes: usize,
+    /// Operations of the handlers in the current function, which are checked
+    /// after it like functions of their own
+    pending_handlers: Vec<Function>,
 }
 
 /// Function signature for ownership analysis
@@ -26,10 +87,22 @@ struct FunctionSig {
     /// Parameter ownership requirements
     params: Vec<ParamOwnership>,
     /// Return value ownership
-    #[allow(dead_code)]
     returns: ReturnOwnership,
 }
 
+impl FunctionSig {
+    /// Whether a returned reference may borrow from the argument passed for `param`
+    fn returns_borrow_of(&self, param: &ParamOwnership) -> bool {
+        match (&self.returns, param) {
+            (
+                ReturnOwnership::Borrowed(output),
+                ParamOwnership::Borrow(input) | ParamOwnership::BorrowMut(input),
+            ) => *output == Lifetime::Named("fn".to_string()) || input == output,
+            _ => false,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 enum ParamOwnership {
     /// Parameter takes ownership (moves the value)
@@ -46,8 +119,7 @@ enum ParamOwnership {
 enum ReturnOwnership {
     /// Returns owned value
     Owned,
-    /// Returns borrowed value with lifetime
-    #[allow(dead_code)]
+    /// Returns borrowed value with lifetime; `'fn` may borrow from any reference argument
     Borrowed(Lifetime),
     /// No return value
     Unit,
@@ -77,6 +149,29 @@ impl Default for BorrowChecker {
             },
         );
 
+        // Output and formatting intrinsics generated by println! and friends
+        for name in ["__write_stdout", "__write_stderr"] {
+            functions.insert(
+                name.to_string(),
+                FunctionSig {
+                    params: vec![ParamOwnership::Copy],
+                    returns: ReturnOwnership::Unit,
+                },
+            );
+        }
+        for kind in crate::macros::format::FormatKind::ALL {
+            functions.insert(
+                kind.intrinsic().to_string(),
+                FunctionSig {
+                    params: vec![
+                        ParamOwnership::Borrow(Lifetime::Named("fn".to_string())),
+                        ParamOwnership::Copy,
+                    ],
+                    returns: ReturnOwnership::Owned,
+                },
+            );
+        }
+
         // String manipulation functions
         functions.insert(
             "string_concat".to_string(),
@@ -89,6 +184,17 @@ impl Default for BorrowChecker {
             },
         );
 
+        functions.insert(
+            "string_push_str".to_string(),
+            FunctionSig {
+                params: vec![
+                    ParamOwnership::BorrowMut(Lifetime::Named("fn".to_string())),
+                    ParamOwnership::Borrow(Lifetime::Named("fn".to_string())),
+                ],
+                returns: ReturnOwnership::Unit,
+            },
+        );
+
         functions.insert(
             "string_substring".to_string(),
             FunctionSig {
@@ -279,11 +385,31 @@ impl Default for BorrowChecker {
         );
 
         Self {
-            context: OwnershipContext::new(),
+            cfg: Cfg::new(),
+            current: Cfg::ENTRY,
+            exit: Cfg::ENTRY,
+            loops: Vec::new(),
+            blocks: Vec::new(),
+            declarations: HashMap::new(),
+            immutable_locals: HashSet::new(),
             functions,
+            return_types: HashMap::new(),
             current_function: None,
             local_types: HashMap::new(),
+            structs: HashMap::new(),
+            storing_params: HashMap::new(),
+            enums: HashSet::new(),
+            traits: TraitResolver::new(),
+            param_loans: HashMap::new(),
+            reborrows: HashSet::new(),
+            return_lifetime: None,
+            impl_lifetimes: Vec::new(),
+            impl_type: None,
             unsafe_depth: 0,
+            moved_locals: HashMap::new(),
+            async_scopes: Vec::new(),
+            opened_scopes: 0,
+            pending_handlers: Vec::new(),
         }
     }
 }
@@ -293,6 +419,11 @@ impl BorrowChecker {
         Self::default()
     }
 
+    /// Locals each checked function moves out of, keyed by the function's span
+    pub fn get_moved_locals(&self) -> HashMap<Span, HashSet<String>> {
+        self.moved_locals.clone()
+    }
+
     /// Check if we're currently in an unsafe context
     #[allow(dead_code)]
     fn in_unsafe_context(&self) -> bool {
@@ -301,6 +432,20 @@ impl BorrowChecker {
 
     /// Check a program for ownership violations
     pub fn check_program(&mut self, program: &Program) -> Result<()> {
+        // Which types are `Copy` follows from the program's impls. Malformed
+        // traits and impls have already been reported by the type checker.
+        let prelude = crate::typeck::prelude::visible_items(program);
+        for item in prelude.iter().chain(&program.items) {
+            if let Item::Trait(trait_def) = item {
+                let _ = self.traits.register_trait(trait_def);
+            }
+        }
+        for item in &program.items {
+            if let Item::Impl(impl_block) = item {
+                let _ = self.traits.register_impl(impl_block);
+            }
+        }
+
         // First pass: collect function signatures
         for item in &program.items {
             match item {
@@ -315,6 +460,20 @@ impl BorrowChecker {
                         self.collect_function_sig_with_name(method, &qualified_name);
                     }
                 }
+                Item::Struct(struct_def) => {
+                    for (_, ty) in &struct_def.fields {
+                        Self::check_lifetime_names(
+                            ty,
+                            &struct_def.lifetime_params,
+                            struct_def.span,
+                        )?;
+                    }
+                    self.structs
+                        .insert(struct_def.name.clone(), struct_def.fields.clone());
+                }
+                Item::Enum(enum_def) if enum_def.type_params.is_empty() => {
+                    self.enums.insert(enum_def.name.clone());
+                }
                 _ => {}
             }
         }
@@ -327,9 +486,13 @@ impl BorrowChecker {
                 }
                 Item::Impl(impl_block) => {
                     // Check method bodies from impl blocks
+                    self.impl_lifetimes = impl_block.lifetime_params.clone();
+                    self.impl_type = Some(impl_block.for_type.clone());
                     for method in &impl_block.methods {
                         self.check_function(method)?;
                     }
+                    self.impl_lifetimes.clear();
+                    self.impl_type = None;
                 }
                 _ => {}
             }
@@ -347,98 +510,765 @@ impl BorrowChecker {
     fn collect_function_sig_with_name(&mut self, func: &Function, name: &str) {
         let mut params = Vec::new();
 
-        for param in &func.params {
+        for (index, param) in func.params.iter().enumerate() {
             let ownership = match &param.ty {
                 Type::String | Type::Array(_, _) | Type::Custom(_) => {
-                    // Non-copy types
                     if param.mutable {
                         ParamOwnership::BorrowMut(Lifetime::Named("fn".to_string()))
+                    } else if self.traits.is_copy(&param.ty) {
+                        ParamOwnership::Copy
                     } else {
                         ParamOwnership::Move
                     }
                 }
                 Type::Reference { mutable, .. } => {
+                    let lifetime = Self::param_lifetime(param, index)
+                        .unwrap_or_else(|| Lifetime::Named("fn".to_string()));
                     if *mutable {
-                        ParamOwnership::BorrowMut(Lifetime::Named("fn".to_string()))
+                        ParamOwnership::BorrowMut(lifetime)
                     } else {
-                        ParamOwnership::Borrow(Lifetime::Named("fn".to_string()))
+                        ParamOwnership::Borrow(lifetime)
                     }
                 }
+                Type::Generic { name, .. }
+                    if matches!(name.as_str(), "Box" | "Vec" | "HashMap" | "HashSet") =>
+                {
+                    ParamOwnership::Move
+                }
                 _ => ParamOwnership::Copy, // Primitives are Copy
             };
             params.push(ownership);
         }
 
-        let returns = match &func.return_type {
-            Some(Type::Reference { .. }) => {
+        let borrows = params.iter().any(|param| {
+            matches!(
+                param,
+                ParamOwnership::Borrow(_) | ParamOwnership::BorrowMut(_)
+            )
+        });
+        let returns = match Self::output_lifetime(func) {
+            Some(lifetime) => ReturnOwnership::Borrowed(lifetime),
+            // The future of an async fn holds its arguments until it completes
+            None if func.is_async && borrows => {
                 ReturnOwnership::Borrowed(Lifetime::Named("fn".to_string()))
             }
-            Some(_) => ReturnOwnership::Owned,
+            None if func.return_type.is_some() => ReturnOwnership::Owned,
             None => ReturnOwnership::Unit,
         };
 
+        if let Some(ty) = &func.return_type {
+            self.return_types.insert(name.to_string(), ty.clone());
+        }
+        let storing = func
+            .params
+            .iter()
+            .enumerate()
+            .filter(|(_, param)| match &param.ty {
+                Type::Reference {
+                    mutable: true,
+                    inner,
+                    ..
+                } => self.holds_references(inner),
+                _ => false,
+            })
+            .map(|(index, _)| index)
+            .collect();
+        self.storing_params.insert(name.to_string(), storing);
         self.functions
             .insert(name.to_string(), FunctionSig { params, returns });
     }
 
+    /// Whether a value of type `ty` may hold a reference: the program's structs
+    /// only hold one in a field of reference type
+    fn holds_references(&self, ty: &Type) -> bool {
+        match ty {
+            Type::Reference { .. } => true,
+            Type::Array(elem, _) => self.holds_references(elem),
+            Type::Generic { args, .. } => args.iter().any(|arg| match arg {
+                GenericArg::Type(ty) => self.holds_references(ty),
+                GenericArg::Const(_) => false,
+            }),
+            Type::Custom(name) => self.structs.get(name).is_some_and(|fields| {
+                fields
+                    .iter()
+                    .any(|(_, ty)| matches!(ty, Type::Reference { .. }))
+            }),
+            _ => false,
+        }
+    }
+
+    /// Lifetime of a reference parameter; elided ones each get a lifetime of their own
+    fn param_lifetime(param: &Param, index: usize) -> Option<Lifetime> {
+        match &param.ty {
+            Type::Reference {
+                lifetime: Some(name),
+                ..
+            } => Some(named_lifetime(name)),
+            Type::Reference { lifetime: None, .. } => Some(Lifetime::Anonymous(index as u32)),
+            _ => None,
+        }
+    }
+
+    /// Lifetime of the reference a function returns. An elided one is taken from
+    /// `self`, or else from the only reference parameter; with several reference
+    /// parameters it is inferred as `'fn`, borrowing from any of them, and with
+    /// none it can only be `'static`.
+    fn output_lifetime(func: &Function) -> Option<Lifetime> {
+        match &func.return_type {
+            Some(Type::Reference {
+                lifetime: Some(name),
+                ..
+            }) => Some(named_lifetime(name)),
+            Some(Type::Reference { lifetime: None, .. }) => {
+                let inputs: Vec<(&Param, Lifetime)> = func
+                    .params
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, p)| Some((p, Self::param_lifetime(p, i)?)))
+                    .collect();
+                match (inputs.iter().find(|(p, _)| p.name == "self"), &inputs[..]) {
+                    (Some((_, lifetime)), _) | (None, [(_, lifetime)]) => Some(lifetime.clone()),
+                    (None, []) => Some(Lifetime::Static),
+                    _ => Some(Lifetime::Named("fn".to_string())),
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Every named lifetime in a type must be declared (or be `'static`)
+    fn check_lifetime_names(ty: &Type, declared: &[String], span: Span) -> Result<()> {
+        match ty {
+            Type::Reference {
+                lifetime, inner, ..
+            } => {
+                if let Some(name) = lifetime {
+                    let name = name.trim_start_matches('\'');
+                    let known = name == "static"
+                        || declared.iter().any(|d| d.trim_start_matches('\'') == name);
+                    if !known {
+                        return Err(CompileError::LifetimeError {
+                            message: format!("use of undeclared lifetime name `'{}`", name),
+                            span: Some(span),
+                        });
+                    }
+                }
+                Self::check_lifetime_names(inner, declared, span)
+            }
+            Type::Array(elem, _) => Self::check_lifetime_names(elem, declared, span),
+            Type::Tuple(types) => types
+                .iter()
+                .try_for_each(|t| Self::check_lifetime_names(t, declared, span)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Every lifetime named in a function signature must be declared
+    fn check_signature(&self, func: &Function) -> Result<()> {
+        let declared: Vec<String> = func
+            .lifetime_params
+            .iter()
+            .chain(&self.impl_lifetimes)
+            .cloned()
+            .collect();
+        for ty in func.params.iter().map(|p| &p.ty).chain(&func.return_type) {
+            Self::check_lifetime_names(ty, &declared, func.span)?;
+        }
+        Ok(())
+    }
+
     /// Check a function for ownership violations
     fn check_function(&mut self, func: &Function) -> Result<()> {
         self.current_function = Some(func.name.clone());
-        self.context.enter_scope();
         self.local_types.clear();
-
-        // Initialize parameters and their types
+        self.cfg = Cfg::new();
+        self.current = Cfg::ENTRY;
+        self.loops.clear();
+        self.blocks = vec![Vec::new()];
+        self.declarations.clear();
+        self.immutable_locals.clear();
         for param in &func.params {
-            let place = Place::Local(param.name.clone());
-            self.context.init_owned(place);
+            let local = self.declare(&param.name);
+            if !param.mutable {
+                self.immutable_locals.insert(local);
+            }
+        }
+        self.exit = self.cfg.new_block();
+        self.param_loans.clear();
+        self.reborrows.clear();
+        self.opened_scopes = 0;
+        self.check_signature(func)?;
+        self.return_lifetime = Self::output_lifetime(func);
+
+        // Initialize parameters and their types; a reference parameter holds a loan
+        // standing for the caller's value it points to
+        for (index, param) in func.params.iter().enumerate() {
             self.local_types
                 .insert(param.name.clone(), param.ty.clone());
+            let mut origin = Origin::default();
+            if let (Type::Reference { mutable, .. }, Some(lifetime)) =
+                (&param.ty, Self::param_lifetime(param, index))
+            {
+                let kind = if *mutable {
+                    RefKind::Mutable
+                } else {
+                    RefKind::Shared
+                };
+                let loan = self
+                    .cfg
+                    .new_loan(Place::Local(param.name.clone()), kind, func.span);
+                self.param_loans.insert(loan, lifetime);
+                origin.loans.push(loan);
+            }
+            self.write(Place::Local(param.name.clone()), origin, func.span);
         }
 
-        // Check function body
-        for stmt in &func.body {
-            self.check_stmt(stmt)?;
+        // Lower the body to a control-flow graph, then check every program point.
+        // A trailing expression is the function's value.
+        let tail = match func.body.last() {
+            Some(Stmt::Expr(expr)) if func.return_type.is_some() => Some(expr),
+            _ => None,
+        };
+        let stmts = &func.body[..func.body.len() - tail.iter().count()];
+        for stmt in stmts {
+            self.lower_stmt(stmt, func.span)?;
+        }
+        if let Some(expr) = tail {
+            self.lower_return(expr, func.span)?;
         }
+        self.cfg.add_edge(self.current, self.exit);
+        self.check_cfg()?;
+
+        // Code generation gives the moved locals drop flags
+        let moved = self
+            .cfg
+            .blocks
+            .iter()
+            .flat_map(|block| &block.events)
+            .filter(|event| matches!(event, Event::Move { .. }))
+            .filter_map(|event| event.place().root())
+            .map(|root| local_name(root).to_string())
+            .collect();
+        self.moved_locals.insert(func.span, moved);
 
-        self.context.exit_scope();
         self.current_function = None;
+        for operation in std::mem::take(&mut self.pending_handlers) {
+            self.check_function(&operation)?;
+        }
         Ok(())
     }
 
-    /// Check a statement for ownership violations
-    fn check_stmt(&mut self, stmt: &Stmt) -> Result<()> {
-        match stmt {
-            Stmt::Let {
-                name, value, ty, ..
-            } => {
-                // Check the value expression
-                self.check_expr(value)?;
+    /// Check the lowered function: every use must see an initialized, unmoved value
+    /// and no loan held by a local that is still live may conflict with it
+    fn check_cfg(&self) -> Result<()> {
+        let reachable = self.cfg.reachable();
+        let live = self.cfg.live_after();
+        let held_in = self.cfg.forward(&LoanFlow);
+        let init_in = self.cfg.forward(&InitFlow);
+
+        for (block, data) in self.cfg.blocks.iter().enumerate() {
+            if !reachable[block] {
+                continue;
+            }
+            let mut held = held_in[block].clone();
+            let mut init = init_in[block].clone();
+            for (index, event) in data.events.iter().enumerate() {
+                let point = (block, index);
+                let mut active: Vec<LoanId> = live[block][index]
+                    .iter()
+                    .filter_map(|local| held.get(local))
+                    .flatten()
+                    .filter(|loan| !self.param_loans.contains_key(loan))
+                    .copied()
+                    .collect();
+                active.sort_unstable();
+                active.dedup();
+
+                match event {
+                    Event::Escape {
+                        place,
+                        loans,
+                        sources,
+                        span,
+                    } => self.check_escape(place, loans, sources, &held, *span)?,
+                    _ => self.check_event(event, point, &init, &active)?,
+                }
+                LoanFlow.transfer(&mut held, event, point);
+                InitFlow.transfer(&mut init, event, point);
+            }
+        }
+        Ok(())
+    }
 
-                // Store the type if provided
-                if let Some(ty) = ty {
-                    self.local_types.insert(name.clone(), ty.clone());
-                } else {
-                    // Infer type from expression
-                    let inferred_type = self.expr_type(value);
-                    self.local_types.insert(name.clone(), inferred_type);
+    /// Check one event against the initialization state and the active loans
+    fn check_event(
+        &self,
+        event: &Event,
+        point: Point,
+        init: &InitMap,
+        active: &[LoanId],
+    ) -> Result<()> {
+        let place = event.place();
+        let span = event.span();
+
+        if !matches!(event, Event::Write { .. } | Event::Dead { .. }) {
+            let state = init_state(init, place);
+            if let Some(&(_, moved_at)) = state.moves.first() {
+                let in_loop = state.moves.iter().any(|(p, _)| *p == point);
+                if in_loop || state.init || state.uninit {
+                    return Err(CompileError::UseOfPossiblyMovedValue {
+                        name: place.to_string(),
+                        reason: if in_loop {
+                            "in the previous iteration of the loop"
+                        } else {
+                            "in one branch"
+                        },
+                        moved_at: Some(moved_at),
+                        span: Some(span),
+                    });
+                }
+                return Err(CompileError::UseOfMovedValue {
+                    name: place.to_string(),
+                    moved_at: Some(moved_at),
+                    span: Some(span),
+                });
+            }
+            if state.uninit {
+                return Err(CompileError::UseOfUninitializedValue {
+                    name: place.to_string(),
+                    span: Some(span),
+                });
+            }
+
+            // Using the whole value needs every part of it
+            let mut moved_parts: Vec<&Place> = init
+                .iter()
+                .filter(|(part, state)| {
+                    *part != place && part.is_within(place) && !state.moves.is_empty()
+                })
+                .map(|(part, _)| part)
+                .collect();
+            moved_parts.sort_by_key(|part| part.to_string());
+            if let Some(part) = moved_parts.first() {
+                return Err(CompileError::UseOfPartiallyMovedValue {
+                    name: place.to_string(),
+                    moved: part.to_string(),
+                    span: Some(span),
+                });
+            }
+        }
+
+        // A local taking its first value is a fresh place; loans of an earlier
+        // one were reported when it went out of scope
+        let initializes = matches!((event, place), (Event::Write { .. }, Place::Local(_)))
+            && !init_state(init, place).init;
+        for loan in active.iter().map(|&id| &self.cfg.loans[id]) {
+            if !loan.place.overlaps(place) || initializes {
+                continue;
+            }
+            let borrowed_mut = loan.kind == RefKind::Mutable;
+            let borrowed = if loan.place == *place {
+                "it".to_string()
+            } else {
+                format!("`{}`", loan.place)
+            };
+            let message = match event {
+                Event::Read { .. } if borrowed_mut => {
+                    format!(
+                        "cannot use `{}` because {} is mutably borrowed",
+                        place, borrowed
+                    )
+                }
+                Event::Borrow { kind, .. } if borrowed_mut || *kind == RefKind::Mutable => {
+                    format!(
+                        "cannot borrow `{}` as {} because {} is also borrowed as {}",
+                        place,
+                        if *kind == RefKind::Mutable {
+                            "mutable"
+                        } else {
+                            "immutable"
+                        },
+                        borrowed,
+                        if borrowed_mut { "mutable" } else { "immutable" }
+                    )
+                }
+                Event::Move { .. } => {
+                    return Err(CompileError::CannotMoveOutOfBorrowedContent { span: Some(span) })
+                }
+                Event::Write { .. } => {
+                    format!(
+                        "cannot assign to `{}` because {} is borrowed",
+                        place, borrowed
+                    )
                 }
+                Event::Dead { .. } => {
+                    return Err(CompileError::DroppedWhileBorrowed {
+                        name: place.to_string(),
+                        borrowed_at: Some(loan.span),
+                        span: Some(span),
+                    });
+                }
+                _ => continue,
+            };
+            return Err(CompileError::ConflictingBorrows {
+                message,
+                borrowed_at: Some(loan.span),
+                span: Some(span),
+            });
+        }
+        Ok(())
+    }
+
+    /// Check that a value leaving the function only refers to memory that outlives it.
+    /// Stores through a reference only leave the function if it points into a parameter.
+    fn check_escape(
+        &self,
+        place: &Place,
+        loans: &[LoanId],
+        sources: &[String],
+        held: &HeldLoans,
+        span: Span,
+    ) -> Result<()> {
+        let returned = *place == Place::Local(RETURN_SLOT.to_string());
+        if *place == Place::Local(SPAWN_SLOT.to_string()) {
+            return self.check_spawn(loans, sources, held, span);
+        }
+        let into_caller = place
+            .root()
+            .and_then(|root| held.get(root))
+            .is_some_and(|loans| loans.iter().any(|l| self.param_loans.contains_key(l)));
+        if !returned && !into_caller {
+            return Ok(());
+        }
 
-                // Initialize the new variable
-                let place = Place::Local(name.clone());
+        let mut carried: BTreeSet<LoanId> = loans.iter().copied().collect();
+        for source in sources {
+            carried.extend(held.get(source).into_iter().flatten());
+        }
 
-                // Check if value is moved or copied
-                if let Some(from_place) = expr_to_place(value) {
-                    if self.is_expr_copy(value) {
-                        // Copy types don't move
-                        self.context.init_owned(place);
+        let function = self.current_function.clone().unwrap_or_default();
+        for id in carried {
+            // A reborrow carries the loans of the reference it goes through
+            if self.reborrows.contains(&id) {
+                continue;
+            }
+            let loan = &self.cfg.loans[id];
+            let Some(input) = self.param_loans.get(&id) else {
+                return Err(CompileError::DanglingReference {
+                    name: local_name(loan.place.root().unwrap_or_default()).to_string(),
+                    function,
+                    escapes: if returned {
+                        "returned from the function"
                     } else {
-                        // Move ownership
-                        self.context.move_value(from_place, place, value.span())?;
-                    }
+                        "stored in a value that outlives the function"
+                    },
+                    span: Some(loan.span),
+                });
+            };
+
+            let allowed = match &self.return_lifetime {
+                Some(output) if returned => {
+                    *output == Lifetime::Named("fn".to_string())
+                        || input == output
+                        || *input == Lifetime::Static
+                }
+                _ => true,
+            };
+            if !allowed {
+                let mut tied: Vec<String> = self
+                    .param_loans
+                    .iter()
+                    .filter(|(_, lifetime)| Some(*lifetime) == self.return_lifetime.as_ref())
+                    .map(|(&l, _)| format!("`{}`", self.cfg.loans[l].place))
+                    .collect();
+                tied.sort();
+                let requires = if tied.is_empty() {
+                    format!(
+                        "requires lifetime {}",
+                        self.return_lifetime.as_ref().unwrap()
+                    )
+                } else {
+                    format!("only borrows from {}", tied.join(", "))
+                };
+                return Err(CompileError::LifetimeError {
+                    message: format!(
+                        "lifetime mismatch: `{}` may return a reference to `{}`, but its return type {}",
+                        function, loan.place, requires
+                    ),
+                    span: Some(span),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that a future spawned as a detached task borrows nothing: the task
+    /// may outlive whatever it borrows, even the caller's values
+    fn check_spawn(
+        &self,
+        loans: &[LoanId],
+        sources: &[String],
+        held: &HeldLoans,
+        span: Span,
+    ) -> Result<()> {
+        let mut carried: BTreeSet<LoanId> = loans.iter().copied().collect();
+        for source in sources {
+            carried.extend(held.get(source).into_iter().flatten());
+        }
+        match carried.first() {
+            Some(&id) => Err(CompileError::BorrowChecker {
+                message: format!(
+                    "spawned task borrows `{}`, which it may outlive; spawn it inside an `async scope`",
+                    self.cfg.loans[id].place
+                ),
+                span: Some(span),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Append an event to the block being built
+    fn emit(&mut self, event: Event) {
+        self.cfg.push(self.current, event);
+    }
+
+    /// A place borrowed mutably must be a `mut` binding or reached through a `ref mut`
+    fn check_mutable(&self, place: &Place, span: Span) -> Result<()> {
+        let Some(root) = place.root() else {
+            return Ok(());
+        };
+        let message = match self.local_types.get(root) {
+            Some(Type::Reference { mutable: true, .. }) => return Ok(()),
+            Some(Type::Reference { mutable: false, .. }) => format!(
+                "cannot borrow `{}` as mutable, as it is behind a `ref` reference",
+                place
+            ),
+            _ if self.immutable_locals.contains(root) => format!(
+                "cannot borrow `{}` as mutable, as `{}` is not declared as mutable",
+                place,
+                local_name(root)
+            ),
+            _ => return Ok(()),
+        };
+        Err(CompileError::BorrowChecker {
+            message,
+            span: Some(span),
+        })
+    }
+
+    /// Move the value out of a place. Parts of a value behind a reference can't be moved.
+    fn move_out(&mut self, place: Place, span: Span) -> Result<Origin> {
+        let behind_reference = !matches!(place, Place::Local(_))
+            && place
+                .root()
+                .and_then(|root| self.local_types.get(root))
+                .is_some_and(|ty| matches!(ty, Type::Reference { .. }));
+        if behind_reference {
+            return Err(CompileError::CannotMoveOutOfBorrowedContent { span: Some(span) });
+        }
+        // Its destructor needs the whole value, so nothing can be moved out of a `Drop` type
+        if let Place::Field { base, .. } = &place {
+            if let Some(ty) = self.place_type(base) {
+                if self.traits.type_implements_trait(&ty, "Drop") {
+                    return Err(CompileError::BorrowChecker {
+                        message: format!(
+                            "cannot move out of `{}`, whose type `{}` implements `Drop`",
+                            base, ty
+                        ),
+                        span: Some(span),
+                    });
+                }
+            }
+        }
+        let origin = Origin::from_place(&place);
+        self.emit(Event::Move { place, span });
+        Ok(origin)
+    }
+
+    /// Whether a place is reached through a reference held by its root local
+    fn through_reference(&self, place: &Place, deref: bool) -> bool {
+        (deref || !matches!(place, Place::Local(_)))
+            && place
+                .root()
+                .and_then(|root| self.local_types.get(root))
+                .is_some_and(|ty| matches!(ty, Type::Reference { .. }))
+    }
+
+    /// Store a value carrying `stored`'s references into what the `&mut`
+    /// argument `target` points to. An owned local holds them from then on;
+    /// through a reference parameter they reach the caller
+    fn store_through(&mut self, target: &Expr, stored: Origin, span: Span) {
+        let place = match target {
+            Expr::Reference { expr, .. } => self.place_of(expr),
+            _ => self.place_of(target),
+        };
+        let Some(place) = place else {
+            return;
+        };
+        let element = Place::Index {
+            base: Box::new(place),
+            index: "_".to_string(),
+        };
+        if self.through_reference(&element, false) {
+            self.emit(Event::Escape {
+                place: element.clone(),
+                loans: stored.loans.clone(),
+                sources: stored.sources.clone(),
+                span,
+            });
+        }
+        self.write(element, stored, span);
+    }
+
+    /// Lower the function's value and let it escape through the return slot
+    fn lower_return(&mut self, expr: &Expr, outer: Span) -> Result<()> {
+        let origin = self.lower_value(expr, outer)?;
+        self.emit(Event::Escape {
+            place: Place::Local(RETURN_SLOT.to_string()),
+            loans: origin.loans,
+            sources: origin.sources,
+            span: Self::span_or(expr, outer),
+        });
+        Ok(())
+    }
+
+    /// Initialize a place with a value carrying `origin`'s references
+    fn write(&mut self, place: Place, origin: Origin, span: Span) {
+        self.emit(Event::Write {
+            place,
+            loans: origin.loans,
+            sources: origin.sources,
+            span,
+        });
+    }
+
+    /// Start a new block reached from the current one
+    fn branch(&mut self) -> BlockId {
+        let block = self.cfg.new_block();
+        self.cfg.add_edge(self.current, block);
+        block
+    }
+
+    /// Continue in a fresh block after control left the current one
+    fn diverge(&mut self, target: BlockId) {
+        self.cfg.add_edge(self.current, target);
+        self.current = self.cfg.new_block();
+    }
+
+    /// Lower the statements of a block, whose locals go out of scope at `end`
+    fn lower_block(&mut self, body: &[Stmt], span: Span, end: Span) -> Result<()> {
+        self.blocks.push(Vec::new());
+        for stmt in body {
+            self.lower_stmt(stmt, span)?;
+        }
+        self.close_block(end);
+        Ok(())
+    }
+
+    /// Declare a local in the innermost open block. Every declaration is a
+    /// local of its own, even one shadowing a name still in scope
+    fn declare(&mut self, name: &str) -> String {
+        let count = self.declarations.entry(name.to_string()).or_default();
+        let local = match *count {
+            0 => name.to_string(),
+            n => format!("{}#{}", name, n),
+        };
+        *count += 1;
+        if let Some(block) = self.blocks.last_mut() {
+            block.push((name.to_string(), local.clone()));
+        }
+        local
+    }
+
+    /// The local a name refers to: its innermost declaration in scope
+    fn local(&self, name: &str) -> String {
+        self.blocks
+            .iter()
+            .flatten()
+            .rev()
+            .find(|(declared, _)| declared == name)
+            .map_or_else(|| name.to_string(), |(_, local)| local.clone())
+    }
+
+    /// The place an expression names, rooted at the local its name refers to
+    fn place_of(&self, expr: &Expr) -> Option<Place> {
+        expr_to_place(expr).map(|place| self.resolve(place))
+    }
+
+    /// Root a place at the local its root's name refers to
+    fn resolve(&self, place: Place) -> Place {
+        match place {
+            Place::Local(name) => Place::Local(self.local(&name)),
+            Place::Field { base, field } => Place::Field {
+                base: Box::new(self.resolve(*base)),
+                field,
+            },
+            Place::Index { base, index } => Place::Index {
+                base: Box::new(self.resolve(*base)),
+                index,
+            },
+            Place::Temp(id) => Place::Temp(id),
+        }
+    }
+
+    /// End the innermost open block, dropping its locals at `end`
+    fn close_block(&mut self, end: Span) {
+        self.drop_locals(self.blocks.len() - 1, end);
+        self.blocks.pop();
+    }
+
+    /// Drop the locals of the open block at `index`. Nothing may borrow them
+    /// afterwards.
+    fn drop_locals(&mut self, index: usize, span: Span) {
+        let dead: Vec<String> = self.blocks[index]
+            .iter()
+            .map(|(_, local)| local.clone())
+            .collect();
+        for local in dead {
+            self.emit(Event::Dead {
+                place: Place::Local(local),
+                span,
+            });
+        }
+    }
+
+    /// Lower a statement into the control-flow graph
+    fn lower_stmt(&mut self, stmt: &Stmt, outer: Span) -> Result<()> {
+        match stmt {
+            Stmt::Let {
+                name,
+                value,
+                ty,
+                mutable,
+                span,
+            } => {
+                // A `&mut` bound where one is expected is reborrowed rather than moved
+                let reborrow = matches!(ty, Some(Type::Reference { mutable: true, .. }))
+                    && self.place_of(value).is_some()
+                    && matches!(self.expr_type(value), Type::Reference { mutable: true, .. });
+                let origin = if reborrow {
+                    let reborrowed = Expr::Reference {
+                        mutable: true,
+                        expr: Box::new(Expr::Deref {
+                            expr: Box::new(value.clone()),
+                            span: *span,
+                        }),
+                        span: *span,
+                    };
+                    self.lower_expr(&reborrowed, *span)?
                 } else {
-                    // Temporary value (like string literal), take ownership
-                    self.context.init_owned(place);
+                    self.lower_value(value, *span)?
+                };
+
+                // Infer the type from the expression unless one is given
+                let ty = ty.clone().unwrap_or_else(|| self.expr_type(value));
+                let local = self.declare(name);
+                if !mutable {
+                    self.immutable_locals.insert(local.clone());
                 }
+                self.local_types.insert(local.clone(), ty);
+
+                self.write(Place::Local(local), origin, *span);
             }
 
             Stmt::Assign {
@@ -446,19 +1276,17 @@ impl BorrowChecker {
                 value,
                 span,
             } => {
-                // Check the value expression
-                self.check_expr(value)?;
+                let origin = self.lower_value(value, *span)?;
 
                 // Get target place
                 let target_place = match target {
-                    AssignTarget::Ident(name) => Place::Local(name.clone()),
+                    AssignTarget::Ident(name) => Place::Local(self.local(name)),
                     AssignTarget::Index { array, index } => {
-                        self.check_expr(array)?;
-                        self.check_expr(index)?;
-                        if let Some(base) = expr_to_place(array) {
+                        self.lower_expr(index, *span)?;
+                        if let Some(base) = self.place_of(array) {
                             Place::Index {
                                 base: Box::new(base),
-                                index: "dynamic".to_string(),
+                                index: "_".to_string(),
                             }
                         } else {
                             return Err(CompileError::BorrowChecker {
@@ -468,8 +1296,7 @@ impl BorrowChecker {
                         }
                     }
                     AssignTarget::FieldAccess { object, field } => {
-                        self.check_expr(object)?;
-                        if let Some(base) = expr_to_place(object) {
+                        if let Some(base) = self.place_of(object) {
                             Place::Field {
                                 base: Box::new(base),
                                 field: field.clone(),
@@ -482,119 +1309,215 @@ impl BorrowChecker {
                         }
                     }
                     AssignTarget::Deref { expr } => {
-                        self.check_expr(expr)?;
-                        // For dereference assignment, we need the place that the reference points to
-                        if let Some(place) = expr_to_place(expr) {
-                            // The dereferenced place is what we're assigning to
-                            place
+                        // Writing through a reference uses the reference itself
+                        if let Some(place) = self.place_of(expr) {
+                            self.emit(Event::Read {
+                                place: place.clone(),
+                                span: *span,
+                            });
+                            self.emit(Event::Escape {
+                                place,
+                                loans: origin.loans,
+                                sources: origin.sources,
+                                span: *span,
+                            });
                         } else {
                             return Err(CompileError::BorrowChecker {
                                 message: "Cannot dereference temporary value".to_string(),
                                 span: Some(*span),
                             });
                         }
+                        return Ok(());
                     }
                 };
 
-                // Check if assignment is allowed
-                if let Some(from_place) = expr_to_place(value) {
-                    if !self.is_expr_copy(value) {
-                        // Move ownership
-                        self.context.move_value(from_place, target_place, *span)?;
+                if self.through_reference(&target_place, false) {
+                    // Writing through a reference uses the reference itself
+                    if let Some(root) = target_place.root() {
+                        self.emit(Event::Read {
+                            place: Place::Local(root.to_string()),
+                            span: *span,
+                        });
                     }
+                    self.emit(Event::Escape {
+                        place: target_place.clone(),
+                        loans: origin.loans.clone(),
+                        sources: origin.sources.clone(),
+                        span: *span,
+                    });
                 }
+                self.write(target_place, origin, *span);
             }
 
             Stmt::Expr(expr) => {
-                self.check_expr(expr)?;
+                self.lower_expr(expr, outer)?;
             }
 
-            Stmt::Return(Some(expr)) => {
-                self.check_expr(expr)?;
-                // TODO: Check return value ownership matches function signature
+            Stmt::Return(value) => {
+                if let Some(expr) = value {
+                    self.lower_return(expr, outer)?;
+                }
+                self.diverge(self.exit);
             }
 
-            Stmt::Return(None) => {}
-
             Stmt::If {
                 condition,
                 then_branch,
                 else_branch,
-                ..
+                span,
+                then_end,
+                else_end,
             } => {
-                self.check_expr(condition)?;
+                self.lower_expr(condition, *span)?;
+                let branch_point = self.current;
+                let join = self.cfg.new_block();
 
-                self.context.enter_scope();
-                for stmt in then_branch {
-                    self.check_stmt(stmt)?;
-                }
-                self.context.exit_scope();
+                self.current = self.branch();
+                self.lower_block(then_branch, *span, *then_end)?;
+                self.cfg.add_edge(self.current, join);
 
+                self.current = branch_point;
                 if let Some(else_stmts) = else_branch {
-                    self.context.enter_scope();
-                    for stmt in else_stmts {
-                        self.check_stmt(stmt)?;
-                    }
-                    self.context.exit_scope();
+                    self.current = self.branch();
+                    self.lower_block(else_stmts, *span, else_end.unwrap_or(*span))?;
                 }
+                self.cfg.add_edge(self.current, join);
+                self.current = join;
             }
 
             Stmt::While {
-                condition, body, ..
+                condition,
+                body,
+                span,
+                body_end,
             } => {
-                self.check_expr(condition)?;
-
-                self.context.enter_scope();
-                for stmt in body {
-                    self.check_stmt(stmt)?;
-                }
-                self.context.exit_scope();
+                let head = self.branch();
+                self.current = head;
+                self.lower_expr(condition, *span)?;
+                let after = self.cfg.new_block();
+                self.cfg.add_edge(self.current, after);
+
+                self.current = self.branch();
+                self.loops.push((head, after, self.blocks.len()));
+                self.lower_block(body, *span, *body_end)?;
+                self.loops.pop();
+                self.cfg.add_edge(self.current, head);
+                self.current = after;
             }
 
             Stmt::For {
-                var, iter, body, ..
+                var,
+                pair,
+                iter,
+                body,
+                span,
+                body_end,
             } => {
-                self.check_expr(iter)?;
-
-                self.context.enter_scope();
-                // Initialize loop variable
-                let place = Place::Local(var.clone());
-                self.context.init_owned(place);
-
+                self.lower_iterable(iter, *span)?;
+                let head = self.branch();
+                self.current = head;
+                let after = self.cfg.new_block();
+                self.cfg.add_edge(head, after);
+
+                // Each iteration binds the loop variable afresh
+                self.current = self.branch();
+                self.loops.push((head, after, self.blocks.len()));
+                self.blocks.push(Vec::new());
+                for name in std::iter::once(var).chain(pair) {
+                    let local = self.declare(name);
+                    self.write(Place::Local(local), Origin::default(), *span);
+                }
                 for stmt in body {
-                    self.check_stmt(stmt)?;
+                    self.lower_stmt(stmt, *span)?;
                 }
-                self.context.exit_scope();
+                self.close_block(*body_end);
+                self.loops.pop();
+                self.cfg.add_edge(self.current, head);
+                self.current = after;
             }
 
-            Stmt::Match { expr, arms, .. } => {
-                self.check_expr(expr)?;
+            Stmt::Match { expr, arms, span } => {
+                let scrutinee = self.lower_expr(expr, *span)?;
+                let branch_point = self.current;
+                let join = self.cfg.new_block();
 
                 for arm in arms {
-                    self.context.enter_scope();
-
-                    // Bind pattern variables
-                    self.bind_pattern(&arm.pattern)?;
+                    self.current = branch_point;
+                    self.current = self.branch();
 
+                    // Bind pattern variables, which live until the end of the arm
+                    self.blocks.push(Vec::new());
+                    self.bind_pattern(&arm.pattern, &scrutinee, *span);
                     for stmt in &arm.body {
-                        self.check_stmt(stmt)?;
+                        self.lower_stmt(stmt, *span)?;
                     }
+                    self.close_block(arm.end);
+                    self.cfg.add_edge(self.current, join);
+                }
+                self.current = join;
+            }
 
-                    self.context.exit_scope();
+            Stmt::Break { span } | Stmt::Continue { span } => {
+                let Some(&(head, after, depth)) = self.loops.last() else {
+                    return Err(CompileError::BorrowChecker {
+                        message: "`break` or `continue` outside of a loop".to_string(),
+                        span: Some(*span),
+                    });
+                };
+                let target = if matches!(stmt, Stmt::Break { .. }) {
+                    after
+                } else {
+                    head
+                };
+                // Leaving the loop's body drops the locals of the blocks inside it
+                for index in (depth..self.blocks.len()).rev() {
+                    self.drop_locals(index, *span);
                 }
+                self.diverge(target);
             }
 
-            Stmt::Break { .. } | Stmt::Continue { .. } => {}
+            Stmt::AsyncScope {
+                body,
+                span,
+                body_end,
+            } => {
+                // The tasks spawned in the scope hold their loans in a local of its
+                // own, which is used once they have all finished at the scope's end
+                self.opened_scopes += 1;
+                let holder = format!("{}{}", SCOPE_SLOT, self.opened_scopes);
+                self.write(Place::Local(holder.clone()), Origin::default(), *span);
+                self.async_scopes.push(holder.clone());
+                self.lower_block(body, *span, *body_end)?;
+                self.async_scopes.pop();
+                self.emit(Event::Read {
+                    place: Place::Local(holder),
+                    span: *span,
+                });
+            }
+
+            Stmt::Handle {
+                body,
+                handlers,
+                span,
+                body_end,
+            } => {
+                self.lower_block(body, *span, *body_end)?;
+                self.pending_handlers.extend(
+                    handlers
+                        .iter()
+                        .flat_map(|handler| handler.operations.iter().cloned()),
+                );
+            }
 
-            Stmt::Unsafe { body, .. } => {
+            Stmt::Unsafe {
+                body,
+                span,
+                body_end,
+            } => {
                 // In unsafe blocks, we still perform ownership checks
                 // but allow certain operations that would normally be forbidden
                 self.unsafe_depth += 1;
-                self.context.enter_scope();
-                for stmt in body {
-                    self.check_stmt(stmt)?;
-                }
-                self.context.exit_scope();
+                self.lower_block(body, *span, *body_end)?;
                 self.unsafe_depth -= 1;
             }
         }
@@ -602,231 +1525,384 @@ impl BorrowChecker {
         Ok(())
     }
 
-    /// Check an expression for ownership violations
-    fn check_expr(&mut self, expr: &Expr) -> Result<()> {
+    /// Lower what a for loop or an iterator chain walks. Collections are only
+    /// read; a user type implementing `Iterator` is used up by the loop.
+    fn lower_iterable(&mut self, iter: &Expr, span: Span) -> Result<Origin> {
+        if let Some((receiver, method, args)) = iter.iterator_call() {
+            // `map` and `filter` take function names rather than values
+            if !matches!(method, "map" | "filter") {
+                for arg in args {
+                    self.lower_iterable(arg, span)?;
+                }
+            }
+            return self.lower_iterable(receiver, span);
+        }
+        let ty = self.expr_type(iter);
+        if ["Iterator", "IntoIterator"]
+            .iter()
+            .any(|name| self.traits.type_implements_trait(&ty, name))
+        {
+            return self.lower_value(iter, span);
+        }
+        self.lower_expr(iter, span)
+    }
+
+    /// Lower an expression whose value is consumed: non-Copy places are moved
+    fn lower_value(&mut self, expr: &Expr, outer: Span) -> Result<Origin> {
+        match self.place_of(expr) {
+            Some(place) if !matches!(expr, Expr::Deref { .. }) && !self.is_expr_copy(expr) => {
+                self.move_out(place, Self::span_or(expr, outer))
+            }
+            _ => self.lower_expr(expr, outer),
+        }
+    }
+
+    /// Lower an expression that is evaluated without consuming it
+    fn lower_expr(&mut self, expr: &Expr, outer: Span) -> Result<Origin> {
+        let span = Self::span_or(expr, outer);
         match expr {
             Expr::Ident(name) => {
-                // Check if this is a function name or a variable
-                if self.functions.contains_key(name) {
-                    // It's a function - no ownership check needed
-                    return Ok(());
+                // Function names aren't values with ownership
+                let local = self.local(name);
+                if self.functions.contains_key(name) && !self.local_types.contains_key(&local) {
+                    return Ok(Origin::default());
                 }
+                let place = Place::Local(local);
+                let origin = Origin::from_place(&place);
+                self.emit(Event::Read { place, span });
+                Ok(origin)
+            }
 
-                let place = Place::Local(name.clone());
+            Expr::FieldAccess { object, .. } => match self.place_of(expr) {
+                Some(place) => {
+                    let origin = Origin::from_place(&place);
+                    self.emit(Event::Read { place, span });
+                    Ok(origin)
+                }
+                None => self.lower_expr(object, span),
+            },
 
-                // Check if the value is initialized and not moved
-                match self.context.get_ownership(&place) {
-                    Some(crate::ownership::Ownership::Owned) => {
-                        // Value is accessible
-                    }
-                    Some(crate::ownership::Ownership::Borrowed { .. }) => {
-                        // Value is borrowed but still accessible
-                    }
-                    Some(crate::ownership::Ownership::BorrowedMut { .. }) => {
-                        // Value is mutably borrowed but still accessible
-                    }
-                    Some(crate::ownership::Ownership::Moved) => {
-                        return Err(CompileError::UseOfMovedValue {
-                            name: name.clone(),
-                            span: Some(expr.span()),
-                        });
-                    }
-                    None => {
-                        return Err(CompileError::UseOfUninitializedValue {
-                            name: name.clone(),
-                            span: Some(expr.span()),
-                        });
+            Expr::Index { array, index, .. } => {
+                self.lower_expr(index, span)?;
+                match self.place_of(expr) {
+                    Some(place) => {
+                        let origin = Origin::from_place(&place);
+                        self.emit(Event::Read { place, span });
+                        Ok(origin)
                     }
+                    None => self.lower_expr(array, span),
                 }
             }
 
-            Expr::Call { func, args, span } => {
-                // Check function expression
-                self.check_expr(func)?;
-
-                // Check arguments and handle ownership transfer
-                if let Expr::Ident(func_name) = func.as_ref() {
-                    // Clone the function signature to avoid borrow conflicts
-                    let sig_opt = self.functions.get(func_name).cloned();
-
-                    if let Some(sig) = sig_opt {
-                        for (i, arg) in args.iter().enumerate() {
-                            self.check_expr(arg)?;
-
-                            // Handle ownership based on parameter type
-                            if let Some(param_ownership) = sig.params.get(i) {
-                                if let Some(place) = expr_to_place(arg) {
-                                    match param_ownership {
-                                        ParamOwnership::Move => {
-                                            // Move the argument
-                                            let temp = self.context.new_temp();
-                                            self.context.move_value(place, temp, *span)?;
-                                        }
-                                        ParamOwnership::Borrow(lifetime) => {
-                                            // Borrow immutably
-                                            self.context.borrow(
-                                                place,
-                                                RefKind::Shared,
-                                                lifetime.clone(),
-                                                *span,
-                                            )?;
-                                        }
-                                        ParamOwnership::BorrowMut(lifetime) => {
-                                            // Borrow mutably
-                                            self.context.borrow(
-                                                place,
-                                                RefKind::Mutable,
-                                                lifetime.clone(),
-                                                *span,
-                                            )?;
-                                        }
-                                        ParamOwnership::Copy => {
-                                            // No ownership transfer
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    } else {
-                        // Function not found, just check arguments
-                        for arg in args {
-                            self.check_expr(arg)?;
-                        }
-                    }
-                } else {
-                    // Check all arguments without special ownership handling
-                    for arg in args {
-                        self.check_expr(arg)?;
+            Expr::Call { .. } if expr.clone_receiver().is_some() => {
+                // `.clone()` only reads its receiver; the copy holds the same references
+                self.lower_expr(expr.clone_receiver().unwrap(), span)
+            }
+
+            Expr::Call { .. } if expr.iterator_call().is_some() => self.lower_iterable(expr, span),
+
+            Expr::Call { func, args, .. }
+                if func.callee_name() == Some("spawn") && args.len() == 1 =>
+            {
+                // A task keeps what its future borrows until it finishes: the end of
+                // its async scope, or for a detached task, possibly never
+                let origin = self.lower_value(&args[0], span)?;
+                match self.async_scopes.last().cloned() {
+                    // Stored into a part of the scope's local, the loans join the others'
+                    Some(holder) => {
+                        let task = Place::Field {
+                            base: Box::new(Place::Local(holder)),
+                            field: "task".to_string(),
+                        };
+                        self.write(task, origin, span);
                     }
+                    None => self.emit(Event::Escape {
+                        place: Place::Local(SPAWN_SLOT.to_string()),
+                        loans: origin.loans,
+                        sources: origin.sources,
+                        span,
+                    }),
                 }
+                Ok(Origin::default())
             }
 
-            Expr::Binary { left, right, .. } => {
-                self.check_expr(left)?;
-                self.check_expr(right)?;
+            Expr::Call { func, args, .. } => {
+                // Direct calls name a function; anything else is evaluated
+                let func_name = func.callee_name();
+                if func_name.is_none() {
+                    self.lower_expr(func, span)?;
+                }
+
+                let sig = func_name.and_then(|name| self.functions.get(name).cloned());
+                let storing = func_name
+                    .and_then(|name| self.storing_params.get(name).cloned())
+                    .unwrap_or_default();
+                let mut returned = Origin::default();
+                let mut arg_origins = Vec::new();
+                for (i, arg) in args.iter().enumerate() {
+                    let param = sig.as_ref().and_then(|sig| sig.params.get(i));
+                    let arg_origin = match (param, self.place_of(arg)) {
+                        (Some(ParamOwnership::Move), Some(place))
+                            if !matches!(arg, Expr::Deref { .. }) =>
+                        {
+                            self.move_out(place, span)?
+                        }
+                        (
+                            Some(ParamOwnership::Borrow(_) | ParamOwnership::BorrowMut(_)),
+                            Some(place),
+                        ) => {
+                            // The loan ends with the call unless the result keeps it
+                            let kind = if matches!(param, Some(ParamOwnership::BorrowMut(_))) {
+                                self.check_mutable(&place, span)?;
+                                RefKind::Mutable
+                            } else {
+                                RefKind::Shared
+                            };
+                            let mut origin = Origin::from_place(&place);
+                            let loan = self.cfg.new_loan(place.clone(), kind.clone(), span);
+                            if self.through_reference(&place, matches!(arg, Expr::Deref { .. })) {
+                                self.reborrows.insert(loan);
+                            }
+                            self.emit(Event::Borrow {
+                                place,
+                                kind,
+                                loan,
+                                span,
+                            });
+                            origin.loans.push(loan);
+                            origin
+                        }
+                        (Some(ParamOwnership::Move), None) => self.lower_value(arg, span)?,
+                        _ => self.lower_expr(arg, span)?,
+                    };
+                    // A returned reference borrows from the arguments its lifetime ties it to
+                    if let (Some(sig), Some(param)) = (&sig, param) {
+                        if sig.returns_borrow_of(param) {
+                            returned.extend(arg_origin.clone());
+                        }
+                    }
+                    arg_origins.push(arg_origin);
+                }
+
+                // What the other arguments refer to may be stored behind a `&mut` one
+                for &target in &storing {
+                    let mut stored = Origin::default();
+                    for (i, origin) in arg_origins.iter().enumerate() {
+                        if i != target {
+                            stored.extend(origin.clone());
+                        }
+                    }
+                    if let Some(arg) = args.get(target) {
+                        self.store_through(arg, stored, span);
+                    }
+                }
+                Ok(returned)
+            }
+
+            Expr::Turbofish { .. } => {
+                // A path to a generic function - nothing is owned
+                Ok(Origin::default())
+            }
+
+            Expr::Binary { left, right, .. } => {
+                self.lower_expr(left, span)?;
+                self.lower_expr(right, span)?;
+                Ok(Origin::default())
             }
 
             Expr::Unary { operand, .. } => {
-                self.check_expr(operand)?;
+                self.lower_expr(operand, span)?;
+                Ok(Origin::default())
             }
 
             Expr::ArrayLiteral { elements, .. } => {
+                let mut origin = Origin::default();
                 for elem in elements {
-                    self.check_expr(elem)?;
+                    origin.extend(self.lower_value(elem, span)?);
                 }
+                Ok(origin)
             }
 
             Expr::ArrayRepeat { value, count, .. } => {
-                self.check_expr(value)?;
-                self.check_expr(count)?;
-            }
-
-            Expr::Index { array, index, .. } => {
-                self.check_expr(array)?;
-                self.check_expr(index)?;
+                let origin = self.lower_value(value, span)?;
+                self.lower_expr(count, span)?;
+                Ok(origin)
             }
 
-            Expr::StructLiteral { fields, .. } => {
+            Expr::StructLiteral {
+                name, fields, base, ..
+            } => {
+                let mut origin = Origin::default();
                 for (_, expr) in fields {
-                    self.check_expr(expr)?;
+                    origin.extend(self.lower_value(expr, span)?);
                 }
-            }
 
-            Expr::FieldAccess { object, .. } => {
-                self.check_expr(object)?;
+                if let Some(StructBase::Expr(base)) = base {
+                    // Taking a non-Copy field moves the base; Copy fields are copied out
+                    let takes_owned_field = self.structs.get(name).is_some_and(|declared| {
+                        declared.iter().any(|(field, ty)| {
+                            !fields.iter().any(|(f, _)| f == field) && !self.traits.is_copy(ty)
+                        })
+                    });
+                    match self.place_of(base) {
+                        Some(place) if takes_owned_field => {
+                            origin.extend(self.move_out(place, span)?);
+                        }
+                        _ => origin.extend(self.lower_expr(base, span)?),
+                    }
+                }
+                Ok(origin)
             }
 
-            Expr::EnumConstructor { data, .. } => match data {
-                Some(crate::ast::EnumConstructorData::Tuple(exprs)) => {
-                    for expr in exprs {
-                        self.check_expr(expr)?;
+            Expr::EnumConstructor {
+                enum_name,
+                variant,
+                data: Some(crate::ast::EnumConstructorData::Tuple(exprs)),
+                ..
+            } if matches!(enum_name.as_str(), "Vec" | "HashMap" | "HashSet" | "Entry")
+                && !self.enums.contains(enum_name)
+                && !matches!(variant.as_str(), "new" | "with_capacity" | "from") =>
+            {
+                // Collection operations borrow the collection only for the call,
+                // but what they insert stays in it
+                let mut stored = Origin::default();
+                for (i, expr) in exprs.iter().enumerate() {
+                    let origin = self.lower_value(expr, span)?;
+                    if i > 0 {
+                        stored.extend(origin);
                     }
                 }
-                Some(crate::ast::EnumConstructorData::Struct(fields)) => {
-                    for (_, expr) in fields {
-                        self.check_expr(expr)?;
+                if matches!(variant.as_str(), "push" | "insert") {
+                    if let Some(collection) = exprs.first() {
+                        self.store_through(collection, stored, span);
                     }
                 }
-                None => {}
-            },
+                Ok(Origin::default())
+            }
 
-            Expr::Range { start, end, .. } => {
-                self.check_expr(start)?;
-                self.check_expr(end)?;
+            Expr::EnumConstructor { data, .. } => {
+                let mut origin = Origin::default();
+                match data {
+                    Some(crate::ast::EnumConstructorData::Tuple(exprs)) => {
+                        for expr in exprs {
+                            origin.extend(self.lower_value(expr, span)?);
+                        }
+                    }
+                    Some(crate::ast::EnumConstructorData::Struct(fields)) => {
+                        for (_, expr) in fields {
+                            origin.extend(self.lower_value(expr, span)?);
+                        }
+                    }
+                    None => {}
+                }
+                Ok(origin)
             }
 
-            Expr::Reference {
-                mutable,
-                expr,
-                span,
-            } => {
-                // Taking a reference to an expression
-                self.check_expr(expr)?;
+            Expr::Range { start, end, .. } => {
+                self.lower_expr(start, span)?;
+                self.lower_expr(end, span)?;
+                Ok(Origin::default())
+            }
 
+            Expr::Reference { mutable, expr, .. } => {
                 // If we can get a place for the expression, create a borrow
-                if let Some(place) = expr_to_place(expr) {
-                    let lifetime = self.context.new_lifetime();
+                if let Some(place) = self.place_of(expr) {
                     let kind = if *mutable {
+                        self.check_mutable(&place, span)?;
                         RefKind::Mutable
                     } else {
                         RefKind::Shared
                     };
-                    self.context.borrow(place, kind, lifetime, *span)?;
+                    let loan = self.cfg.new_loan(place.clone(), kind.clone(), span);
+                    // A reborrow points wherever the reference it goes through does
+                    let sources =
+                        if self.through_reference(&place, matches!(**expr, Expr::Deref { .. })) {
+                            self.reborrows.insert(loan);
+                            place.root().map(str::to_string).into_iter().collect()
+                        } else {
+                            vec![]
+                        };
+                    self.emit(Event::Borrow {
+                        place,
+                        kind,
+                        loan,
+                        span,
+                    });
+                    Ok(Origin {
+                        loans: vec![loan],
+                        sources,
+                    })
                 } else {
                     // Can't take reference to temporary
-                    return Err(CompileError::BorrowChecker {
+                    Err(CompileError::BorrowChecker {
                         message: "Cannot take reference to temporary value".to_string(),
-                        span: Some(*span),
-                    });
+                        span: Some(span),
+                    })
                 }
             }
 
             Expr::Deref { expr, .. } => {
-                // Dereferencing an expression
-                self.check_expr(expr)?;
+                // Dereferencing reads the reference
                 // TODO: Check that the expression is actually a reference type
+                self.lower_expr(expr, span)?;
+                Ok(Origin::default())
             }
 
             Expr::Question { expr, .. } => {
-                // Question operator checks the inner expression
-                self.check_expr(expr)?;
-                // TODO: Handle ownership implications of early return
+                // `?` may return early with the error
+                self.lower_expr(expr, span)?;
+                let continue_block = self.branch();
+                self.cfg.add_edge(self.current, self.exit);
+                self.current = continue_block;
+                Ok(Origin::default())
             }
 
             // Literals don't need ownership checking
-            Expr::String(_) | Expr::Integer(_) | Expr::Bool(_) => {}
+            Expr::String(_) | Expr::Integer(_) | Expr::Bool(_) => Ok(Origin::default()),
             Expr::MacroInvocation { .. } => {
                 // Macros should have been expanded before borrow checking
-                return Err(CompileError::Generic(
+                Err(CompileError::Generic(
                     "Unexpected macro invocation in borrow checking - macros should be expanded before this phase".to_string()
-                ));
+                ))
             }
             Expr::Await { expr, .. } => {
-                self.check_expr(expr)?;
+                // The future's loans end with it, unless its output is a reference
+                let origin = self.lower_expr(expr, span)?;
+                if matches!(self.expr_type(expr), Type::Reference { .. }) {
+                    Ok(origin)
+                } else {
+                    Ok(Origin::default())
+                }
             }
         }
+    }
 
-        Ok(())
+    /// The expression's own span, or the enclosing one for expressions without
+    fn span_or(expr: &Expr, outer: Span) -> Span {
+        match expr.span() {
+            span if span == Span::dummy() => outer,
+            span => span,
+        }
     }
 
-    /// Bind variables in a pattern
-    fn bind_pattern(&mut self, pattern: &Pattern) -> Result<()> {
+    /// Bind variables in a pattern; they may hold references from the scrutinee
+    fn bind_pattern(&mut self, pattern: &Pattern, scrutinee: &Origin, span: Span) {
         match pattern {
             Pattern::Ident(name) => {
-                let place = Place::Local(name.clone());
-                self.context.init_owned(place);
+                let local = self.declare(name);
+                self.write(Place::Local(local), scrutinee.clone(), span);
             }
             Pattern::EnumPattern { data, .. } => {
                 if let Some(pattern_data) = data {
                     match pattern_data {
                         crate::ast::PatternData::Tuple(patterns) => {
                             for pattern in patterns {
-                                self.bind_pattern(pattern)?;
+                                self.bind_pattern(pattern, scrutinee, span);
                             }
                         }
                         crate::ast::PatternData::Struct(fields) => {
                             for (_, pattern) in fields {
-                                self.bind_pattern(pattern)?;
+                                self.bind_pattern(pattern, scrutinee, span);
                             }
                         }
                     }
@@ -834,25 +1910,6 @@ impl BorrowChecker {
             }
             Pattern::Wildcard => {}
         }
-        Ok(())
-    }
-
-    /// Check if a type is Copy (doesn't move on assignment)
-    #[allow(clippy::only_used_in_recursion)]
-    fn is_copy_type(&self, ty: &Type) -> bool {
-        match ty {
-            Type::I32 | Type::I64 | Type::U32 | Type::U64 | Type::Bool => true,
-            Type::String | Type::Array(_, _) | Type::Custom(_) => false,
-            Type::Reference { .. } => true, // References are Copy
-            Type::Unit => true,
-            Type::TypeParam(_) => false, // Conservative: assume not Copy
-            Type::Generic { .. } => false, // Conservative: assume not Copy
-            Type::Future { .. } => false, // Futures are not Copy
-            Type::Tuple(types) => {
-                // Tuple is Copy if all its elements are Copy
-                types.iter().all(|t| self.is_copy_type(t))
-            }
-        }
     }
 
     /// Check if an expression type is Copy
@@ -860,16 +1917,72 @@ impl BorrowChecker {
         match expr {
             Expr::Integer(_) | Expr::Bool(_) => true,
             Expr::String(_) => false, // Strings are not Copy
-            Expr::Ident(name) => {
-                // Look up the type of the identifier from local_types
-                if let Some(ty) = self.local_types.get(name) {
-                    self.is_copy_type(ty)
-                } else {
-                    // If we can't find the type, conservatively assume non-Copy
-                    false
-                }
+            // Function names are function pointers
+            Expr::Ident(name)
+                if self.functions.contains_key(name)
+                    && !self.local_types.contains_key(&self.local(name)) =>
+            {
+                true
             }
-            _ => false, // Conservative default
+            _ => match self
+                .place_of(expr)
+                .and_then(|place| self.place_type(&place))
+            {
+                Some(ty) => self.traits.is_copy(&ty),
+                // If we can't find the type, conservatively assume non-Copy
+                None => false,
+            },
+        }
+    }
+
+    /// Declared type of a place, when known
+    fn place_type(&self, place: &Place) -> Option<Type> {
+        match place {
+            Place::Local(name) => match (self.local_types.get(name)?, &self.impl_type) {
+                (Type::Custom(name), Some(impl_type)) if name == "Self" => Some(impl_type.clone()),
+                (
+                    Type::Reference {
+                        lifetime,
+                        mutable,
+                        inner,
+                    },
+                    Some(impl_type),
+                ) if matches!(inner.as_ref(), Type::Custom(name) if name == "Self") => {
+                    Some(Type::Reference {
+                        lifetime: lifetime.clone(),
+                        mutable: *mutable,
+                        inner: Box::new(impl_type.clone()),
+                    })
+                }
+                (ty, _) => Some(ty.clone()),
+            },
+            Place::Field { base, field } => match self.place_type(base)? {
+                Type::Custom(name) => self
+                    .structs
+                    .get(&name)?
+                    .iter()
+                    .find(|(f, _)| f == field)
+                    .map(|(_, ty)| ty.clone()),
+                Type::Reference { inner, .. } => match *inner {
+                    Type::Custom(name) => self
+                        .structs
+                        .get(&name)?
+                        .iter()
+                        .find(|(f, _)| f == field)
+                        .map(|(_, ty)| ty.clone()),
+                    _ => None,
+                },
+                _ => None,
+            },
+            Place::Index { base, .. } => match self.place_type(base)? {
+                Type::Array(elem, _) => Some(*elem),
+                Type::Generic { name, args } if name == "Vec" => match args.as_slice() {
+                    [GenericArg::Type(elem)] => Some(elem.clone()),
+                    _ => None,
+                },
+                _ => None,
+            },
+            Place::Temp(_) => None,
         }
     }
 
@@ -879,10 +1992,117 @@ impl BorrowChecker {
             Expr::Integer(_) => Type::I64,
             Expr::String(_) => Type::String,
             Expr::Bool(_) => Type::Bool,
-            Expr::Ident(_) => Type::I64, // TODO: Proper type lookup
-            _ => Type::I64,              // Default for now
+            Expr::ArrayLiteral { elements, .. } => Type::Array(
+                Box::new(
+                    elements
+                        .first()
+                        .map_or(Type::I64, |elem| self.expr_type(elem)),
+                ),
+                ArraySize::Literal(elements.len()),
+            ),
+            // Elements at a computed index have the element type
+            Expr::Index { array, .. } if self.place_of(expr).is_none() => {
+                match self.expr_type(array) {
+                    Type::Array(elem, _) => *elem,
+                    Type::Generic { name, args } if name == "Vec" => match args.as_slice() {
+                        [GenericArg::Type(elem)] => elem.clone(),
+                        _ => Type::I64,
+                    },
+                    _ => Type::I64,
+                }
+            }
+            Expr::Ident(_) | Expr::FieldAccess { .. } | Expr::Index { .. } => self
+                .place_of(expr)
+                .and_then(|place| self.place_type(&place))
+                .unwrap_or(Type::I64),
+            Expr::StructLiteral { name, .. } => Type::Custom(name.clone()),
+            Expr::EnumConstructor {
+                enum_name,
+                variant,
+                data: Some(crate::ast::EnumConstructorData::Tuple(args)),
+                ..
+            } if enum_name == "Box" && variant == "new" && args.len() == 1 => Type::Generic {
+                name: "Box".to_string(),
+                args: vec![GenericArg::Type(self.expr_type(&args[0]))],
+            },
+            Expr::EnumConstructor { enum_name, data, .. } if enum_name == "Vec" => {
+                let element = match data {
+                    Some(crate::ast::EnumConstructorData::Tuple(args)) if args.len() == 1 => {
+                        match self.expr_type(&args[0]) {
+                            Type::Array(element, _) => *element,
+                            _ => Type::I64,
+                        }
+                    }
+                    _ => Type::I64,
+                };
+                Type::Generic {
+                    name: "Vec".to_string(),
+                    args: vec![GenericArg::Type(element)],
+                }
+            }
+            Expr::EnumConstructor {
+                enum_name, variant, ..
+            } if matches!(enum_name.as_str(), "HashMap" | "HashSet") && variant == "new" => {
+                Type::Generic {
+                    name: enum_name.clone(),
+                    args: vec![],
+                }
+            }
+            Expr::EnumConstructor { enum_name, .. } if self.enums.contains(enum_name) => {
+                Type::Custom(enum_name.clone())
+            }
+            Expr::Reference { mutable, expr, .. } => Type::Reference {
+                lifetime: None,
+                mutable: *mutable,
+                inner: Box::new(self.expr_type(expr)),
+            },
+            // A clone has the type of what it copies, even through a reference
+            Expr::Call { .. } if expr.clone_receiver().is_some() => {
+                match expr
+                    .clone_receiver()
+                    .map(|receiver| self.expr_type(receiver))
+                {
+                    Some(Type::Reference { inner, .. }) => *inner,
+                    ty => ty.unwrap_or(Type::I64),
+                }
+            }
+            Expr::Call { func, .. } => func
+                .callee_name()
+                .and_then(|name| self.return_types.get(name).cloned())
+                .unwrap_or(Type::I64),
+            _ => Type::I64, // Default for now
+        }
+    }
+}
+
+/// Lifetime named in a type or generic parameter list, with or without its quote
+fn named_lifetime(name: &str) -> Lifetime {
+    match name.trim_start_matches('\'') {
+        "static" => Lifetime::Static,
+        name => Lifetime::Named(name.to_string()),
+    }
+}
+
+/// References a value may carry: loans it holds directly, and locals it was
+/// copied or moved from (whose loans it inherits)
+#[derive(Debug, Clone, Default)]
+struct Origin {
+    loans: Vec<LoanId>,
+    sources: Vec<String>,
+}
+
+impl Origin {
+    fn from_place(place: &Place) -> Self {
+        Self {
+            loans: Vec::new(),
+            sources: place.root().map(str::to_string).into_iter().collect(),
         }
     }
+
+    fn extend(&mut self, other: Origin) {
+        self.loans.extend(other.loans);
+        self.sources.extend(other.sources);
+    }
 }
 
 #[cfg(test)]
@@ -908,6 +2128,7 @@ mod tests {
             params: vec![],
             return_type: None,
             effects: None,
+            effect_params: vec![],
             body: vec![
                 Stmt::Let {
                     name: "x".to_string(),
@@ -947,6 +2168,7 @@ mod tests {
             params: vec![],
             return_type: None,
             effects: None,
+            effect_params: vec![],
             body: vec![
                 Stmt::Let {
                     name: "x".to_string(),
@@ -974,4 +2196,765 @@ mod tests {
         }
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn test_struct_update_moves_base() {
+        let check = |source: &str| {
+            let tokens = crate::lexer::Lexer::new(source).collect_tokens().unwrap();
+            let program = crate::parser::Parser::new(tokens).parse().unwrap();
+            BorrowChecker::new().check_program(&program)
+        };
+
+        // Taking the String field moves `a`
+        let moved = check(
+            r#"
+            struct P { n: i64, s: String }
+            fn main() {
+                let a = P { n: 1, s: "x" };
+                let b = P { n: 2, ..a };
+                let c = a;
+            }
+            "#,
+        );
+        assert!(matches!(moved, Err(CompileError::UseOfMovedValue { .. })));
+
+        // Only Copy fields are taken, so `a` stays usable
+        let copied = check(
+            r#"
+            struct P { n: i64, s: String }
+            fn main() {
+                let a = P { n: 1, s: "x" };
+                let b = P { s: "y", ..a };
+                let c = a;
+            }
+            "#,
+        );
+        assert!(copied.is_ok());
+    }
+
+    fn check_source(source: &str) -> Result<()> {
+        let tokens = crate::lexer::Lexer::new(source).collect_tokens().unwrap();
+        let program = crate::parser::Parser::new(tokens).parse().unwrap();
+        BorrowChecker::new().check_program(&program)
+    }
+
+    #[test]
+    fn test_borrow_ends_at_last_use() {
+        let result = check_source(
+            r#"
+            fn main() {
+                let mut x = 1;
+                let m = &mut x;
+                *m = 2;
+                x = 3;
+                print_int(x);
+            }
+            "#,
+        );
+        assert!(result.is_ok());
+
+        // Still using the borrow afterwards keeps it alive
+        let result = check_source(
+            r#"
+            fn main() {
+                let mut x = 1;
+                let m = &mut x;
+                x = 3;
+                *m = 2;
+            }
+            "#,
+        );
+        assert!(matches!(
+            result,
+            Err(CompileError::ConflictingBorrows { .. })
+        ));
+
+        // So does writing to a field or element through it
+        for write in ["a.x = 5;", "a.xs[0] = 5;"] {
+            let result = check_source(&format!(
+                r#"
+                struct Inner {{ n: i64 }}
+                struct P {{ x: i64, xs: [i64; 2], inner: Inner }}
+                fn main() {{
+                    let mut p = P {{ x: 1, xs: [1, 2], inner: Inner {{ n: 2 }} }};
+                    let a = &mut p;
+                    let b = &p.inner.n;
+                    {}
+                    print_int(*b);
+                }}
+                "#,
+                write
+            ));
+            assert!(
+                matches!(result, Err(CompileError::ConflictingBorrows { .. })),
+                "{}: {:?}",
+                write,
+                result
+            );
+        }
+    }
+
+    #[test]
+    fn test_borrow_error_locations() {
+        let line = |span: Option<Span>| span.map(|span| span.line);
+
+        let result = check_source(
+            r#"
+            fn main() {
+                let mut x = 1;
+                let m = &mut x;
+                x = 3;
+                *m = 2;
+            }
+            "#,
+        );
+        match result {
+            Err(CompileError::ConflictingBorrows {
+                borrowed_at, span, ..
+            }) => {
+                assert_eq!(line(span), Some(5));
+                assert_eq!(line(borrowed_at), Some(4));
+            }
+            other => panic!("expected conflicting borrows, got {:?}", other),
+        }
+
+        let result = check_source(
+            r#"
+            fn take(s: String) {}
+            fn main() {
+                let s = "hi";
+                take(s);
+                take(s);
+            }
+            "#,
+        );
+        match result {
+            Err(CompileError::UseOfMovedValue { moved_at, span, .. }) => {
+                assert_eq!(line(span), Some(6));
+                assert_eq!(line(moved_at), Some(5));
+            }
+            other => panic!("expected a use of a moved value, got {:?}", other),
+        }
+
+        // A field read in an initializer is reported at the field, after a move of the whole value
+        let result = check_source(
+            r#"
+            struct P { name: String, age: i64 }
+            fn take(p: P) {}
+            fn main() {
+                let p = P { name: "a", age: 1 };
+                take(p);
+                let n = p.name;
+            }
+            "#,
+        );
+        match result {
+            Err(CompileError::UseOfMovedValue {
+                name,
+                moved_at,
+                span,
+            }) => {
+                assert_eq!(name, "p.name");
+                assert_eq!(span.map(|span| (span.line, span.column)), Some((7, 25)));
+                assert_eq!(line(moved_at), Some(6));
+            }
+            other => panic!("expected a use of a moved value, got {:?}", other),
+        }
+
+        // ... and after a struct update moved it out
+        let result = check_source(
+            r#"
+            struct P { name: String, age: i64 }
+            fn main() {
+                let p = P { name: "a", age: 1 };
+                let q = P { age: 2, ..p };
+                let n = p.name;
+            }
+            "#,
+        );
+        match result {
+            Err(CompileError::UseOfMovedValue {
+                name,
+                moved_at,
+                span,
+            }) => {
+                assert_eq!(name, "p.name");
+                assert_eq!(span.map(|span| (span.line, span.column)), Some((6, 25)));
+                assert_eq!(line(moved_at), Some(5));
+            }
+            other => panic!("expected a use of a moved value, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_conditional_move() {
+        let result = check_source(
+            r#"
+            fn take(s: String) {}
+            fn main() {
+                let s = "hi";
+                if true {
+                    take(s);
+                }
+                take(s);
+            }
+            "#,
+        );
+        match result {
+            Err(CompileError::UseOfPossiblyMovedValue { reason, .. }) => {
+                assert_eq!(reason, "in one branch")
+            }
+            other => panic!("expected a conditional move error, got {:?}", other),
+        }
+
+        // Moving on every path and reinitializing is fine
+        let result = check_source(
+            r#"
+            fn take(s: String) {}
+            fn main() {
+                let mut s = "hi";
+                if true {
+                    take(s);
+                } else {
+                    take(s);
+                }
+                s = "again";
+                take(s);
+            }
+            "#,
+        );
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_move_in_loop() {
+        let result = check_source(
+            r#"
+            fn take(s: String) {}
+            fn main() {
+                let s = "hi";
+                let mut i = 0;
+                while i < 3 {
+                    take(s);
+                    i = i + 1;
+                }
+            }
+            "#,
+        );
+        match result {
+            Err(CompileError::UseOfPossiblyMovedValue { reason, .. }) => {
+                assert_eq!(reason, "in the previous iteration of the loop")
+            }
+            other => panic!("expected a loop move error, got {:?}", other),
+        }
+
+        // Leaving the loop right after the move is fine
+        let result = check_source(
+            r#"
+            fn take(s: String) {}
+            fn main() {
+                let s = "hi";
+                while true {
+                    take(s);
+                    break;
+                }
+            }
+            "#,
+        );
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_disjoint_field_borrows() {
+        let result = check_source(
+            r#"
+            struct Point { x: i64, y: i64 }
+            fn main() {
+                let mut p = Point { x: 1, y: 2 };
+                let a = &mut p.x;
+                let b = p.y;
+                *a = b;
+            }
+            "#,
+        );
+        assert!(result.is_ok());
+
+        // Borrowing the whole struct overlaps the borrowed field
+        let result = check_source(
+            r#"
+            struct Inner { buf: String }
+            struct Outer { inner: Inner, n: i64 }
+            fn main() {
+                let mut p = Outer { inner: Inner { buf: "a" }, n: 1 };
+                let a = &mut p.inner.buf;
+                let b = &p;
+                *a = "b";
+            }
+            "#,
+        );
+        match result {
+            Err(CompileError::ConflictingBorrows { message, .. }) => {
+                assert!(message.contains("`p.inner.buf`"), "{}", message)
+            }
+            other => panic!("expected a conflicting borrow, got {:?}", other),
+        }
+
+        // An element at a computed index is named by its array
+        let result = check_source(
+            r#"
+            fn main() {
+                let i = 0;
+                let mut xs = [1, 2, 3];
+                let r = &xs;
+                xs[i] = 4;
+                print_int(r[0]);
+            }
+            "#,
+        );
+        match result {
+            Err(CompileError::ConflictingBorrows { message, .. }) => assert!(
+                message.contains("cannot assign to `xs[_]` because `xs` is borrowed"),
+                "{}",
+                message
+            ),
+            other => panic!("expected a conflicting borrow, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_partial_move() {
+        let source = |use_stmt: &str| {
+            format!(
+                r#"
+                struct Inner {{ buf: String, len: i64 }}
+                struct Outer {{ inner: Inner, n: i64 }}
+                fn take(s: String) {{}}
+                fn main() {{
+                    let p = Outer {{ inner: Inner {{ buf: "a", len: 1 }}, n: 1 }};
+                    take(p.inner.buf);
+                    {}
+                }}
+                "#,
+                use_stmt
+            )
+        };
+
+        // Other fields stay usable
+        assert!(check_source(&source("let n = p.n + p.inner.len;")).is_ok());
+
+        match check_source(&source("let q = p;")) {
+            Err(CompileError::UseOfPartiallyMovedValue { name, moved, .. }) => {
+                assert_eq!(name, "p");
+                assert_eq!(moved, "p.inner.buf");
+            }
+            other => panic!("expected a partial move error, got {:?}", other),
+        }
+
+        match check_source(&source("take(p.inner.buf);")) {
+            Err(CompileError::UseOfMovedValue { name, .. }) => assert_eq!(name, "p.inner.buf"),
+            other => panic!("expected a move error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_returned_reference_to_local() {
+        match check_source("fn f() -> &i64 { let x = 1; &x } fn main() {}") {
+            Err(CompileError::DanglingReference { name, function, .. }) => {
+                assert_eq!(name, "x");
+                assert_eq!(function, "f");
+            }
+            other => panic!("expected a dangling reference, got {:?}", other),
+        }
+
+        // Borrowing through the only reference parameter is fine, and so is its caller
+        let result = check_source(
+            r#"
+            struct P { x: i64 }
+            fn get(p: &P) -> &i64 { &p.x }
+            fn main() {
+                let p = P { x: 1 };
+                let r = get(&p);
+                print_int(*r);
+            }
+            "#,
+        );
+        assert!(result.is_ok());
+
+        // The elided output lifetime ties the result to the argument
+        let result = check_source(
+            r#"
+            fn id(x: &i64) -> &i64 { x }
+            fn f(y: &i64) -> &i64 { let a = 2; id(&a) }
+            fn main() {}
+            "#,
+        );
+        assert!(matches!(
+            result,
+            Err(CompileError::DanglingReference { .. })
+        ));
+    }
+
+    #[test]
+    fn test_explicit_lifetimes() {
+        let result =
+            check_source("fn pick<'a, 'b>(x: &'a i64, y: &'b i64) -> &'a i64 { y } fn main() {}");
+        match result {
+            Err(CompileError::LifetimeError { message, .. }) => {
+                assert!(message.contains("`y`"), "{}", message)
+            }
+            other => panic!("expected a lifetime mismatch, got {:?}", other),
+        }
+
+        let result =
+            check_source("fn pick<'a, 'b>(x: &'a i64, y: &'b i64) -> &'a i64 { x } fn main() {}");
+        assert!(result.is_ok());
+
+        let result = check_source("fn f<'a>(x: &'b i64) -> i64 { *x } fn main() {}");
+        assert!(matches!(result, Err(CompileError::LifetimeError { .. })));
+    }
+
+    #[test]
+    fn test_reference_stored_through_parameter() {
+        let result = check_source(
+            r#"
+            struct Holder<'a> { r: &'a i64 }
+            fn fill(h: &mut Holder) {
+                let x = 1;
+                h.r = &x;
+            }
+            fn main() {}
+            "#,
+        );
+        assert!(matches!(
+            result,
+            Err(CompileError::DanglingReference { .. })
+        ));
+
+        // Storing into a local holder doesn't leave the function
+        let result = check_source(
+            r#"
+            struct Holder<'a> { r: &'a i64 }
+            fn main() {
+                let x = 1;
+                let y = 2;
+                let mut h = Holder { r: &x };
+                let m = &mut h;
+                m.r = &y;
+            }
+            "#,
+        );
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_copy_is_a_trait() {
+        let source = |impls: &str| {
+            format!(
+                r#"
+                struct P {{ x: i64 }}
+                {}
+                fn take(p: P) -> i64 {{ p.x }}
+                fn main() {{
+                    let p = P {{ x: 1 }};
+                    take(p);
+                    take(p);
+                }}
+                "#,
+                impls
+            )
+        };
+        assert!(matches!(
+            check_source(&source("")),
+            Err(CompileError::UseOfMovedValue { .. })
+        ));
+        assert!(check_source(&source("impl Copy for P {}")).is_ok());
+    }
+
+    #[test]
+    fn test_copy_arrays_and_shared_references() {
+        let source = |body: &str| {
+            format!(
+                r#"
+                fn main() {{
+                    let mut x = 1;
+                    {}
+                }}
+                "#,
+                body
+            )
+        };
+        let moved = |body: &str| {
+            matches!(
+                check_source(&source(body)),
+                Err(CompileError::UseOfMovedValue { .. })
+            )
+        };
+
+        // Arrays are `Copy` when their elements are
+        assert!(check_source(&source("let a = [1, 2]; let b = a; let c = a;")).is_ok());
+        assert!(moved("let a = [\"x\"]; let b = a; let c = a;"));
+
+        // Shared references are copied; a `&mut` is moved, or reborrowed where
+        // a `&mut` is expected
+        assert!(check_source(&source("let r = &x; let s = r; let t = r;")).is_ok());
+        assert!(moved("let r = &mut x; let s = r; let t = r;"));
+        let reborrowed = "let r = &mut x; let s: &mut i64 = r; let t: &mut i64 = r;";
+        assert!(check_source(&source(reborrowed)).is_ok());
+    }
+
+    #[test]
+    fn test_cannot_move_out_of_drop_type() {
+        let result = check_source(
+            r#"
+            struct Inner { s: String }
+            struct Guard { inner: Inner }
+            impl Drop for Guard {
+                fn drop(&mut self) {}
+            }
+            fn main() {
+                let g = Guard { inner: Inner { s: "x" } };
+                let i = g.inner;
+            }
+            "#,
+        );
+        match result {
+            Err(CompileError::BorrowChecker { message, .. }) => {
+                assert!(message.contains("implements `Drop`"), "{}", message)
+            }
+            other => panic!("expected a move out of a Drop type, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_async_scope_borrows() {
+        let source = |body: &str| {
+            format!(
+                r#"
+                struct Slot {{ value: i64 }}
+                async fn fill(slot: &mut Slot, n: i64) {{
+                    slot.value = n;
+                }}
+                async fn main() {{
+                    let mut a = Slot {{ value: 0 }};
+                    let mut b = Slot {{ value: 0 }};
+                    {}
+                }}
+                "#,
+                body
+            )
+        };
+        let message = |body: &str| match check_source(&source(body)) {
+            Err(CompileError::BorrowChecker { message, .. })
+            | Err(CompileError::ConflictingBorrows { message, .. }) => message,
+            Err(err @ CompileError::DroppedWhileBorrowed { .. }) => err.to_string(),
+            other => panic!("expected a borrow error, got {:?}", other),
+        };
+
+        // Tasks of a scope may borrow disjoint values, which are free once it ends
+        let disjoint = "async scope { spawn(fill(&mut a, 1)); spawn(fill(&mut b, 2)); }
+                        a.value = 3;";
+        assert!(check_source(&source(disjoint)).is_ok());
+
+        // Until then the borrows are held
+        let aliased = "async scope { spawn(fill(&mut a, 1)); spawn(fill(&mut a, 2)); }";
+        assert!(message(aliased).contains("cannot borrow `a` as mutable"));
+        let written = "async scope { spawn(fill(&mut a, 1)); a.value = 3; }";
+        assert!(message(written).contains("cannot assign to `a.value`"));
+
+        // Borrowed data must outlive the scope, and detached tasks can't borrow
+        let local = "async scope { let mut c = Slot { value: 0 }; spawn(fill(&mut c, 1)); }";
+        assert!(message(local).contains("`c` does not live long enough"));
+        let detached = "spawn(fill(&mut a, 1));";
+        assert!(message(detached).contains("spawn it inside an `async scope`"));
+    }
+
+    #[test]
+    fn test_references_stored_through_mut_arguments() {
+        // A reference pushed into a vector lives as long as the vector
+        let pushed = r#"
+            fn main() {
+                let mut v: Vec<&i64> = Vec::new();
+                let mut i = 0;
+                while i < 3 {
+                    let x = i * 10;
+                    Vec::push(&mut v, &x);
+                    i = i + 1;
+                }
+                print_int(*v[0] + *v[1]);
+            }
+            "#;
+        match check_source(pushed) {
+            Err(CompileError::DroppedWhileBorrowed { name, .. }) => assert_eq!(name, "x"),
+            other => panic!("expected `x` to be dropped while borrowed, got {:?}", other),
+        }
+
+        // Through a reference parameter it reaches the caller
+        let escaping = r#"
+            fn f(v: &mut Vec<&i64>) {
+                let x = 1;
+                Vec::push(v, &x);
+            }
+            fn main() {}
+            "#;
+        match check_source(escaping) {
+            Err(CompileError::DanglingReference { name, .. }) => assert_eq!(name, "x"),
+            other => panic!("expected `x` to escape `f`, got {:?}", other),
+        }
+
+        // User functions taking such a `&mut` may store their other arguments in it
+        let kept = r#"
+            fn keep(v: &mut Vec<&i64>, x: &i64) {
+                Vec::push(v, x);
+            }
+            fn add(total: &mut i64, x: &i64) {
+                *total = *total + *x;
+            }
+            fn main() {
+                let mut v: Vec<&i64> = Vec::new();
+                let mut total = 0;
+                let mut i = 0;
+                while i < 3 {
+                    let x = i * 10;
+                    add(&mut total, &x);
+                    BODY
+                    i = i + 1;
+                }
+                print_int(total + *v[0]);
+            }
+            "#;
+        assert!(check_source(&kept.replace("BODY", "")).is_ok());
+        assert!(matches!(
+            check_source(&kept.replace("BODY", "keep(&mut v, &x);")),
+            Err(CompileError::DroppedWhileBorrowed { .. })
+        ));
+    }
+
+    #[test]
+    fn test_block_locals_dropped_at_block_end() {
+        let source = |body: &str| {
+            format!(
+                r#"
+                fn main() {{
+                    let x = 1;
+                    let mut r = &x;
+                    {}
+                    print_int(*r);
+                }}
+                "#,
+                body
+            )
+        };
+        // Where `y` is dropped, and where it was borrowed
+        let dropped = |body: &str| match check_source(&source(body)) {
+            Err(CompileError::DroppedWhileBorrowed {
+                name,
+                borrowed_at,
+                span,
+            }) => {
+                assert_eq!(name, "y");
+                let span = span.expect("the drop has a location");
+                let borrowed_at = borrowed_at.expect("the borrow has a location");
+                (
+                    (span.line, span.column),
+                    (borrowed_at.line, borrowed_at.column),
+                )
+            }
+            other => panic!("expected `y` to be dropped while borrowed, got {:?}", other),
+        };
+        // The body is on line 5, from column 21; its last brace closes `y`'s block
+        let column = |body: &str, part: &str| body.find(part).unwrap() + 21;
+        let end = |body: &str| body.rfind('}').unwrap() + 21;
+
+        // A reference escaping an `if` or a loop body outlives what it borrows,
+        // which is dropped at the closing brace of its block
+        let body = "if true { let y = 5; r = &y; }";
+        assert_eq!(dropped(body), ((5, end(body)), (5, column(body, "&y"))));
+        let body = "if true { let z = 1; } else { let y = 5; r = &y; }";
+        assert_eq!(dropped(body).0, (5, end(body)));
+        let body = "while true { let y = 5; r = &y; }";
+        assert_eq!(dropped(body).0, (5, end(body)));
+        let body = "while true { let y = 5; r = &y; break; }";
+        assert_eq!(dropped(body).0, (5, column(body, "break")));
+
+        // A local shadowing an outer one of the same name is dropped all the same
+        let body = "let y = 1; r = &y; if true { let y = 5; r = &y; }";
+        assert_eq!(dropped(body).0, (5, end(body)));
+        let body = "let y = 1; if true { let y = 5; r = &y; let y = 6; }";
+        assert_eq!(dropped(body).0, (5, end(body)));
+        let body = "let y = 1; if true { let y = 5; } r = &y;";
+        assert!(check_source(&source(body)).is_ok());
+
+        // Borrows that end inside the block, or of outer values, are fine
+        assert!(check_source(&source("if true { let y = 5; let s = &y; print_int(*s); }")).is_ok());
+        assert!(check_source(&source("while true { let y = 5; r = &x; }")).is_ok());
+    }
+
+    #[test]
+    fn test_mutable_borrow_needs_mut_binding() {
+        let not_mutable = |result: Result<()>| match result {
+            Err(CompileError::BorrowChecker { message, .. }) => message,
+            other => panic!("expected a mutable borrow to be rejected, got {:?}", other),
+        };
+
+        // A `ref mut` argument, a built-in's included, needs a `mut` binding
+        let message = not_mutable(check_source(
+            r#"
+            fn main() {
+                let s = "a";
+                string_push_str(s, "b");
+            }
+            "#,
+        ));
+        assert_eq!(
+            message,
+            "cannot borrow `s` as mutable, as `s` is not declared as mutable"
+        );
+        not_mutable(check_source(
+            r#"
+            fn push(s: ref mut String) {
+                string_push_str(s, "b");
+            }
+            fn main() {
+                let s = "a";
+                push(s);
+            }
+            "#,
+        ));
+        not_mutable(check_source(
+            r#"
+            fn main() {
+                let x = 1;
+                let r = &mut x;
+            }
+            "#,
+        ));
+
+        // ... or one reached through a `ref mut`, not a `ref`
+        let message = not_mutable(check_source(
+            r#"
+            fn push(s: ref String) {
+                string_push_str(s, "b");
+            }
+            "#,
+        ));
+        assert_eq!(
+            message,
+            "cannot borrow `s` as mutable, as it is behind a `ref` reference"
+        );
+
+        assert!(check_source(
+            r#"
+            fn push(s: ref mut String) {
+                string_push_str(s, "b");
+            }
+            fn main() {
+                let mut s = "a";
+                push(s);
+                string_push_str(s, "c");
+                let r = &mut s;
+            }
+            "#,
+        )
+        .is_ok());
+    }
 }
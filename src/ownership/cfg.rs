@@ -0,0 +1,465 @@
+// Control-flow graphs for Palladium borrow checking
+// "A borrow lasts exactly as long as someone still needs it"
+
+use super::{Place, RefKind};
+use crate::errors::Span;
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+/// Index of a basic block in a [`Cfg`]
+pub type BlockId = usize;
+
+/// Index of a loan in [`Cfg::loans`]
+pub type LoanId = usize;
+
+/// A program point: a block and the index of an event in it
+pub type Point = (BlockId, usize);
+
+/// An ownership-relevant action at one program point
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// A place is read (copied, compared, passed to a Copy parameter)
+    Read { place: Place, span: Span },
+    /// The value in a place is moved out
+    Move { place: Place, span: Span },
+    /// A place is borrowed; the loan stays active while a live local holds it
+    Borrow {
+        place: Place,
+        kind: RefKind,
+        loan: LoanId,
+        span: Span,
+    },
+    /// A place is (re)initialized. Afterwards it holds `loans` plus every loan
+    /// held by the `sources` locals the value was copied or moved from.
+    Write {
+        place: Place,
+        loans: Vec<LoanId>,
+        sources: Vec<String>,
+        span: Span,
+    },
+    /// A value carrying `loans` and the loans of `sources` outlives the function:
+    /// it is returned (`place` is the return slot), spawned as a detached task
+    /// (the spawn slot) or stored through the reference `place`
+    Escape {
+        place: Place,
+        loans: Vec<LoanId>,
+        sources: Vec<String>,
+        span: Span,
+    },
+    /// A local goes out of scope; nothing may still borrow it
+    Dead { place: Place, span: Span },
+}
+
+impl Event {
+    pub fn place(&self) -> &Place {
+        match self {
+            Event::Read { place, .. }
+            | Event::Move { place, .. }
+            | Event::Borrow { place, .. }
+            | Event::Write { place, .. }
+            | Event::Escape { place, .. }
+            | Event::Dead { place, .. } => place,
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        match self {
+            Event::Read { span, .. }
+            | Event::Move { span, .. }
+            | Event::Borrow { span, .. }
+            | Event::Write { span, .. }
+            | Event::Escape { span, .. }
+            | Event::Dead { span, .. } => *span,
+        }
+    }
+
+    /// The local whose current value this event uses
+    fn used_local(&self) -> Option<&str> {
+        match self {
+            Event::Read { place, .. } | Event::Move { place, .. } | Event::Borrow { place, .. } => {
+                place.root()
+            }
+            Event::Write { .. } | Event::Escape { .. } | Event::Dead { .. } => None,
+        }
+    }
+
+    /// The local this event overwrites completely
+    fn defined_local(&self) -> Option<&str> {
+        match self {
+            Event::Write {
+                place: Place::Local(name),
+                ..
+            } => Some(name),
+            _ => None,
+        }
+    }
+}
+
+/// A borrow created somewhere in the function
+#[derive(Debug, Clone)]
+pub struct Loan {
+    pub place: Place,
+    pub kind: RefKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BasicBlock {
+    pub events: Vec<Event>,
+    pub succs: Vec<BlockId>,
+}
+
+/// Control-flow graph of one function body
+#[derive(Debug, Clone)]
+pub struct Cfg {
+    pub blocks: Vec<BasicBlock>,
+    pub loans: Vec<Loan>,
+}
+
+impl Default for Cfg {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A forward dataflow problem over a [`Cfg`]
+pub trait Analysis {
+    type State: Clone + PartialEq + Default;
+
+    /// Merge the state flowing in along another edge
+    fn join(&self, into: &mut Self::State, other: &Self::State);
+
+    /// Apply one event to the state
+    fn transfer(&self, state: &mut Self::State, event: &Event, point: Point);
+}
+
+impl Cfg {
+    pub const ENTRY: BlockId = 0;
+
+    pub fn new() -> Self {
+        Self {
+            blocks: vec![BasicBlock::default()],
+            loans: Vec::new(),
+        }
+    }
+
+    pub fn new_block(&mut self) -> BlockId {
+        self.blocks.push(BasicBlock::default());
+        self.blocks.len() - 1
+    }
+
+    pub fn push(&mut self, block: BlockId, event: Event) {
+        self.blocks[block].events.push(event);
+    }
+
+    pub fn add_edge(&mut self, from: BlockId, to: BlockId) {
+        if !self.blocks[from].succs.contains(&to) {
+            self.blocks[from].succs.push(to);
+        }
+    }
+
+    pub fn new_loan(&mut self, place: Place, kind: RefKind, span: Span) -> LoanId {
+        self.loans.push(Loan { place, kind, span });
+        self.loans.len() - 1
+    }
+
+    /// Which blocks can be reached from the entry
+    pub fn reachable(&self) -> Vec<bool> {
+        let mut seen = vec![false; self.blocks.len()];
+        let mut stack = vec![Self::ENTRY];
+        while let Some(block) = stack.pop() {
+            if !std::mem::replace(&mut seen[block], true) {
+                stack.extend(&self.blocks[block].succs);
+            }
+        }
+        seen
+    }
+
+    fn predecessors(&self) -> Vec<Vec<BlockId>> {
+        let mut preds = vec![Vec::new(); self.blocks.len()];
+        for (block, data) in self.blocks.iter().enumerate() {
+            for &succ in &data.succs {
+                preds[succ].push(block);
+            }
+        }
+        preds
+    }
+
+    /// Solve a forward dataflow problem, returning the state on entry to each block
+    pub fn forward<A: Analysis>(&self, analysis: &A) -> Vec<A::State> {
+        let preds = self.predecessors();
+        let reachable = self.reachable();
+        let mut entry = vec![A::State::default(); self.blocks.len()];
+        // Exit states of blocks not yet visited are unknown, not empty
+        let mut exit: Vec<Option<A::State>> = vec![None; self.blocks.len()];
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for block in (0..self.blocks.len()).filter(|&b| reachable[b]) {
+                let mut incoming = preds[block].iter().filter_map(|&p| exit[p].as_ref());
+                let mut state = match incoming.next() {
+                    Some(first) => first.clone(),
+                    None if block == Self::ENTRY => A::State::default(),
+                    // Wait until some predecessor has been visited
+                    None => continue,
+                };
+                for other in incoming {
+                    analysis.join(&mut state, other);
+                }
+                entry[block] = state.clone();
+                for (index, event) in self.blocks[block].events.iter().enumerate() {
+                    analysis.transfer(&mut state, event, (block, index));
+                }
+                if exit[block].as_ref() != Some(&state) {
+                    exit[block] = Some(state);
+                    changed = true;
+                }
+            }
+        }
+        entry
+    }
+
+    /// Locals whose current value is used again later, after each event
+    pub fn live_after(&self) -> Vec<Vec<HashSet<String>>> {
+        let mut live_in: Vec<HashSet<String>> = vec![HashSet::new(); self.blocks.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for block in (0..self.blocks.len()).rev() {
+                let mut live = self.live_out(block, &live_in);
+                for event in self.blocks[block].events.iter().rev() {
+                    Self::live_transfer(&mut live, event);
+                }
+                if live != live_in[block] {
+                    live_in[block] = live;
+                    changed = true;
+                }
+            }
+        }
+
+        (0..self.blocks.len())
+            .map(|block| {
+                let events = &self.blocks[block].events;
+                let mut live = self.live_out(block, &live_in);
+                let mut after = vec![HashSet::new(); events.len()];
+                for (index, event) in events.iter().enumerate().rev() {
+                    after[index] = live.clone();
+                    Self::live_transfer(&mut live, event);
+                }
+                after
+            })
+            .collect()
+    }
+
+    fn live_out(&self, block: BlockId, live_in: &[HashSet<String>]) -> HashSet<String> {
+        self.blocks[block]
+            .succs
+            .iter()
+            .flat_map(|&succ| live_in[succ].iter().cloned())
+            .collect()
+    }
+
+    fn live_transfer(live: &mut HashSet<String>, event: &Event) {
+        if let Some(local) = event.defined_local() {
+            live.remove(local);
+        }
+        if let Some(local) = event.used_local() {
+            live.insert(local.to_string());
+        }
+    }
+}
+
+/// Loans held by each local
+pub type HeldLoans = HashMap<String, BTreeSet<LoanId>>;
+
+/// Tracks which locals hold which loans
+pub struct LoanFlow;
+
+impl Analysis for LoanFlow {
+    type State = HeldLoans;
+
+    fn join(&self, into: &mut HeldLoans, other: &HeldLoans) {
+        for (local, loans) in other {
+            into.entry(local.clone()).or_default().extend(loans);
+        }
+    }
+
+    fn transfer(&self, state: &mut HeldLoans, event: &Event, _point: Point) {
+        if let Event::Write {
+            place,
+            loans,
+            sources,
+            ..
+        } = event
+        {
+            let mut held: BTreeSet<LoanId> = loans.iter().copied().collect();
+            for source in sources {
+                if let Some(loans) = state.get(source) {
+                    held.extend(loans);
+                }
+            }
+            match (place, place.root()) {
+                (Place::Local(name), _) => {
+                    state.insert(name.clone(), held);
+                }
+                // A reference stored into a field is held by the whole local
+                (_, Some(root)) => state.entry(root.to_string()).or_default().extend(held),
+                (_, None) => {}
+            }
+        }
+    }
+}
+
+/// What the paths reaching a point did to a place
+#[derive(Debug, Clone, PartialEq)]
+pub struct InitState {
+    /// Some path initialized the place and hasn't moved it since
+    pub init: bool,
+    /// Some path never initialized it
+    pub uninit: bool,
+    /// Where paths that moved the value did so
+    pub moves: Vec<(Point, Span)>,
+}
+
+impl InitState {
+    const UNINIT: InitState = InitState {
+        init: false,
+        uninit: true,
+        moves: Vec::new(),
+    };
+
+    fn join(&mut self, other: &InitState) {
+        self.init |= other.init;
+        self.uninit |= other.uninit;
+        for mv in &other.moves {
+            if !self.moves.contains(mv) {
+                self.moves.push(*mv);
+            }
+        }
+    }
+}
+
+/// Initialization state of places; places without an entry share their base's
+pub type InitMap = HashMap<Place, InitState>;
+
+/// State of a place, falling back on its base for fields and elements
+pub fn init_state(map: &InitMap, place: &Place) -> InitState {
+    match (map.get(place), place) {
+        (Some(state), _) => state.clone(),
+        (None, Place::Field { base, .. } | Place::Index { base, .. }) => init_state(map, base),
+        (None, _) => InitState::UNINIT,
+    }
+}
+
+/// Tracks initialization and moves of every place
+pub struct InitFlow;
+
+impl Analysis for InitFlow {
+    type State = InitMap;
+
+    fn join(&self, into: &mut InitMap, other: &InitMap) {
+        let places: Vec<Place> = into.keys().chain(other.keys()).cloned().collect();
+        let mut joined = InitMap::new();
+        for place in places {
+            let mut state = init_state(into, &place);
+            state.join(&init_state(other, &place));
+            joined.insert(place, state);
+        }
+        *into = joined;
+    }
+
+    fn transfer(&self, state: &mut InitMap, event: &Event, point: Point) {
+        match event {
+            Event::Move { place, span } => {
+                state.insert(
+                    place.clone(),
+                    InitState {
+                        init: false,
+                        uninit: false,
+                        moves: vec![(point, *span)],
+                    },
+                );
+            }
+            Event::Write { place, .. } => {
+                state.retain(|other, _| !other.is_within(place));
+                state.insert(
+                    place.clone(),
+                    InitState {
+                        init: true,
+                        uninit: false,
+                        moves: Vec::new(),
+                    },
+                );
+            }
+            // The next value a local out of scope gets is a fresh one
+            Event::Dead { place, .. } => {
+                state.retain(|other, _| !other.is_within(place));
+                state.insert(place.clone(), InitState::UNINIT);
+            }
+            Event::Read { .. } | Event::Borrow { .. } | Event::Escape { .. } => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local(name: &str) -> Place {
+        Place::Local(name.to_string())
+    }
+
+    fn write(name: &str, loans: Vec<LoanId>) -> Event {
+        Event::Write {
+            place: local(name),
+            loans,
+            sources: vec![],
+            span: Span::dummy(),
+        }
+    }
+
+    fn read(name: &str) -> Event {
+        Event::Read {
+            place: local(name),
+            span: Span::dummy(),
+        }
+    }
+
+    #[test]
+    fn test_liveness_ends_at_last_use() {
+        // r = &x; read r; read x
+        let mut cfg = Cfg::new();
+        let loan = cfg.new_loan(local("x"), RefKind::Mutable, Span::dummy());
+        cfg.push(Cfg::ENTRY, write("x", vec![]));
+        cfg.push(Cfg::ENTRY, write("r", vec![loan]));
+        cfg.push(Cfg::ENTRY, read("r"));
+        cfg.push(Cfg::ENTRY, read("x"));
+
+        let live = cfg.live_after();
+        assert!(live[0][1].contains("r"));
+        assert!(!live[0][2].contains("r"));
+    }
+
+    #[test]
+    fn test_moves_join_across_branches() {
+        let mut cfg = Cfg::new();
+        let then_block = cfg.new_block();
+        let join = cfg.new_block();
+        cfg.push(Cfg::ENTRY, write("s", vec![]));
+        cfg.add_edge(Cfg::ENTRY, then_block);
+        cfg.add_edge(Cfg::ENTRY, join);
+        cfg.push(
+            then_block,
+            Event::Move {
+                place: local("s"),
+                span: Span::dummy(),
+            },
+        );
+        cfg.add_edge(then_block, join);
+
+        let entry = cfg.forward(&InitFlow);
+        let state = init_state(&entry[join], &local("s"));
+        assert!(state.init);
+        assert_eq!(state.moves.len(), 1);
+    }
+}
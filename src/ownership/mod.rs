@@ -2,6 +2,7 @@
 // "Every value has a single owner"
 
 pub mod borrow_checker;
+pub mod cfg;
 
 pub use borrow_checker::BorrowChecker;
 
@@ -77,6 +78,8 @@ pub struct OwnershipContext {
     ownership: HashMap<Place, Ownership>,
     /// Active borrows
     borrows: Vec<Borrow>,
+    /// Where each moved place was moved
+    moves: HashMap<Place, Span>,
     /// Current scope ID
     current_scope: u32,
     /// Next anonymous lifetime ID
@@ -140,10 +143,11 @@ impl OwnershipContext {
     /// Move a value from one place to another
     pub fn move_value(&mut self, from: Place, to: Place, span: Span) -> Result<()> {
         // Check if the source can be moved
-        match self.ownership.get(&from) {
+        match self.state_of(&from) {
             Some(Ownership::Owned) => {
                 // Move is allowed
                 self.ownership.insert(from.clone(), Ownership::Moved);
+                self.moves.insert(from.clone(), span);
                 self.ownership.insert(to, Ownership::Owned);
                 Ok(())
             }
@@ -155,6 +159,7 @@ impl OwnershipContext {
             }
             Some(Ownership::Moved) => Err(CompileError::UseOfMovedValue {
                 name: from.to_string(),
+                moved_at: self.moved_at(&from),
                 span: Some(span),
             }),
             None => Err(CompileError::UseOfUninitializedValue {
@@ -173,11 +178,11 @@ impl OwnershipContext {
         span: Span,
     ) -> Result<()> {
         // Check if the place can be borrowed
-        match self.ownership.get(&place) {
+        match self.state_of(&place) {
             Some(Ownership::Owned) | Some(Ownership::Borrowed { .. }) => {
                 // Check for conflicting borrows
                 for existing_borrow in &self.borrows {
-                    if existing_borrow.place == place {
+                    if existing_borrow.place.overlaps(&place) {
                         match (&existing_borrow.kind, &kind) {
                             (RefKind::Mutable, _) | (_, RefKind::Mutable) => {
                                 return Err(CompileError::ConflictingBorrows {
@@ -186,6 +191,7 @@ impl OwnershipContext {
                                         if kind == RefKind::Mutable { "mutable" } else { "immutable" },
                                         if existing_borrow.kind == RefKind::Mutable { "mutable" } else { "immutable" }
                                     ),
+                                    borrowed_at: Some(existing_borrow.span),
                                     span: Some(span),
                                 });
                             }
@@ -226,10 +232,16 @@ impl OwnershipContext {
                     "cannot borrow `{}` because it is already mutably borrowed",
                     place
                 ),
+                borrowed_at: self
+                    .borrows
+                    .iter()
+                    .find(|borrow| borrow.kind == RefKind::Mutable && borrow.place.overlaps(&place))
+                    .map(|borrow| borrow.span),
                 span: Some(span),
             }),
             Some(Ownership::Moved) => Err(CompileError::UseOfMovedValue {
                 name: place.to_string(),
+                moved_at: self.moved_at(&place),
                 span: Some(span),
             }),
             None => Err(CompileError::UseOfUninitializedValue {
@@ -254,6 +266,67 @@ impl OwnershipContext {
     pub fn get_ownership(&self, place: &Place) -> Option<&Ownership> {
         self.ownership.get(place)
     }
+
+    /// Where a moved place, or the value it is part of, was moved
+    fn moved_at(&self, place: &Place) -> Option<Span> {
+        match (self.moves.get(place), place) {
+            (Some(span), _) => Some(*span),
+            (None, Place::Field { base, .. } | Place::Index { base, .. }) => self.moved_at(base),
+            (None, _) => None,
+        }
+    }
+
+    /// State of a place; fields and elements without their own state share their base's
+    fn state_of(&self, place: &Place) -> Option<&Ownership> {
+        match (self.ownership.get(place), place) {
+            (Some(state), _) => Some(state),
+            (None, Place::Field { base, .. } | Place::Index { base, .. }) => self.state_of(base),
+            (None, _) => None,
+        }
+    }
+}
+
+impl Place {
+    /// The local variable this place is part of
+    pub fn root(&self) -> Option<&str> {
+        match self {
+            Place::Local(name) => Some(name),
+            Place::Field { base, .. } | Place::Index { base, .. } => base.root(),
+            Place::Temp(_) => None,
+        }
+    }
+
+    /// Whether this place is `other` or lies inside it
+    pub fn is_within(&self, other: &Place) -> bool {
+        self == other
+            || match self {
+                Place::Field { base, .. } | Place::Index { base, .. } => base.is_within(other),
+                _ => false,
+            }
+    }
+
+    /// Whether the two places may share memory, i.e. one is a prefix of the other.
+    /// Elements of the same array are assumed to overlap.
+    pub fn overlaps(&self, other: &Place) -> bool {
+        let (ours, theirs) = (self.projections(), other.projections());
+        ours.iter().zip(&theirs).all(|pair| match pair {
+            (Place::Local(a), Place::Local(b)) => a == b,
+            (Place::Field { field: a, .. }, Place::Field { field: b, .. }) => a == b,
+            (Place::Index { .. }, Place::Index { .. }) => true,
+            (Place::Temp(a), Place::Temp(b)) => a == b,
+            _ => false,
+        })
+    }
+
+    /// The place and its bases, outermost base first
+    fn projections(&self) -> Vec<&Place> {
+        let mut places = match self {
+            Place::Field { base, .. } | Place::Index { base, .. } => base.projections(),
+            Place::Local(_) | Place::Temp(_) => Vec::new(),
+        };
+        places.push(self);
+        places
+    }
 }
 
 /// Convert expression to a place (if possible)
@@ -284,10 +357,16 @@ pub fn expr_to_place(expr: &Expr) -> Option<Place> {
     }
 }
 
+/// Name a local was declared with. The borrow checker gives each redeclaration
+/// of a name its own local, told apart by a `#n` suffix
+pub fn local_name(local: &str) -> &str {
+    local.split('#').next().unwrap_or(local)
+}
+
 impl std::fmt::Display for Place {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Place::Local(name) => write!(f, "{}", name),
+            Place::Local(name) => write!(f, "{}", local_name(name)),
             Place::Field { base, field } => write!(f, "{}.{}", base, field),
             Place::Index { base, index } => write!(f, "{}[{}]", base, index),
             Place::Temp(id) => write!(f, "_temp{}", id),
@@ -346,4 +425,19 @@ mod tests {
         let result = ctx.borrow(x.clone(), RefKind::Mutable, lifetime, Span::dummy());
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_place_overlaps() {
+        let field = |base: &Place, name: &str| Place::Field {
+            base: Box::new(base.clone()),
+            field: name.to_string(),
+        };
+        let p = Place::Local("p".to_string());
+        let buf = field(&field(&p, "inner"), "buf");
+
+        assert!(buf.overlaps(&p));
+        assert!(p.overlaps(&buf));
+        assert!(!buf.overlaps(&field(&p, "n")));
+        assert!(!p.overlaps(&Place::Local("q".to_string())));
+    }
 }
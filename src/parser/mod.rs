@@ -12,6 +12,8 @@ pub struct Parser {
     type_params_in_scope: Vec<String>,
     /// Cache for current token to avoid repeated bounds checking
     current_token_cache: Option<(Token, Span)>,
+    /// False while parsing `if`/`while`/`for`/`match` heads, where `{` starts the body
+    struct_literals_allowed: bool,
 }
 
 impl Parser {
@@ -27,9 +29,31 @@ impl Parser {
             current: 0,
             type_params_in_scope: Vec::new(),
             current_token_cache,
+            struct_literals_allowed: true,
         }
     }
 
+    /// Parse the head of `if`/`while`/`for`/`match`, where `x {` is never a struct literal
+    fn parse_condition(&mut self) -> Result<Expr> {
+        self.with_struct_literals(false, Self::parse_expression)
+    }
+
+    /// Parse an expression inside delimiters, where struct literals are allowed again
+    fn parse_delimited_expression(&mut self) -> Result<Expr> {
+        self.with_struct_literals(true, Self::parse_expression)
+    }
+
+    fn with_struct_literals(
+        &mut self,
+        allowed: bool,
+        parse: impl FnOnce(&mut Self) -> Result<Expr>,
+    ) -> Result<Expr> {
+        let saved = std::mem::replace(&mut self.struct_literals_allowed, allowed);
+        let result = parse(self);
+        self.struct_literals_allowed = saved;
+        result
+    }
+
     /// Parse generic parameters (<'a, T, const N: usize>)
     #[allow(clippy::type_complexity)]
     fn parse_generic_params(&mut self) -> Result<(Vec<String>, Vec<String>, Vec<(String, Type)>)> {
@@ -149,6 +173,7 @@ impl Parser {
             Expr::Bool(_) => Span::dummy(),
             Expr::Ident(_) => Span::dummy(),
             // Expressions with span field
+            Expr::Turbofish { span, .. } => *span,
             Expr::Binary { span, .. } => *span,
             Expr::Unary { span, .. } => *span,
             Expr::Call { span, .. } => *span,
@@ -167,6 +192,14 @@ impl Parser {
         }
     }
 
+    /// Start of an expression, or `fallback` for expressions without a span
+    fn expr_start(expr: &Expr, fallback: Span) -> Span {
+        match Self::expr_span(expr) {
+            span if span == Span::dummy() => fallback,
+            span => span,
+        }
+    }
+
     /// Parse a complete program
     pub fn parse(&mut self) -> Result<Program> {
         let mut imports = Vec::new();
@@ -389,6 +422,16 @@ impl Parser {
                 Ok(Item::TypeAlias(type_alias))
             }
             Token::Macro => Ok(Item::Macro(self.parse_macro()?)),
+            // `effect` is only a keyword in front of an effect's name
+            Token::Identifier(name)
+                if name == "effect"
+                    && !is_async
+                    && self.check_at(1, &Token::Identifier(String::new())) =>
+            {
+                let mut effect_def = self.parse_effect()?;
+                effect_def.visibility = visibility;
+                Ok(Item::Effect(effect_def))
+            }
             _ => {
                 if is_async {
                     Err(CompileError::SyntaxError {
@@ -397,7 +440,7 @@ impl Parser {
                     })
                 } else {
                     Err(CompileError::SyntaxError {
-                        message: "Expected function, struct, enum, trait, type, impl, or macro declaration".to_string(),
+                        message: "Expected function, struct, enum, trait, type, impl, macro, or effect declaration".to_string(),
                         span: self.current_span(),
                     })
                 }
@@ -421,7 +464,7 @@ impl Parser {
         };
 
         // Parse generic parameters (lifetimes, types, and consts) if present
-        let (lifetime_params, type_params, const_params) = self.parse_generic_params()?;
+        let (lifetime_params, mut type_params, const_params) = self.parse_generic_params()?;
 
         // Set type parameters in scope for parsing function signature and body
         self.type_params_in_scope = type_params.clone();
@@ -521,6 +564,23 @@ impl Parser {
             None
         };
 
+        // Parse declared effects if present, like `![io, async]`
+        let effects = self.parse_effect_clause()?;
+
+        // Generic parameters naming effects rather than types are effect
+        // variables, like the `e` of `fn apply<e>(f: fn() ! e) ! e`
+        let mut effect_names: Vec<&String> = effects.iter().flatten().collect();
+        for param in &params {
+            collect_effect_names(&param.ty, &mut effect_names);
+        }
+        if let Some(return_type) = &return_type {
+            collect_effect_names(return_type, &mut effect_names);
+        }
+        let (effect_params, kept): (Vec<String>, Vec<String>) = type_params
+            .into_iter()
+            .partition(|param| effect_names.contains(&param));
+        type_params = kept;
+
         self.consume(Token::LeftBrace, "Expected '{'")?;
 
         let body = self.parse_block_with_implicit_return()?;
@@ -546,10 +606,157 @@ impl Parser {
                 start_span.line,
                 start_span.column,
             ),
-            effects: None, // Effects will be inferred during analysis
+            effects, // Inferred during analysis when not declared
+            effect_params,
+        })
+    }
+
+    /// Parse an effect declaration: `effect Log { fn log(msg: String); }`
+    fn parse_effect(&mut self) -> Result<EffectDef> {
+        let start_span = self.current_span().unwrap_or_else(Span::dummy);
+        self.advance()?; // consume 'effect'
+
+        let name = match self.advance()? {
+            (Token::Identifier(name), _) => name,
+            (token, _) => {
+                return Err(CompileError::UnexpectedToken {
+                    expected: "effect name".to_string(),
+                    found: token.to_string(),
+                    span: self.current_span(),
+                });
+            }
+        };
+
+        self.consume(Token::LeftBrace, "Expected '{' after effect name")?;
+
+        let mut operations = Vec::new();
+        while !self.check(&Token::RightBrace) && !self.is_at_end() {
+            let operation_start = self.consume(Token::Fn, "Expected 'fn' for effect operation")?;
+
+            let operation_name = match self.advance()? {
+                (Token::Identifier(name), _) => name,
+                (token, _) => {
+                    return Err(CompileError::UnexpectedToken {
+                        expected: "operation name".to_string(),
+                        found: token.to_string(),
+                        span: self.current_span(),
+                    });
+                }
+            };
+
+            self.consume(Token::LeftParen, "Expected '(' after operation name")?;
+            let mut params = Vec::new();
+            while !self.check(&Token::RightParen) {
+                let param_name = match self.advance()? {
+                    (Token::Identifier(name), _) => name,
+                    (token, _) => {
+                        return Err(CompileError::UnexpectedToken {
+                            expected: "parameter name".to_string(),
+                            found: token.to_string(),
+                            span: self.current_span(),
+                        });
+                    }
+                };
+                self.consume(Token::Colon, "Expected ':' after parameter name")?;
+                params.push(Param {
+                    name: param_name,
+                    ty: self.parse_type()?,
+                    mutable: false,
+                });
+
+                if !self.check(&Token::Comma) {
+                    break;
+                }
+                self.advance()?; // consume ','
+            }
+            self.consume(Token::RightParen, "Expected ')'")?;
+
+            let return_type = if self.check(&Token::Arrow) {
+                self.advance()?; // consume '->'
+                Some(self.parse_type()?)
+            } else {
+                None
+            };
+
+            let operation_end =
+                self.consume(Token::Semicolon, "Expected ';' after effect operation")?;
+
+            operations.push(EffectOperation {
+                name: operation_name,
+                params,
+                return_type,
+                span: Span::new(
+                    operation_start.start,
+                    operation_end.end,
+                    operation_start.line,
+                    operation_start.column,
+                ),
+            });
+        }
+
+        let end_span = self.consume(Token::RightBrace, "Expected '}' after effect operations")?;
+
+        Ok(EffectDef {
+            visibility: crate::ast::Visibility::Private, // Will be set by caller
+            name,
+            operations,
+            span: Span::new(
+                start_span.start,
+                end_span.end,
+                start_span.line,
+                start_span.column,
+            ),
         })
     }
 
+    /// Parse the effects of a function or function type, if declared: `![io, e]`,
+    /// `![]` for pure, or `! e` for a single effect
+    fn parse_effect_clause(&mut self) -> Result<Option<Vec<String>>> {
+        if !self.check(&Token::Not) {
+            return Ok(None);
+        }
+        self.advance()?; // consume '!'
+
+        if let Token::Identifier(name) = self.peek()? {
+            let effect = name.clone();
+            self.advance()?;
+            return Ok(Some(vec![effect]));
+        }
+        self.parse_effect_list().map(Some)
+    }
+
+    /// Parse a list of effects: `[io, async]`, or `[]` for pure
+    fn parse_effect_list(&mut self) -> Result<Vec<String>> {
+        self.consume(Token::LeftBracket, "Expected '[' after '!'")?;
+
+        let mut effects = Vec::new();
+        while !self.check(&Token::RightBracket) {
+            // `async` and `unsafe` are keywords, the other effects identifiers
+            let effect = match self.peek()? {
+                Token::Identifier(name) => name.clone(),
+                Token::Async => "async".to_string(),
+                Token::Unsafe => "unsafe".to_string(),
+                token => {
+                    return Err(CompileError::UnexpectedToken {
+                        expected: "effect name".to_string(),
+                        found: token.to_string(),
+                        span: self.current_span(),
+                    });
+                }
+            };
+            self.advance()?;
+            effects.push(effect);
+
+            if !self.check(&Token::Comma) {
+                break;
+            }
+            self.advance()?; // consume ','
+        }
+
+        self.consume(Token::RightBracket, "Expected ']' after effects")?;
+        Ok(effects)
+    }
+
     /// Parse a struct definition
     fn parse_struct(&mut self) -> Result<StructDef> {
         let start_span = self.consume(Token::Struct, "Expected 'struct'")?;
@@ -571,6 +778,7 @@ impl Parser {
         self.consume(Token::LeftBrace, "Expected '{' after struct name")?;
 
         let mut fields = Vec::new();
+        let mut field_defaults = Vec::new();
 
         while !self.check(&Token::RightBrace) && !self.is_at_end() {
             // Parse field name
@@ -588,6 +796,12 @@ impl Parser {
             self.consume(Token::Colon, "Expected ':' after field name")?;
             let field_type = self.parse_type()?;
 
+            // Optional default value: `name: Type = value`
+            if self.check(&Token::Eq) {
+                self.advance()?;
+                field_defaults.push((field_name.clone(), self.parse_expression()?));
+            }
+
             fields.push((field_name, field_type));
 
             // Fields are separated by commas
@@ -605,6 +819,7 @@ impl Parser {
             type_params,
             const_params,
             fields,
+            field_defaults,
             span: Span::new(
                 start_span.start,
                 end_span.end,
@@ -862,6 +1077,34 @@ impl Parser {
 
             if !self.check(&Token::RightParen) {
                 loop {
+                    // Methods taking `self` by value or by reference
+                    if self.check(&Token::SelfParam) || self.check(&Token::Ampersand) {
+                        let mut ty = Type::Custom("Self".to_string());
+                        if self.check(&Token::Ampersand) {
+                            self.advance()?;
+                            let mutable = self.check(&Token::Mut);
+                            if mutable {
+                                self.advance()?;
+                            }
+                            ty = Type::Reference {
+                                lifetime: None,
+                                mutable,
+                                inner: Box::new(ty),
+                            };
+                        }
+                        self.consume(Token::SelfParam, "Expected 'self'")?;
+                        params.push(Param {
+                            name: "self".to_string(),
+                            ty,
+                            mutable: false,
+                        });
+                        if !self.check(&Token::Comma) {
+                            break;
+                        }
+                        self.advance()?;
+                        continue;
+                    }
+
                     let mutable = if self.check(&Token::Mut) {
                         self.advance()?;
                         true
@@ -1236,7 +1479,7 @@ impl Parser {
 
         match token {
             Token::Identifier(s) => AstToken::Ident(s),
-            Token::String(s) => AstToken::Literal(format!("\"{}\"", s)),
+            Token::String(_) => AstToken::Literal(token.source_text()),
             Token::Integer(n) => AstToken::Literal(n.to_string()),
             Token::True => AstToken::Literal("true".to_string()),
             Token::False => AstToken::Literal("false".to_string()),
@@ -1255,7 +1498,8 @@ impl Parser {
             Token::Slash => AstToken::Punct('/'),
             Token::Not => AstToken::Punct('!'),
             Token::Eq => AstToken::Punct('='),
-            _ => AstToken::Ident(format!("{:?}", token)), // Fallback for other tokens
+            // Other tokens keep their source spelling so they re-lex unchanged
+            _ => AstToken::Ident(token.source_text()),
         }
     }
 
@@ -1305,16 +1549,20 @@ impl Parser {
             Token::Continue => self.parse_continue(),
             Token::Match => self.parse_match(),
             Token::Unsafe => self.parse_unsafe(),
-            Token::Identifier(_) | Token::Star => {
+            Token::Async => self.parse_async_scope(),
+            Token::Identifier(name) if name == "handle" && self.check_at(1, &Token::LeftBrace) => {
+                self.parse_handle()
+            }
+            Token::Identifier(_) | Token::SelfParam | Token::Star => {
                 // Could be assignment or expression statement
                 // Parse the left-hand side as an expression first
                 let checkpoint = self.current;
+                let start_span = self.current_span().unwrap_or_else(Span::dummy);
                 let expr = self.parse_expression()?; // Parse full expression including dereference
 
                 // Check if this is an assignment
                 if self.check(&Token::Eq) && !self.check_at(1, &Token::Eq) {
                     // This is an assignment
-                    let start_span = expr.span();
                     self.advance()?; // consume '='
                     let value = self.parse_expression()?;
                     let end_span =
@@ -1430,21 +1678,22 @@ impl Parser {
     fn parse_if(&mut self) -> Result<Stmt> {
         let start_span = self.consume(Token::If, "Expected 'if'")?;
 
-        let condition = self.parse_expression()?;
+        let condition = self.parse_condition()?;
 
         self.consume(Token::LeftBrace, "Expected '{' after if condition")?;
 
         let then_branch = self.parse_block_with_implicit_return()?;
 
-        self.consume(Token::RightBrace, "Expected '}' after if body")?;
+        let then_end = self.consume(Token::RightBrace, "Expected '}' after if body")?;
 
+        let mut else_end = None;
         let else_branch = if self.check(&Token::Else) {
             self.advance()?; // consume 'else'
             self.consume(Token::LeftBrace, "Expected '{' after else")?;
 
             let else_stmts = self.parse_block_with_implicit_return()?;
 
-            let _end_span = self.consume(Token::RightBrace, "Expected '}' after else body")?;
+            else_end = Some(self.consume(Token::RightBrace, "Expected '}' after else body")?);
             Some(else_stmts)
         } else {
             None
@@ -1462,6 +1711,8 @@ impl Parser {
                 start_span.line,
                 start_span.column,
             ),
+            then_end,
+            else_end,
         })
     }
 
@@ -1469,7 +1720,7 @@ impl Parser {
     fn parse_while(&mut self) -> Result<Stmt> {
         let start_span = self.consume(Token::While, "Expected 'while'")?;
 
-        let condition = self.parse_expression()?;
+        let condition = self.parse_condition()?;
 
         self.consume(Token::LeftBrace, "Expected '{' after while condition")?;
 
@@ -1489,6 +1740,7 @@ impl Parser {
                 start_span.line,
                 start_span.column,
             ),
+            body_end: end_span,
         })
     }
 
@@ -1496,22 +1748,25 @@ impl Parser {
     fn parse_for(&mut self) -> Result<Stmt> {
         let start_span = self.consume(Token::For, "Expected 'for'")?;
 
-        // Parse the loop variable
-        let var = match self.advance()? {
-            (Token::Identifier(name), _) => name,
-            (token, _) => {
-                return Err(CompileError::UnexpectedToken {
-                    expected: "variable name".to_string(),
-                    found: token.to_string(),
-                    span: self.current_span(),
-                });
-            }
+        // Parse the loop variable, or a `(a, b)` pair of them
+        let paired = self.check(&Token::LeftParen);
+        if paired {
+            self.advance()?;
+        }
+        let var = self.parse_for_variable()?;
+        let pair = if paired {
+            self.consume(Token::Comma, "Expected ',' between for variables")?;
+            let pair = self.parse_for_variable()?;
+            self.consume(Token::RightParen, "Expected ')' after for variables")?;
+            Some(pair)
+        } else {
+            None
         };
 
         self.consume(Token::In, "Expected 'in' after for variable")?;
 
         // Parse the iterator expression (array or range)
-        let iter = self.parse_expression()?;
+        let iter = self.parse_condition()?;
 
         self.consume(Token::LeftBrace, "Expected '{' after for header")?;
 
@@ -1524,6 +1779,7 @@ impl Parser {
 
         Ok(Stmt::For {
             var,
+            pair,
             iter,
             body,
             span: Span::new(
@@ -1532,9 +1788,22 @@ impl Parser {
                 start_span.line,
                 start_span.column,
             ),
+            body_end: end_span,
         })
     }
 
+    /// Parse a name bound by a for loop
+    fn parse_for_variable(&mut self) -> Result<String> {
+        match self.advance()? {
+            (Token::Identifier(name), _) => Ok(name),
+            (token, _) => Err(CompileError::UnexpectedToken {
+                expected: "variable name".to_string(),
+                found: token.to_string(),
+                span: self.current_span(),
+            }),
+        }
+    }
+
     /// Parse a break statement
     fn parse_break(&mut self) -> Result<Stmt> {
         let start_span = self.consume(Token::Break, "Expected 'break'")?;
@@ -1569,7 +1838,7 @@ impl Parser {
     fn parse_match(&mut self) -> Result<Stmt> {
         let start_span = self.consume(Token::Match, "Expected 'match'")?;
 
-        let expr = self.parse_expression()?;
+        let expr = self.parse_condition()?;
 
         self.consume(Token::LeftBrace, "Expected '{' after match expression")?;
 
@@ -1606,7 +1875,8 @@ impl Parser {
                 vec![Stmt::Expr(expr)]
             };
 
-            arms.push(MatchArm { pattern, body });
+            let end = self.tokens[self.current - 1].1;
+            arms.push(MatchArm { pattern, body, end });
         }
 
         let end_span = self.consume(Token::RightBrace, "Expected '}' after match arms")?;
@@ -1623,6 +1893,107 @@ impl Parser {
         })
     }
 
+    /// Parse an async scope: `async scope { ... }`
+    fn parse_async_scope(&mut self) -> Result<Stmt> {
+        let start_span = self.consume(Token::Async, "Expected 'async'")?;
+
+        match self.advance()? {
+            (Token::Identifier(name), _) if name == "scope" => {}
+            (_, span) => {
+                return Err(CompileError::SyntaxError {
+                    message: "Expected 'scope' after 'async' in a statement".to_string(),
+                    span: Some(span),
+                })
+            }
+        }
+        self.consume(Token::LeftBrace, "Expected '{' after async scope")?;
+
+        let mut body = Vec::new();
+        while !self.check(&Token::RightBrace) && !self.is_at_end() {
+            body.push(self.parse_statement()?);
+        }
+
+        let end_span = self.consume(Token::RightBrace, "Expected '}' after async scope")?;
+
+        Ok(Stmt::AsyncScope {
+            body,
+            span: Span::new(
+                start_span.start,
+                end_span.end,
+                start_span.line,
+                start_span.column,
+            ),
+            body_end: end_span,
+        })
+    }
+
+    /// Parse a handle block with its handlers:
+    /// `handle { ... } with Log { fn log(msg: String) { ... } }`
+    fn parse_handle(&mut self) -> Result<Stmt> {
+        let start_span = self.current_span().unwrap_or_else(Span::dummy);
+        self.advance()?; // consume 'handle'
+        self.consume(Token::LeftBrace, "Expected '{' after handle")?;
+
+        let mut body = Vec::new();
+        while !self.check(&Token::RightBrace) && !self.is_at_end() {
+            body.push(self.parse_statement()?);
+        }
+        let body_end = self.consume(Token::RightBrace, "Expected '}' after handle block")?;
+        let mut end_span = body_end;
+
+        let mut handlers = Vec::new();
+        while matches!(self.peek()?, Token::Identifier(name) if name == "with") {
+            let handler_start = self.current_span().unwrap_or_else(Span::dummy);
+            self.advance()?; // consume 'with'
+            let effect = match self.advance()? {
+                (Token::Identifier(name), _) => name,
+                (token, _) => {
+                    return Err(CompileError::UnexpectedToken {
+                        expected: "effect name".to_string(),
+                        found: token.to_string(),
+                        span: self.current_span(),
+                    });
+                }
+            };
+            self.consume(Token::LeftBrace, "Expected '{' after handled effect")?;
+
+            let mut operations = Vec::new();
+            while !self.check(&Token::RightBrace) && !self.is_at_end() {
+                operations.push(self.parse_function()?);
+            }
+            end_span = self.consume(Token::RightBrace, "Expected '}' after handler")?;
+
+            handlers.push(EffectHandler {
+                effect,
+                operations,
+                span: Span::new(
+                    handler_start.start,
+                    end_span.end,
+                    handler_start.line,
+                    handler_start.column,
+                ),
+            });
+        }
+        if handlers.is_empty() {
+            return Err(CompileError::SyntaxError {
+                message: "Expected 'with' and a handler after handle block".to_string(),
+                span: self.current_span(),
+            });
+        }
+
+        Ok(Stmt::Handle {
+            body,
+            handlers,
+            span: Span::new(
+                start_span.start,
+                end_span.end,
+                start_span.line,
+                start_span.column,
+            ),
+            body_end,
+        })
+    }
+
     /// Parse an unsafe block
     fn parse_unsafe(&mut self) -> Result<Stmt> {
         let start_span = self.consume(Token::Unsafe, "Expected 'unsafe'")?;
@@ -1644,6 +2015,7 @@ impl Parser {
                 start_span.line,
                 start_span.column,
             ),
+            body_end: end_span,
         })
     }
 
@@ -1990,6 +2362,32 @@ impl Parser {
                 // Self type in trait or impl contexts
                 Ok(Type::Custom("Self".to_string()))
             }
+            (Token::Fn, _) => {
+                // Parse function pointer type: fn(T, U) -> R ![effects]
+                self.consume(Token::LeftParen, "Expected '(' after 'fn'")?;
+                let mut params = Vec::new();
+                while !self.check(&Token::RightParen) {
+                    params.push(self.parse_type()?);
+                    if !self.check(&Token::Comma) {
+                        break;
+                    }
+                    self.advance()?; // consume ','
+                }
+                self.consume(Token::RightParen, "Expected ')' after parameter types")?;
+
+                let return_type = if self.check(&Token::Arrow) {
+                    self.advance()?; // consume '->'
+                    self.parse_type()?
+                } else {
+                    Type::Unit
+                };
+
+                Ok(Type::Function {
+                    params,
+                    return_type: Box::new(return_type),
+                    effects: self.parse_effect_clause()?,
+                })
+            }
             (Token::Ampersand, _) => {
                 // Parse reference type: &T or &mut T or &'a T or &'a mut T
                 let mut lifetime = None;
@@ -2027,6 +2425,36 @@ impl Parser {
                     inner: Box::new(inner),
                 })
             }
+            (Token::Identifier(name), _) if name == "ref" && self.starts_ref_type() => {
+                // Parse reference type: ref T, ref mut T or ref<'a> T
+                let mut lifetime = None;
+                if self.check(&Token::Lt) {
+                    self.advance()?; // consume '<'
+                    self.consume(Token::SingleQuote, "Expected lifetime after 'ref<'")?;
+                    match self.advance()? {
+                        (Token::Identifier(lt), _) => lifetime = Some(lt),
+                        _ => {
+                            return Err(CompileError::UnexpectedToken {
+                                expected: "lifetime name".to_string(),
+                                found: self.peek()?.to_string(),
+                                span: self.current_span(),
+                            });
+                        }
+                    }
+                    self.consume(Token::Gt, "Expected '>' after lifetime")?;
+                }
+                let mutable = self.check(&Token::Mut);
+                if mutable {
+                    self.advance()?;
+                }
+                let inner = self.parse_type()?;
+
+                Ok(Type::Reference {
+                    lifetime,
+                    mutable,
+                    inner: Box::new(inner),
+                })
+            }
             (Token::Identifier(name), _) => {
                 // First check if it's a type parameter in scope
                 if self.type_params_in_scope.contains(&name) {
@@ -2040,6 +2468,7 @@ impl Parser {
                     "u64" => Type::U64,
                     "bool" => Type::Bool,
                     "String" => Type::String,
+                    "str" => Type::Str,
                     _ => Type::Custom(name.clone()),
                 };
 
@@ -2191,13 +2620,28 @@ impl Parser {
                 // Check if this is a struct literal
                 // We need to be careful here - only parse as struct literal if we see
                 // identifier followed by field pattern (identifier + colon)
-                if self.check(&Token::LeftBrace) && self.check_struct_literal_pattern() {
+                if self.struct_literals_allowed
+                    && self.check(&Token::LeftBrace)
+                    && self.check_struct_literal_pattern()
+                {
                     let start_span = span;
                     self.advance()?; // consume '{'
 
                     let mut fields = Vec::new();
+                    let mut base = None;
 
                     while !self.check(&Token::RightBrace) && !self.is_at_end() {
+                        // `..` or `..base` takes the remaining fields and ends the literal
+                        if self.check(&Token::DotDot) {
+                            self.advance()?;
+                            base = Some(if self.check(&Token::RightBrace) {
+                                StructBase::Defaults
+                            } else {
+                                StructBase::Expr(Box::new(self.parse_expression()?))
+                            });
+                            break;
+                        }
+
                         // Parse field name
                         let field_name = match self.advance()? {
                             (Token::Identifier(fname), _) => fname,
@@ -2210,8 +2654,13 @@ impl Parser {
                             }
                         };
 
-                        self.consume(Token::Colon, "Expected ':' after field name")?;
-                        let field_expr = self.parse_expression()?;
+                        // `Name { x }` is shorthand for `Name { x: x }`
+                        let field_expr = if self.check(&Token::Colon) {
+                            self.advance()?;
+                            self.parse_expression()?
+                        } else {
+                            Expr::Ident(field_name.clone())
+                        };
 
                         fields.push((field_name, field_expr));
 
@@ -2226,6 +2675,7 @@ impl Parser {
                     Ok(Expr::StructLiteral {
                         name,
                         fields,
+                        base,
                         span: Span::new(
                             start_span.start,
                             end_span.end,
@@ -2239,7 +2689,7 @@ impl Parser {
             }
             (Token::LeftParen, _) => {
                 // Parse parenthesized expression
-                let expr = self.parse_expression()?;
+                let expr = self.parse_delimited_expression()?;
                 self.consume(Token::RightParen, "Expected ')' after expression")?;
                 Ok(expr)
             }
@@ -2255,12 +2705,12 @@ impl Parser {
                 }
 
                 // Parse first element
-                let first_elem = self.parse_expression()?;
+                let first_elem = self.parse_delimited_expression()?;
 
                 // Check if this is array repeat syntax
                 if self.check(&Token::Semicolon) {
                     self.advance()?; // consume ';'
-                    let count = self.parse_expression()?;
+                    let count = self.parse_delimited_expression()?;
                     let end_span =
                         self.consume(Token::RightBracket, "Expected ']' after array repeat count")?;
 
@@ -2279,7 +2729,7 @@ impl Parser {
                             // Trailing comma
                             break;
                         }
-                        elements.push(self.parse_expression()?);
+                        elements.push(self.parse_delimited_expression()?);
                     }
 
                     let end_span =
@@ -2373,13 +2823,15 @@ impl Parser {
 
     /// Parse postfix expressions (array indexing, function calls)
     fn parse_postfix(&mut self) -> Result<Expr> {
+        // Literals and identifiers carry no span, so postfix forms start at the primary's token
+        let primary_span = self.current_span().unwrap_or_else(Span::dummy);
         let mut expr = self.parse_primary()?;
 
         loop {
             match self.peek() {
                 Ok(Token::LeftBracket) => {
                     let start_span = self.advance()?.1; // consume '['
-                    let index = self.parse_expression()?;
+                    let index = self.parse_delimited_expression()?;
                     let end_span =
                         self.consume(Token::RightBracket, "Expected ']' after array index")?;
 
@@ -2401,7 +2853,7 @@ impl Parser {
 
                     if !self.check(&Token::RightParen) {
                         loop {
-                            args.push(self.parse_expression()?);
+                            args.push(self.parse_delimited_expression()?);
 
                             if !self.check(&Token::Comma) {
                                 break;
@@ -2424,7 +2876,7 @@ impl Parser {
                     };
                 }
                 Ok(Token::Dot) if self.check_at(1, &Token::Await) => {
-                    let start_span = Self::expr_span(&expr);
+                    let start_span = Self::expr_start(&expr, primary_span);
                     self.advance()?; // consume '.'
                     let end_span = self.consume(Token::Await, "Expected 'await'")?;
 
@@ -2439,7 +2891,7 @@ impl Parser {
                     };
                 }
                 Ok(Token::Dot) => {
-                    let start_span = Self::expr_span(&expr);
+                    let start_span = Self::expr_start(&expr, primary_span);
 
                     self.advance()?; // consume '.'
 
@@ -2470,9 +2922,35 @@ impl Parser {
                 Ok(Token::DoubleColon) => {
                     // Handle enum constructor: EnumName::Variant
                     if let Expr::Ident(enum_name) = expr {
-                        let start_span = self.tokens[self.current - 2].1; // Get span from before :: token
+                        let start_span = self.tokens[self.current.saturating_sub(2)].1; // Get span from before :: token
                         self.advance()?; // consume '::'
 
+                        // Turbofish: func::<T, U>
+                        if self.check(&Token::Lt) {
+                            self.advance()?; // consume '<'
+                            let mut type_args = Vec::new();
+                            loop {
+                                type_args.push(self.parse_type()?);
+                                if !self.check(&Token::Comma) {
+                                    break;
+                                }
+                                self.advance()?; // consume ','
+                            }
+                            let end_span =
+                                self.consume(Token::Gt, "Expected '>' after type arguments")?;
+                            expr = Expr::Turbofish {
+                                name: enum_name,
+                                type_args,
+                                span: Span::new(
+                                    start_span.start,
+                                    end_span.end,
+                                    start_span.line,
+                                    start_span.column,
+                                ),
+                            };
+                            continue;
+                        }
+
                         let variant = match self.advance()? {
                             (Token::Identifier(name), _) => name,
                             (token, _) => {
@@ -2572,7 +3050,7 @@ impl Parser {
                     }
                 }
                 Ok(Token::Question) => {
-                    let start_span = Self::expr_span(&expr);
+                    let start_span = Self::expr_start(&expr, primary_span);
                     let (_, end_span) = self.advance()?; // consume '?'
 
                     expr = Expr::Question {
@@ -2586,13 +3064,19 @@ impl Parser {
                     };
                 }
                 Ok(Token::Not) => {
-                    // Macro invocation: name!(args)
+                    // Macro invocation: name!(args) or name![args]
                     if let Expr::Ident(name) = expr {
                         let start_span = self.tokens[self.current - 1].1; // Get span from identifier
                         self.advance()?; // consume '!'
 
-                        // Parse macro arguments (simplified for now - just collect tokens in parens)
-                        self.consume(Token::LeftParen, "Expected '(' after macro name!")?;
+                        // Parse macro arguments (simplified for now - just collect tokens
+                        // up to the matching delimiter)
+                        let (open, close) = if self.check(&Token::LeftBracket) {
+                            (Token::LeftBracket, Token::RightBracket)
+                        } else {
+                            (Token::LeftParen, Token::RightParen)
+                        };
+                        self.consume(open.clone(), "Expected '(' after macro name!")?;
 
                         let mut args = Vec::new();
                         let mut paren_depth = 1;
@@ -2601,11 +3085,11 @@ impl Parser {
                             let (token, _) = self.advance()?;
 
                             match &token {
-                                Token::LeftParen => {
+                                _ if token == open => {
                                     paren_depth += 1;
                                     args.push(self.token_to_ast_token(token));
                                 }
-                                Token::RightParen => {
+                                _ if token == close => {
                                     paren_depth -= 1;
                                     if paren_depth > 0 {
                                         args.push(self.token_to_ast_token(token));
@@ -2653,16 +3137,20 @@ impl Parser {
             return false;
         }
 
-        // Check if next token after { is an identifier or }
+        // Check if next token after { is an identifier, `..` or }
         match &self.tokens[self.current + 1].0 {
             Token::Identifier(_) => {
-                // Check if token after identifier is :
+                // Check if token after identifier is `:`, or `,` / `}` for shorthand
                 if self.current + 2 < self.tokens.len() {
-                    matches!(&self.tokens[self.current + 2].0, Token::Colon)
+                    matches!(
+                        &self.tokens[self.current + 2].0,
+                        Token::Colon | Token::Comma | Token::RightBrace
+                    )
                 } else {
                     false
                 }
             }
+            Token::DotDot => true,     // Only the remaining fields
             Token::RightBrace => true, // Empty struct literal
             _ => false,
         }
@@ -2694,6 +3182,21 @@ impl Parser {
         }
     }
 
+    /// Whether the tokens after a `ref` identifier spell the type it refers to
+    fn starts_ref_type(&self) -> bool {
+        [
+            Token::Mut,
+            Token::Identifier(String::new()),
+            Token::SelfType,
+            Token::Ampersand,
+            Token::LeftParen,
+            Token::LeftBracket,
+        ]
+        .iter()
+        .any(|token| self.check(token))
+            || (self.check(&Token::Lt) && self.check_at(1, &Token::SingleQuote))
+    }
+
     /// Check if a token at offset matches the given token
     fn check_at(&self, offset: usize, token: &Token) -> bool {
         let index = self.current + offset;
@@ -2735,6 +3238,39 @@ impl Parser {
     }
 }
 
+/// Collect the effects named by the function types within a type
+fn collect_effect_names<'t>(ty: &'t Type, names: &mut Vec<&'t String>) {
+    match ty {
+        Type::Function {
+            params,
+            return_type,
+            effects,
+        } => {
+            names.extend(effects.iter().flatten());
+            for param in params {
+                collect_effect_names(param, names);
+            }
+            collect_effect_names(return_type, names);
+        }
+        Type::Array(inner, _) | Type::Reference { inner, .. } | Type::Future { output: inner } => {
+            collect_effect_names(inner, names)
+        }
+        Type::Generic { args, .. } => {
+            for arg in args {
+                if let GenericArg::Type(ty) = arg {
+                    collect_effect_names(ty, names);
+                }
+            }
+        }
+        Type::Tuple(types) => {
+            for ty in types {
+                collect_effect_names(ty, names);
+            }
+        }
+        _ => {}
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -2799,6 +3335,120 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_effect_annotations() {
+        let source = r#"
+        async fn fetch(n: i64) -> i64 ![io, async] {
+            return n;
+        }
+        fn add(a: i64, b: i64) -> i64 ![] {
+            return a + b;
+        }
+        fn main() {
+        }
+        "#;
+
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.collect_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let ast = parser.parse().unwrap();
+
+        let effects: Vec<_> = ast
+            .items
+            .iter()
+            .map(|item| match item {
+                Item::Function(func) => func.effects.clone(),
+                _ => panic!("Expected function"),
+            })
+            .collect();
+        assert_eq!(
+            effects,
+            vec![
+                Some(vec!["io".to_string(), "async".to_string()]),
+                Some(vec![]),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_effect_variables() {
+        let source = r#"
+        fn map<T, U, e>(xs: [T; 3], f: fn(T) -> U ! e) -> [U; 3] ! e {
+            return xs;
+        }
+        "#;
+
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.collect_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let ast = parser.parse().unwrap();
+
+        match &ast.items[0] {
+            Item::Function(func) => {
+                assert_eq!(func.type_params, vec!["T", "U"]);
+                assert_eq!(func.effect_params, vec!["e"]);
+                assert_eq!(func.effects, Some(vec!["e".to_string()]));
+                assert_eq!(func.params[1].ty.to_string(), "fn(T) -> U ![e]");
+            }
+            _ => panic!("Expected function"),
+        }
+    }
+
+    #[test]
+    fn test_parse_effect_handlers() {
+        let source = r#"
+        effect Log {
+            fn log(msg: String);
+            fn level() -> i64;
+        }
+        fn main() {
+            handle {
+                Log::log("hi");
+            } with Log {
+                fn log(msg: String) {
+                    print(msg);
+                }
+                fn level() -> i64 {
+                    return 0;
+                }
+            }
+        }
+        "#;
+
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.collect_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let ast = parser.parse().unwrap();
+
+        match &ast.items[0] {
+            Item::Effect(effect) => {
+                assert_eq!(effect.name, "Log");
+                assert_eq!(effect.operations.len(), 2);
+                assert_eq!(effect.operations[0].params[0].name, "msg");
+                assert_eq!(effect.operations[1].return_type, Some(Type::I64));
+            }
+            _ => panic!("Expected effect declaration"),
+        }
+        match &ast.items[1] {
+            Item::Function(func) => match &func.body[0] {
+                Stmt::Handle { body, handlers, .. } => {
+                    assert_eq!(body.len(), 1);
+                    assert_eq!(handlers.len(), 1);
+                    assert_eq!(handlers[0].effect, "Log");
+                    assert_eq!(handlers[0].operations[1].name, "level");
+                }
+                _ => panic!("Expected handle block"),
+            },
+            _ => panic!("Expected function"),
+        }
+
+        // `effect` and `handle` stay usable as ordinary names
+        let source = "fn main() { let effect = 1; let handle = effect; handle(); }";
+        let tokens = Lexer::new(source).collect_tokens().unwrap();
+        assert!(Parser::new(tokens).parse().is_ok());
+    }
+
     #[test]
     fn test_parse_for_loop() {
         let source = r#"
@@ -3477,6 +4127,105 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_struct_shorthand_and_update() {
+        let source = r#"
+        struct Config {
+            host: String,
+            port: i64 = 8080,
+        }
+
+        fn main() {
+            let a = Config { host, port };
+            let b = Config { port: 1, ..a };
+            let c = Config { host: "h", .. };
+            if ready { go(); }
+        }
+        "#;
+
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.collect_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let ast = parser.parse().unwrap();
+
+        let Item::Struct(config) = &ast.items[0] else {
+            panic!("Expected struct");
+        };
+        assert_eq!(config.field_defaults.len(), 1);
+        assert_eq!(config.field_defaults[0].0, "port");
+
+        let Item::Function(main) = &ast.items[1] else {
+            panic!("Expected function");
+        };
+        let literals: Vec<&Expr> = main.body[..3]
+            .iter()
+            .map(|stmt| match stmt {
+                Stmt::Let { value, .. } => value,
+                other => panic!("Expected let, got {:?}", other),
+            })
+            .collect();
+
+        assert!(matches!(
+            literals[0],
+            Expr::StructLiteral { fields, base: None, .. }
+                if fields[0] == ("host".to_string(), Expr::Ident("host".to_string()))
+        ));
+        assert!(matches!(
+            literals[1],
+            Expr::StructLiteral { base: Some(StructBase::Expr(base)), .. }
+                if **base == Expr::Ident("a".to_string())
+        ));
+        assert!(matches!(
+            literals[2],
+            Expr::StructLiteral {
+                base: Some(StructBase::Defaults),
+                ..
+            }
+        ));
+
+        // `ready { ... }` is the if body, not a struct literal
+        assert!(matches!(
+            &main.body[3],
+            Stmt::If { condition: Expr::Ident(name), .. } if name == "ready"
+        ));
+    }
+
+    #[test]
+    fn test_parse_turbofish_call() {
+        let source = r#"
+        fn main() {
+            let p = make::<i64, Pair<String, bool>>();
+        }
+        "#;
+
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.collect_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let ast = parser.parse().unwrap();
+
+        let Item::Function(main) = &ast.items[0] else {
+            panic!("Expected function");
+        };
+        let Stmt::Let {
+            value: Expr::Call { func, args, .. },
+            ..
+        } = &main.body[0]
+        else {
+            panic!("Expected call");
+        };
+        assert!(args.is_empty());
+        let Expr::Turbofish {
+            name, type_args, ..
+        } = func.as_ref()
+        else {
+            panic!("Expected turbofish callee");
+        };
+        assert_eq!(name, "make");
+        assert_eq!(type_args.len(), 2);
+        assert_eq!(type_args[0], Type::I64);
+        assert!(matches!(&type_args[1], Type::Generic { name, .. } if name == "Pair"));
+    }
+
     #[test]
     fn test_parse_struct_returns() {
         let source = r#"
@@ -3713,4 +4462,30 @@ mod tests {
             panic!("Expected function");
         }
     }
+
+    #[test]
+    fn test_parse_ref_types() {
+        let source = r#"
+        fn greet(name: ref str, out: ref mut String) {
+        }
+        "#;
+
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.collect_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let ast = parser.parse().unwrap();
+
+        if let Item::Function(func) = &ast.items[0] {
+            assert!(matches!(
+                &func.params[0].ty,
+                Type::Reference { mutable: false, inner, .. } if **inner == Type::Str
+            ));
+            assert!(matches!(
+                &func.params[1].ty,
+                Type::Reference { mutable: true, inner, .. } if **inner == Type::String
+            ));
+        } else {
+            panic!("Expected function");
+        }
+    }
 }
@@ -183,6 +183,11 @@ impl ModuleResolver {
                 crate::ast::Item::Macro(_) => {
                     // Macros are handled during expansion phase, skip here
                 }
+                crate::ast::Item::Effect(effect_def) => {
+                    if matches!(effect_def.visibility, crate::ast::Visibility::Public) {
+                        exports.insert(effect_def.name.clone());
+                    }
+                }
             }
         }
 
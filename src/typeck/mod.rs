@@ -2,8 +2,9 @@
 // "Ensuring legends are logically sound"
 
 use crate::ast::{AssignTarget, UnaryOp, *};
-use crate::errors::{CompileError, Result};
-use std::collections::HashMap;
+use crate::errors::{CompileError, Result, Span};
+use crate::macros::format::FormatKind;
+use std::collections::{HashMap, HashSet};
 
 mod suggestions;
 use suggestions::TypeErrorHelper;
@@ -11,14 +12,18 @@ use suggestions::TypeErrorHelper;
 mod exhaustiveness;
 use exhaustiveness::{EnumInfo, ExhaustivenessChecker, VariantInfo};
 
-mod trait_resolution;
-use trait_resolution::TraitResolver;
+pub mod trait_resolution;
+pub use trait_resolution::TraitResolver;
+
+pub mod prelude;
 
 /// Type representation for type checker (wraps AST Type)
 #[derive(Debug, Clone, PartialEq)]
 pub enum CheckerType {
     Unit,
     String,
+    /// A borrowed string view (`ref str`); a `String` can be passed for one
+    Str,
     Int,
     Bool,
     Array(Box<CheckerType>, ArraySizeValue),
@@ -31,6 +36,8 @@ pub enum CheckerType {
         args: Vec<GenericArgValue>,
     },
     Tuple(Vec<CheckerType>),
+    /// Inference variable standing for a type not yet known
+    Var(u32),
 }
 
 /// Array size value for type checking
@@ -68,6 +75,7 @@ impl From<&crate::ast::Type> for CheckerType {
         match ast_type {
             crate::ast::Type::Unit => CheckerType::Unit,
             crate::ast::Type::String => CheckerType::String,
+            crate::ast::Type::Str => CheckerType::Str,
             crate::ast::Type::I32 | crate::ast::Type::I64 => CheckerType::Int,
             crate::ast::Type::Bool => CheckerType::Bool,
             crate::ast::Type::U32 | crate::ast::Type::U64 => CheckerType::Int,
@@ -122,6 +130,14 @@ impl From<&crate::ast::Type> for CheckerType {
             crate::ast::Type::Tuple(types) => {
                 CheckerType::Tuple(types.iter().map(CheckerType::from).collect())
             }
+            crate::ast::Type::Function {
+                params,
+                return_type,
+                ..
+            } => CheckerType::Function(
+                params.iter().map(CheckerType::from).collect(),
+                Box::new(CheckerType::from(return_type.as_ref())),
+            ),
         }
     }
 }
@@ -131,6 +147,7 @@ impl std::fmt::Display for CheckerType {
         match self {
             CheckerType::Unit => write!(f, "()"),
             CheckerType::String => write!(f, "String"),
+            CheckerType::Str => write!(f, "ref str"),
             CheckerType::Int => write!(f, "Int"),
             CheckerType::Bool => write!(f, "Bool"),
             CheckerType::Array(elem_type, size) => match size {
@@ -176,6 +193,7 @@ impl std::fmt::Display for CheckerType {
                 }
                 write!(f, ")")
             }
+            CheckerType::Var(_) => write!(f, "_"),
         }
     }
 }
@@ -301,6 +319,42 @@ pub struct StructInstantiation {
     pub type_args: Vec<String>, // Concrete types like "i64", "String"
 }
 
+/// A concrete instantiation of a generic enum
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct EnumInstantiation {
+    pub name: String,
+    pub type_args: Vec<String>, // Concrete types like "i64", "String"
+}
+
+/// A generic call whose type arguments are still being inferred
+#[derive(Debug, Clone)]
+struct PendingCall {
+    name: String,
+    type_params: Vec<String>,
+    type_args: Vec<CheckerType>,
+    return_type: CheckerType,
+    span: Span,
+}
+
+/// How codegen should lower a `?` expression
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuestionLowering {
+    /// The operand's enum and its concrete type arguments
+    pub operand: EnumInstantiation,
+    /// `(target, source)` error types when the error goes through `From`
+    pub error_conversion: Option<(String, String)>,
+}
+
+/// Type of a value passed to a formatting intrinsic
+#[derive(Debug, Clone, PartialEq)]
+pub enum FormatArgType {
+    Int,
+    Bool,
+    Str,
+    /// A struct or enum formatted through its Display/Debug impl
+    User(String),
+}
+
 pub struct TypeChecker {
     /// Function signatures
     functions: HashMap<String, CheckerType>,
@@ -310,6 +364,8 @@ pub struct TypeChecker {
     instantiations: HashMap<FunctionInstantiation, CheckerType>,
     /// Struct definitions
     structs: HashMap<String, Vec<(String, CheckerType)>>,
+    /// Fields with declared default values, per struct
+    struct_defaults: HashMap<String, Vec<String>>,
     /// Generic struct definitions
     generic_structs: HashMap<String, GenericStruct>,
     /// Trait resolver
@@ -324,20 +380,70 @@ pub struct TypeChecker {
     type_aliases: HashMap<String, crate::ast::Type>,
     /// Generic type alias definitions
     generic_type_aliases: HashMap<String, GenericTypeAlias>,
+    /// Which parameters of each non-generic function take a reference
+    reference_params: HashMap<String, Vec<bool>>,
+    /// Names of the structs, enums and type aliases the program declares, known
+    /// before any of their definitions are collected
+    declared_types: HashSet<String>,
     /// Current function return type (for checking return statements)
     current_function_return: Option<CheckerType>,
+    /// Whether the current function is an `async fn`, where `.await` may suspend
+    current_function_async: bool,
     /// Symbol table for variables
     symbols: SymbolTable,
     /// Imported modules and their exported items
     imported_modules: HashMap<String, crate::resolver::ModuleInfo>,
     /// Loop depth counter (for break/continue validation)
     loop_depth: usize,
+    /// Loop depth on entry to each enclosing `async scope`, which control flow
+    /// can't leave before the scope's tasks are awaited
+    async_scopes: Vec<usize>,
     /// Error helper for better suggestions
     error_helper: TypeErrorHelper,
     /// Unsafe block depth counter (for tracking unsafe context)
     unsafe_depth: usize,
     /// Current impl type (for resolving Self types)
     current_impl_type: Option<String>,
+    /// Instantiated generic enums
+    enum_instantiations: HashMap<EnumInstantiation, CheckerType>,
+    /// `From` implementations: target type -> (source type, source as written)
+    from_impls: HashMap<String, Vec<(CheckerType, String)>>,
+    /// Concrete types of generic enum constructor expressions and of
+    /// `Box::new`, keyed by span
+    enum_expr_types: HashMap<Span, EnumInstantiation>,
+    /// Lowering information for `?` expressions, keyed by span
+    question_lowerings: HashMap<Span, QuestionLowering>,
+    /// Argument types of formatting intrinsics, keyed by call span
+    format_args: HashMap<Span, FormatArgType>,
+    /// Bindings of inference variables, indexed by variable id
+    type_vars: Vec<Option<CheckerType>>,
+    /// Generic calls awaiting the end of inference for their function
+    pending_calls: Vec<PendingCall>,
+    /// Concrete type arguments of each generic function call, keyed by call span.
+    /// Calls in the body of a generic function may name its type parameters
+    generic_call_types: HashMap<Span, Vec<String>>,
+    /// Type parameters of the generic function whose body is being checked
+    body_type_params: HashSet<String>,
+    /// Types noted in the body being checked that name its type parameters
+    body_open_types: Vec<CheckerType>,
+    /// Types naming the type parameters of each generic function, noted again
+    /// with the type arguments of every instantiation
+    open_types: HashMap<String, Vec<CheckerType>>,
+    /// Type arguments of the generic function calls in the program
+    instantiation_args: Vec<(String, Vec<CheckerType>)>,
+    /// Types held in a `Box` somewhere in the program, as type argument names
+    box_types: HashSet<String>,
+    /// Element types of the built-in `Vec`s in the program, as type argument names
+    vec_types: HashSet<String>,
+    /// Key and value types of the built-in `HashMap`s in the program
+    map_types: HashSet<(String, String)>,
+    /// Element types of the built-in `HashSet`s in the program
+    set_types: HashSet<String>,
+    /// User-defined effects with their operations
+    effects: HashMap<String, EffectDef>,
+    /// Operations of the handlers in the function being checked, which are
+    /// checked after it like functions of their own
+    pending_handlers: Vec<Function>,
 }
 
 impl Default for TypeChecker {
@@ -353,7 +459,7 @@ impl TypeChecker {
         // Built-in functions
         functions.insert(
             "print".to_string(),
-            CheckerType::Function(vec![CheckerType::String], Box::new(CheckerType::Unit)),
+            CheckerType::Function(vec![CheckerType::Str], Box::new(CheckerType::Unit)),
         );
 
         // print_int built-in function
@@ -362,42 +468,55 @@ impl TypeChecker {
             CheckerType::Function(vec![CheckerType::Int], Box::new(CheckerType::Unit)),
         );
 
+        // yield_now: a future that is pending once, letting other tasks run
+        functions.insert(
+            "yield_now".to_string(),
+            CheckerType::Function(vec![], Box::new(Self::future_of(CheckerType::Unit))),
+        );
+
         // panic built-in function
         functions.insert(
             "panic".to_string(),
-            CheckerType::Function(vec![CheckerType::String], Box::new(CheckerType::Unit)),
+            CheckerType::Function(vec![CheckerType::Str], Box::new(CheckerType::Unit)),
         );
 
         // String manipulation functions
         functions.insert(
             "string_len".to_string(),
-            CheckerType::Function(vec![CheckerType::String], Box::new(CheckerType::Int)),
+            CheckerType::Function(vec![CheckerType::Str], Box::new(CheckerType::Int)),
         );
         functions.insert(
             "string_concat".to_string(),
             CheckerType::Function(
-                vec![CheckerType::String, CheckerType::String],
+                vec![CheckerType::Str, CheckerType::Str],
                 Box::new(CheckerType::String),
             ),
         );
+        functions.insert(
+            "string_push_str".to_string(),
+            CheckerType::Function(
+                vec![CheckerType::String, CheckerType::Str],
+                Box::new(CheckerType::Unit),
+            ),
+        );
         functions.insert(
             "string_eq".to_string(),
             CheckerType::Function(
-                vec![CheckerType::String, CheckerType::String],
+                vec![CheckerType::Str, CheckerType::Str],
                 Box::new(CheckerType::Bool),
             ),
         );
         functions.insert(
             "string_char_at".to_string(),
             CheckerType::Function(
-                vec![CheckerType::String, CheckerType::Int],
+                vec![CheckerType::Str, CheckerType::Int],
                 Box::new(CheckerType::Int),
             ),
         );
         functions.insert(
             "string_substring".to_string(),
             CheckerType::Function(
-                vec![CheckerType::String, CheckerType::Int, CheckerType::Int],
+                vec![CheckerType::Str, CheckerType::Int, CheckerType::Int],
                 Box::new(CheckerType::String),
             ),
         );
@@ -419,13 +538,13 @@ impl TypeChecker {
         );
         functions.insert(
             "string_to_int".to_string(),
-            CheckerType::Function(vec![CheckerType::String], Box::new(CheckerType::Int)),
+            CheckerType::Function(vec![CheckerType::Str], Box::new(CheckerType::Int)),
         );
 
         // File I/O functions
         functions.insert(
             "file_open".to_string(),
-            CheckerType::Function(vec![CheckerType::String], Box::new(CheckerType::Int)),
+            CheckerType::Function(vec![CheckerType::Str], Box::new(CheckerType::Int)),
         );
         functions.insert(
             "file_read_all".to_string(),
@@ -438,7 +557,7 @@ impl TypeChecker {
         functions.insert(
             "file_write".to_string(),
             CheckerType::Function(
-                vec![CheckerType::Int, CheckerType::String],
+                vec![CheckerType::Int, CheckerType::Str],
                 Box::new(CheckerType::Bool),
             ),
         );
@@ -448,49 +567,49 @@ impl TypeChecker {
         );
         functions.insert(
             "file_exists".to_string(),
-            CheckerType::Function(vec![CheckerType::String], Box::new(CheckerType::Bool)),
+            CheckerType::Function(vec![CheckerType::Str], Box::new(CheckerType::Bool)),
         );
         
         // Enhanced I/O functions
         functions.insert(
             "path_exists".to_string(),
-            CheckerType::Function(vec![CheckerType::String], Box::new(CheckerType::Bool)),
+            CheckerType::Function(vec![CheckerType::Str], Box::new(CheckerType::Bool)),
         );
         functions.insert(
             "path_is_file".to_string(),
-            CheckerType::Function(vec![CheckerType::String], Box::new(CheckerType::Bool)),
+            CheckerType::Function(vec![CheckerType::Str], Box::new(CheckerType::Bool)),
         );
         functions.insert(
             "path_is_dir".to_string(),
-            CheckerType::Function(vec![CheckerType::String], Box::new(CheckerType::Bool)),
+            CheckerType::Function(vec![CheckerType::Str], Box::new(CheckerType::Bool)),
         );
         functions.insert(
             "create_dir".to_string(),
-            CheckerType::Function(vec![CheckerType::String], Box::new(CheckerType::Int)),
+            CheckerType::Function(vec![CheckerType::Str], Box::new(CheckerType::Int)),
         );
         functions.insert(
             "create_dir_all".to_string(),
-            CheckerType::Function(vec![CheckerType::String], Box::new(CheckerType::Int)),
+            CheckerType::Function(vec![CheckerType::Str], Box::new(CheckerType::Int)),
         );
         functions.insert(
             "remove_file".to_string(),
-            CheckerType::Function(vec![CheckerType::String], Box::new(CheckerType::Int)),
+            CheckerType::Function(vec![CheckerType::Str], Box::new(CheckerType::Int)),
         );
         functions.insert(
             "remove_dir".to_string(),
-            CheckerType::Function(vec![CheckerType::String], Box::new(CheckerType::Int)),
+            CheckerType::Function(vec![CheckerType::Str], Box::new(CheckerType::Int)),
         );
         functions.insert(
             "remove_dir_all".to_string(),
-            CheckerType::Function(vec![CheckerType::String], Box::new(CheckerType::Int)),
+            CheckerType::Function(vec![CheckerType::Str], Box::new(CheckerType::Int)),
         );
         functions.insert(
             "read_file_to_string".to_string(),
-            CheckerType::Function(vec![CheckerType::String], Box::new(CheckerType::String)),
+            CheckerType::Function(vec![CheckerType::Str], Box::new(CheckerType::String)),
         );
         functions.insert(
             "write_string_to_file".to_string(),
-            CheckerType::Function(vec![CheckerType::String, CheckerType::String], Box::new(CheckerType::Int)),
+            CheckerType::Function(vec![CheckerType::Str, CheckerType::Str], Box::new(CheckerType::Int)),
         );
         functions.insert(
             "file_flush".to_string(),
@@ -504,7 +623,7 @@ impl TypeChecker {
         // Enhanced file operations with mode support
         functions.insert(
             "file_open_ex".to_string(),
-            CheckerType::Function(vec![CheckerType::String, CheckerType::Int], Box::new(CheckerType::Int)),
+            CheckerType::Function(vec![CheckerType::Str, CheckerType::Int], Box::new(CheckerType::Int)),
         );
         functions.insert(
             "file_close_ex".to_string(),
@@ -512,18 +631,18 @@ impl TypeChecker {
         );
         functions.insert(
             "file_read_ex".to_string(),
-            CheckerType::Function(vec![CheckerType::Int, CheckerType::String, CheckerType::Int], Box::new(CheckerType::Int)),
+            CheckerType::Function(vec![CheckerType::Int, CheckerType::Str, CheckerType::Int], Box::new(CheckerType::Int)),
         );
         functions.insert(
             "file_write_ex".to_string(),
-            CheckerType::Function(vec![CheckerType::Int, CheckerType::String, CheckerType::Int], Box::new(CheckerType::Int)),
+            CheckerType::Function(vec![CheckerType::Int, CheckerType::Str, CheckerType::Int], Box::new(CheckerType::Int)),
         );
 
         // String operations
         functions.insert(
             "string_concat".to_string(),
             CheckerType::Function(
-                vec![CheckerType::String, CheckerType::String],
+                vec![CheckerType::Str, CheckerType::Str],
                 Box::new(CheckerType::String),
             ),
         );
@@ -532,11 +651,61 @@ impl TypeChecker {
             CheckerType::Function(vec![CheckerType::Int], Box::new(CheckerType::String)),
         );
 
+        // Output targets of print!/eprint! and friends
+        functions.insert(
+            "__write_stdout".to_string(),
+            CheckerType::Function(vec![CheckerType::Str], Box::new(CheckerType::Unit)),
+        );
+        functions.insert(
+            "__write_stderr".to_string(),
+            CheckerType::Function(vec![CheckerType::Str], Box::new(CheckerType::Unit)),
+        );
+
+        // Reactor futures: a timer, and readiness of a file descriptor
+        for name in ["sleep_ms", "readable", "writable"] {
+            functions.insert(
+                name.to_string(),
+                CheckerType::Function(
+                    vec![CheckerType::Int],
+                    Box::new(Self::future_of(CheckerType::Unit)),
+                ),
+            );
+        }
+
+        // Non-blocking sockets and file descriptors for the reactor
+        functions.insert(
+            "tcp_listen".to_string(),
+            CheckerType::Function(vec![CheckerType::Int], Box::new(CheckerType::Int)),
+        );
+        functions.insert(
+            "tcp_accept".to_string(),
+            CheckerType::Function(vec![CheckerType::Int], Box::new(CheckerType::Int)),
+        );
+        functions.insert(
+            "fd_read".to_string(),
+            CheckerType::Function(
+                vec![CheckerType::Int, CheckerType::Int],
+                Box::new(CheckerType::String),
+            ),
+        );
+        functions.insert(
+            "fd_write".to_string(),
+            CheckerType::Function(
+                vec![CheckerType::Int, CheckerType::Str],
+                Box::new(CheckerType::Int),
+            ),
+        );
+        functions.insert(
+            "fd_close".to_string(),
+            CheckerType::Function(vec![CheckerType::Int], Box::new(CheckerType::Unit)),
+        );
+
         Self {
             functions,
             generic_functions: HashMap::new(),
             instantiations: HashMap::new(),
             structs: HashMap::new(),
+            struct_defaults: HashMap::new(),
             generic_structs: HashMap::new(),
             trait_resolver: TraitResolver::new(),
             struct_instantiations: HashMap::new(),
@@ -544,13 +713,35 @@ impl TypeChecker {
             generic_enums: HashMap::new(),
             type_aliases: HashMap::new(),
             generic_type_aliases: HashMap::new(),
+            reference_params: HashMap::new(),
+            declared_types: HashSet::new(),
             current_function_return: None,
+            current_function_async: false,
             symbols: SymbolTable::new(),
             imported_modules: HashMap::new(),
             loop_depth: 0,
+            async_scopes: Vec::new(),
             error_helper: TypeErrorHelper::new(),
             unsafe_depth: 0,
             current_impl_type: None,
+            enum_instantiations: HashMap::new(),
+            from_impls: HashMap::new(),
+            enum_expr_types: HashMap::new(),
+            question_lowerings: HashMap::new(),
+            format_args: HashMap::new(),
+            type_vars: Vec::new(),
+            pending_calls: Vec::new(),
+            generic_call_types: HashMap::new(),
+            body_type_params: HashSet::new(),
+            body_open_types: Vec::new(),
+            open_types: HashMap::new(),
+            instantiation_args: Vec::new(),
+            box_types: HashSet::new(),
+            vec_types: HashSet::new(),
+            map_types: HashSet::new(),
+            set_types: HashSet::new(),
+            effects: HashMap::new(),
+            pending_handlers: Vec::new(),
         }
     }
 
@@ -596,6 +787,11 @@ impl TypeChecker {
                                     .as_ref()
                                     .map(CheckerType::from)
                                     .unwrap_or(CheckerType::Unit);
+                                let return_type = if func.is_async {
+                                    Self::future_of(return_type)
+                                } else {
+                                    return_type
+                                };
 
                                 let func_type =
                                     CheckerType::Function(param_types, Box::new(return_type));
@@ -706,6 +902,9 @@ impl TypeChecker {
                     crate::ast::Item::Macro(_) => {
                         // Macros are handled during expansion phase, skip here
                     }
+                    crate::ast::Item::Effect(_) => {
+                        // Effects are performed and handled within the program declaring them
+                    }
                 }
             }
         }
@@ -713,8 +912,22 @@ impl TypeChecker {
 
     /// Type check a program
     pub fn check(&mut self, program: &Program) -> Result<()> {
+        // Prelude items come first so the program can refer to (or shadow) them
+        let prelude_items = prelude::visible_items(program);
+
+        // Field types may name types declared later, or the type being declared
+        for item in prelude_items.iter().chain(&program.items) {
+            let name = match item {
+                Item::Struct(struct_def) => &struct_def.name,
+                Item::Enum(enum_def) => &enum_def.name,
+                Item::TypeAlias(type_alias) => &type_alias.name,
+                _ => continue,
+            };
+            self.declared_types.insert(name.clone());
+        }
+
         // First pass: collect all function signatures and struct definitions
-        for item in &program.items {
+        for item in prelude_items.iter().chain(&program.items) {
             match item {
                 Item::Function(func) => {
                     if !func.type_params.is_empty() {
@@ -745,15 +958,29 @@ impl TypeChecker {
                             .as_ref()
                             .map(|t| self.ast_type_to_checker_type(t))
                             .unwrap_or(CheckerType::Unit);
+                        let return_type = if func.is_async {
+                            Self::future_of(return_type)
+                        } else {
+                            return_type
+                        };
 
                         let func_type = CheckerType::Function(param_types, Box::new(return_type));
                         self.functions.insert(func.name.clone(), func_type);
+                        self.reference_params
+                            .insert(func.name.clone(), Self::reference_params_of(&func.params));
                     }
                 }
                 Item::Struct(struct_def) => {
                     // Check if this is a generic struct
                     if !struct_def.type_params.is_empty() || !struct_def.lifetime_params.is_empty()
                     {
+                        if let Some((field, _)) = struct_def.field_defaults.first() {
+                            return Err(CompileError::Generic(format!(
+                                "Field '{}' of generic struct '{}' cannot have a default value",
+                                field, struct_def.name
+                            )));
+                        }
+
                         // Store as generic struct
                         let generic_struct = GenericStruct {
                             lifetime_params: struct_def.lifetime_params.clone(),
@@ -767,10 +994,21 @@ impl TypeChecker {
                         let fields: Vec<(String, CheckerType)> = struct_def
                             .fields
                             .iter()
-                            .map(|(name, ty)| (name.clone(), CheckerType::from(ty)))
+                            .map(|(name, ty)| (name.clone(), self.ast_type_to_checker_type(ty)))
                             .collect();
+                        for (_, ty) in &fields {
+                            self.note_type(ty);
+                        }
 
                         self.structs.insert(struct_def.name.clone(), fields);
+                        self.struct_defaults.insert(
+                            struct_def.name.clone(),
+                            struct_def
+                                .field_defaults
+                                .iter()
+                                .map(|(name, _)| name.clone())
+                                .collect(),
+                        );
                     }
                 }
                 Item::Enum(enum_def) => {
@@ -796,19 +1034,33 @@ impl TypeChecker {
                             let variant_fields = match &variant.data {
                                 crate::ast::EnumVariantData::Unit => EnumVariantFields::Unit,
                                 crate::ast::EnumVariantData::Tuple(types) => {
-                                    let field_types: Vec<CheckerType> =
-                                        types.iter().map(CheckerType::from).collect();
+                                    let field_types: Vec<CheckerType> = types
+                                        .iter()
+                                        .map(|ty| self.ast_type_to_checker_type(ty))
+                                        .collect();
                                     EnumVariantFields::Tuple(field_types)
                                 }
                                 crate::ast::EnumVariantData::Struct(fields) => {
                                     let named_fields: Vec<(String, CheckerType)> = fields
                                         .iter()
-                                        .map(|(name, ty)| (name.clone(), CheckerType::from(ty)))
+                                        .map(|(name, ty)| {
+                                            (name.clone(), self.ast_type_to_checker_type(ty))
+                                        })
                                         .collect();
                                     EnumVariantFields::Named(named_fields)
                                 }
                             };
 
+                            match &variant_fields {
+                                EnumVariantFields::Unit => {}
+                                EnumVariantFields::Tuple(types) => {
+                                    types.iter().for_each(|ty| self.note_type(ty))
+                                }
+                                EnumVariantFields::Named(fields) => {
+                                    fields.iter().for_each(|(_, ty)| self.note_type(ty))
+                                }
+                            }
+
                             variants.push(EnumVariant {
                                 name: variant.name.clone(),
                                 fields: variant_fields,
@@ -823,14 +1075,16 @@ impl TypeChecker {
                                     CheckerType::Function(vec![], Box::new(enum_type.clone()))
                                 }
                                 crate::ast::EnumVariantData::Tuple(types) => {
-                                    let param_types: Vec<CheckerType> =
-                                        types.iter().map(CheckerType::from).collect();
+                                    let param_types: Vec<CheckerType> = types
+                                        .iter()
+                                        .map(|ty| self.ast_type_to_checker_type(ty))
+                                        .collect();
                                     CheckerType::Function(param_types, Box::new(enum_type.clone()))
                                 }
                                 crate::ast::EnumVariantData::Struct(fields) => {
                                     let param_types: Vec<CheckerType> = fields
                                         .iter()
-                                        .map(|(_, ty)| CheckerType::from(ty))
+                                        .map(|(_, ty)| self.ast_type_to_checker_type(ty))
                                         .collect();
                                     CheckerType::Function(param_types, Box::new(enum_type.clone()))
                                 }
@@ -869,33 +1123,65 @@ impl TypeChecker {
                     self.trait_resolver.register_impl(impl_block)?;
 
                     // If this is a trait impl, verify all required methods are implemented
-                    if let Some(Type::Custom(trait_name)) = &impl_block.trait_type {
-                        self.trait_resolver
-                            .check_trait_impl_complete(impl_block, trait_name)?;
+                    match &impl_block.trait_type {
+                        Some(Type::Custom(trait_name))
+                        | Some(Type::Generic {
+                            name: trait_name, ..
+                        }) => {
+                            self.trait_resolver
+                                .check_trait_impl_complete(impl_block, trait_name)?;
+                        }
+                        _ => {}
+                    }
+
+                    // Remember `From` conversions for error propagation with `?`
+                    if let Some(Type::Generic { name, args }) = &impl_block.trait_type {
+                        if let Some(GenericArg::Type(source)) =
+                            args.first().filter(|_| name == "From")
+                        {
+                            let source_type = self.ast_type_to_checker_type(source);
+                            self.from_impls
+                                .entry(impl_block.for_type.to_string())
+                                .or_default()
+                                .push((source_type, source.to_string()));
+                        }
                     }
 
                     // Register methods from impl blocks
+                    let self_type: HashMap<String, Type> =
+                        HashMap::from([("Self".to_string(), impl_block.for_type.clone())]);
                     for method in &impl_block.methods {
-                        // Create qualified method name
-                        let method_name = if let Some(_trait_type) = &impl_block.trait_type {
-                            // Trait implementation method
-                            format!("{}::{}", impl_block.for_type, method.name)
-                        } else {
-                            // Inherent method
-                            format!("{}::{}", impl_block.for_type, method.name)
+                        // Create qualified method name; `impl<T> Vec<T>` methods live under `Vec`
+                        let method_name = match &impl_block.for_type {
+                            Type::Generic { name, .. } if !impl_block.type_params.is_empty() => {
+                                format!("{}::{}", name, method.name)
+                            }
+                            for_type => format!("{}::{}", for_type, method.name),
                         };
 
-                        if !method.type_params.is_empty() {
-                            // Generic method - store for later instantiation
+                        if !method.type_params.is_empty() || !impl_block.type_params.is_empty() {
+                            // Generic method - store for later instantiation. Methods of a
+                            // generic impl are generic over the impl's parameters too.
                             let generic_func = GenericFunction {
                                 lifetime_params: method.lifetime_params.clone(),
-                                type_params: method.type_params.clone(),
+                                type_params: impl_block
+                                    .type_params
+                                    .iter()
+                                    .chain(&method.type_params)
+                                    .cloned()
+                                    .collect(),
                                 params: method
                                     .params
                                     .iter()
-                                    .map(|p| (p.name.clone(), p.ty.clone()))
+                                    .map(|p| {
+                                        let ty = self.substitute_type_params_map(&p.ty, &self_type);
+                                        (p.name.clone(), ty)
+                                    })
                                     .collect(),
-                                return_type: method.return_type.clone(),
+                                return_type: method
+                                    .return_type
+                                    .as_ref()
+                                    .map(|ty| self.substitute_type_params_map(ty, &self_type)),
                                 body: method.body.clone(),
                             };
                             self.generic_functions.insert(method_name, generic_func);
@@ -915,6 +1201,10 @@ impl TypeChecker {
 
                             let func_type =
                                 CheckerType::Function(param_types, Box::new(return_type));
+                            self.reference_params.insert(
+                                method_name.clone(),
+                                Self::reference_params_of(&method.params),
+                            );
                             self.functions.insert(method_name, func_type);
                         }
                     }
@@ -922,9 +1212,37 @@ impl TypeChecker {
                 Item::Macro(_) => {
                     // Macros are handled during expansion phase, skip here
                 }
+                Item::Effect(effect_def) => {
+                    // Operations are performed like functions named `Effect::operation`
+                    for operation in &effect_def.operations {
+                        let param_types = operation
+                            .params
+                            .iter()
+                            .map(|param| self.ast_type_to_checker_type(&param.ty))
+                            .collect();
+                        let return_type = operation
+                            .return_type
+                            .as_ref()
+                            .map(|t| self.ast_type_to_checker_type(t))
+                            .unwrap_or(CheckerType::Unit);
+                        self.functions.insert(
+                            format!("{}::{}", effect_def.name, operation.name),
+                            CheckerType::Function(param_types, Box::new(return_type)),
+                        );
+                    }
+                    self.effects
+                        .insert(effect_def.name.clone(), effect_def.clone());
+                }
             }
         }
 
+        // `Copy` impls are checked once every impl is known, since fields may be
+        // `Copy` through impls that come later in the program
+        self.check_copy_impls(program)?;
+        self.check_trait_receivers(program)?;
+        self.check_hash_impls(program)?;
+        self.check_recursive_types(program)?;
+
         // Check for main function
         if !self.functions.contains_key("main") {
             return Err(TypeErrorHelper::missing_main());
@@ -936,8 +1254,10 @@ impl TypeChecker {
                 Item::Function(func) => {
                     self.check_function(func)?;
                 }
-                Item::Struct(_) => {
-                    // Structs are already processed in the first pass
+                Item::Struct(struct_def) => {
+                    // Structs are registered in the first pass; check their default values
+                    self.check_struct_defaults(struct_def)?;
+                    self.finish_inference()?;
                 }
                 Item::Enum(_) => {
                     // Enums are already processed in the first pass
@@ -976,12 +1296,42 @@ impl TypeChecker {
                 Item::Macro(_) => {
                     // Macros are handled during expansion phase, skip here
                 }
+                Item::Effect(_) => {
+                    // Effects are registered in the first pass
+                }
             }
         }
+        self.note_instantiated_types();
 
         Ok(())
     }
 
+    /// Note the types generic function bodies use with the type arguments of
+    /// each of their instantiations
+    fn note_instantiated_types(&mut self) {
+        for (name, type_args) in std::mem::take(&mut self.instantiation_args) {
+            let (Some(generic_func), Some(open_types)) = (
+                self.generic_functions.get(&name),
+                self.open_types.get(&name),
+            ) else {
+                continue;
+            };
+            let subst: HashMap<String, CheckerType> = generic_func
+                .type_params
+                .iter()
+                .cloned()
+                .zip(type_args)
+                .collect();
+            let types: Vec<CheckerType> = open_types
+                .iter()
+                .map(|ty| Self::substitute_checker_type(ty, &subst))
+                .collect();
+            for ty in &types {
+                self.note_type(ty);
+            }
+        }
+    }
+
     /// Convert AST type to CheckerType considering context (struct vs enum)
     fn ast_type_to_checker_type(&self, ast_type: &crate::ast::Type) -> CheckerType {
         match ast_type {
@@ -1007,6 +1357,10 @@ impl TypeChecker {
                     return self.ast_type_to_checker_type(aliased_type);
                 }
 
+                if self.body_type_params.contains(name) {
+                    return CheckerType::TypeParam(name.clone());
+                }
+
                 // Check if it's an enum
                 if self.enums.contains_key(name) {
                     CheckerType::Enum(name.clone())
@@ -1023,17 +1377,7 @@ impl TypeChecker {
                         // TODO: Proper error handling for wrong number of type arguments
                         let checker_args: Vec<GenericArgValue> = args
                             .iter()
-                            .map(|arg| match arg {
-                                GenericArg::Type(t) => {
-                                    GenericArgValue::Type(self.ast_type_to_checker_type(t))
-                                }
-                                GenericArg::Const(c) => GenericArgValue::Const(match c {
-                                    ConstValue::Integer(n) => ConstValueResolved::Integer(*n),
-                                    ConstValue::ConstParam(name) => {
-                                        ConstValueResolved::ConstParam(name.clone())
-                                    }
-                                }),
-                            })
+                            .map(|arg| self.generic_arg_to_checker(arg))
                             .collect();
                         return CheckerType::Generic {
                             name: name.clone(),
@@ -1064,17 +1408,7 @@ impl TypeChecker {
                 // Not a type alias, convert generic types normally
                 let checker_args: Vec<GenericArgValue> = args
                     .iter()
-                    .map(|arg| match arg {
-                        GenericArg::Type(t) => {
-                            GenericArgValue::Type(self.ast_type_to_checker_type(t))
-                        }
-                        GenericArg::Const(c) => GenericArgValue::Const(match c {
-                            ConstValue::Integer(n) => ConstValueResolved::Integer(*n),
-                            ConstValue::ConstParam(name) => {
-                                ConstValueResolved::ConstParam(name.clone())
-                            }
-                        }),
-                    })
+                    .map(|arg| self.generic_arg_to_checker(arg))
                     .collect();
 
                 CheckerType::Generic {
@@ -1082,16 +1416,47 @@ impl TypeChecker {
                     args: checker_args,
                 }
             }
+            // References are treated as their inner type, which may be `Self`
+            crate::ast::Type::Reference { inner, .. } => self.ast_type_to_checker_type(inner),
             _ => CheckerType::from(ast_type),
         }
     }
 
+    /// Convert a generic argument. The parser reads uppercase names like `U` as
+    /// const parameters, so those naming a declared type are types after all.
+    fn generic_arg_to_checker(&self, arg: &GenericArg) -> GenericArgValue {
+        match arg {
+            GenericArg::Type(t) => GenericArgValue::Type(self.ast_type_to_checker_type(t)),
+            GenericArg::Const(ConstValue::ConstParam(name))
+                if self.is_declared_type(name) || self.body_type_params.contains(name) =>
+            {
+                GenericArgValue::Type(
+                    self.ast_type_to_checker_type(&crate::ast::Type::Custom(name.clone())),
+                )
+            }
+            GenericArg::Const(ConstValue::Integer(n)) => {
+                GenericArgValue::Const(ConstValueResolved::Integer(*n))
+            }
+            GenericArg::Const(ConstValue::ConstParam(name)) => {
+                GenericArgValue::Const(ConstValueResolved::ConstParam(name.clone()))
+            }
+        }
+    }
+
+    /// Whether `name` is a struct, enum or type alias of the program
+    fn is_declared_type(&self, name: &str) -> bool {
+        self.declared_types.contains(name)
+            || self.structs.contains_key(name)
+            || self.enums.contains_key(name)
+            || self.generic_structs.contains_key(name)
+            || self.generic_enums.contains_key(name)
+            || self.type_aliases.contains_key(name)
+    }
+
     /// Type check a function
     fn check_function(&mut self, func: &Function) -> Result<()> {
-        // Skip generic functions - they'll be checked when instantiated
-        if !func.type_params.is_empty() {
-            return Ok(());
-        }
+        // A generic body is checked once, with its type parameters left open
+        self.body_type_params = func.type_params.iter().cloned().collect();
 
         // Enter function scope
         self.symbols.enter_scope();
@@ -1099,6 +1464,7 @@ impl TypeChecker {
         // Add function parameters to symbol table
         for param in &func.params {
             let checker_type = self.ast_type_to_checker_type(&param.ty);
+            self.note_type(&checker_type);
             self.symbols
                 .define(param.name.clone(), checker_type, param.mutable)?;
         }
@@ -1109,30 +1475,151 @@ impl TypeChecker {
             .as_ref()
             .map(|t| self.ast_type_to_checker_type(t))
             .unwrap_or(CheckerType::Unit);
+        self.note_type(&base_return_type);
 
-        // If function is async, wrap return type in Future
-        let return_type = if func.is_async {
-            CheckerType::Generic {
-                name: "Future".to_string(),
-                args: vec![GenericArgValue::Type(base_return_type)],
-            }
-        } else {
-            base_return_type
-        };
-
-        self.current_function_return = Some(return_type);
+        // Callers of an async function get a Future, but its body returns the output
+        self.current_function_return = Some(base_return_type);
+        self.current_function_async = func.is_async;
 
         // Type check each statement in the body
         for stmt in &func.body {
             self.check_statement(stmt)?;
         }
+        self.finish_inference()?;
+        let open_types = std::mem::take(&mut self.body_open_types);
+        if !func.type_params.is_empty() {
+            self.open_types.insert(func.name.clone(), open_types);
+        }
+        self.body_type_params.clear();
 
         // Exit function scope
         self.symbols.exit_scope();
         self.current_function_return = None;
+        self.current_function_async = false;
+
+        // Handlers can't see the locals of the function handling the effect
+        for operation in std::mem::take(&mut self.pending_handlers) {
+            self.check_function(&operation)?;
+        }
+        Ok(())
+    }
+
+    /// Check a handler against the operations its effect declares, and queue
+    /// its operations to be checked once the enclosing function is
+    fn check_handler(&mut self, handler: &EffectHandler) -> Result<()> {
+        let effect_def = self
+            .effects
+            .get(&handler.effect)
+            .cloned()
+            .ok_or_else(|| self.error_helper.undefined_effect(&handler.effect, handler.span))?;
+
+        for operation in &handler.operations {
+            let declared = effect_def
+                .operations
+                .iter()
+                .find(|declared| declared.name == operation.name)
+                .ok_or_else(|| {
+                    self.error_helper.unknown_effect_operation(
+                        &handler.effect,
+                        &operation.name,
+                        operation.span,
+                    )
+                })?;
+
+            // The handler takes the operation's arguments and returns its result
+            let signature = |params: &[Param], return_type: &Option<Type>| Type::Function {
+                params: params.iter().map(|param| param.ty.clone()).collect(),
+                return_type: Box::new(return_type.clone().unwrap_or(Type::Unit)),
+                effects: None,
+            };
+            let expected = signature(&declared.params, &declared.return_type);
+            let found = signature(&operation.params, &operation.return_type);
+            if !operation.type_params.is_empty()
+                || operation.is_async
+                || CheckerType::from(&expected) != CheckerType::from(&found)
+            {
+                return Err(CompileError::TypeMismatch {
+                    expected: format!("{} for {}::{}", expected, handler.effect, declared.name),
+                    found: found.to_string(),
+                    span: Some(operation.span),
+                });
+            }
+        }
+        if let Some(missing) = effect_def.operations.iter().find(|declared| {
+            !handler
+                .operations
+                .iter()
+                .any(|operation| operation.name == declared.name)
+        }) {
+            return Err(self.error_helper.missing_handler_operation(
+                &handler.effect,
+                &missing.name,
+                handler.span,
+            ));
+        }
+
+        self.pending_handlers
+            .extend(handler.operations.iter().cloned());
+        Ok(())
+    }
+
+    /// Check the arguments of a call to a function taking `param_types`
+    fn check_call_args(
+        &mut self,
+        name: &str,
+        param_types: &[CheckerType],
+        args: &[Expr],
+        span: Span,
+    ) -> Result<()> {
+        if args.len() != param_types.len() {
+            return Err(CompileError::ArgumentCountMismatch {
+                name: name.to_string(),
+                expected: param_types.len(),
+                found: args.len(),
+                span: None,
+            });
+        }
+
+        let by_reference = self.reference_params.get(name).cloned().unwrap_or_default();
+        for (i, (arg, expected_type)) in args.iter().zip(param_types).enumerate() {
+            let arg_type = self.check_expression_expecting(arg, expected_type)?;
+            // A box lends its contents where a reference to them is expected
+            let arg_type = match Self::boxed_type(&self.resolve(&arg_type)) {
+                Some(contents)
+                    if by_reference.get(i) == Some(&true)
+                        && Self::boxed_type(&self.resolve(expected_type)).is_none() =>
+                {
+                    contents.clone()
+                }
+                _ => arg_type,
+            };
+            if !self.unify(expected_type, &arg_type) {
+                return Err(CompileError::TypeMismatch {
+                    expected: expected_type.to_string(),
+                    found: self.resolve(&arg_type).to_string(),
+                    span: Self::arg_span(arg, span),
+                });
+            }
+            self.settle_expr_type(arg, expected_type);
+        }
         Ok(())
     }
 
+    /// Where a mismatched argument of the call at `call_span` is reported: at
+    /// the argument, or at the call for arguments that record no span
+    fn arg_span(arg: &Expr, call_span: Span) -> Option<Span> {
+        let span = Some(arg.span()).filter(|span| *span != Span::dummy());
+        Some(span.unwrap_or(call_span))
+    }
+
+    /// Which of `params` take a reference
+    fn reference_params_of(params: &[crate::ast::Param]) -> Vec<bool> {
+        params
+            .iter()
+            .map(|param| matches!(param.ty, Type::Reference { .. }))
+            .collect()
+    }
+
     /// Type check a statement
     fn check_statement(&mut self, stmt: &Stmt) -> Result<()> {
         match stmt {
@@ -1140,6 +1627,9 @@ impl TypeChecker {
                 self.check_expression(expr)?;
                 Ok(())
             }
+            Stmt::Return(_) if !self.async_scopes.is_empty() => {
+                Err(self.error_helper.leave_async_scope("return"))
+            }
             Stmt::Return(None) => {
                 // Returning nothing is Unit type
                 if self.current_function_return != Some(CheckerType::Unit) {
@@ -1152,15 +1642,13 @@ impl TypeChecker {
                 Ok(())
             }
             Stmt::Return(Some(expr)) => {
-                let expr_type = self.check_expression(expr)?;
-                if let Some(expected) = &self.current_function_return {
-                    if expr_type != *expected {
-                        return Err(CompileError::TypeMismatch {
-                            expected: expected.to_string(),
-                            found: expr_type.to_string(),
-                            span: None,
-                        });
-                    }
+                if let Some(expected) = self.current_function_return.clone() {
+                    let expr_type = self.check_expression_expecting(expr, &expected)?;
+                    let span = Some(expr.span()).filter(|span| *span != Span::dummy());
+                    self.expect_type_at(&expected, &expr_type, span)?;
+                    self.settle_expr_type(expr, &expected);
+                } else {
+                    self.check_expression(expr)?;
                 }
                 Ok(())
             }
@@ -1169,25 +1657,20 @@ impl TypeChecker {
                 ty,
                 value,
                 mutable,
-                ..
+                span,
             } => {
-                // Type check the value expression
-                let value_type = self.check_expression(value)?;
-
-                // If type annotation is provided, check that it matches
+                // If type annotation is provided, check the value against it
                 if let Some(annotated_type) = ty {
                     let expected_type = self.ast_type_to_checker_type(annotated_type);
-                    if value_type != expected_type {
-                        return Err(self.error_helper.type_mismatch(
-                            &expected_type.to_string(),
-                            &value_type.to_string(),
-                            None,
-                        ));
-                    }
+                    let value_type = self.check_expression_expecting(value, &expected_type)?;
+                    self.expect_type_at(&expected_type, &value_type, Some(*span))?;
+                    self.note_type(&expected_type);
+                    self.settle_expr_type(value, &expected_type);
                     // Define variable with annotated type
                     self.symbols.define(name.clone(), expected_type, *mutable)?;
                 } else {
                     // Define variable with inferred type
+                    let value_type = self.check_expression(value)?;
                     self.symbols.define(name.clone(), value_type, *mutable)?;
                 }
 
@@ -1218,17 +1701,11 @@ impl TypeChecker {
                             return Err(self.error_helper.immutable_assignment(name));
                         }
 
-                        // Type check the value expression
-                        let value_type = self.check_expression(value)?;
-
-                        // Check that types match
-                        if value_type != var_type {
-                            return Err(self.error_helper.type_mismatch(
-                                &var_type.to_string(),
-                                &value_type.to_string(),
-                                None,
-                            ));
-                        }
+                        // Type check the value expression against the variable's type
+                        let value_type = self.check_expression_expecting(value, &var_type)?;
+                        self.expect_type(&var_type, &value_type)?;
+                        let var_type = self.resolve(&var_type);
+                        self.settle_expr_type(value, &var_type);
 
                         Ok(())
                     }
@@ -1246,9 +1723,13 @@ impl TypeChecker {
                             });
                         }
 
-                        // Extract element type from array type
-                        let elem_type = match array_type {
-                            CheckerType::Array(elem_type, _size) => elem_type.as_ref().clone(),
+                        // Extract element type from array or vector type
+                        let array_type = self.resolve(&array_type);
+                        let elem_type = match (&array_type, self.vec_element(&array_type)) {
+                            (_, Some(elem_type)) => elem_type.clone(),
+                            (CheckerType::Array(elem_type, _size), None) => {
+                                elem_type.as_ref().clone()
+                            }
                             _ => {
                                 return Err(CompileError::Generic(format!(
                                     "Cannot index into non-array type: {}",
@@ -1258,22 +1739,23 @@ impl TypeChecker {
                         };
 
                         // Type check the value expression
-                        let value_type = self.check_expression(value)?;
+                        let value_type = self.check_expression_expecting(value, &elem_type)?;
 
                         // Check that types match
-                        if value_type != elem_type {
+                        if !self.unify(&elem_type, &value_type) {
                             return Err(CompileError::TypeMismatch {
                                 expected: elem_type.to_string(),
-                                found: value_type.to_string(),
+                                found: self.resolve(&value_type).to_string(),
                                 span: None,
                             });
                         }
+                        self.settle_expr_type(value, &elem_type);
 
                         Ok(())
                     }
                     AssignTarget::FieldAccess { object, field } => {
-                        // Type check the object expression
-                        let object_type = self.check_expression(object)?;
+                        // Type check the object expression, looking through boxes
+                        let object_type = Self::auto_deref(self.check_expression(object)?);
 
                         let field_type = match &object_type {
                             // Handle non-generic structs
@@ -1426,28 +1908,33 @@ impl TypeChecker {
                 Ok(())
             }
             Stmt::For {
-                var, iter, body, ..
+                var,
+                pair,
+                iter,
+                body,
+                ..
             } => {
-                // Type check the iterator expression
-                let iter_type = self.check_expression(iter)?;
-
-                // Extract element type from array
-                let elem_type = match iter_type {
-                    CheckerType::Array(elem_type, _size) => elem_type.as_ref().clone(),
-                    _ => {
-                        return Err(CompileError::Generic(format!(
-                            "For loop requires an array, found {}",
-                            iter_type
-                        )));
-                    }
-                };
+                // `for x in expr` walks whatever expr turns into an iterator
+                let (item_type, _) = self.check_iterator(iter)?;
+                let item_type = self.resolve(&item_type);
 
                 // Enter new scope for loop body
                 self.symbols.enter_scope();
                 self.loop_depth += 1;
 
-                // Define loop variable with element type
-                self.symbols.define(var.clone(), elem_type, false)?;
+                // Define the loop variables with the item type, split if it's a pair
+                match (pair, &item_type) {
+                    (Some(pair), CheckerType::Tuple(types)) if types.len() == 2 => {
+                        self.symbols.define(var.clone(), types[0].clone(), false)?;
+                        self.symbols.define(pair.clone(), types[1].clone(), false)?;
+                    }
+                    (None, CheckerType::Tuple(_)) | (Some(_), _) => {
+                        return Err(self
+                            .error_helper
+                            .for_loop_pattern_mismatch(&item_type.to_string(), pair.is_some()));
+                    }
+                    (None, _) => self.symbols.define(var.clone(), item_type, false)?,
+                }
 
                 // Type check body
                 for stmt in body {
@@ -1460,22 +1947,25 @@ impl TypeChecker {
                 Ok(())
             }
             Stmt::Break { .. } | Stmt::Continue { .. } => {
+                let keyword = if matches!(stmt, Stmt::Break { .. }) {
+                    "break"
+                } else {
+                    "continue"
+                };
                 // Check that we're inside a loop
                 if self.loop_depth == 0 {
-                    let keyword = if matches!(stmt, Stmt::Break { .. }) {
-                        "break"
-                    } else {
-                        "continue"
-                    };
                     return Err(self.error_helper.control_flow_outside_loop(keyword));
                 }
+                if self.async_scopes.last() == Some(&self.loop_depth) {
+                    return Err(self.error_helper.leave_async_scope(keyword));
+                }
                 Ok(())
             }
             Stmt::Match {
                 expr, arms, span, ..
             } => {
-                // Type check the match expression
-                let expr_type = self.check_expression(expr)?;
+                // Type check the match expression; patterns look through boxes
+                let expr_type = Self::auto_deref(self.check_expression(expr)?);
 
                 // For each arm, check the pattern matches the expression type
                 // and type check the body
@@ -1497,35 +1987,15 @@ impl TypeChecker {
                 }
 
                 // Pattern exhaustiveness checking
-                if let CheckerType::Enum(enum_name) = &expr_type {
-                    // Build enum info for exhaustiveness checker
-                    let mut enum_infos = HashMap::new();
-                    for (name, variants) in &self.enums {
-                        let variant_infos: Vec<VariantInfo> = variants
-                            .iter()
-                            .map(|v| {
-                                let arity = match &v.fields {
-                                    EnumVariantFields::Unit => 0,
-                                    EnumVariantFields::Tuple(types) => types.len(),
-                                    EnumVariantFields::Named(fields) => fields.len(),
-                                };
-                                VariantInfo {
-                                    name: v.name.clone(),
-                                    arity,
-                                }
-                            })
-                            .collect();
-
-                        enum_infos.insert(
-                            name.clone(),
-                            EnumInfo {
-                                name: name.clone(),
-                                variants: variant_infos,
-                            },
-                        );
+                let matched_enum = match &expr_type {
+                    CheckerType::Enum(name) => Some(name),
+                    CheckerType::Generic { name, .. } if self.generic_enums.contains_key(name) => {
+                        Some(name)
                     }
-
-                    let exhaustiveness_checker = ExhaustivenessChecker::new(enum_infos);
+                    _ => None,
+                };
+                if let Some(enum_name) = matched_enum {
+                    let exhaustiveness_checker = ExhaustivenessChecker::new(self.enum_infos());
                     let patterns: Vec<Pattern> =
                         arms.iter().map(|arm| arm.pattern.clone()).collect();
                     exhaustiveness_checker.check_match(enum_name, &patterns, *span)?;
@@ -1533,6 +2003,28 @@ impl TypeChecker {
 
                 Ok(())
             }
+            Stmt::AsyncScope { body, span, .. } => {
+                if !self.current_function_async {
+                    return Err(self.error_helper.scope_outside_async(*span));
+                }
+                self.async_scopes.push(self.loop_depth);
+                self.symbols.enter_scope();
+                let checked = body.iter().try_for_each(|stmt| self.check_statement(stmt));
+                self.symbols.exit_scope();
+                self.async_scopes.pop();
+                checked
+            }
+            Stmt::Handle { body, handlers, .. } => {
+                self.symbols.enter_scope();
+                let checked = body.iter().try_for_each(|stmt| self.check_statement(stmt));
+                self.symbols.exit_scope();
+                checked?;
+
+                for handler in handlers {
+                    self.check_handler(handler)?;
+                }
+                Ok(())
+            }
             Stmt::Unsafe { body, .. } => {
                 // Enter unsafe context
                 self.unsafe_depth += 1;
@@ -1658,7 +2150,7 @@ impl TypeChecker {
             Expr::Ident(name) => {
                 // First check if it's a variable
                 if let Some(var_info) = self.symbols.lookup(name) {
-                    return Ok(var_info.ty.clone());
+                    return Ok(self.resolve(&var_info.ty));
                 }
 
                 // Then check if it's a function
@@ -1703,77 +2195,85 @@ impl TypeChecker {
                     }
                 }
             }
-            Expr::Call { func, args, .. } => {
+            Expr::Turbofish { name, .. } => Err(CompileError::Generic(format!(
+                "Generic function '{}' can only be called, not used as a value",
+                name
+            ))),
+            Expr::Call { func, args, span } => {
+                // `value.clone()` copies any Clone value
+                if let Some(receiver) = expr.clone_receiver() {
+                    return self.check_clone(receiver);
+                }
+
+                // Iterator chains compile to loops; as values they must be consumed
+                if let Some((receiver, method, args)) = expr.iterator_call() {
+                    return self.check_iterator_consumer(receiver, method, args);
+                }
+
                 // Get function name (for v0.1, only direct calls)
-                let func_name = match func.as_ref() {
-                    Expr::Ident(name) => name,
-                    _ => {
+                let func_name = match func.callee_name() {
+                    Some(name) => name,
+                    None => {
                         return Err(CompileError::Generic(
                             "Indirect function calls not yet supported".to_string(),
                         ))
                     }
                 };
 
-                // First check if it's a generic function that needs instantiation
-                if let Some(generic_func) = self.generic_functions.get(func_name).cloned() {
-                    // Infer type arguments from the call
-                    let type_args = self.infer_type_args(&generic_func, args)?;
-
-                    // Create instantiation key
-                    let instantiation = FunctionInstantiation {
-                        name: func_name.clone(),
-                        type_args: type_args.clone(),
-                    };
-
-                    // Check if we've already instantiated this combination
-                    if let Some(func_type) = self.instantiations.get(&instantiation) {
-                        return self.check_call_with_type(func_name, func_type.clone(), args);
-                    }
+                // Formatting intrinsics produced by format!/println!
+                if let Some(kind) = FormatKind::from_intrinsic(func_name) {
+                    return self.check_format_arg(kind, args, *span);
+                }
 
-                    // Need to instantiate the generic function
-                    let func_type = self.instantiate_generic_function(&generic_func, &type_args)?;
-                    self.instantiations.insert(instantiation, func_type.clone());
+                // spawn runs a future as a task of the executor
+                if func_name == "spawn"
+                    && !self.functions.contains_key(func_name)
+                    && !self.generic_functions.contains_key(func_name)
+                {
+                    return self.check_spawn(args);
+                }
 
-                    return self.check_call_with_type(func_name, func_type, args);
+                // Generic functions infer their type arguments at the call
+                if let Some(generic_func) = self.generic_functions.get(func_name).cloned() {
+                    let type_args = match func.as_ref() {
+                        Expr::Turbofish { type_args, .. } => Some(type_args.as_slice()),
+                        _ => None,
+                    };
+                    return self.check_generic_call(
+                        func_name,
+                        &generic_func,
+                        type_args,
+                        args,
+                        *span,
+                        None,
+                    );
+                }
+                if matches!(func.as_ref(), Expr::Turbofish { .. }) {
+                    return Err(CompileError::Generic(format!(
+                        "Function '{}' is not generic and takes no type arguments",
+                        func_name
+                    )));
                 }
 
-                // Look up regular function type
-                let func_type = match self.functions.get(func_name) {
-                    Some(ft) => ft.clone(),
-                    None => {
-                        // Update error helper with available functions
-                        let available_funcs = self.get_available_functions();
-                        self.error_helper
-                            .update_available(vec![], available_funcs, vec![]);
-                        return Err(self.error_helper.undefined_function(func_name, None));
-                    }
+                // Look up the function, or the function pointer a variable holds
+                let func_type = match self.symbols.lookup(func_name) {
+                    Some(var_info) => self.resolve(&var_info.ty),
+                    None => match self.functions.get(func_name) {
+                        Some(ft) => ft.clone(),
+                        None => {
+                            // Update error helper with available functions
+                            let available_funcs = self.get_available_functions();
+                            self.error_helper
+                                .update_available(vec![], available_funcs, vec![]);
+                            return Err(self.error_helper.undefined_function(func_name, None));
+                        }
+                    },
                 };
 
                 // Check function type
                 match func_type {
                     CheckerType::Function(param_types, return_type) => {
-                        // Check argument count
-                        if args.len() != param_types.len() {
-                            return Err(CompileError::ArgumentCountMismatch {
-                                name: func_name.clone(),
-                                expected: param_types.len(),
-                                found: args.len(),
-                                span: None,
-                            });
-                        }
-
-                        // Check argument types
-                        for (arg, expected_type) in args.iter().zip(param_types.iter()) {
-                            let arg_type = self.check_expression(arg)?;
-                            if arg_type != *expected_type {
-                                return Err(CompileError::TypeMismatch {
-                                    expected: expected_type.to_string(),
-                                    found: arg_type.to_string(),
-                                    span: None,
-                                });
-                            }
-                        }
-
+                        self.check_call_args(func_name, &param_types, args, *span)?;
                         Ok(return_type.as_ref().clone())
                     }
                     _ => Err(CompileError::Generic(format!(
@@ -1785,8 +2285,13 @@ impl TypeChecker {
             Expr::Binary {
                 op, left, right, ..
             } => {
-                let left_type = self.check_expression(left)?;
-                let right_type = self.check_expression(right)?;
+                // Operators only read their operands, so a view acts as the string it borrows
+                let operand = |ty: CheckerType| match ty {
+                    CheckerType::Str => CheckerType::String,
+                    ty => ty,
+                };
+                let left_type = operand(self.check_expression(left)?);
+                let right_type = operand(self.check_expression(right)?);
 
                 match op {
                     BinOp::Add => {
@@ -1920,6 +2425,18 @@ impl TypeChecker {
             Expr::Index { array, index, .. } => {
                 // Type check the array expression
                 let array_type = self.check_expression(array)?;
+                let array_type = self.resolve(&array_type);
+
+                // Slicing a vector with a range copies the elements into a new one
+                if let (Some(elem), Expr::Range { .. }) =
+                    (self.vec_element(&array_type), index.as_ref())
+                {
+                    if !self.is_clone(elem) {
+                        return Err(self.error_helper.clone_missing(&elem.to_string()));
+                    }
+                    self.check_expression(index)?;
+                    return Ok(array_type);
+                }
 
                 // Type check the index expression (must be Int)
                 let index_type = self.check_expression(index)?;
@@ -1931,34 +2448,51 @@ impl TypeChecker {
                     });
                 }
 
-                // Extract element type from array type
-                match array_type {
-                    CheckerType::Array(elem_type, _size) => Ok(elem_type.as_ref().clone()),
+                // Extract element type from array or vector type
+                match (&array_type, self.vec_element(&array_type)) {
+                    (_, Some(elem_type)) => Ok(elem_type.clone()),
+                    (CheckerType::Array(elem_type, _size), None) => Ok(elem_type.as_ref().clone()),
                     _ => Err(CompileError::Generic(format!(
                         "Cannot index into non-array type: {}",
                         array_type
                     ))),
                 }
             }
-            Expr::StructLiteral { name, fields, .. } => {
+            Expr::StructLiteral {
+                name,
+                fields,
+                base,
+                span,
+            } => {
                 // First check if this is a generic struct
                 if let Some(generic_struct) = self.generic_structs.get(name).cloned() {
                     // For generic structs, we need to infer type parameters from field values
                     let mut type_substitutions: HashMap<String, CheckerType> = HashMap::new();
 
-                    // First pass: check that all provided fields are valid
-                    for (field_name, _) in fields {
-                        // Find the field's declared type in the generic struct
-                        generic_struct
-                            .fields
-                            .iter()
-                            .find(|(fname, _)| fname == field_name)
-                            .ok_or_else(|| {
-                                CompileError::Generic(format!(
-                                    "Unknown field '{}' for struct '{}'",
-                                    field_name, name
-                                ))
-                            })?;
+                    // First pass: check that the provided fields are valid and complete
+                    let field_names: Vec<&String> =
+                        generic_struct.fields.iter().map(|(n, _)| n).collect();
+                    self.check_struct_literal_fields(name, &field_names, fields, base, *span)?;
+
+                    // A base of the same struct fixes the type arguments it carries
+                    let base_type = match base {
+                        Some(StructBase::Expr(base_expr)) => {
+                            Some(self.check_expression(base_expr)?)
+                        }
+                        _ => None,
+                    };
+                    if let Some(CheckerType::Generic {
+                        name: base_name,
+                        args,
+                    }) = &base_type
+                    {
+                        if base_name == name {
+                            for (param, arg) in generic_struct.type_params.iter().zip(args) {
+                                if let GenericArgValue::Type(arg) = arg {
+                                    type_substitutions.insert(param.clone(), arg.clone());
+                                }
+                            }
+                        }
                     }
 
                     // Second pass: collect type constraints from field values
@@ -2023,18 +2557,13 @@ impl TypeChecker {
                         }
                     }
 
-                    // Check that all required fields are provided
+                    // Check the provided fields against the concrete field types
                     for (field_name, field_type) in &generic_struct.fields {
-                        let provided_expr = fields
-                            .iter()
-                            .find(|(fname, _)| fname == field_name)
-                            .map(|(_, expr)| expr)
-                            .ok_or_else(|| {
-                                CompileError::Generic(format!(
-                                    "Missing field '{}' in struct literal",
-                                    field_name
-                                ))
-                            })?;
+                        let Some((_, provided_expr)) = fields.iter().find(|(f, _)| f == field_name)
+                        else {
+                            // Taken from the base
+                            continue;
+                        };
 
                         // Substitute type parameters in the field type
                         let concrete_checker_type = self.substitute_type_params(
@@ -2103,6 +2632,17 @@ impl TypeChecker {
                             .collect(),
                     };
 
+                    if let (Some(base_type), Some(StructBase::Expr(base_expr))) = (&base_type, base)
+                    {
+                        if *base_type != instantiated_type {
+                            return Err(CompileError::TypeMismatch {
+                                expected: instantiated_type.to_string(),
+                                found: base_type.to_string(),
+                                span: Some(base_expr.span()),
+                            });
+                        }
+                    }
+
                     self.struct_instantiations
                         .insert(instantiation, instantiated_type.clone());
 
@@ -2117,47 +2657,39 @@ impl TypeChecker {
                     .ok_or_else(|| CompileError::Generic(format!("Unknown struct type: {}", name)))?
                     .clone();
 
-                // Check that all fields are provided and have correct types
+                let field_names: Vec<&String> = struct_fields.iter().map(|(n, _)| n).collect();
+                self.check_struct_literal_fields(name, &field_names, fields, base, *span)?;
+
+                // Check that the provided fields have correct types
                 for (field_name, field_type) in &struct_fields {
-                    let provided_expr = fields
-                        .iter()
-                        .find(|(fname, _)| fname == field_name)
-                        .map(|(_, expr)| expr)
-                        .ok_or_else(|| {
-                            CompileError::Generic(format!(
-                                "Missing field '{}' in struct literal",
-                                field_name
-                            ))
-                        })?;
+                    let Some((_, provided_expr)) = fields.iter().find(|(f, _)| f == field_name)
+                    else {
+                        // Taken from the base or the default
+                        continue;
+                    };
 
-                    let provided_type = self.check_expression(provided_expr)?;
-                    if provided_type != *field_type {
+                    let provided_type =
+                        self.check_expression_expecting(provided_expr, field_type)?;
+                    if !self.unify(field_type, &provided_type) {
                         return Err(CompileError::TypeMismatch {
                             expected: field_type.to_string(),
-                            found: provided_type.to_string(),
-                            span: None,
+                            found: self.resolve(&provided_type).to_string(),
+                            span: Self::arg_span(provided_expr, *span),
                         });
                     }
+                    self.settle_expr_type(provided_expr, field_type);
                 }
 
-                // Check that no extra fields are provided
-                for (provided_name, _) in fields {
-                    if !struct_fields
-                        .iter()
-                        .any(|(fname, _)| fname == provided_name)
-                    {
-                        return Err(CompileError::Generic(format!(
-                            "Unknown field '{}' for struct '{}'",
-                            provided_name, name
-                        )));
-                    }
+                let struct_type = CheckerType::Struct(name.clone());
+                if let Some(StructBase::Expr(base_expr)) = base {
+                    self.check_struct_base(base_expr, &struct_type)?;
                 }
 
-                Ok(CheckerType::Struct(name.clone()))
+                Ok(struct_type)
             }
             Expr::FieldAccess { object, field, .. } => {
-                // Type check the object expression
-                let object_type = self.check_expression(object)?;
+                // Type check the object expression, looking through boxes
+                let object_type = Self::auto_deref(self.check_expression(object)?);
 
                 match &object_type {
                     // Handle non-generic structs
@@ -2229,13 +2761,53 @@ impl TypeChecker {
                 enum_name,
                 variant,
                 data,
-                ..
+                span,
             } => {
+                // `Effect::operation(..)` performs an operation of an effect
+                if self.effects.contains_key(enum_name) {
+                    let name = format!("{}::{}", enum_name, variant);
+                    let Some(CheckerType::Function(param_types, return_type)) =
+                        self.functions.get(&name).cloned()
+                    else {
+                        return Err(self
+                            .error_helper
+                            .unknown_effect_operation(enum_name, variant, *span));
+                    };
+                    let args = match data {
+                        Some(crate::ast::EnumConstructorData::Tuple(args)) => args.as_slice(),
+                        _ => &[],
+                    };
+                    self.check_call_args(&name, &param_types, args, *span)?;
+                    return Ok(*return_type);
+                }
+
+                // `Type::function(..)` on a generic impl
+                if let Some((name, generic_func, _, args, span)) = self.generic_call_parts(expr) {
+                    return self.check_generic_call(&name, &generic_func, None, &args, span, None);
+                }
+
+                // `Box::new(value)` moves a value to the heap
+                if enum_name == "Box" && variant == "new" && !self.enums.contains_key(enum_name) {
+                    return self.check_box_new(data.as_ref(), *span);
+                }
+                // `Vec::from(array)` of the built-in `Vec`, sized by its array
+                if enum_name == "Vec" && variant == "from" && self.has_builtin_vec() {
+                    return self.check_vec_from(data.as_ref(), *span);
+                }
+                // `JoinHandle::abort(&handle)` cancels a spawned task
+                if enum_name == "JoinHandle"
+                    && variant == "abort"
+                    && !self.enums.contains_key(enum_name)
+                {
+                    return self.check_abort(data.as_ref(), *span);
+                }
+
                 // Type check enum constructors
                 // First check if the enum exists (could be generic or regular)
                 if let Some(generic_enum) = self.generic_enums.get(enum_name).cloned() {
                     // Handle generic enum - infer type parameters from constructor arguments
-                    let mut inferred_types = Vec::new();
+                    let mut inferred_types: Vec<Option<CheckerType>> =
+                        vec![None; generic_enum.type_params.len()];
 
                     // Find the variant in the generic enum definition
                     let variant_data = generic_enum
@@ -2256,6 +2828,15 @@ impl TypeChecker {
                             crate::ast::EnumVariantData::Tuple(param_types),
                             Some(crate::ast::EnumConstructorData::Tuple(arg_exprs)),
                         ) => {
+                            if param_types.len() != arg_exprs.len() {
+                                return Err(CompileError::ArgumentCountMismatch {
+                                    name: format!("{}::{}", enum_name, variant),
+                                    expected: param_types.len(),
+                                    found: arg_exprs.len(),
+                                    span: Some(*span),
+                                });
+                            }
+
                             // For each type parameter in the variant, infer from arguments
                             for (param_type, arg_expr) in param_types.iter().zip(arg_exprs) {
                                 let arg_type = self.check_expression(arg_expr)?;
@@ -2278,35 +2859,34 @@ impl TypeChecker {
                                         .iter()
                                         .position(|p| p == param_name)
                                     {
-                                        // Ensure we have enough slots
-                                        while inferred_types.len() <= idx {
-                                            inferred_types.push(CheckerType::Unit);
-                                            // placeholder
-                                        }
-                                        inferred_types[idx] = arg_type;
+                                        inferred_types[idx] = Some(arg_type);
                                     }
                                 }
                             }
                         }
                         _ => {
-                            // For other cases, we can't infer yet
-                            // Return a basic enum type for now
+                            // Unit variants (e.g. `Option::None`) constrain nothing;
+                            // the expected type fills the parameters in later
                         }
                     }
 
-                    // If we inferred any types, return a generic type
-                    if !inferred_types.is_empty() {
-                        return Ok(CheckerType::Generic {
-                            name: enum_name.clone(),
-                            args: inferred_types
-                                .iter()
-                                .map(|t| GenericArgValue::Type(t.clone()))
-                                .collect(),
-                        });
-                    } else {
-                        // No type parameters inferred, return basic enum
-                        return Ok(CheckerType::Enum(enum_name.clone()));
-                    }
+                    // Parameters not fixed by the arguments stay open as placeholders
+                    let enum_type = CheckerType::Generic {
+                        name: enum_name.clone(),
+                        args: generic_enum
+                            .type_params
+                            .iter()
+                            .zip(inferred_types)
+                            .map(|(param, inferred)| {
+                                GenericArgValue::Type(
+                                    inferred
+                                        .unwrap_or_else(|| CheckerType::TypeParam(param.clone())),
+                                )
+                            })
+                            .collect(),
+                    };
+                    self.settle_expr_type(expr, &enum_type);
+                    return Ok(enum_type);
                 }
 
                 if !self.enums.contains_key(enum_name) {
@@ -2355,11 +2935,11 @@ impl TypeChecker {
                         // Type check each expression
                         for (expected, expr) in expected_types.iter().zip(exprs) {
                             let expr_type = self.check_expression(expr)?;
-                            if &expr_type != expected {
+                            if !self.unify(expected, &expr_type) {
                                 return Err(CompileError::TypeMismatch {
                                     expected: expected.to_string(),
                                     found: expr_type.to_string(),
-                                    span: None,
+                                    span: Self::arg_span(expr, *span),
                                 });
                             }
                         }
@@ -2393,11 +2973,11 @@ impl TypeChecker {
                                 })?;
 
                             let expr_type = self.check_expression(expr)?;
-                            if &expr_type != expected_type {
+                            if !self.unify(expected_type, &expr_type) {
                                 return Err(CompileError::TypeMismatch {
                                     expected: expected_type.to_string(),
                                     found: expr_type.to_string(),
-                                    span: None,
+                                    span: Self::arg_span(expr, *span),
                                 });
                             }
                         }
@@ -2482,85 +3062,21 @@ impl TypeChecker {
                 // Type check the expression being dereferenced
                 let expr_type = self.check_expression(expr)?;
 
+                // A box dereferences to its contents
+                if let Some(inner) = Self::boxed_type(&expr_type) {
+                    return Ok(inner.clone());
+                }
+
                 // For now, assume dereference returns the same type
                 // TODO: Proper reference type handling - should check that expr_type is a reference
                 Ok(expr_type)
             }
-            Expr::Question { expr, .. } => {
-                // Type check the expression
-                let expr_type = self.check_expression(expr)?;
-
-                // The expression must be a Result<T, E> type
-                match &expr_type {
-                    CheckerType::Generic { name, args } if name == "Result" && args.len() == 2 => {
-                        // Extract the Ok type (T) and Error type (E) from Result<T, E>
-                        let ok_type = &args[0];
-                        let err_type = &args[1];
-
-                        // Check that the current function returns a Result type
-                        if let Some(return_type) = &self.current_function_return {
-                            match return_type {
-                                CheckerType::Generic { name: ret_name, args: ret_args }
-                                    if ret_name == "Result" && ret_args.len() == 2 => {
-                                    // Check that error types match
-                                    let ret_err_type = &ret_args[1];
-                                    if err_type != ret_err_type {
-                                        let ret_err_str = match ret_err_type {
-                                            GenericArgValue::Type(t) => self.checker_type_to_string(t),
-                                            GenericArgValue::Const(c) => match c {
-                                                ConstValueResolved::Integer(n) => n.to_string(),
-                                                ConstValueResolved::ConstParam(name) => name.clone(),
-                                            }
-                                        };
-                                        let err_str = match err_type {
-                                            GenericArgValue::Type(t) => self.checker_type_to_string(t),
-                                            GenericArgValue::Const(c) => match c {
-                                                ConstValueResolved::Integer(n) => n.to_string(),
-                                                ConstValueResolved::ConstParam(name) => name.clone(),
-                                            }
-                                        };
-                                        let ok_str = match ok_type {
-                                            GenericArgValue::Type(t) => self.checker_type_to_string(t),
-                                            GenericArgValue::Const(c) => match c {
-                                                ConstValueResolved::Integer(n) => n.to_string(),
-                                                ConstValueResolved::ConstParam(name) => name.clone(),
-                                            }
-                                        };
-                                        return Err(CompileError::TypeMismatch {
-                                            expected: format!("Result<_, {}>", ret_err_str),
-                                            found: format!("Result<{}, {}>", ok_str, err_str),
-                                            span: None,
-                                        });
-                                    }
-
-                                    // Return the Ok type
-                                    match ok_type {
-                                        GenericArgValue::Type(t) => Ok(t.clone()),
-                                        _ => Err(CompileError::Generic("Expected type in Result".to_string()))
-                                    }
-                                }
-                                _ => Err(CompileError::Generic(
-                                    "The ? operator can only be used in functions that return Result".to_string()
-                                ))
-                            }
-                        } else {
-                            Err(CompileError::Generic(
-                                "The ? operator can only be used inside a function".to_string(),
-                            ))
-                        }
-                    }
-                    CheckerType::Enum(name) if name == "Result" => {
-                        // Handle non-generic Result (shouldn't happen in practice)
-                        Err(CompileError::Generic(
-                            "Result type must have generic parameters".to_string(),
-                        ))
-                    }
-                    _ => Err(CompileError::TypeMismatch {
-                        expected: "Result<T, E>".to_string(),
-                        found: expr_type.to_string(),
-                        span: None,
-                    }),
-                }
+            Expr::Question {
+                expr: operand,
+                span,
+            } => {
+                let operand_type = self.check_expression(operand)?;
+                self.check_question(&operand_type, *span)
             }
             Expr::MacroInvocation { .. } => {
                 // Macros should have been expanded before type checking
@@ -2568,19 +3084,15 @@ impl TypeChecker {
                     "Unexpected macro invocation in type checking - macros should be expanded before this phase".to_string()
                 ))
             }
-            Expr::Await { expr, .. } => {
-                // Check that the expression is a Future type
+            Expr::Await { expr, span } => {
+                if !self.current_function_async {
+                    return Err(self.error_helper.await_outside_async(*span));
+                }
+                // Futures and the join handles of spawned tasks can be awaited
                 let expr_type = self.check_expression(expr)?;
-                match &expr_type {
-                    CheckerType::Generic { name, args } if name == "Future" && args.len() == 1 => {
-                        // Extract the output type from Future<T>
-                        if let GenericArgValue::Type(output_type) = &args[0] {
-                            Ok(output_type.clone())
-                        } else {
-                            Err(CompileError::Generic("Invalid Future type".to_string()))
-                        }
-                    }
-                    _ => Err(CompileError::TypeMismatch {
+                match Self::awaited_output(&expr_type, &["Future", "JoinHandle"]) {
+                    Some(output_type) => Ok(output_type),
+                    None => Err(CompileError::TypeMismatch {
                         expected: "Future<T>".to_string(),
                         found: self.checker_type_to_string(&expr_type),
                         span: None,
@@ -2590,630 +3102,2861 @@ impl TypeChecker {
         }
     }
 
-    /// Check that a pattern is compatible with the given type
-    fn check_pattern(&self, pattern: &Pattern, expected_type: &CheckerType) -> Result<()> {
-        match pattern {
-            Pattern::Wildcard => {
-                // Wildcard matches any type
-                Ok(())
+    /// Type check `operand?` and return the type of the success value
+    /// Check that a struct literal names each field at most once, names no unknown
+    /// fields, and leaves out only fields that its base or defaults provide
+    fn check_struct_literal_fields(
+        &self,
+        struct_name: &str,
+        declared: &[&String],
+        fields: &[(String, Expr)],
+        base: &Option<StructBase>,
+        span: Span,
+    ) -> Result<()> {
+        for (i, (field_name, _)) in fields.iter().enumerate() {
+            if !declared.contains(&field_name) {
+                return Err(CompileError::Generic(format!(
+                    "Unknown field '{}' for struct '{}'",
+                    field_name, struct_name
+                )));
             }
-            Pattern::Ident(_) => {
-                // Identifier pattern matches any type and binds it
-                Ok(())
+            if fields[..i].iter().any(|(earlier, _)| earlier == field_name) {
+                return Err(CompileError::Generic(format!(
+                    "Field '{}' is specified more than once in struct literal",
+                    field_name
+                )));
             }
-            Pattern::EnumPattern {
-                enum_name,
-                variant: _,
-                data: _,
-            } => {
-                // Check that the expected type matches the enum
-                match expected_type {
-                    CheckerType::Enum(name) if name == enum_name => Ok(()),
-                    CheckerType::Generic { name, .. } if name == enum_name => Ok(()),
-                    _ => Err(CompileError::TypeMismatch {
-                        expected: format!("enum {}", enum_name),
-                        found: expected_type.to_string(),
-                        span: None,
-                    }),
-                }
+        }
+
+        if matches!(base, Some(StructBase::Expr(_))) {
+            return Ok(());
+        }
+
+        let defaults = match base {
+            Some(StructBase::Defaults) => self.struct_defaults.get(struct_name),
+            _ => None,
+        };
+        let missing: Vec<String> = declared
+            .iter()
+            .filter(|name| !fields.iter().any(|(f, _)| f == **name))
+            .filter(|name| !defaults.is_some_and(|d| d.contains(name)))
+            .map(|name| name.to_string())
+            .collect();
+
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(CompileError::MissingStructFields {
+                struct_name: struct_name.to_string(),
+                fields: missing,
+                span: Some(span),
+            })
+        }
+    }
+
+    /// Check the `..base` of a struct literal against the literal's struct type
+    fn check_struct_base(&mut self, base: &Expr, struct_type: &CheckerType) -> Result<()> {
+        let base_type = self.check_expression(base)?;
+        if base_type != *struct_type {
+            return Err(CompileError::TypeMismatch {
+                expected: struct_type.to_string(),
+                found: base_type.to_string(),
+                span: Some(base.span()),
+            });
+        }
+        Ok(())
+    }
+
+    /// Check declared default field values against the field types
+    fn check_struct_defaults(&mut self, struct_def: &StructDef) -> Result<()> {
+        for (field_name, default) in &struct_def.field_defaults {
+            let Some((_, field_type)) = struct_def.fields.iter().find(|(f, _)| f == field_name)
+            else {
+                continue;
+            };
+            let expected = CheckerType::from(field_type);
+            let found = self.check_expression(default)?;
+            if found != expected {
+                return Err(CompileError::TypeMismatch {
+                    expected: expected.to_string(),
+                    found: found.to_string(),
+                    span: Some(default.span()),
+                });
             }
         }
+        Ok(())
     }
 
-    /// Bind variables from patterns to the symbol table
-    fn bind_pattern_variables(
+    /// Check a value formatted by a `__fmt_*` intrinsic
+    fn check_format_arg(
         &mut self,
-        pattern: &Pattern,
-        value_type: &CheckerType,
-    ) -> Result<()> {
-        match pattern {
-            Pattern::Wildcard => {
-                // No bindings
-                Ok(())
+        kind: FormatKind,
+        args: &[Expr],
+        span: Span,
+    ) -> Result<CheckerType> {
+        let value_type = self.check_expression(&args[0])?;
+        let arg_type = match &value_type {
+            CheckerType::Int => FormatArgType::Int,
+            CheckerType::Bool => FormatArgType::Bool,
+            CheckerType::String | CheckerType::Str => FormatArgType::Str,
+            CheckerType::Struct(name) | CheckerType::Enum(name) => {
+                FormatArgType::User(name.clone())
             }
-            Pattern::Ident(name) => {
-                // Bind the identifier to the value type
-                self.symbols.define(
-                    name.clone(),
-                    value_type.clone(),
-                    false, // Pattern bindings are immutable by default
-                )?;
-                Ok(())
+            _ => {
+                return Err(self.error_helper.format_trait_missing(
+                    &value_type.to_string(),
+                    "Display",
+                    span,
+                ))
             }
-            Pattern::EnumPattern {
-                enum_name,
-                variant,
-                data,
-                ..
-            } => {
-                // Bind variables from nested patterns
-                if let Some(pattern_data) = data {
-                    // Get enum variant info to determine field types
-
-                    // Handle both regular and generic enums
-                    match value_type {
-                        CheckerType::Enum(expected_enum) if expected_enum == enum_name => {
-                            // Regular enum - check if it's actually a generic enum
-                            if self.generic_enums.contains_key(enum_name) {
-                                // This shouldn't happen - generic enums should have Generic type
-                                return Err(CompileError::Generic(format!(
-                                    "Generic enum {} used without type parameters",
-                                    enum_name
-                                )));
-                            }
+        };
 
-                            // Handle regular enum
-                            let variants = self
-                                .enums
-                                .get(enum_name)
-                                .ok_or_else(|| {
-                                    CompileError::Generic(format!(
-                                        "Undefined enum type: {}",
-                                        enum_name
-                                    ))
-                                })?
-                                .clone();
+        if kind.is_integer_only() && arg_type != FormatArgType::Int {
+            return Err(self.error_helper.format_expects_integer(
+                kind.placeholder(),
+                &value_type.to_string(),
+                span,
+            ));
+        }
 
-                            let variant_info = variants
-                                .iter()
-                                .find(|v| &v.name == variant)
-                                .ok_or_else(|| {
-                                    CompileError::Generic(format!(
-                                        "Unknown variant {}::{}",
-                                        enum_name, variant
-                                    ))
-                                })?
-                                .clone();
+        if let FormatArgType::User(name) = &arg_type {
+            let trait_name = if kind == FormatKind::Debug {
+                "Debug"
+            } else {
+                "Display"
+            };
+            if !self
+                .trait_resolver
+                .type_implements_trait(&Type::Custom(name.clone()), trait_name)
+            {
+                return Err(self
+                    .error_helper
+                    .format_trait_missing(name, trait_name, span));
+            }
+        }
 
-                            match (pattern_data, &variant_info.fields) {
-                                (
-                                    PatternData::Tuple(patterns),
-                                    EnumVariantFields::Tuple(field_types),
-                                ) => {
-                                    // Bind each tuple pattern with its corresponding type
-                                    if patterns.len() != field_types.len() {
-                                        return Err(CompileError::Generic(format!(
-                                            "Pattern has wrong number of fields for {}::{}",
-                                            enum_name, variant
-                                        )));
-                                    }
+        self.format_args.insert(span, arg_type);
+        Ok(CheckerType::String)
+    }
 
-                                    for (pattern, field_type) in patterns.iter().zip(field_types) {
-                                        self.bind_pattern_variables(pattern, field_type)?;
-                                    }
-                                }
-                                (
-                                    PatternData::Struct(field_patterns),
-                                    EnumVariantFields::Named(expected_fields),
-                                ) => {
-                                    // Bind each struct field pattern with its type
-                                    for (field_name, pattern) in field_patterns {
-                                        let field_type = expected_fields
-                                            .iter()
-                                            .find(|(name, _)| name == field_name)
-                                            .map(|(_, ty)| ty)
-                                            .ok_or_else(|| {
-                                                CompileError::Generic(format!(
-                                                    "Unknown field {} in {}::{}",
-                                                    field_name, enum_name, variant
-                                                ))
-                                            })?;
+    /// Every field (or variant payload) of a type with an `impl Copy` must be `Copy`,
+    /// and the type itself can't have a destructor
+    fn check_copy_impls(&self, program: &Program) -> Result<()> {
+        for item in &program.items {
+            let Item::Impl(impl_block) = item else {
+                continue;
+            };
+            let (Some(Type::Custom(trait_name)), Type::Custom(type_name)) =
+                (&impl_block.trait_type, &impl_block.for_type)
+            else {
+                continue;
+            };
+            if trait_name != "Copy" {
+                continue;
+            }
+            if self
+                .trait_resolver
+                .type_implements_trait(&impl_block.for_type, "Drop")
+            {
+                return Err(self.error_helper.copy_and_drop(type_name));
+            }
 
-                                        self.bind_pattern_variables(pattern, field_type)?;
-                                    }
-                                }
-                                _ => {
-                                    return Err(CompileError::Generic(format!(
-                                        "Pattern structure doesn't match variant {}::{}",
-                                        enum_name, variant
-                                    )));
-                                }
-                            }
-                        }
-                        CheckerType::Generic { name, args } if name == enum_name => {
-                            // Generic enum with type arguments
-                            if let Some(generic_enum) = self.generic_enums.get(enum_name).cloned() {
-                                // Find the variant
-                                let variant_data = generic_enum
-                                    .variants
-                                    .iter()
-                                    .find(|(v_name, _)| v_name == variant)
-                                    .map(|(_, data)| data)
-                                    .ok_or_else(|| {
-                                        CompileError::Generic(format!(
-                                            "Unknown variant {}::{}",
-                                            enum_name, variant
-                                        ))
-                                    })?;
+            let fields: Vec<(String, &Type)> = program
+                .items
+                .iter()
+                .filter_map(Self::declared_fields)
+                .filter(|(name, _)| *name == type_name)
+                .flat_map(|(_, fields)| fields)
+                .collect();
+            if let Some((field, ty)) = fields
+                .iter()
+                .find(|(_, ty)| !self.trait_resolver.is_copy(ty))
+            {
+                return Err(self.error_helper.copy_field_not_copy(type_name, field, ty));
+            }
+        }
+        Ok(())
+    }
 
-                                // Bind pattern variables based on variant data
-                                match (pattern_data, variant_data) {
-                                    (
-                                        PatternData::Tuple(patterns),
-                                        crate::ast::EnumVariantData::Tuple(param_types),
-                                    ) => {
-                                        if patterns.len() != param_types.len() {
-                                            return Err(CompileError::Generic(format!(
-                                                "Pattern has wrong number of fields for {}::{}",
-                                                enum_name, variant
-                                            )));
-                                        }
+    /// Trait methods are called the way their trait declares them, so an impl
+    /// must take `self` the same way: by value, as `&self` or as `&mut self`
+    fn check_trait_receivers(&self, program: &Program) -> Result<()> {
+        for item in &program.items {
+            let Item::Impl(impl_block) = item else {
+                continue;
+            };
+            let trait_name = match &impl_block.trait_type {
+                Some(Type::Custom(name) | Type::Generic { name, .. }) => name,
+                _ => continue,
+            };
+            for method in &impl_block.methods {
+                let Some(declared) = self
+                    .trait_resolver
+                    .method_receiver(trait_name, &method.name)
+                else {
+                    continue;
+                };
+                let receiver = method
+                    .params
+                    .first()
+                    .filter(|param| param.name == "self")
+                    .map(|param| &param.ty);
+                if Self::receiver_kind(receiver) != Self::receiver_kind(declared) {
+                    return Err(self.error_helper.trait_receiver(
+                        trait_name,
+                        &method.name,
+                        &impl_block.for_type.to_string(),
+                        Self::receiver_kind(declared),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
 
-                                        // For each pattern, determine its type by substituting type parameters
-                                        let type_params = generic_enum.type_params.clone();
-                                        for (pattern, param_type) in
-                                            patterns.iter().zip(param_types)
-                                        {
-                                            // Extract types from generic args
-                                            let concrete_types: Vec<CheckerType> = args
-                                                .iter()
-                                                .filter_map(|arg| match arg {
-                                                    GenericArgValue::Type(t) => Some(t.clone()),
-                                                    _ => None,
-                                                })
-                                                .collect();
-                                            let concrete_type = self.substitute_type_params(
-                                                param_type,
-                                                &type_params,
-                                                &concrete_types,
-                                            )?;
-                                            self.bind_pattern_variables(pattern, &concrete_type)?;
-                                        }
-                                        return Ok(());
-                                    }
-                                    _ => {
-                                        // TODO: Handle other pattern types
-                                        return Ok(());
-                                    }
-                                }
-                            } else {
-                                return Err(CompileError::Generic(format!(
-                                    "Generic enum {} not found in definitions",
-                                    enum_name
-                                )));
-                            }
-                        }
-                        _ => {
-                            return Err(CompileError::TypeMismatch {
-                                expected: format!("enum {}", enum_name),
-                                found: value_type.to_string(),
-                                span: None,
-                            });
+    /// How a method takes `self`: not at all, by value (`Some(None)`) or by a
+    /// reference of the given mutability
+    fn receiver_kind(receiver: Option<&Type>) -> Option<Option<bool>> {
+        receiver.map(|ty| match ty {
+            Type::Reference { mutable, .. } => Some(*mutable),
+            _ => None,
+        })
+    }
+
+    /// `Hash` and `Eq` are derived field by field, so every field (or variant
+    /// payload) of a type implementing them must implement them too
+    fn check_hash_impls(&self, program: &Program) -> Result<()> {
+        for item in &program.items {
+            let Item::Impl(impl_block) = item else {
+                continue;
+            };
+            let (Some(Type::Custom(trait_name)), Type::Custom(type_name)) =
+                (&impl_block.trait_type, &impl_block.for_type)
+            else {
+                continue;
+            };
+            let derived: fn(&TraitResolver, &Type) -> bool = match trait_name.as_str() {
+                "Hash" => TraitResolver::is_hash,
+                "Eq" => TraitResolver::is_eq,
+                _ => continue,
+            };
+
+            let fields: Vec<(String, &Type)> = program
+                .items
+                .iter()
+                .filter_map(Self::declared_fields)
+                .filter(|(name, _)| *name == type_name)
+                .flat_map(|(_, fields)| fields)
+                .collect();
+            if let Some((field, ty)) = fields
+                .iter()
+                .find(|(_, ty)| !derived(&self.trait_resolver, ty))
+            {
+                return Err(self
+                    .error_helper
+                    .derived_field_missing(type_name, trait_name, field, ty));
+            }
+        }
+        Ok(())
+    }
+
+    /// Name of a struct or enum with its fields, labelled by field or variant
+    fn declared_fields(item: &Item) -> Option<(&String, Vec<(String, &Type)>)> {
+        match item {
+            Item::Struct(def) => Some((
+                &def.name,
+                def.fields
+                    .iter()
+                    .map(|(name, ty)| (name.clone(), ty))
+                    .collect(),
+            )),
+            Item::Enum(def) => Some((
+                &def.name,
+                def.variants
+                    .iter()
+                    .flat_map(|variant| match &variant.data {
+                        EnumVariantData::Unit => vec![],
+                        EnumVariantData::Tuple(types) => {
+                            types.iter().map(|ty| (variant.name.clone(), ty)).collect()
                         }
-                    }
+                        EnumVariantData::Struct(fields) => fields
+                            .iter()
+                            .map(|(name, ty)| (format!("{}.{}", variant.name, name), ty))
+                            .collect(),
+                    })
+                    .collect(),
+            )),
+            _ => None,
+        }
+    }
+
+    /// Reject structs and enums that contain themselves without indirection,
+    /// which would have infinite size
+    fn check_recursive_types(&self, program: &Program) -> Result<()> {
+        let declared: HashMap<&String, Vec<(String, &Type)>> = program
+            .items
+            .iter()
+            .filter_map(Self::declared_fields)
+            .collect();
+        for item in &program.items {
+            let Some((type_name, fields)) = Self::declared_fields(item) else {
+                continue;
+            };
+            for (field, ty) in fields {
+                let mut visited = HashSet::new();
+                if Self::contains_by_value(ty, type_name, &declared, &mut visited) {
+                    return Err(self.error_helper.recursive_type(type_name, &field, ty));
                 }
-                Ok(())
             }
         }
+        Ok(())
     }
 
-    /// Infer type arguments for a generic function call
-    fn infer_type_args(
-        &self,
-        generic_func: &GenericFunction,
-        args: &[Expr],
-    ) -> Result<Vec<String>> {
-        let mut type_map: HashMap<String, String> = HashMap::new();
+    /// Whether values of `ty` hold a `target` inline, directly or through
+    /// the fields of other types. A `Box` or reference breaks the chain.
+    fn contains_by_value<'a>(
+        ty: &'a Type,
+        target: &str,
+        declared: &HashMap<&String, Vec<(String, &'a Type)>>,
+        visited: &mut HashSet<&'a str>,
+    ) -> bool {
+        let name = match ty {
+            Type::Custom(name) => name,
+            Type::Generic { name, .. } if name == "Box" => return false,
+            Type::Generic { name, args } => {
+                let in_args = args.iter().any(|arg| match arg {
+                    GenericArg::Type(t) => Self::contains_by_value(t, target, declared, visited),
+                    GenericArg::Const(_) => false,
+                });
+                if in_args {
+                    return true;
+                }
+                name
+            }
+            Type::Array(elem, _) => return Self::contains_by_value(elem, target, declared, visited),
+            Type::Tuple(types) => {
+                return types
+                    .iter()
+                    .any(|t| Self::contains_by_value(t, target, declared, visited))
+            }
+            _ => return false,
+        };
+        if name == target {
+            return true;
+        }
+        if !visited.insert(name) {
+            return false;
+        }
+        declared.get(name).is_some_and(|fields| {
+            fields
+                .iter()
+                .any(|(_, t)| Self::contains_by_value(t, target, declared, visited))
+        })
+    }
 
-        // Check argument count
-        if args.len() != generic_func.params.len() {
-            return Err(CompileError::Generic(format!(
-                "Function expects {} arguments, got {}",
-                generic_func.params.len(),
-                args.len()
-            )));
+    /// Check a `value.clone()` call; the clone has the receiver's type
+    fn check_clone(&mut self, receiver: &Expr) -> Result<CheckerType> {
+        let receiver_type = self.check_expression(receiver)?;
+        let receiver_type = self.resolve(&receiver_type);
+        if !self.is_clone(&receiver_type) {
+            return Err(self.error_helper.clone_missing(&receiver_type.to_string()));
         }
+        Ok(receiver_type)
+    }
 
-        // Infer types from each argument
-        for (arg_expr, (_param_name, param_type)) in args.iter().zip(&generic_func.params) {
-            self.infer_from_expr_and_type(arg_expr, param_type, &mut type_map)?;
+    /// Whether `.clone()` is available on values of a type
+    fn is_clone(&self, ty: &CheckerType) -> bool {
+        match ty {
+            CheckerType::Unit | CheckerType::Int | CheckerType::Bool | CheckerType::String => true,
+            CheckerType::Array(elem, _) => self.is_clone(elem),
+            CheckerType::Tuple(types) => types.iter().all(|t| self.is_clone(t)),
+            CheckerType::Generic { .. } if self.vec_element(ty).is_some() => {
+                self.vec_element(ty).is_some_and(|elem| self.is_clone(elem))
+            }
+            CheckerType::Struct(name) | CheckerType::Enum(name) => {
+                self.trait_resolver.is_clone(&Type::Custom(name.clone()))
+            }
+            CheckerType::Generic { name, .. } => self.trait_resolver.is_clone(&Type::Generic {
+                name: name.clone(),
+                args: vec![],
+            }),
+            _ => false,
         }
+    }
 
-        // Make sure all type parameters were inferred
-        let mut type_args = Vec::new();
-        for type_param in &generic_func.type_params {
-            match type_map.get(type_param) {
-                Some(concrete_type) => type_args.push(concrete_type.clone()),
-                None => {
-                    return Err(CompileError::Generic(format!(
-                        "Could not infer type parameter '{}' from function arguments",
-                        type_param
-                    )));
-                }
+    /// Item type of what a for loop or an iterator method walks, and whether the
+    /// items are fresh values rather than views into a collection
+    fn check_iterator(&mut self, iter: &Expr) -> Result<(CheckerType, bool)> {
+        if let Some((receiver, method, args)) = iter.iterator_call() {
+            return self.check_iterator_method(receiver, method, args);
+        }
+        match iter {
+            Expr::Range { .. } => {
+                self.check_expression(iter)?;
+                return Ok((CheckerType::Int, true));
+            }
+            Expr::Reference { expr, .. } => return self.check_iterator(expr),
+            // A slice is walked in place
+            Expr::Index { array, index, .. } if matches!(index.as_ref(), Expr::Range { .. }) => {
+                let array_type = self.check_expression(array)?;
+                let array_type = self.resolve(&array_type);
+                self.check_expression(index)?;
+                return match (&array_type, self.vec_element(&array_type)) {
+                    (_, Some(elem)) => Ok((elem.clone(), false)),
+                    (CheckerType::Array(elem, _), None) => Ok((elem.as_ref().clone(), false)),
+                    _ => Err(self
+                        .error_helper
+                        .for_loop_non_array(&array_type.to_string())),
+                };
             }
+            _ => {}
         }
 
-        Ok(type_args)
+        let iter_type = self.check_expression(iter)?;
+        let iter_type = self.resolve(&iter_type);
+        if let Some(elem) = self
+            .vec_element(&iter_type)
+            .or_else(|| self.set_element(&iter_type))
+        {
+            return Ok((elem.clone(), false));
+        }
+        if let Some((key, value)) = self.map_entry_types(&iter_type) {
+            return Ok((CheckerType::Tuple(vec![key.clone(), value.clone()]), false));
+        }
+        match &iter_type {
+            CheckerType::Array(elem, _) => Ok((elem.as_ref().clone(), false)),
+            CheckerType::Struct(name) | CheckerType::Enum(name) => self.user_iterator_item(name),
+            _ => Err(self.error_helper.for_loop_non_array(&iter_type.to_string())),
+        }
     }
 
-    /// Helper to infer type parameters from an expression and expected type
-    fn infer_from_expr_and_type(
-        &self,
-        expr: &Expr,
-        expected_type: &crate::ast::Type,
-        type_map: &mut HashMap<String, String>,
-    ) -> Result<()> {
-        match expected_type {
-            crate::ast::Type::TypeParam(param_name) => {
-                // This is a type parameter - infer its type from the expression
-                let expr_type = self.infer_expr_type(expr)?;
+    /// Item type of a user type implementing `Iterator<T>`, or of the iterator
+    /// its `IntoIterator` implementation returns
+    fn user_iterator_item(&mut self, name: &str) -> Result<(CheckerType, bool)> {
+        let ty = Type::Custom(name.to_string());
+        let method = if self.trait_resolver.type_implements_trait(&ty, "Iterator") {
+            "next"
+        } else if self
+            .trait_resolver
+            .type_implements_trait(&ty, "IntoIterator")
+        {
+            "into_iter"
+        } else {
+            return Err(self.error_helper.for_loop_non_array(name));
+        };
+        let return_type = match self.functions.get(&format!("{}::{}", name, method)) {
+            Some(CheckerType::Function(_, return_type)) => self.resolve(return_type),
+            _ => return Err(self.error_helper.for_loop_non_array(name)),
+        };
+        match (method, &return_type) {
+            ("next", CheckerType::Generic { name, args }) if name == "Option" => {
+                let item = Self::type_args(args).remove(0);
+                self.note_type(&item);
+                Ok((item, true))
+            }
+            ("into_iter", CheckerType::Struct(iterator) | CheckerType::Enum(iterator))
+                if iterator != name =>
+            {
+                let iterator = iterator.clone();
+                self.user_iterator_item(&iterator)
+            }
+            _ => Err(self.error_helper.for_loop_non_array(name)),
+        }
+    }
 
-                // Check if we already have a mapping for this type parameter
-                if let Some(existing_type) = type_map.get(param_name) {
-                    if existing_type != &expr_type {
-                        return Err(CompileError::Generic(format!(
-                            "Type parameter '{}' has conflicting types: '{}' and '{}'",
-                            param_name, existing_type, expr_type
-                        )));
-                    }
-                } else {
-                    type_map.insert(param_name.clone(), expr_type);
+    /// Item type of an iterator adapter applied to `receiver`
+    fn check_iterator_method(
+        &mut self,
+        receiver: &Expr,
+        method: &str,
+        args: &[Expr],
+    ) -> Result<(CheckerType, bool)> {
+        let expected_args = match method {
+            "map" | "filter" | "zip" | "take" | "step_by" => 1,
+            _ => 0,
+        };
+        if args.len() != expected_args {
+            return Err(CompileError::ArgumentCountMismatch {
+                name: method.to_string(),
+                expected: expected_args,
+                found: args.len(),
+                span: None,
+            });
+        }
+
+        match method {
+            "chars" => {
+                let receiver_type = self.check_expression(receiver)?;
+                if !self.unify(&CheckerType::Str, &receiver_type) {
+                    return Err(CompileError::TypeMismatch {
+                        expected: "String".to_string(),
+                        found: self.resolve(&receiver_type).to_string(),
+                        span: None,
+                    });
                 }
-                Ok(())
+                Ok((CheckerType::Int, true))
             }
-            crate::ast::Type::Array(elem_type, _size) => {
-                // For arrays, we need to infer the element type
-                match expr {
-                    Expr::ArrayLiteral { elements, .. } => {
-                        if !elements.is_empty() {
-                            // Use first element to infer the type parameter
-                            self.infer_from_expr_and_type(&elements[0], elem_type, type_map)?;
-                        }
-                    }
-                    Expr::ArrayRepeat { value, .. } => {
-                        // Use the repeated value to infer the type parameter
-                        self.infer_from_expr_and_type(value, elem_type, type_map)?;
-                    }
-                    Expr::Ident(name) => {
-                        // For identifiers, we need to look up their type and extract element type
-                        if let Some(var_info) = self.symbols.lookup(name) {
-                            if let CheckerType::Array(var_elem_type, _) = &var_info.ty {
-                                // If elem_type is a type parameter, map it
-                                if let crate::ast::Type::TypeParam(param_name) = elem_type.as_ref()
-                                {
-                                    let elem_type_str = self.checker_type_to_string(var_elem_type);
-                                    type_map.insert(param_name.clone(), elem_type_str);
-                                }
-                            }
-                        }
-                    }
+            "map" | "filter" => {
+                let (item, owned) = self.check_iterator(receiver)?;
+                let (param, result) = self.iterator_function(method, &args[0])?;
+                if !self.unify(&param, &item) {
+                    return Err(CompileError::TypeMismatch {
+                        expected: param.to_string(),
+                        found: self.resolve(&item).to_string(),
+                        span: None,
+                    });
+                }
+                if method == "map" {
+                    self.note_type(&result);
+                    return Ok((result, true));
+                }
+                if result != CheckerType::Bool {
+                    return Err(CompileError::TypeMismatch {
+                        expected: "Bool".to_string(),
+                        found: result.to_string(),
+                        span: None,
+                    });
+                }
+                Ok((item, owned))
+            }
+            "enumerate" | "zip" => {
+                let (first, owned) = match method {
+                    "enumerate" => (CheckerType::Int, true),
+                    _ => self.check_iterator(receiver)?,
+                };
+                let (second, _) = match method {
+                    "enumerate" => self.check_iterator(receiver)?,
+                    _ => self.check_iterator(&args[0])?,
+                };
+                // Pairs don't nest
+                if let Some(pair) = [&first, &second]
+                    .into_iter()
+                    .find(|item| matches!(item, CheckerType::Tuple(_)))
+                {
+                    return Err(self
+                        .error_helper
+                        .iterator_pairs_unsupported(method, &pair.to_string()));
+                }
+                Ok((CheckerType::Tuple(vec![first, second]), owned))
+            }
+            "take" | "step_by" => {
+                let count_type = self.check_expression(&args[0])?;
+                if !self.unify(&count_type, &CheckerType::Int) {
+                    return Err(CompileError::TypeMismatch {
+                        expected: "Int".to_string(),
+                        found: self.resolve(&count_type).to_string(),
+                        span: None,
+                    });
+                }
+                self.check_iterator(receiver)
+            }
+            "rev" => {
+                let mut source = receiver;
+                while let Some((inner, "iter" | "into_iter", _)) = source.iterator_call() {
+                    source = inner;
+                }
+                if let Expr::Reference { expr, .. } = source {
+                    source = expr;
+                }
+                let item = self.check_iterator(receiver)?;
+                let reversible = match source {
+                    Expr::Range { .. } | Expr::Index { .. } => true,
+                    _ if source.iterator_call().is_some() => false,
                     _ => {
-                        // For other expressions, try to infer their type
-                        if let crate::ast::Type::TypeParam(_) = elem_type.as_ref() {
-                            self.infer_from_expr_and_type(expr, elem_type, type_map)?;
-                        }
+                        let source_type = self.check_expression(source)?;
+                        let source_type = self.resolve(&source_type);
+                        matches!(source_type, CheckerType::Array(..))
+                            || self.vec_element(&source_type).is_some()
                     }
+                };
+                if !reversible {
+                    return Err(self.error_helper.iterator_not_reversible());
                 }
-                Ok(())
-            }
-            _ => {
-                // Non-generic type - nothing to infer
-                Ok(())
+                Ok(item)
             }
+            "sum" | "collect" => Err(self
+                .error_helper
+                .for_loop_non_array(&format!("the result of {}()", method))),
+            // `iter` and `into_iter` walk the receiver itself
+            _ => self.check_iterator(receiver),
         }
     }
 
-    /// Get a string representation of the expression's type for inference
-    fn infer_expr_type(&self, expr: &Expr) -> Result<String> {
-        match expr {
-            Expr::String(_) => Ok("String".to_string()),
-            Expr::Integer(_) => Ok("i64".to_string()), // Default to i64
-            Expr::Bool(_) => Ok("bool".to_string()),
-            Expr::Ident(name) => {
-                // Look up variable type
-                if let Some(var_info) = self.symbols.lookup(name) {
-                    Ok(self.checker_type_to_string(&var_info.ty))
-                } else {
-                    Err(CompileError::Generic(format!("Unknown variable: {}", name)))
-                }
+    /// Parameter and return type of the function named as the argument of `map` or `filter`
+    fn iterator_function(
+        &self,
+        method: &str,
+        function: &Expr,
+    ) -> Result<(CheckerType, CheckerType)> {
+        let signature = match function {
+            Expr::Ident(name) if !self.generic_functions.contains_key(name) => {
+                self.functions.get(name)
             }
-            Expr::ArrayLiteral { elements, .. } => {
-                // Infer array type from elements
-                if elements.is_empty() {
-                    return Err(CompileError::Generic(
-                        "Cannot infer type from empty array".to_string(),
-                    ));
-                }
+            _ => None,
+        };
+        match signature {
+            Some(CheckerType::Function(params, result)) if params.len() == 1 => {
+                Ok((params[0].clone(), result.as_ref().clone()))
+            }
+            _ => Err(self.error_helper.iterator_function_expected(method)),
+        }
+    }
 
-                // Get type of first element (assume all elements have same type)
-                let elem_type_str = self.infer_expr_type(&elements[0])?;
-                let size = elements.len();
-                Ok(format!("[{}; {}]", elem_type_str, size))
+    /// Check an iterator chain in value position, where only `sum` and
+    /// `collect` may end it
+    fn check_iterator_consumer(
+        &mut self,
+        receiver: &Expr,
+        method: &str,
+        args: &[Expr],
+    ) -> Result<CheckerType> {
+        if !matches!(method, "sum" | "collect") {
+            return Err(self.error_helper.iterator_not_consumed(method));
+        }
+        if !args.is_empty() {
+            return Err(CompileError::ArgumentCountMismatch {
+                name: method.to_string(),
+                expected: 0,
+                found: args.len(),
+                span: None,
+            });
+        }
+        let (item, owned) = self.check_iterator(receiver)?;
+        let item = self.resolve(&item);
+        if method == "sum" {
+            if item != CheckerType::Int {
+                return Err(CompileError::TypeMismatch {
+                    expected: "Int".to_string(),
+                    found: item.to_string(),
+                    span: None,
+                });
             }
-            Expr::ArrayRepeat { value, count, .. } => {
-                // Infer array type from repeated value
-                let elem_type_str = self.infer_expr_type(value)?;
+            return Ok(CheckerType::Int);
+        }
 
-                // Extract count from expression (simplified - assumes integer literal)
-                let size = match count.as_ref() {
-                    Expr::Integer(n) => *n as usize,
-                    _ => {
-                        return Err(CompileError::Generic(
-                            "Array size must be a constant integer".to_string(),
-                        ))
-                    }
-                };
+        // Items that are views into a collection are cloned into the new vector
+        if let CheckerType::Tuple(_) = item {
+            return Err(self
+                .error_helper
+                .iterator_pairs_unsupported(method, &item.to_string()));
+        }
+        if !owned && !self.is_clone(&item) {
+            return Err(self.error_helper.clone_missing(&item.to_string()));
+        }
+        let collected = CheckerType::Generic {
+            name: "Vec".to_string(),
+            args: vec![GenericArgValue::Type(item)],
+        };
+        self.note_type(&collected);
+        Ok(collected)
+    }
 
-                Ok(format!("[{}; {}]", elem_type_str, size))
+    fn check_question(&mut self, operand_type: &CheckerType, span: Span) -> Result<CheckerType> {
+        if !self.async_scopes.is_empty() {
+            return Err(self.error_helper.leave_async_scope("?"));
+        }
+        let (kind, args) = match operand_type {
+            CheckerType::Generic { name, args }
+                if (name == "Result" && args.len() == 2)
+                    || (name == "Option" && args.len() == 1) =>
+            {
+                (name.clone(), Self::type_args(args))
             }
             _ => {
-                // For other complex expressions, we'd need full type checking
-                Err(CompileError::Generic(
-                    "Cannot infer type from complex expression".to_string(),
+                return Err(self
+                    .error_helper
+                    .question_on_non_try_type(&operand_type.to_string(), span))
+            }
+        };
+
+        let function_return = self
+            .current_function_return
+            .clone()
+            .unwrap_or(CheckerType::Unit);
+
+        let return_args = match &function_return {
+            CheckerType::Generic {
+                name,
+                args: return_args,
+            } if *name == kind && return_args.len() == args.len() => Self::type_args(return_args),
+            _ => {
+                return Err(self.error_helper.question_in_incompatible_function(
+                    &kind,
+                    &self.checker_type_to_string(&function_return),
+                    span,
                 ))
             }
+        };
+
+        // Differing error types are converted through `From`
+        let error_conversion =
+            if kind == "Result" && !self.types_compatible(&return_args[1], &args[1]) {
+                match self.find_from_impl(&return_args[1], &args[1]) {
+                    Some(conversion) => Some(conversion),
+                    None => {
+                        return Err(self.error_helper.question_error_conversion(
+                            &self.checker_type_to_string(&args[1]),
+                            &self.checker_type_to_string(&return_args[1]),
+                            span,
+                        ))
+                    }
+                }
+            } else {
+                None
+            };
+
+        if let Some(operand) = self.enum_instantiation(operand_type) {
+            self.note_type(operand_type);
+            self.note_type(&function_return);
+            self.question_lowerings.insert(
+                span,
+                QuestionLowering {
+                    operand,
+                    error_conversion,
+                },
+            );
         }
+
+        Ok(args[0].clone())
     }
 
-    /// Convert CheckerType to string for type arguments
-    #[allow(clippy::only_used_in_recursion)]
-    fn checker_type_to_string(&self, ty: &CheckerType) -> String {
-        match ty {
-            CheckerType::Unit => "()".to_string(),
-            CheckerType::String => "String".to_string(),
-            CheckerType::Int => "i64".to_string(),
-            CheckerType::Bool => "bool".to_string(),
-            CheckerType::Array(elem, size) => {
-                format!("[{}; {}]", self.checker_type_to_string(elem), size)
-            }
+    /// Find an `impl From<source> for target`, returning the types as written
+    fn find_from_impl(
+        &self,
+        target: &CheckerType,
+        source: &CheckerType,
+    ) -> Option<(String, String)> {
+        let target_name = self.checker_type_to_string(target);
+        self.from_impls
+            .get(&target_name)?
+            .iter()
+            .find(|(from, _)| self.types_compatible(from, source))
+            .map(|(_, written)| (target_name.clone(), written.clone()))
+    }
+
+    /// Exhaustiveness information for every known enum, generic or not
+    fn enum_infos(&self) -> HashMap<String, EnumInfo> {
+        let mut enum_infos = HashMap::new();
+        for (name, variants) in &self.enums {
+            let variant_infos: Vec<VariantInfo> = variants
+                .iter()
+                .map(|v| {
+                    let arity = match &v.fields {
+                        EnumVariantFields::Unit => 0,
+                        EnumVariantFields::Tuple(types) => types.len(),
+                        EnumVariantFields::Named(fields) => fields.len(),
+                    };
+                    VariantInfo {
+                        name: v.name.clone(),
+                        arity,
+                    }
+                })
+                .collect();
+
+            enum_infos.insert(
+                name.clone(),
+                EnumInfo {
+                    name: name.clone(),
+                    variants: variant_infos,
+                },
+            );
+        }
+        for (name, generic_enum) in &self.generic_enums {
+            let variant_infos: Vec<VariantInfo> = generic_enum
+                .variants
+                .iter()
+                .map(|(variant, data)| VariantInfo {
+                    name: variant.clone(),
+                    arity: match data {
+                        crate::ast::EnumVariantData::Unit => 0,
+                        crate::ast::EnumVariantData::Tuple(types) => types.len(),
+                        crate::ast::EnumVariantData::Struct(fields) => fields.len(),
+                    },
+                })
+                .collect();
+
+            enum_infos.insert(
+                name.clone(),
+                EnumInfo {
+                    name: name.clone(),
+                    variants: variant_infos,
+                },
+            );
+        }
+        enum_infos
+    }
+
+    /// Check that a pattern is compatible with the given type
+    fn check_pattern(&self, pattern: &Pattern, expected_type: &CheckerType) -> Result<()> {
+        match pattern {
+            Pattern::Wildcard => {
+                // Wildcard matches any type
+                Ok(())
+            }
+            Pattern::Ident(_) => {
+                // Identifier pattern matches any type and binds it
+                Ok(())
+            }
+            Pattern::EnumPattern {
+                enum_name,
+                variant: _,
+                data: _,
+            } => {
+                // Check that the expected type matches the enum
+                match expected_type {
+                    CheckerType::Enum(name) if name == enum_name => Ok(()),
+                    CheckerType::Generic { name, .. } if name == enum_name => Ok(()),
+                    _ => Err(CompileError::TypeMismatch {
+                        expected: format!("enum {}", enum_name),
+                        found: expected_type.to_string(),
+                        span: None,
+                    }),
+                }
+            }
+        }
+    }
+
+    /// Bind variables from patterns to the symbol table
+    fn bind_pattern_variables(
+        &mut self,
+        pattern: &Pattern,
+        value_type: &CheckerType,
+    ) -> Result<()> {
+        match pattern {
+            Pattern::Wildcard => {
+                // No bindings
+                Ok(())
+            }
+            Pattern::Ident(name) => {
+                // Bind the identifier to the value type
+                self.symbols.define(
+                    name.clone(),
+                    value_type.clone(),
+                    false, // Pattern bindings are immutable by default
+                )?;
+                Ok(())
+            }
+            Pattern::EnumPattern {
+                enum_name,
+                variant,
+                data,
+                ..
+            } => {
+                // Bind variables from nested patterns
+                if let Some(pattern_data) = data {
+                    // Get enum variant info to determine field types
+
+                    // Handle both regular and generic enums
+                    match value_type {
+                        CheckerType::Enum(expected_enum) if expected_enum == enum_name => {
+                            // Regular enum - check if it's actually a generic enum
+                            if self.generic_enums.contains_key(enum_name) {
+                                // This shouldn't happen - generic enums should have Generic type
+                                return Err(CompileError::Generic(format!(
+                                    "Generic enum {} used without type parameters",
+                                    enum_name
+                                )));
+                            }
+
+                            // Handle regular enum
+                            let variants = self
+                                .enums
+                                .get(enum_name)
+                                .ok_or_else(|| {
+                                    CompileError::Generic(format!(
+                                        "Undefined enum type: {}",
+                                        enum_name
+                                    ))
+                                })?
+                                .clone();
+
+                            let variant_info = variants
+                                .iter()
+                                .find(|v| &v.name == variant)
+                                .ok_or_else(|| {
+                                    CompileError::Generic(format!(
+                                        "Unknown variant {}::{}",
+                                        enum_name, variant
+                                    ))
+                                })?
+                                .clone();
+
+                            match (pattern_data, &variant_info.fields) {
+                                (
+                                    PatternData::Tuple(patterns),
+                                    EnumVariantFields::Tuple(field_types),
+                                ) => {
+                                    // Bind each tuple pattern with its corresponding type
+                                    if patterns.len() != field_types.len() {
+                                        return Err(CompileError::Generic(format!(
+                                            "Pattern has wrong number of fields for {}::{}",
+                                            enum_name, variant
+                                        )));
+                                    }
+
+                                    for (pattern, field_type) in patterns.iter().zip(field_types) {
+                                        self.bind_pattern_variables(pattern, field_type)?;
+                                    }
+                                }
+                                (
+                                    PatternData::Struct(field_patterns),
+                                    EnumVariantFields::Named(expected_fields),
+                                ) => {
+                                    // Bind each struct field pattern with its type
+                                    for (field_name, pattern) in field_patterns {
+                                        let field_type = expected_fields
+                                            .iter()
+                                            .find(|(name, _)| name == field_name)
+                                            .map(|(_, ty)| ty)
+                                            .ok_or_else(|| {
+                                                CompileError::Generic(format!(
+                                                    "Unknown field {} in {}::{}",
+                                                    field_name, enum_name, variant
+                                                ))
+                                            })?;
+
+                                        self.bind_pattern_variables(pattern, field_type)?;
+                                    }
+                                }
+                                _ => {
+                                    return Err(CompileError::Generic(format!(
+                                        "Pattern structure doesn't match variant {}::{}",
+                                        enum_name, variant
+                                    )));
+                                }
+                            }
+                        }
+                        CheckerType::Generic { name, args } if name == enum_name => {
+                            // Generic enum with type arguments
+                            if let Some(generic_enum) = self.generic_enums.get(enum_name).cloned() {
+                                // Find the variant
+                                let variant_data = generic_enum
+                                    .variants
+                                    .iter()
+                                    .find(|(v_name, _)| v_name == variant)
+                                    .map(|(_, data)| data)
+                                    .ok_or_else(|| {
+                                        CompileError::Generic(format!(
+                                            "Unknown variant {}::{}",
+                                            enum_name, variant
+                                        ))
+                                    })?;
+
+                                // Bind pattern variables based on variant data
+                                match (pattern_data, variant_data) {
+                                    (
+                                        PatternData::Tuple(patterns),
+                                        crate::ast::EnumVariantData::Tuple(param_types),
+                                    ) => {
+                                        if patterns.len() != param_types.len() {
+                                            return Err(CompileError::Generic(format!(
+                                                "Pattern has wrong number of fields for {}::{}",
+                                                enum_name, variant
+                                            )));
+                                        }
+
+                                        // For each pattern, determine its type by substituting type parameters
+                                        let type_params = generic_enum.type_params.clone();
+                                        for (pattern, param_type) in
+                                            patterns.iter().zip(param_types)
+                                        {
+                                            // Extract types from generic args
+                                            let concrete_types: Vec<CheckerType> = args
+                                                .iter()
+                                                .filter_map(|arg| match arg {
+                                                    GenericArgValue::Type(t) => Some(t.clone()),
+                                                    _ => None,
+                                                })
+                                                .collect();
+                                            let concrete_type = self.substitute_type_params(
+                                                param_type,
+                                                &type_params,
+                                                &concrete_types,
+                                            )?;
+                                            self.bind_pattern_variables(pattern, &concrete_type)?;
+                                        }
+                                        return Ok(());
+                                    }
+                                    _ => {
+                                        // TODO: Handle other pattern types
+                                        return Ok(());
+                                    }
+                                }
+                            } else {
+                                return Err(CompileError::Generic(format!(
+                                    "Generic enum {} not found in definitions",
+                                    enum_name
+                                )));
+                            }
+                        }
+                        _ => {
+                            return Err(CompileError::TypeMismatch {
+                                expected: format!("enum {}", enum_name),
+                                found: value_type.to_string(),
+                                span: None,
+                            });
+                        }
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Check whether a value of type `found` can be used where `expected` is required.
+    /// Parameters a generic enum constructor left open (the `E` of `Result::Ok(1)`)
+    /// are compatible with anything.
+    #[allow(clippy::only_used_in_recursion)]
+    fn types_compatible(&self, expected: &CheckerType, found: &CheckerType) -> bool {
+        match (expected, found) {
+            (_, CheckerType::TypeParam(_)) => true,
+            // A `String` is borrowed where a view is expected, never the reverse
+            (CheckerType::Str, CheckerType::String) => true,
+            (
+                CheckerType::Struct(a) | CheckerType::Enum(a),
+                CheckerType::Struct(b) | CheckerType::Enum(b),
+            ) => a == b,
+            (
+                CheckerType::Generic { name: n1, args: a1 },
+                CheckerType::Generic { name: n2, args: a2 },
+            ) => {
+                n1 == n2
+                    && a1.len() == a2.len()
+                    && a1.iter().zip(a2).all(|(e, f)| match (e, f) {
+                        (GenericArgValue::Type(e), GenericArgValue::Type(f)) => {
+                            self.types_compatible(e, f)
+                        }
+                        _ => e == f,
+                    })
+            }
+            (CheckerType::Array(e1, s1), CheckerType::Array(e2, s2)) => {
+                s1 == s2 && self.types_compatible(e1, e2)
+            }
+            (CheckerType::Tuple(t1), CheckerType::Tuple(t2)) => {
+                t1.len() == t2.len() && t1.iter().zip(t2).all(|(e, f)| self.types_compatible(e, f))
+            }
+            _ => expected == found,
+        }
+    }
+
+    /// Create a new, unbound inference variable
+    fn fresh_type_var(&mut self) -> CheckerType {
+        self.type_vars.push(None);
+        CheckerType::Var(self.type_vars.len() as u32 - 1)
+    }
+
+    /// Replace every bound inference variable in a type with its binding
+    fn resolve(&self, ty: &CheckerType) -> CheckerType {
+        match ty {
+            CheckerType::Var(id) => match &self.type_vars[*id as usize] {
+                Some(bound) => self.resolve(bound),
+                None => ty.clone(),
+            },
+            CheckerType::Array(elem, size) => {
+                CheckerType::Array(Box::new(self.resolve(elem)), size.clone())
+            }
+            CheckerType::Function(params, ret) => CheckerType::Function(
+                params.iter().map(|p| self.resolve(p)).collect(),
+                Box::new(self.resolve(ret)),
+            ),
+            CheckerType::Generic { name, args } => CheckerType::Generic {
+                name: name.clone(),
+                args: args
+                    .iter()
+                    .map(|arg| match arg {
+                        GenericArgValue::Type(t) => GenericArgValue::Type(self.resolve(t)),
+                        GenericArgValue::Const(_) => arg.clone(),
+                    })
+                    .collect(),
+            },
+            CheckerType::Tuple(types) => {
+                CheckerType::Tuple(types.iter().map(|t| self.resolve(t)).collect())
+            }
+            _ => ty.clone(),
+        }
+    }
+
+    /// Whether inference variable `id` appears in a type
+    fn occurs(&self, id: u32, ty: &CheckerType) -> bool {
+        match self.resolve(ty) {
+            CheckerType::Var(other) => other == id,
+            CheckerType::Array(elem, _) => self.occurs(id, &elem),
+            CheckerType::Function(params, ret) => {
+                params.iter().any(|p| self.occurs(id, p)) || self.occurs(id, &ret)
+            }
+            CheckerType::Generic { args, .. } => {
+                Self::type_args(&args).iter().any(|t| self.occurs(id, t))
+            }
+            CheckerType::Tuple(types) => types.iter().any(|t| self.occurs(id, t)),
+            _ => false,
+        }
+    }
+
+    /// Make `expected` and `found` equal by binding inference variables on either
+    /// side. Like `types_compatible`, open enum parameters in `found` match anything.
+    fn unify(&mut self, expected: &CheckerType, found: &CheckerType) -> bool {
+        let expected = match expected {
+            CheckerType::Var(_) => self.resolve(expected),
+            _ => expected.clone(),
+        };
+        let found = match found {
+            CheckerType::Var(_) => self.resolve(found),
+            _ => found.clone(),
+        };
+        match (&expected, &found) {
+            (CheckerType::Var(a), CheckerType::Var(b)) if a == b => true,
+            // An open enum parameter says nothing about the variable, unlike a
+            // type parameter of the function being checked
+            (CheckerType::Var(_), CheckerType::TypeParam(name))
+                if !self.body_type_params.contains(name) =>
+            {
+                true
+            }
+            (CheckerType::Var(id), other) | (other, CheckerType::Var(id)) => {
+                if self.occurs(*id, other) {
+                    return false;
+                }
+                self.type_vars[*id as usize] = Some(other.clone());
+                true
+            }
+            (_, CheckerType::TypeParam(_)) => true,
+            (
+                CheckerType::Generic { name: n1, args: a1 },
+                CheckerType::Generic { name: n2, args: a2 },
+            ) => {
+                n1 == n2
+                    && a1.len() == a2.len()
+                    && a1.iter().zip(a2).all(|(e, f)| match (e, f) {
+                        (GenericArgValue::Type(e), GenericArgValue::Type(f)) => self.unify(e, f),
+                        _ => e == f,
+                    })
+            }
+            (CheckerType::Array(e1, s1), CheckerType::Array(e2, s2)) => {
+                s1 == s2 && self.unify(e1, e2)
+            }
+            (CheckerType::Tuple(t1), CheckerType::Tuple(t2)) => {
+                t1.len() == t2.len() && t1.iter().zip(t2).all(|(e, f)| self.unify(e, f))
+            }
+            (CheckerType::Function(p1, r1), CheckerType::Function(p2, r2)) => {
+                p1.len() == p2.len()
+                    && p1.iter().zip(p2).all(|(e, f)| self.unify(e, f))
+                    && self.unify(r1, r2)
+            }
+            _ => self.types_compatible(&expected, &found),
+        }
+    }
+
+    /// Check a value against the type its context requires, reporting a mismatch
+    fn expect_type(&mut self, expected: &CheckerType, found: &CheckerType) -> Result<()> {
+        self.expect_type_at(expected, found, None)
+    }
+
+    /// `expect_type` for a value written at `span`
+    fn expect_type_at(
+        &mut self,
+        expected: &CheckerType,
+        found: &CheckerType,
+        span: Option<Span>,
+    ) -> Result<()> {
+        if self.unify(expected, found) {
+            Ok(())
+        } else {
+            Err(self.error_helper.type_mismatch(
+                &self.resolve(expected).to_string(),
+                &self.resolve(found).to_string(),
+                span,
+            ))
+        }
+    }
+
+    /// Substitute a generic signature's type parameters, as written in `ty`
+    fn instantiate_type(
+        &self,
+        ty: &crate::ast::Type,
+        subst: &HashMap<String, CheckerType>,
+    ) -> CheckerType {
+        let marked = Self::mark_type_params(ty, subst);
+        Self::substitute_checker_type(&self.ast_type_to_checker_type(&marked), subst)
+    }
+
+    /// Mark the names of a signature's type parameters as such, so types of the
+    /// program that happen to share a name with them are left alone
+    fn mark_type_params(
+        ty: &crate::ast::Type,
+        params: &HashMap<String, CheckerType>,
+    ) -> crate::ast::Type {
+        use crate::ast::Type;
+        let mark = |t: &Type| Self::mark_type_params(t, params);
+        match ty {
+            Type::Custom(name) if params.contains_key(name) => Type::TypeParam(name.clone()),
+            Type::Generic { name, args } => Type::Generic {
+                name: name.clone(),
+                args: args
+                    .iter()
+                    .map(|arg| match arg {
+                        GenericArg::Type(t) => GenericArg::Type(mark(t)),
+                        // Single-letter parameters can parse as const parameters
+                        GenericArg::Const(ConstValue::ConstParam(name))
+                            if params.contains_key(name) =>
+                        {
+                            GenericArg::Type(Type::TypeParam(name.clone()))
+                        }
+                        GenericArg::Const(_) => arg.clone(),
+                    })
+                    .collect(),
+            },
+            Type::Array(elem, size) => Type::Array(Box::new(mark(elem)), size.clone()),
+            Type::Reference {
+                lifetime,
+                mutable,
+                inner,
+            } => Type::Reference {
+                lifetime: lifetime.clone(),
+                mutable: *mutable,
+                inner: Box::new(mark(inner)),
+            },
+            Type::Future { output } => Type::Future {
+                output: Box::new(mark(output)),
+            },
+            Type::Tuple(types) => Type::Tuple(types.iter().map(mark).collect()),
+            Type::Function {
+                params: fn_params,
+                return_type,
+                effects,
+            } => Type::Function {
+                params: fn_params.iter().map(mark).collect(),
+                return_type: Box::new(mark(return_type)),
+                effects: effects.clone(),
+            },
+            _ => ty.clone(),
+        }
+    }
+
+    fn substitute_checker_type(
+        ty: &CheckerType,
+        subst: &HashMap<String, CheckerType>,
+    ) -> CheckerType {
+        match ty {
+            CheckerType::TypeParam(name) if subst.contains_key(name) => subst[name].clone(),
+            CheckerType::Array(elem, size) => CheckerType::Array(
+                Box::new(Self::substitute_checker_type(elem, subst)),
+                size.clone(),
+            ),
+            CheckerType::Function(params, ret) => CheckerType::Function(
+                params
+                    .iter()
+                    .map(|p| Self::substitute_checker_type(p, subst))
+                    .collect(),
+                Box::new(Self::substitute_checker_type(ret, subst)),
+            ),
+            CheckerType::Generic { name, args } => CheckerType::Generic {
+                name: name.clone(),
+                args: args
+                    .iter()
+                    .map(|arg| match arg {
+                        GenericArgValue::Type(t) => {
+                            GenericArgValue::Type(Self::substitute_checker_type(t, subst))
+                        }
+                        GenericArgValue::Const(_) => arg.clone(),
+                    })
+                    .collect(),
+            },
+            CheckerType::Tuple(types) => CheckerType::Tuple(
+                types
+                    .iter()
+                    .map(|t| Self::substitute_checker_type(t, subst))
+                    .collect(),
+            ),
+            _ => ty.clone(),
+        }
+    }
+
+    /// Type check an expression against the type its context expects, so generic
+    /// calls can infer parameters that only appear in their return type
+    fn check_expression_expecting(
+        &mut self,
+        expr: &Expr,
+        expected: &CheckerType,
+    ) -> Result<CheckerType> {
+        if let Some((name, generic_func, type_args, args, span)) = self.generic_call_parts(expr) {
+            return self.check_generic_call(
+                &name,
+                &generic_func,
+                type_args.as_deref(),
+                &args,
+                span,
+                Some(expected),
+            );
+        }
+        self.check_expression(expr)
+    }
+
+    /// The generic function an expression calls, with its turbofish, arguments and span.
+    /// `Type::method(..)` calls reach associated functions of generic impls.
+    #[allow(clippy::type_complexity)]
+    fn generic_call_parts(
+        &self,
+        expr: &Expr,
+    ) -> Option<(String, GenericFunction, Option<Vec<Type>>, Vec<Expr>, Span)> {
+        match expr {
+            Expr::Call { func, args, span } => {
+                let name = func.callee_name()?;
+                let generic_func = self.generic_functions.get(name)?.clone();
+                let type_args = match func.as_ref() {
+                    Expr::Turbofish { type_args, .. } => Some(type_args.clone()),
+                    _ => None,
+                };
+                Some((
+                    name.to_string(),
+                    generic_func,
+                    type_args,
+                    args.clone(),
+                    *span,
+                ))
+            }
+            Expr::EnumConstructor {
+                enum_name,
+                variant,
+                data,
+                span,
+            } if !self.generic_enums.contains_key(enum_name)
+                && !self.enums.contains_key(enum_name) =>
+            {
+                let name = format!("{}::{}", enum_name, variant);
+                let generic_func = self.generic_functions.get(&name)?.clone();
+                let args = match data {
+                    Some(crate::ast::EnumConstructorData::Tuple(args)) => args.clone(),
+                    _ => return None,
+                };
+                Some((name, generic_func, None, args, *span))
+            }
+            _ => None,
+        }
+    }
+
+    /// Check a call to a generic function, inferring its type arguments from an
+    /// explicit turbofish, the call's arguments and the type its context expects
+    fn check_generic_call(
+        &mut self,
+        name: &str,
+        generic_func: &GenericFunction,
+        explicit: Option<&[Type]>,
+        args: &[Expr],
+        span: Span,
+        expected: Option<&CheckerType>,
+    ) -> Result<CheckerType> {
+        if args.len() != generic_func.params.len() {
+            return Err(CompileError::ArgumentCountMismatch {
+                name: name.to_string(),
+                expected: generic_func.params.len(),
+                found: args.len(),
+                span: Some(span),
+            });
+        }
+
+        let type_args: Vec<CheckerType> = match explicit {
+            Some(types) if types.len() != generic_func.type_params.len() => {
+                return Err(CompileError::Generic(format!(
+                    "Function '{}' takes {} type arguments, but {} were supplied",
+                    name,
+                    generic_func.type_params.len(),
+                    types.len()
+                )));
+            }
+            Some(types) => types
+                .iter()
+                .map(|t| self.ast_type_to_checker_type(t))
+                .collect(),
+            None => generic_func
+                .type_params
+                .iter()
+                .map(|_| self.fresh_type_var())
+                .collect(),
+        };
+        let subst: HashMap<String, CheckerType> = generic_func
+            .type_params
+            .iter()
+            .cloned()
+            .zip(type_args.iter().cloned())
+            .collect();
+        let param_types: Vec<CheckerType> = generic_func
+            .params
+            .iter()
+            .map(|(_, ty)| self.instantiate_type(ty, &subst))
+            .collect();
+        let return_type = generic_func
+            .return_type
+            .as_ref()
+            .map(|ty| self.instantiate_type(ty, &subst))
+            .unwrap_or(CheckerType::Unit);
+
+        // The expected type is only a hint; a real mismatch is reported by the caller
+        if let Some(expected) = expected {
+            let snapshot = self.type_vars.clone();
+            if !self.unify(expected, &return_type) {
+                self.type_vars = snapshot;
+            }
+        }
+
+        for (arg, param_type) in args.iter().zip(&param_types) {
+            let hint = self.resolve(param_type);
+            let arg_type = self.check_expression_expecting(arg, &hint)?;
+            if !self.unify(param_type, &arg_type) {
+                return Err(CompileError::TypeMismatch {
+                    expected: self.resolve(param_type).to_string(),
+                    found: self.resolve(&arg_type).to_string(),
+                    span: Self::arg_span(arg, span),
+                });
+            }
+            let param_type = self.resolve(param_type);
+            self.settle_expr_type(arg, &param_type);
+        }
+
+        self.pending_calls.push(PendingCall {
+            name: name.to_string(),
+            type_params: generic_func.type_params.clone(),
+            type_args,
+            return_type: return_type.clone(),
+            span,
+        });
+        Ok(self.resolve(&return_type))
+    }
+
+    /// Settle the type arguments of the generic calls checked so far, reporting
+    /// any that neither arguments nor context determined
+    fn finish_inference(&mut self) -> Result<()> {
+        for call in std::mem::take(&mut self.pending_calls) {
+            let mut type_args = Vec::new();
+            let mut resolved_args = Vec::new();
+            for (param, arg) in call.type_params.iter().zip(&call.type_args) {
+                let arg = self.resolve(arg);
+                if self.is_undetermined(&arg) {
+                    return Err(CompileError::TypeAnnotationsNeeded {
+                        name: call.name,
+                        param: param.clone(),
+                        span: Some(call.span),
+                    });
+                }
+                type_args.push(self.checker_type_to_string(&arg));
+                resolved_args.push(arg);
+            }
+            // Calls on the body's own type parameters are settled per instantiation
+            let open = resolved_args.iter().any(Self::has_placeholders);
+            if !open {
+                self.check_collection_call(&call.name, &resolved_args)?;
+            }
+            let return_type = self.resolve(&call.return_type);
+            self.note_type(&return_type);
+
+            // Associated functions of generic impls are generated with their type
+            if !open && !call.name.contains("::") {
+                let instantiation = FunctionInstantiation {
+                    name: call.name.clone(),
+                    type_args: type_args.clone(),
+                };
+                self.instantiation_args
+                    .push((call.name.clone(), resolved_args.clone()));
+                if !self.instantiations.contains_key(&instantiation) {
+                    let generic_func = self.generic_functions[&call.name].clone();
+                    let func_type = self.instantiate_generic_function(&generic_func, &type_args)?;
+                    self.instantiations.insert(instantiation, func_type);
+                }
+            }
+            self.generic_call_types.insert(call.span, type_args);
+        }
+        self.type_vars.clear();
+        Ok(())
+    }
+
+    /// Hash collections need `Hash + Eq` keys, and listing a map's keys or values
+    /// clones them
+    fn check_collection_call(&self, name: &str, type_args: &[CheckerType]) -> Result<()> {
+        let Some((collection, function)) = name.split_once("::") else {
+            return Ok(());
+        };
+        if !matches!(collection, "HashMap" | "HashSet" | "Entry") || !self.is_builtin(collection) {
+            return Ok(());
+        }
+        if let Some(key) = type_args.first().filter(|key| !self.is_hash_key(key)) {
+            return Err(self.error_helper.hash_key_missing(&key.to_string()));
+        }
+        let cloned = match (collection, function) {
+            ("HashMap", "keys") => type_args.first(),
+            ("HashMap", "values") => type_args.get(1),
+            _ => None,
+        };
+        if let Some(ty) = cloned.filter(|ty| !self.is_clone(ty)) {
+            return Err(self.error_helper.clone_missing(&ty.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Whether inference left a type open, other than by naming the type
+    /// parameters of the generic function being checked
+    fn is_undetermined(&self, ty: &CheckerType) -> bool {
+        match ty {
+            CheckerType::TypeParam(name) => !self.body_type_params.contains(name),
+            CheckerType::Var(_) => true,
+            CheckerType::Generic { args, .. } => args
+                .iter()
+                .any(|arg| matches!(arg, GenericArgValue::Type(t) if self.is_undetermined(t))),
+            CheckerType::Array(elem, _) => self.is_undetermined(elem),
+            CheckerType::Tuple(types) => types.iter().any(|t| self.is_undetermined(t)),
+            CheckerType::Function(params, ret) => {
+                params.iter().any(|t| self.is_undetermined(t)) || self.is_undetermined(ret)
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether a type still has parameters left open by inference
+    fn has_placeholders(ty: &CheckerType) -> bool {
+        match ty {
+            CheckerType::TypeParam(_) | CheckerType::Var(_) => true,
+            CheckerType::Generic { args, .. } => args
+                .iter()
+                .any(|arg| matches!(arg, GenericArgValue::Type(t) if Self::has_placeholders(t))),
+            CheckerType::Array(elem, _) => Self::has_placeholders(elem),
+            CheckerType::Tuple(types) => types.iter().any(Self::has_placeholders),
+            CheckerType::Function(params, ret) => {
+                params.iter().any(Self::has_placeholders) || Self::has_placeholders(ret)
+            }
+            _ => false,
+        }
+    }
+
+    /// The type of a future that completes with `output`
+    fn future_of(output: CheckerType) -> CheckerType {
+        CheckerType::Generic {
+            name: "Future".to_string(),
+            args: vec![GenericArgValue::Type(output)],
+        }
+    }
+
+    /// Output of a value of one of the awaitable generic types `names`
+    fn awaited_output(ty: &CheckerType, names: &[&str]) -> Option<CheckerType> {
+        match ty {
+            CheckerType::Generic { name, args } if names.contains(&name.as_str()) => {
+                match args.as_slice() {
+                    [GenericArgValue::Type(output)] => Some(output.clone()),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Type check `spawn(future)`, which hands the future to the executor and
+    /// returns a `JoinHandle` awaiting its output
+    fn check_spawn(&mut self, args: &[Expr]) -> Result<CheckerType> {
+        if args.len() != 1 {
+            return Err(CompileError::ArgumentCountMismatch {
+                name: "spawn".to_string(),
+                expected: 1,
+                found: args.len(),
+                span: None,
+            });
+        }
+        let future_type = self.check_expression(&args[0])?;
+        let future_type = self.resolve(&future_type);
+        match Self::awaited_output(&future_type, &["Future"]) {
+            Some(output) => Ok(CheckerType::Generic {
+                name: "JoinHandle".to_string(),
+                args: vec![GenericArgValue::Type(output)],
+            }),
+            None => Err(CompileError::TypeMismatch {
+                expected: "Future<T>".to_string(),
+                found: self.checker_type_to_string(&future_type),
+                span: None,
+            }),
+        }
+    }
+
+    /// Type arguments of a generic type, skipping const arguments
+    fn type_args(args: &[GenericArgValue]) -> Vec<CheckerType> {
+        args.iter()
+            .filter_map(|arg| match arg {
+                GenericArgValue::Type(t) => Some(t.clone()),
+                GenericArgValue::Const(_) => None,
+            })
+            .collect()
+    }
+
+    /// Check `Box::new(value)`, remembering the boxed type for code generation
+    fn check_box_new(
+        &mut self,
+        data: Option<&EnumConstructorData>,
+        span: Span,
+    ) -> Result<CheckerType> {
+        let value = match data {
+            Some(EnumConstructorData::Tuple(args)) if args.len() == 1 => &args[0],
+            _ => {
+                return Err(CompileError::ArgumentCountMismatch {
+                    name: "Box::new".to_string(),
+                    expected: 1,
+                    found: match data {
+                        Some(EnumConstructorData::Tuple(args)) => args.len(),
+                        _ => 0,
+                    },
+                    span: Some(span),
+                })
+            }
+        };
+        let inner = self.check_expression(value)?;
+        let inner = self.resolve(&inner);
+        if !Self::has_placeholders(&inner) {
+            let instantiation = EnumInstantiation {
+                name: "Box".to_string(),
+                type_args: vec![self.checker_type_to_string(&inner)],
+            };
+            self.enum_expr_types.insert(span, instantiation);
+        }
+        let box_type = CheckerType::Generic {
+            name: "Box".to_string(),
+            args: vec![GenericArgValue::Type(inner)],
+        };
+        self.note_type(&box_type);
+        Ok(box_type)
+    }
+
+    /// Check `JoinHandle::abort(&handle)`, which cancels the task of a join handle
+    fn check_abort(
+        &mut self,
+        data: Option<&EnumConstructorData>,
+        span: Span,
+    ) -> Result<CheckerType> {
+        let handle = match data {
+            Some(EnumConstructorData::Tuple(args)) if args.len() == 1 => &args[0],
+            _ => {
+                return Err(CompileError::ArgumentCountMismatch {
+                    name: "JoinHandle::abort".to_string(),
+                    expected: 1,
+                    found: match data {
+                        Some(EnumConstructorData::Tuple(args)) => args.len(),
+                        _ => 0,
+                    },
+                    span: Some(span),
+                })
+            }
+        };
+        let handle_type = self.check_expression(handle)?;
+        let handle_type = self.resolve(&handle_type);
+        let is_handle =
+            matches!(&handle_type, CheckerType::Generic { name, .. } if name == "JoinHandle");
+        if !is_handle || !matches!(handle, Expr::Reference { .. }) {
+            return Err(CompileError::TypeMismatch {
+                expected: "&JoinHandle<T>".to_string(),
+                found: self.checker_type_to_string(&handle_type),
+                span: Self::arg_span(handle, span),
+            });
+        }
+        Ok(CheckerType::Unit)
+    }
+
+    /// Check `Vec::from(array)`, which moves the elements of an array into a new vector
+    fn check_vec_from(
+        &mut self,
+        data: Option<&EnumConstructorData>,
+        span: Span,
+    ) -> Result<CheckerType> {
+        let array = match data {
+            Some(EnumConstructorData::Tuple(args)) if args.len() == 1 => &args[0],
+            _ => {
+                return Err(CompileError::ArgumentCountMismatch {
+                    name: "Vec::from".to_string(),
+                    expected: 1,
+                    found: match data {
+                        Some(EnumConstructorData::Tuple(args)) => args.len(),
+                        _ => 0,
+                    },
+                    span: Some(span),
+                })
+            }
+        };
+        let elem = match self.check_expression(array)? {
+            CheckerType::Array(elem, _) => *elem,
+            other => {
+                return Err(CompileError::TypeMismatch {
+                    expected: "array".to_string(),
+                    found: other.to_string(),
+                    span: Some(span),
+                })
+            }
+        };
+        let vec_type = CheckerType::Generic {
+            name: "Vec".to_string(),
+            args: vec![GenericArgValue::Type(elem.clone())],
+        };
+        self.pending_calls.push(PendingCall {
+            name: "Vec::from".to_string(),
+            type_params: vec!["T".to_string()],
+            type_args: vec![elem],
+            return_type: vec_type.clone(),
+            span,
+        });
+        Ok(vec_type)
+    }
+
+    /// The type a `Box<T>` points to
+    fn boxed_type(ty: &CheckerType) -> Option<&CheckerType> {
+        match ty {
+            CheckerType::Generic { name, args } if name == "Box" => match args.as_slice() {
+                [GenericArgValue::Type(inner)] => Some(inner),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Whether `Vec` is the built-in vector, rather than a type of the program's own
+    fn has_builtin_vec(&self) -> bool {
+        self.is_builtin("Vec")
+    }
+
+    /// Whether a built-in collection name isn't taken by a type of the program's own
+    fn is_builtin(&self, name: &str) -> bool {
+        !self.structs.contains_key(name) && !self.generic_structs.contains_key(name)
+    }
+
+    /// The key and value types of a built-in `HashMap<K, V>`
+    fn map_entry_types<'a>(
+        &self,
+        ty: &'a CheckerType,
+    ) -> Option<(&'a CheckerType, &'a CheckerType)> {
+        match ty {
+            CheckerType::Generic { name, args } if name == "HashMap" && self.is_builtin(name) => {
+                match args.as_slice() {
+                    [GenericArgValue::Type(key), GenericArgValue::Type(value)] => {
+                        Some((key, value))
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// The element type of a built-in `HashSet<T>`
+    fn set_element<'a>(&self, ty: &'a CheckerType) -> Option<&'a CheckerType> {
+        match ty {
+            CheckerType::Generic { name, args } if name == "HashSet" && self.is_builtin(name) => {
+                match args.as_slice() {
+                    [GenericArgValue::Type(elem)] => Some(elem),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether values of a type can key a `HashMap` or `HashSet`: they must be
+    /// both `Hash` and `Eq`
+    fn is_hash_key(&self, ty: &CheckerType) -> bool {
+        let ast_type = match ty {
+            CheckerType::Int => Type::I64,
+            CheckerType::Bool => Type::Bool,
+            CheckerType::String => Type::String,
+            CheckerType::Struct(name) | CheckerType::Enum(name) => Type::Custom(name.clone()),
+            _ => return false,
+        };
+        self.trait_resolver.is_hash(&ast_type) && self.trait_resolver.is_eq(&ast_type)
+    }
+
+    /// The element type of a built-in `Vec<T>`
+    fn vec_element<'a>(&self, ty: &'a CheckerType) -> Option<&'a CheckerType> {
+        if !self.has_builtin_vec() {
+            return None;
+        }
+        match ty {
+            CheckerType::Generic { name, args } if name == "Vec" => match args.as_slice() {
+                [GenericArgValue::Type(elem)] => Some(elem),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// A type with any `Box`es around it looked through, as field access and
+    /// patterns see it
+    fn auto_deref(mut ty: CheckerType) -> CheckerType {
+        while let Some(inner) = Self::boxed_type(&ty) {
+            ty = inner.clone();
+        }
+        ty
+    }
+
+    /// The instantiation a fully known generic enum type refers to
+    fn enum_instantiation(&self, ty: &CheckerType) -> Option<EnumInstantiation> {
+        match ty {
+            CheckerType::Generic { name, args }
+                if self.generic_enums.contains_key(name) && !Self::has_placeholders(ty) =>
+            {
+                Some(EnumInstantiation {
+                    name: name.clone(),
+                    type_args: Self::type_args(args)
+                        .iter()
+                        .map(|t| self.checker_type_to_string(t))
+                        .collect(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Record every generic enum instantiation, boxed type and collection type
+    /// appearing in a type
+    fn note_type(&mut self, ty: &CheckerType) {
+        if !self.body_type_params.is_empty()
+            && Self::has_placeholders(ty)
+            && !self.body_open_types.contains(ty)
+        {
+            self.body_open_types.push(ty.clone());
+        }
+        if let Some(instantiation) = self.enum_instantiation(ty) {
+            self.enum_instantiations
+                .entry(instantiation)
+                .or_insert_with(|| ty.clone());
+        }
+        if let Some(inner) = Self::boxed_type(ty).filter(|t| !Self::has_placeholders(t)) {
+            let inner = self.checker_type_to_string(inner);
+            self.box_types.insert(inner);
+        }
+        if let Some(elem) = self.vec_element(ty).filter(|t| !Self::has_placeholders(t)) {
+            let elem = self.checker_type_to_string(elem);
+            self.vec_types.insert(elem);
+        }
+        if let Some((key, value)) = self
+            .map_entry_types(ty)
+            .filter(|_| !Self::has_placeholders(ty))
+        {
+            let entry = (
+                self.checker_type_to_string(key),
+                self.checker_type_to_string(value),
+            );
+            self.map_types.insert(entry);
+        }
+        if let Some(elem) = self.set_element(ty).filter(|t| !Self::has_placeholders(t)) {
+            let elem = self.checker_type_to_string(elem);
+            self.set_types.insert(elem);
+        }
+        match ty {
+            CheckerType::Generic { args, .. } => {
+                for arg in Self::type_args(args) {
+                    self.note_type(&arg);
+                }
+            }
+            CheckerType::Array(elem, _) => self.note_type(elem),
+            CheckerType::Tuple(types) => {
+                for t in types {
+                    self.note_type(t);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Pin a generic enum constructor to the concrete type its context expects
+    fn settle_expr_type(&mut self, expr: &Expr, expected: &CheckerType) {
+        if let Expr::EnumConstructor {
+            enum_name, span, ..
+        } = expr
+        {
+            if let Some(instantiation) = self
+                .enum_instantiation(expected)
+                .filter(|inst| &inst.name == enum_name)
+            {
+                self.note_type(expected);
+                self.enum_expr_types.insert(*span, instantiation);
+            }
+        }
+    }
+
+    /// Convert CheckerType to string for type arguments
+    #[allow(clippy::only_used_in_recursion)]
+    fn checker_type_to_string(&self, ty: &CheckerType) -> String {
+        match ty {
+            CheckerType::Unit => "()".to_string(),
+            CheckerType::String => "String".to_string(),
+            CheckerType::Str => "str".to_string(),
+            CheckerType::Int => "i64".to_string(),
+            CheckerType::Bool => "bool".to_string(),
+            CheckerType::Array(elem, size) => {
+                format!("[{}; {}]", self.checker_type_to_string(elem), size)
+            }
             CheckerType::Struct(name) => name.clone(),
             CheckerType::TypeParam(name) => name.clone(),
             CheckerType::Enum(name) => name.clone(),
             CheckerType::Function(params, ret) => {
                 let param_strs: Vec<String> = params
                     .iter()
-                    .map(|p| self.checker_type_to_string(p))
-                    .collect();
-                format!(
-                    "fn({}) -> {}",
-                    param_strs.join(", "),
-                    self.checker_type_to_string(ret)
-                )
+                    .map(|p| self.checker_type_to_string(p))
+                    .collect();
+                format!(
+                    "fn({}) -> {}",
+                    param_strs.join(", "),
+                    self.checker_type_to_string(ret)
+                )
+            }
+            CheckerType::Generic { name, args } => {
+                let arg_strs: Vec<String> = args
+                    .iter()
+                    .map(|a| match a {
+                        GenericArgValue::Type(t) => self.checker_type_to_string(t),
+                        GenericArgValue::Const(c) => match c {
+                            ConstValueResolved::Integer(n) => n.to_string(),
+                            ConstValueResolved::ConstParam(name) => name.clone(),
+                        },
+                    })
+                    .collect();
+                format!("{}<{}>", name, arg_strs.join(", "))
+            }
+            CheckerType::Tuple(types) => {
+                let type_strs: Vec<String> = types
+                    .iter()
+                    .map(|t| self.checker_type_to_string(t))
+                    .collect();
+                format!("({})", type_strs.join(", "))
+            }
+            CheckerType::Var(_) => "_".to_string(),
+        }
+    }
+
+    /// Instantiate a generic function with concrete types
+    fn instantiate_generic_function(
+        &mut self,
+        generic_func: &GenericFunction,
+        type_args: &[String],
+    ) -> Result<CheckerType> {
+        // Create a substitution map
+        let mut subst_map: HashMap<String, String> = HashMap::new();
+        for (type_param, type_arg) in generic_func.type_params.iter().zip(type_args) {
+            subst_map.insert(type_param.clone(), type_arg.clone());
+        }
+
+        // Substitute types in parameters
+        let mut param_types = Vec::new();
+        for (_param_name, param_type) in &generic_func.params {
+            let substituted_type = self.substitute_type(param_type, &subst_map)?;
+            param_types.push(CheckerType::from(&substituted_type));
+        }
+
+        // Substitute return type
+        let return_type = match &generic_func.return_type {
+            Some(ret_type) => {
+                let substituted = self.substitute_type(ret_type, &subst_map)?;
+                CheckerType::from(&substituted)
+            }
+            None => CheckerType::Unit,
+        };
+
+        Ok(CheckerType::Function(param_types, Box::new(return_type)))
+    }
+
+    /// Substitute type parameters in a type
+    #[allow(clippy::only_used_in_recursion)]
+    fn substitute_type(
+        &self,
+        ty: &crate::ast::Type,
+        subst_map: &HashMap<String, String>,
+    ) -> Result<crate::ast::Type> {
+        match ty {
+            crate::ast::Type::TypeParam(param_name) => {
+                match subst_map.get(param_name) {
+                    Some(concrete_type) => {
+                        // Convert string back to Type
+                        match concrete_type.as_str() {
+                            "()" => Ok(crate::ast::Type::Unit),
+                            "String" => Ok(crate::ast::Type::String),
+                            "i64" => Ok(crate::ast::Type::I64),
+                            "i32" => Ok(crate::ast::Type::I32),
+                            "u64" => Ok(crate::ast::Type::U64),
+                            "u32" => Ok(crate::ast::Type::U32),
+                            "bool" => Ok(crate::ast::Type::Bool),
+                            _ => Ok(crate::ast::Type::Custom(concrete_type.clone())),
+                        }
+                    }
+                    None => Err(CompileError::Generic(format!(
+                        "Type parameter '{}' not found in substitution map",
+                        param_name
+                    ))),
+                }
+            }
+            crate::ast::Type::Array(elem_type, size) => {
+                let substituted_elem = self.substitute_type(elem_type, subst_map)?;
+                Ok(crate::ast::Type::Array(
+                    Box::new(substituted_elem),
+                    size.clone(),
+                ))
+            }
+            crate::ast::Type::Reference {
+                lifetime,
+                mutable,
+                inner,
+            } => {
+                let substituted_inner = self.substitute_type(inner, subst_map)?;
+                Ok(crate::ast::Type::Reference {
+                    lifetime: lifetime.clone(),
+                    mutable: *mutable,
+                    inner: Box::new(substituted_inner),
+                })
+            }
+            crate::ast::Type::Function {
+                params,
+                return_type,
+                effects,
+            } => Ok(crate::ast::Type::Function {
+                params: params
+                    .iter()
+                    .map(|param| self.substitute_type(param, subst_map))
+                    .collect::<Result<_>>()?,
+                return_type: Box::new(self.substitute_type(return_type, subst_map)?),
+                effects: effects.clone(),
+            }),
+            _ => Ok(ty.clone()),
+        }
+    }
+
+    /// Get all generic enum instantiations for code generation, innermost first
+    pub fn get_enum_instantiations(&self) -> Vec<(String, Vec<String>, GenericEnum)> {
+        let mut result: Vec<(String, Vec<String>, GenericEnum)> = self
+            .enum_instantiations
+            .keys()
+            .filter_map(|instantiation| {
+                self.generic_enums
+                    .get(&instantiation.name)
+                    .map(|generic_enum| {
+                        (
+                            instantiation.name.clone(),
+                            instantiation.type_args.clone(),
+                            generic_enum.clone(),
+                        )
+                    })
+            })
+            .collect();
+
+        // Payload enums must be declared before the enums that contain them
+        result.sort_by_key(|(name, type_args, _)| {
+            let depth: usize = type_args.iter().map(|arg| arg.matches('<').count()).sum();
+            (depth, name.clone(), type_args.clone())
+        });
+        result
+    }
+
+    /// Parameter and return types of a built-in function, spelled as in source;
+    /// no return type for one returning `()`
+    pub fn builtin_signature(&self, name: &str) -> Option<(Vec<String>, Option<String>)> {
+        let CheckerType::Function(params, return_type) = self.functions.get(name)? else {
+            return None;
+        };
+        let spell = |ty: &CheckerType| match ty {
+            CheckerType::Str => "ref str".to_string(),
+            ty => self.checker_type_to_string(ty),
+        };
+        let return_type = (**return_type != CheckerType::Unit).then(|| spell(return_type));
+        Some((params.iter().map(spell).collect(), return_type))
+    }
+
+    /// Get the types held in a `Box`, innermost first
+    pub fn get_box_types(&self) -> Vec<String> {
+        let mut result: Vec<String> = self.box_types.iter().cloned().collect();
+        result.sort_by_key(|inner| (inner.matches('<').count(), inner.clone()));
+        result
+    }
+
+    /// Get the element types of the built-in `Vec`s, innermost first
+    pub fn get_vec_types(&self) -> Vec<String> {
+        let mut result: Vec<String> = self.vec_types.iter().cloned().collect();
+        result.sort_by_key(|elem| (elem.matches('<').count(), elem.clone()));
+        result
+    }
+
+    /// Get the key and value types of the built-in `HashMap`s
+    pub fn get_map_types(&self) -> Vec<(String, String)> {
+        let mut result: Vec<(String, String)> = self.map_types.iter().cloned().collect();
+        result.sort_by_key(|(key, value)| {
+            (
+                key.matches('<').count() + value.matches('<').count(),
+                key.clone(),
+                value.clone(),
+            )
+        });
+        result
+    }
+
+    /// Get the element types of the built-in `HashSet`s, innermost first
+    pub fn get_set_types(&self) -> Vec<String> {
+        let mut result: Vec<String> = self.set_types.iter().cloned().collect();
+        result.sort_by_key(|elem| (elem.matches('<').count(), elem.clone()));
+        result
+    }
+
+    /// Get the concrete types of generic enum constructor expressions
+    pub fn get_enum_expr_types(&self) -> HashMap<Span, EnumInstantiation> {
+        self.enum_expr_types.clone()
+    }
+
+    /// Get the argument types of formatting intrinsics
+    pub fn get_format_args(&self) -> HashMap<Span, FormatArgType> {
+        self.format_args.clone()
+    }
+
+    /// Get the inferred type arguments of generic function calls, keyed by call span
+    pub fn get_generic_call_types(&self) -> HashMap<Span, Vec<String>> {
+        self.generic_call_types.clone()
+    }
+
+    /// Get the lowering information for `?` expressions
+    pub fn get_question_lowerings(&self) -> HashMap<Span, QuestionLowering> {
+        self.question_lowerings.clone()
+    }
+
+    /// Get all generic function instantiations for code generation
+    pub fn get_instantiations(&self) -> Vec<(String, Vec<String>, GenericFunction)> {
+        let mut result = Vec::new();
+
+        for instantiation in self.instantiations.keys() {
+            if let Some(generic_func) = self.generic_functions.get(&instantiation.name) {
+                result.push((
+                    instantiation.name.clone(),
+                    instantiation.type_args.clone(),
+                    generic_func.clone(),
+                ));
+            }
+        }
+
+        result
+    }
+
+    /// Get all generic struct instantiations for code generation
+    pub fn get_struct_instantiations(&self) -> Vec<(String, Vec<String>, GenericStruct)> {
+        let mut result = Vec::new();
+
+        for instantiation in self.struct_instantiations.keys() {
+            if let Some(generic_struct) = self.generic_structs.get(&instantiation.name) {
+                result.push((
+                    instantiation.name.clone(),
+                    instantiation.type_args.clone(),
+                    generic_struct.clone(),
+                ));
+            }
+        }
+
+        result
+    }
+
+    /// Get all available variable names for suggestions
+    fn get_available_variables(&self) -> Vec<String> {
+        let mut vars = Vec::new();
+        for scope in &self.symbols.scopes {
+            for var_name in scope.keys() {
+                vars.push(var_name.clone());
+            }
+        }
+        vars
+    }
+
+    /// Get all available function names for suggestions
+    fn get_available_functions(&self) -> Vec<String> {
+        let mut funcs: Vec<String> = self.functions.keys().cloned().collect();
+        funcs.extend(self.generic_functions.keys().cloned());
+        funcs
+    }
+
+    /// Get all available type names for suggestions
+    #[allow(dead_code)]
+    fn get_available_types(&self) -> Vec<String> {
+        let mut types = vec!["String".to_string(), "i64".to_string(), "bool".to_string()];
+        types.extend(self.structs.keys().cloned());
+        types.extend(self.enums.keys().cloned());
+        types
+    }
+
+    /// Check if we're currently in an unsafe context
+    pub fn in_unsafe_context(&self) -> bool {
+        self.unsafe_depth > 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    #[test]
+    fn test_type_check_hello_world() {
+        let source = r#"
+        fn main() {
+            print("Hello, World!");
+        }
+        "#;
+
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.collect_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let ast = parser.parse().unwrap();
+
+        let mut type_checker = TypeChecker::new();
+        assert!(type_checker.check(&ast).is_ok());
+    }
+
+    #[test]
+    fn test_undefined_function() {
+        let source = r#"
+        fn main() {
+            unknown_function();
+        }
+        "#;
+
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.collect_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let ast = parser.parse().unwrap();
+
+        let mut type_checker = TypeChecker::new();
+        let result = type_checker.check(&ast);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_let_binding() {
+        let source = r#"
+        fn main() {
+            let x = 42;
+            let y: i32 = 10;
+            let message = "Hello";
+        }
+        "#;
+
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.collect_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let ast = parser.parse().unwrap();
+
+        let mut type_checker = TypeChecker::new();
+        assert!(type_checker.check(&ast).is_ok());
+    }
+
+    #[test]
+    fn test_variable_usage() {
+        let source = r#"
+        fn main() {
+            let x = 42;
+            let y = x;
+        }
+        "#;
+
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.collect_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let ast = parser.parse().unwrap();
+
+        let mut type_checker = TypeChecker::new();
+        assert!(type_checker.check(&ast).is_ok());
+    }
+
+    #[test]
+    fn test_undefined_variable() {
+        let source = r#"
+        fn main() {
+            let x = y;
+        }
+        "#;
+
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.collect_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let ast = parser.parse().unwrap();
+
+        let mut type_checker = TypeChecker::new();
+        let result = type_checker.check(&ast);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_binary_operations() {
+        let source = r#"
+        fn main() {
+            let x = 10 + 20;
+            let y = x - 5;
+            let z = y * 2;
+            let w = z / 3;
+        }
+        "#;
+
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.collect_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let ast = parser.parse().unwrap();
+
+        let mut type_checker = TypeChecker::new();
+        assert!(type_checker.check(&ast).is_ok());
+    }
+
+    #[test]
+    fn test_type_mismatch_in_binary() {
+        let source = r#"
+        fn main() {
+            let x = "hello" + 42;
+        }
+        "#;
+
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.collect_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let ast = parser.parse().unwrap();
+
+        let mut type_checker = TypeChecker::new();
+        let result = type_checker.check(&ast);
+        assert!(result.is_err());
+
+        if let Err(CompileError::TypeMismatch {
+            expected,
+            found,
+            span: _,
+            ..
+        }) = result
+        {
+            assert_eq!(expected, "String");
+            assert_eq!(found, "Int");
+        }
+    }
+
+    #[test]
+    fn test_type_annotation_mismatch() {
+        let source = r#"
+        fn main() {
+            let x: i32 = "not an int";
+        }
+        "#;
+
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.collect_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let ast = parser.parse().unwrap();
+
+        let mut type_checker = TypeChecker::new();
+        let result = type_checker.check(&ast);
+        assert!(result.is_err());
+
+        if let Err(CompileError::TypeMismatch {
+            expected,
+            found,
+            span: _,
+            ..
+        }) = result
+        {
+            assert_eq!(expected, "Int");
+            assert_eq!(found, "String");
+        }
+    }
+
+    #[test]
+    fn test_variable_redefinition() {
+        let source = r#"
+        fn main() {
+            let x = 42;
+            let x = "redefined";
+        }
+        "#;
+
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.collect_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let ast = parser.parse().unwrap();
+
+        let mut type_checker = TypeChecker::new();
+        let result = type_checker.check(&ast);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_for_loop_type_checking() {
+        let source = r#"
+        fn main() {
+            let arr = [1, 2, 3, 4, 5];
+            for i in arr {
+                print_int(i);
             }
-            CheckerType::Generic { name, args } => {
-                let arg_strs: Vec<String> = args
-                    .iter()
-                    .map(|a| match a {
-                        GenericArgValue::Type(t) => self.checker_type_to_string(t),
-                        GenericArgValue::Const(c) => match c {
-                            ConstValueResolved::Integer(n) => n.to_string(),
-                            ConstValueResolved::ConstParam(name) => name.clone(),
-                        },
-                    })
-                    .collect();
-                format!("{}<{}>", name, arg_strs.join(", "))
+        }
+        "#;
+
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.collect_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let ast = parser.parse().unwrap();
+
+        let mut type_checker = TypeChecker::new();
+        assert!(type_checker.check(&ast).is_ok());
+    }
+
+    #[test]
+    fn test_for_loop_wrong_type() {
+        let source = r#"
+        fn main() {
+            let x = 42;
+            for i in x {
+                print_int(i);
             }
-            CheckerType::Tuple(types) => {
-                let type_strs: Vec<String> = types
-                    .iter()
-                    .map(|t| self.checker_type_to_string(t))
-                    .collect();
-                format!("({})", type_strs.join(", "))
+        }
+        "#;
+
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.collect_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let ast = parser.parse().unwrap();
+
+        let mut type_checker = TypeChecker::new();
+        let result = type_checker.check(&ast);
+        assert!(result.is_err());
+
+        if let Err(CompileError::Generic(msg)) = result {
+            assert!(msg.contains("For loop requires an array"));
+        }
+    }
+
+    #[test]
+    fn test_break_continue_in_loops() {
+        let source = r#"
+        fn main() {
+            let arr = [1, 2, 3, 4, 5];
+            
+            // Test break and continue in while loop
+            let mut i = 0;
+            while i < 10 {
+                if i == 5 {
+                    break;
+                }
+                if i == 3 {
+                    i = i + 1;
+                    continue;
+                }
+                i = i + 1;
+            }
+            
+            // Test break and continue in for loop
+            for n in arr {
+                if n == 3 {
+                    continue;
+                }
+                if n > 4 {
+                    break;
+                }
+                print_int(n);
+            }
+        }
+        "#;
+
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.collect_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let ast = parser.parse().unwrap();
+
+        let mut type_checker = TypeChecker::new();
+        assert!(type_checker.check(&ast).is_ok());
+    }
+
+    #[test]
+    fn test_string_len_typecheck() {
+        let source = r#"
+        fn main() {
+            let s = "Hello";
+            let len = string_len(s);
+            print_int(len);
+        }
+        "#;
+
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.collect_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let ast = parser.parse().unwrap();
+
+        let mut type_checker = TypeChecker::new();
+        assert!(type_checker.check(&ast).is_ok());
+    }
+
+    #[test]
+    fn test_string_concat_typecheck() {
+        let source = r#"
+        fn main() {
+            let s1 = "Hello";
+            let s2 = " World";
+            let s3 = string_concat(s1, s2);
+            print(s3);
+        }
+        "#;
+
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.collect_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let ast = parser.parse().unwrap();
+
+        let mut type_checker = TypeChecker::new();
+        assert!(type_checker.check(&ast).is_ok());
+    }
+
+    #[test]
+    fn test_string_char_predicates() {
+        let source = r#"
+        fn main() {
+            let c = 65;
+            let is_alpha = char_is_alpha(c);
+            let is_digit = char_is_digit(c);
+            let is_space = char_is_whitespace(c);
+            if is_alpha {
+                print("Is alphabetic");
+            }
+        }
+        "#;
+
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.collect_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let ast = parser.parse().unwrap();
+
+        let mut type_checker = TypeChecker::new();
+        assert!(type_checker.check(&ast).is_ok());
+    }
+
+    #[test]
+    fn test_string_type_errors() {
+        let source = r#"
+        fn main() {
+            let n = 42;
+            let len = string_len(n); // Error: expects string
+        }
+        "#;
+
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.collect_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let ast = parser.parse().unwrap();
+
+        let mut type_checker = TypeChecker::new();
+        assert!(type_checker.check(&ast).is_err());
+    }
+
+    #[test]
+    fn test_file_io_typecheck() {
+        let source = r#"
+        fn main() {
+            let path = "test.txt";
+            let exists = file_exists(path);
+            if exists {
+                let handle = file_open(path);
+                let content = file_read_all(handle);
+                file_close(handle);
             }
         }
+        "#;
+
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.collect_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let ast = parser.parse().unwrap();
+
+        let mut type_checker = TypeChecker::new();
+        assert!(type_checker.check(&ast).is_ok());
     }
 
-    /// Instantiate a generic function with concrete types
-    fn instantiate_generic_function(
-        &mut self,
-        generic_func: &GenericFunction,
-        type_args: &[String],
-    ) -> Result<CheckerType> {
-        // Create a substitution map
-        let mut subst_map: HashMap<String, String> = HashMap::new();
-        for (type_param, type_arg) in generic_func.type_params.iter().zip(type_args) {
-            subst_map.insert(type_param.clone(), type_arg.clone());
+    #[test]
+    fn test_file_write_typecheck() {
+        let source = r#"
+        fn main() {
+            let handle = file_open("output.txt");
+            let success = file_write(handle, "test content");
+            let closed = file_close(handle);
         }
+        "#;
 
-        // Substitute types in parameters
-        let mut param_types = Vec::new();
-        for (_param_name, param_type) in &generic_func.params {
-            let substituted_type = self.substitute_type(param_type, &subst_map)?;
-            param_types.push(CheckerType::from(&substituted_type));
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.collect_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let ast = parser.parse().unwrap();
+
+        let mut type_checker = TypeChecker::new();
+        assert!(type_checker.check(&ast).is_ok());
+    }
+
+    #[test]
+    fn test_file_io_type_errors() {
+        let source = r#"
+        fn main() {
+            let handle = file_open(123); // Error: expects string
         }
+        "#;
 
-        // Substitute return type
-        let return_type = match &generic_func.return_type {
-            Some(ret_type) => {
-                let substituted = self.substitute_type(ret_type, &subst_map)?;
-                CheckerType::from(&substituted)
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.collect_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let ast = parser.parse().unwrap();
+
+        let mut type_checker = TypeChecker::new();
+        assert!(type_checker.check(&ast).is_err());
+    }
+
+    #[test]
+    fn test_result_enum_definition() {
+        let source = r#"
+        enum Result {
+            Ok(String),
+            Err(String),
+        }
+        
+        fn main() {
+            let ok = Result::Ok("success");
+            let err = Result::Err("failure");
+            
+            match ok {
+                Result::Ok(_) => print("ok"),
+                Result::Err(_) => print("err"),
             }
-            None => CheckerType::Unit,
-        };
+        }
+        "#;
 
-        Ok(CheckerType::Function(param_types, Box::new(return_type)))
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.collect_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let ast = parser.parse().unwrap();
+
+        let mut type_checker = TypeChecker::new();
+        match type_checker.check(&ast) {
+            Ok(_) => {}
+            Err(e) => panic!("Type check failed: {}", e),
+        }
     }
 
-    /// Substitute type parameters in a type
-    #[allow(clippy::only_used_in_recursion)]
-    fn substitute_type(
-        &self,
-        ty: &crate::ast::Type,
-        subst_map: &HashMap<String, String>,
-    ) -> Result<crate::ast::Type> {
-        match ty {
-            crate::ast::Type::TypeParam(param_name) => {
-                match subst_map.get(param_name) {
-                    Some(concrete_type) => {
-                        // Convert string back to Type
-                        match concrete_type.as_str() {
-                            "()" => Ok(crate::ast::Type::Unit),
-                            "String" => Ok(crate::ast::Type::String),
-                            "i64" => Ok(crate::ast::Type::I64),
-                            "i32" => Ok(crate::ast::Type::I32),
-                            "u64" => Ok(crate::ast::Type::U64),
-                            "u32" => Ok(crate::ast::Type::U32),
-                            "bool" => Ok(crate::ast::Type::Bool),
-                            _ => Ok(crate::ast::Type::Custom(concrete_type.clone())),
-                        }
-                    }
-                    None => Err(CompileError::Generic(format!(
-                        "Type parameter '{}' not found in substitution map",
-                        param_name
-                    ))),
+    #[test]
+    fn test_result_pattern_matching() {
+        let source = r#"
+        enum IntResult {
+            Ok(i64),
+            Err(String),
+        }
+        
+        fn main() {
+            let result = IntResult::Ok(42);
+            
+            match result {
+                IntResult::Ok(_) => print("Success"),
+                IntResult::Err(_) => print("Error"),
+            }
+        }
+        "#;
+
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.collect_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let ast = parser.parse().unwrap();
+
+        let mut type_checker = TypeChecker::new();
+        assert!(type_checker.check(&ast).is_ok());
+    }
+
+    #[test]
+    fn test_multiple_result_types() {
+        let source = r#"
+        enum StringResult {
+            Ok(String),
+            Err(String),
+        }
+        
+        enum FileResult {
+            Ok(i64),
+            Err(String),
+        }
+        
+        fn main() {
+            let s_result = StringResult::Ok("test");
+            let f_result = FileResult::Err("not found");
+            
+            match s_result {
+                StringResult::Ok(_) => {
+                    print("string ok");
+                }
+                StringResult::Err(_) => {
+                    print("string err");
                 }
             }
-            crate::ast::Type::Array(elem_type, size) => {
-                let substituted_elem = self.substitute_type(elem_type, subst_map)?;
-                Ok(crate::ast::Type::Array(
-                    Box::new(substituted_elem),
-                    size.clone(),
-                ))
+            
+            match f_result {
+                FileResult::Ok(_) => {
+                    print("file ok");
+                }
+                FileResult::Err(_) => {
+                    print("file err");
+                }
             }
-            crate::ast::Type::Reference {
-                lifetime,
-                mutable,
-                inner,
-            } => {
-                let substituted_inner = self.substitute_type(inner, subst_map)?;
-                Ok(crate::ast::Type::Reference {
-                    lifetime: lifetime.clone(),
-                    mutable: *mutable,
-                    inner: Box::new(substituted_inner),
-                })
+        }
+        "#;
+
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.collect_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let ast = parser.parse().unwrap();
+
+        let mut type_checker = TypeChecker::new();
+        assert!(type_checker.check(&ast).is_ok());
+    }
+
+    #[test]
+    fn test_exhaustive_enum_match() {
+        let source = r#"
+        enum Color {
+            Red,
+            Green,
+            Blue,
+        }
+        
+        fn main() {
+            let c = Color::Red;
+            
+            match c {
+                Color::Red => print("red"),
+                Color::Green => print("green"),
+                Color::Blue => print("blue"),
             }
-            _ => Ok(ty.clone()),
         }
-    }
+        "#;
 
-    /// Check a function call with a known function type
-    fn check_call_with_type(
-        &mut self,
-        func_name: &str,
-        func_type: CheckerType,
-        args: &[Expr],
-    ) -> Result<CheckerType> {
-        match func_type {
-            CheckerType::Function(param_types, return_type) => {
-                // Check argument count
-                if args.len() != param_types.len() {
-                    return Err(CompileError::Generic(format!(
-                        "Function '{}' expects {} arguments, got {}",
-                        func_name,
-                        param_types.len(),
-                        args.len()
-                    )));
-                }
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.collect_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let ast = parser.parse().unwrap();
 
-                // Type check each argument
-                for (arg, expected_type) in args.iter().zip(&param_types) {
-                    let arg_type = self.check_expression(arg)?;
-                    if arg_type != *expected_type {
-                        return Err(CompileError::TypeMismatch {
-                            expected: expected_type.to_string(),
-                            found: arg_type.to_string(),
-                            span: None,
-                        });
-                    }
-                }
+        let mut type_checker = TypeChecker::new();
+        assert!(type_checker.check(&ast).is_ok());
+    }
 
-                Ok(*return_type)
+    #[test]
+    fn test_non_exhaustive_enum_match() {
+        let source = r#"
+        enum Color {
+            Red,
+            Green,
+            Blue,
+        }
+        
+        fn main() {
+            let c = Color::Red;
+            
+            match c {
+                Color::Red => print("red"),
+                Color::Green => print("green"),
+                // Missing Blue!
             }
-            _ => Err(CompileError::Generic(format!(
-                "'{}' is not a function",
-                func_name
-            ))),
         }
-    }
+        "#;
 
-    /// Get all generic function instantiations for code generation
-    pub fn get_instantiations(&self) -> Vec<(String, Vec<String>, GenericFunction)> {
-        let mut result = Vec::new();
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.collect_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let ast = parser.parse().unwrap();
 
-        for instantiation in self.instantiations.keys() {
-            if let Some(generic_func) = self.generic_functions.get(&instantiation.name) {
-                result.push((
-                    instantiation.name.clone(),
-                    instantiation.type_args.clone(),
-                    generic_func.clone(),
-                ));
-            }
-        }
+        let mut type_checker = TypeChecker::new();
+        let result = type_checker.check(&ast);
+        assert!(result.is_err());
 
-        result
+        if let Err(CompileError::NonExhaustiveMatch {
+            missing_patterns, ..
+        }) = result
+        {
+            assert!(missing_patterns.contains(&"Color::Blue".to_string()));
+        } else {
+            panic!("Expected NonExhaustiveMatch error");
+        }
     }
 
-    /// Get all generic struct instantiations for code generation
-    pub fn get_struct_instantiations(&self) -> Vec<(String, Vec<String>, GenericStruct)> {
-        let mut result = Vec::new();
-
-        for instantiation in self.struct_instantiations.keys() {
-            if let Some(generic_struct) = self.generic_structs.get(&instantiation.name) {
-                result.push((
-                    instantiation.name.clone(),
-                    instantiation.type_args.clone(),
-                    generic_struct.clone(),
-                ));
+    #[test]
+    fn test_wildcard_makes_match_exhaustive() {
+        let source = r#"
+        enum Color {
+            Red,
+            Green,
+            Blue,
+        }
+        
+        fn main() {
+            let c = Color::Red;
+            
+            match c {
+                Color::Red => print("red"),
+                _ => print("other"),
             }
         }
+        "#;
 
-        result
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.collect_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let ast = parser.parse().unwrap();
+
+        let mut type_checker = TypeChecker::new();
+        assert!(type_checker.check(&ast).is_ok());
     }
 
-    /// Get all available variable names for suggestions
-    fn get_available_variables(&self) -> Vec<String> {
-        let mut vars = Vec::new();
-        for scope in &self.symbols.scopes {
-            for var_name in scope.keys() {
-                vars.push(var_name.clone());
+    #[test]
+    fn test_unreachable_pattern_after_wildcard() {
+        let source = r#"
+        enum Color {
+            Red,
+            Green,
+            Blue,
+        }
+        
+        fn main() {
+            let c = Color::Red;
+            
+            match c {
+                Color::Red => print("red"),
+                _ => print("any"),
+                Color::Blue => print("blue"), // Unreachable!
             }
         }
-        vars
-    }
+        "#;
 
-    /// Get all available function names for suggestions
-    fn get_available_functions(&self) -> Vec<String> {
-        let mut funcs: Vec<String> = self.functions.keys().cloned().collect();
-        funcs.extend(self.generic_functions.keys().cloned());
-        funcs
-    }
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer.collect_tokens().unwrap();
+        let mut parser = Parser::new(tokens);
+        let ast = parser.parse().unwrap();
 
-    /// Get all available type names for suggestions
-    #[allow(dead_code)]
-    fn get_available_types(&self) -> Vec<String> {
-        let mut types = vec!["String".to_string(), "i64".to_string(), "bool".to_string()];
-        types.extend(self.structs.keys().cloned());
-        types.extend(self.enums.keys().cloned());
-        types
-    }
+        let mut type_checker = TypeChecker::new();
+        let result = type_checker.check(&ast);
+        assert!(result.is_err());
 
-    /// Check if we're currently in an unsafe context
-    pub fn in_unsafe_context(&self) -> bool {
-        self.unsafe_depth > 0
+        if let Err(CompileError::UnreachablePattern { .. }) = result {
+            // Expected
+        } else {
+            panic!("Expected UnreachablePattern error");
+        }
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::lexer::Lexer;
-    use crate::parser::Parser;
 
     #[test]
-    fn test_type_check_hello_world() {
+    fn test_prelude_option_and_result() {
         let source = r#"
+        fn half(n: i64) -> Option<i64> {
+            if n % 2 == 0 {
+                return Option::Some(n / 2);
+            }
+            return Option::None;
+        }
+
+        fn check(n: i64) -> Result<i64, String> {
+            if n < 0 {
+                return Result::Err("negative");
+            }
+            return Result::Ok(n);
+        }
+
         fn main() {
-            print("Hello, World!");
+            match half(4) {
+                Option::Some(v) => print_int(v),
+                Option::None => print("odd"),
+            }
+            match check(1) {
+                Result::Ok(v) => print_int(v),
+                Result::Err(e) => print(e),
+            }
         }
         "#;
 
@@ -3224,13 +5967,39 @@ mod tests {
 
         let mut type_checker = TypeChecker::new();
         assert!(type_checker.check(&ast).is_ok());
+
+        let names: Vec<String> = type_checker
+            .get_enum_instantiations()
+            .into_iter()
+            .map(|(name, args, _)| format!("{}<{}>", name, args.join(", ")))
+            .collect();
+        assert!(names.contains(&"Option<i64>".to_string()));
+        assert!(names.contains(&"Result<i64, String>".to_string()));
     }
 
     #[test]
-    fn test_undefined_function() {
+    fn test_question_converts_error_with_from() {
         let source = r#"
+        enum AppError {
+            Parse(String),
+        }
+
+        impl From<String> for AppError {
+            fn from(value: String) -> Self {
+                return AppError::Parse(value);
+            }
+        }
+
+        fn parse(n: i64) -> Result<i64, String> {
+            return Result::Ok(n);
+        }
+
+        fn run(n: i64) -> Result<i64, AppError> {
+            let v = parse(n)?;
+            return Result::Ok(v + 1);
+        }
+
         fn main() {
-            unknown_function();
         }
         "#;
 
@@ -3240,17 +6009,30 @@ mod tests {
         let ast = parser.parse().unwrap();
 
         let mut type_checker = TypeChecker::new();
-        let result = type_checker.check(&ast);
-        assert!(result.is_err());
+        assert!(type_checker.check(&ast).is_ok());
+
+        let lowerings = type_checker.get_question_lowerings();
+        assert_eq!(lowerings.len(), 1);
+        let lowering = lowerings.values().next().unwrap();
+        assert_eq!(
+            lowering.error_conversion,
+            Some(("AppError".to_string(), "String".to_string()))
+        );
     }
 
     #[test]
-    fn test_let_binding() {
+    fn test_question_requires_compatible_return_type() {
         let source = r#"
+        fn parse(n: i64) -> Result<i64, String> {
+            return Result::Ok(n);
+        }
+
+        fn run(n: i64) -> i64 {
+            let v = parse(n)?;
+            return v;
+        }
+
         fn main() {
-            let x = 42;
-            let y: i32 = 10;
-            let message = "Hello";
         }
         "#;
 
@@ -3260,15 +6042,31 @@ mod tests {
         let ast = parser.parse().unwrap();
 
         let mut type_checker = TypeChecker::new();
-        assert!(type_checker.check(&ast).is_ok());
+        match type_checker.check(&ast) {
+            Err(CompileError::InvalidTryOperator { help, .. }) => {
+                assert!(help.contains("Result<..., E>"));
+            }
+            other => panic!("Expected InvalidTryOperator error, got {:?}", other),
+        }
     }
 
     #[test]
-    fn test_variable_usage() {
+    fn test_question_without_from_impl() {
         let source = r#"
+        enum AppError {
+            Io(i64),
+        }
+
+        fn parse(n: i64) -> Result<i64, String> {
+            return Result::Ok(n);
+        }
+
+        fn run(n: i64) -> Result<i64, AppError> {
+            let v = parse(n)?;
+            return Result::Ok(v);
+        }
+
         fn main() {
-            let x = 42;
-            let y = x;
         }
         "#;
 
@@ -3278,582 +6076,790 @@ mod tests {
         let ast = parser.parse().unwrap();
 
         let mut type_checker = TypeChecker::new();
-        assert!(type_checker.check(&ast).is_ok());
+        match type_checker.check(&ast) {
+            Err(CompileError::InvalidTryOperator { help, .. }) => {
+                assert!(help.contains("impl From<String> for AppError"));
+            }
+            other => panic!("Expected InvalidTryOperator error, got {:?}", other),
+        }
     }
 
     #[test]
-    fn test_undefined_variable() {
+    fn test_format_requires_display() {
+        let source = r#"
+        struct Meters {
+            value: i64,
+        }
+
+        fn main() {
+            let m = Meters { value: 3 };
+            println!("{}", m);
+        }
+        "#;
+        let err = check_expanded(source).unwrap_err();
+        assert!(err.to_string().contains("doesn't implement Display"));
+
+        let source = r#"
+        struct Meters {
+            value: i64,
+        }
+
+        impl Display for Meters {
+            fn fmt(self) -> String {
+                return format!("{}m", self.value);
+            }
+        }
+
+        fn main() {
+            let m = Meters { value: 3 };
+            println!("{} {:>5}", m, m);
+        }
+        "#;
+        assert!(check_expanded(source).is_ok());
+    }
+
+    #[test]
+    fn test_format_integer_specs_reject_strings() {
+        let err = check_expanded(r#"fn main() { println!("{:b}", "ten"); }"#).unwrap_err();
+        assert!(err.to_string().contains("expects an integer"));
+    }
+
+    #[test]
+    fn test_struct_literal_missing_fields_listed_together() {
+        let source = r#"
+        struct Config {
+            host: String,
+            port: i64,
+            verbose: bool = false,
+        }
+
+        fn main() {
+            let c = Config { verbose: true };
+        }
+        "#;
+        let err = check_expanded(source).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "missing fields: host, port in initializer of 'Config'"
+        );
+
+        // Defaults only apply with a trailing `..`
+        let source = r#"
+        struct Config {
+            host: String,
+            verbose: bool = false,
+        }
+
+        fn main() {
+            let host = "h";
+            let a = Config { host, .. };
+            let b = Config { verbose: true, ..a };
+        }
+        "#;
+        assert!(check_expanded(source).is_ok());
+    }
+
+    #[test]
+    fn test_struct_update_base_must_match() {
         let source = r#"
-        fn main() {
-            let x = y;
+        struct A {
+            x: i64,
         }
-        "#;
 
-        let mut lexer = Lexer::new(source);
-        let tokens = lexer.collect_tokens().unwrap();
-        let mut parser = Parser::new(tokens);
-        let ast = parser.parse().unwrap();
+        struct B {
+            x: i64,
+        }
 
-        let mut type_checker = TypeChecker::new();
-        let result = type_checker.check(&ast);
-        assert!(result.is_err());
+        fn main() {
+            let b = B { x: 1 };
+            let a = A { ..b };
+        }
+        "#;
+        let err = check_expanded(source).unwrap_err();
+        assert!(matches!(err, CompileError::TypeMismatch { .. }));
     }
 
     #[test]
-    fn test_binary_operations() {
+    fn test_copy_requires_copy_fields() {
         let source = r#"
-        fn main() {
-            let x = 10 + 20;
-            let y = x - 5;
-            let z = y * 2;
-            let w = z / 3;
+        struct Named {
+            name: String,
         }
-        "#;
 
-        let mut lexer = Lexer::new(source);
-        let tokens = lexer.collect_tokens().unwrap();
-        let mut parser = Parser::new(tokens);
-        let ast = parser.parse().unwrap();
+        impl Copy for Named {}
 
-        let mut type_checker = TypeChecker::new();
-        assert!(type_checker.check(&ast).is_ok());
+        fn main() {}
+        "#;
+        let err = check_expanded(source).unwrap_err();
+        assert!(err.to_string().contains("cannot implement Copy"), "{}", err);
     }
 
     #[test]
-    fn test_type_mismatch_in_binary() {
+    fn test_copy_excludes_drop() {
         let source = r#"
-        fn main() {
-            let x = "hello" + 42;
+        struct Token {
+            id: i64,
         }
-        "#;
 
-        let mut lexer = Lexer::new(source);
-        let tokens = lexer.collect_tokens().unwrap();
-        let mut parser = Parser::new(tokens);
-        let ast = parser.parse().unwrap();
-
-        let mut type_checker = TypeChecker::new();
-        let result = type_checker.check(&ast);
-        assert!(result.is_err());
+        impl Copy for Token {}
 
-        if let Err(CompileError::TypeMismatch {
-            expected,
-            found,
-            span: _,
-            ..
-        }) = result
-        {
-            assert_eq!(expected, "String");
-            assert_eq!(found, "Int");
+        impl Drop for Token {
+            fn drop(&mut self) {}
         }
+
+        fn main() {}
+        "#;
+        let err = check_expanded(source).unwrap_err();
+        assert!(err.to_string().contains("both Copy and Drop"), "{}", err);
     }
 
     #[test]
-    fn test_type_annotation_mismatch() {
+    fn test_recursive_type_needs_box() {
         let source = r#"
-        fn main() {
-            let x: i32 = "not an int";
+        enum Expr {
+            Num(i64),
+            Neg(Expr),
         }
-        "#;
 
-        let mut lexer = Lexer::new(source);
-        let tokens = lexer.collect_tokens().unwrap();
-        let mut parser = Parser::new(tokens);
-        let ast = parser.parse().unwrap();
+        fn main() {}
+        "#;
+        let err = check_expanded(source).unwrap_err();
+        assert!(err.to_string().contains("consider boxing it as 'Box<Expr>'"), "{}", err);
 
-        let mut type_checker = TypeChecker::new();
-        let result = type_checker.check(&ast);
-        assert!(result.is_err());
+        let boxed = r#"
+        enum Expr {
+            Num(i64),
+            Neg(Box<Expr>),
+        }
 
-        if let Err(CompileError::TypeMismatch {
-            expected,
-            found,
-            span: _,
-            ..
-        }) = result
-        {
-            assert_eq!(expected, "Int");
-            assert_eq!(found, "String");
+        fn main() {
+            let e = Expr::Neg(Box::new(Expr::Num(1)));
         }
+        "#;
+        assert!(check_expanded(boxed).is_ok());
     }
 
     #[test]
-    fn test_variable_redefinition() {
+    fn test_box_passed_by_reference() {
         let source = r#"
+        enum L {
+            Nil,
+            Cons(i64, Box<L>),
+        }
+
+        fn len(l: &L) -> i64 {
+            return 0;
+        }
+
         fn main() {
-            let x = 42;
-            let x = "redefined";
+            let l = Box::new(L::Cons(1, Box::new(L::Nil)));
+            let n = len(l);
         }
         "#;
+        assert!(check_expanded(source).is_ok());
 
-        let mut lexer = Lexer::new(source);
-        let tokens = lexer.collect_tokens().unwrap();
-        let mut parser = Parser::new(tokens);
-        let ast = parser.parse().unwrap();
-
-        let mut type_checker = TypeChecker::new();
-        let result = type_checker.check(&ast);
-        assert!(result.is_err());
+        // Only a reference borrows the contents; a value would move them out
+        let by_value = source.replace("l: &L", "l: L");
+        let err = check_expanded(&by_value).unwrap_err();
+        assert!(matches!(err, CompileError::TypeMismatch { .. }), "{}", err);
     }
 
     #[test]
-    fn test_for_loop_type_checking() {
+    fn test_vec_element_types() {
         let source = r#"
         fn main() {
-            let arr = [1, 2, 3, 4, 5];
-            for i in arr {
-                print_int(i);
+            let mut v = Vec::new();
+            Vec::push(&mut v, 1);
+            let n: i64 = v[0];
+            let w = vec![true, false];
+            for b in &w {
+                let c: bool = b;
             }
+            Vec::push(&mut v, "one");
         }
         "#;
+        let err = check_expanded(source).unwrap_err();
+        assert!(matches!(err, CompileError::TypeMismatch { .. }), "{}", err);
 
-        let mut lexer = Lexer::new(source);
-        let tokens = lexer.collect_tokens().unwrap();
-        let mut parser = Parser::new(tokens);
-        let ast = parser.parse().unwrap();
+        let slice = r#"
+        struct Handle {
+            id: i64,
+        }
 
-        let mut type_checker = TypeChecker::new();
-        assert!(type_checker.check(&ast).is_ok());
+        fn main() {
+            let v = vec![Handle { id: 1 }];
+            let w = v[0..1];
+        }
+        "#;
+        let err = check_expanded(slice).unwrap_err();
+        assert!(err.to_string().contains("Clone"), "{}", err);
     }
 
     #[test]
-    fn test_for_loop_wrong_type() {
+    fn test_hash_keys_need_hash_and_eq() {
         let source = r#"
+        struct Point {
+            x: i64,
+            y: i64,
+        }
+
+        impl Hash for Point {}
+        impl Eq for Point {}
+
         fn main() {
-            let x = 42;
-            for i in x {
-                print_int(i);
+            let mut m = HashMap::new();
+            HashMap::insert(&mut m, Point { x: 1, y: 2 }, "a");
+            let mut s = HashSet::new();
+            HashSet::insert(&mut s, "b");
+            for word in &s {
+                let w: String = word;
             }
         }
         "#;
+        let program = Parser::new(Lexer::new(source).collect_tokens().unwrap())
+            .parse()
+            .unwrap();
+        let mut checker = TypeChecker::new();
+        checker.check(&program).unwrap();
+        assert_eq!(
+            checker.get_map_types(),
+            vec![("Point".to_string(), "String".to_string())]
+        );
+        assert_eq!(checker.get_set_types(), vec!["String".to_string()]);
 
-        let mut lexer = Lexer::new(source);
-        let tokens = lexer.collect_tokens().unwrap();
-        let mut parser = Parser::new(tokens);
-        let ast = parser.parse().unwrap();
-
-        let mut type_checker = TypeChecker::new();
-        let result = type_checker.check(&ast);
-        assert!(result.is_err());
+        let unhashed = source.replace("impl Eq for Point {}", "");
+        let err = check_expanded(&unhashed).unwrap_err();
+        assert!(err.to_string().contains("Hash and Eq"), "{}", err);
 
-        if let Err(CompileError::Generic(msg)) = result {
-            assert!(msg.contains("For loop requires an array"));
+        let derived = r#"
+        struct Bag {
+            items: Vec<i64>,
         }
+
+        impl Hash for Bag {}
+
+        fn main() {}
+        "#;
+        let err = check_expanded(derived).unwrap_err();
+        assert!(err.to_string().contains("cannot derive Hash"), "{}", err);
     }
 
     #[test]
-    fn test_break_continue_in_loops() {
+    fn test_for_loop_iterators() {
         let source = r#"
-        fn main() {
-            let arr = [1, 2, 3, 4, 5];
-            
-            // Test break and continue in while loop
-            let mut i = 0;
-            while i < 10 {
-                if i == 5 {
-                    break;
-                }
-                if i == 3 {
-                    i = i + 1;
-                    continue;
-                }
-                i = i + 1;
-            }
-            
-            // Test break and continue in for loop
-            for n in arr {
-                if n == 3 {
-                    continue;
-                }
-                if n > 4 {
-                    break;
+        struct Countdown {
+            n: i64,
+        }
+
+        impl Iterator<i64> for Countdown {
+            fn next(&mut self) -> Option<i64> {
+                if self.n == 0 {
+                    return Option::None;
                 }
-                print_int(n);
+                self.n = self.n - 1;
+                return Option::Some(self.n);
             }
         }
-        "#;
-
-        let mut lexer = Lexer::new(source);
-        let tokens = lexer.collect_tokens().unwrap();
-        let mut parser = Parser::new(tokens);
-        let ast = parser.parse().unwrap();
 
-        let mut type_checker = TypeChecker::new();
-        assert!(type_checker.check(&ast).is_ok());
-    }
+        fn twice(x: i64) -> i64 {
+            return x * 2;
+        }
 
-    #[test]
-    fn test_string_len_typecheck() {
-        let source = r#"
         fn main() {
-            let s = "Hello";
-            let len = string_len(s);
-            print_int(len);
+            let total: i64 = (1..10).step_by(3).map(twice).sum();
+            let v = vec![1, 2, 3];
+            let w = v.iter().rev().collect();
+            let n: i64 = w[0];
+            for (i, c) in "hi".chars().enumerate() {
+                let k: i64 = i + c;
+            }
+            let count = Countdown { n: 3 };
+            for x in count {
+                let y: i64 = x;
+            }
         }
         "#;
+        assert!(check_expanded(source).is_ok());
+
+        let unpaired = source.replace("(i, c) in", "i in");
+        let err = check_expanded(&unpaired).unwrap_err();
+        assert!(err.to_string().contains("yields pairs"), "{}", err);
+
+        let scalar = source.replace("in count", "in 3");
+        let err = check_expanded(&scalar).unwrap_err();
+        assert!(
+            err.to_string().contains("For loop requires an array"),
+            "{}",
+            err
+        );
 
-        let mut lexer = Lexer::new(source);
-        let tokens = lexer.collect_tokens().unwrap();
-        let mut parser = Parser::new(tokens);
-        let ast = parser.parse().unwrap();
-
-        let mut type_checker = TypeChecker::new();
-        assert!(type_checker.check(&ast).is_ok());
+        let dangling = source.replace(".map(twice).sum()", ".map(twice)");
+        let err = check_expanded(&dangling).unwrap_err();
+        assert!(err.to_string().contains("must be consumed"), "{}", err);
     }
 
     #[test]
-    fn test_string_concat_typecheck() {
+    fn test_async_functions_return_futures() {
         let source = r#"
-        fn main() {
-            let s1 = "Hello";
-            let s2 = " World";
-            let s3 = string_concat(s1, s2);
-            print(s3);
+        async fn add(a: i64, b: i64) -> i64 {
+            yield_now().await;
+            return a + b;
         }
-        "#;
 
-        let mut lexer = Lexer::new(source);
-        let tokens = lexer.collect_tokens().unwrap();
-        let mut parser = Parser::new(tokens);
-        let ast = parser.parse().unwrap();
+        async fn twice(n: i64) -> i64 {
+            let x: i64 = add(n, n).await;
+            return x;
+        }
 
-        let mut type_checker = TypeChecker::new();
-        assert!(type_checker.check(&ast).is_ok());
+        fn main() {
+            let f = twice(2);
+        }
+        "#;
+        assert!(check_expanded(source).is_ok());
+
+        let unawaited = source.replace("let x: i64 = add(n, n).await;", "let x: i64 = add(n, n);");
+        let err = check_expanded(&unawaited).unwrap_err();
+        assert!(matches!(err, CompileError::TypeMismatch { .. }), "{}", err);
+
+        let blocking = source.replace("let f = twice(2);", "let n = twice(2).await;");
+        let err = check_expanded(&blocking).unwrap_err();
+        assert!(
+            err.to_string().contains("only allowed inside an async fn"),
+            "{}",
+            err
+        );
     }
 
     #[test]
-    fn test_string_char_predicates() {
+    fn test_spawn_returns_join_handle() {
         let source = r#"
-        fn main() {
-            let c = 65;
-            let is_alpha = char_is_alpha(c);
-            let is_digit = char_is_digit(c);
-            let is_space = char_is_whitespace(c);
-            if is_alpha {
-                print("Is alphabetic");
-            }
+        async fn add(a: i64, b: i64) -> i64 {
+            sleep_ms(1).await;
+            return a + b;
+        }
+
+        async fn main() {
+            let handle = spawn(add(1, 2));
+            let sum: i64 = handle.await;
+            print_int(sum);
         }
         "#;
+        assert!(check_expanded(source).is_ok());
 
-        let mut lexer = Lexer::new(source);
-        let tokens = lexer.collect_tokens().unwrap();
-        let mut parser = Parser::new(tokens);
-        let ast = parser.parse().unwrap();
+        let not_future = source.replace("spawn(add(1, 2))", "spawn(3)");
+        let err = check_expanded(&not_future).unwrap_err();
+        assert!(matches!(err, CompileError::TypeMismatch { .. }), "{}", err);
 
-        let mut type_checker = TypeChecker::new();
-        assert!(type_checker.check(&ast).is_ok());
+        let as_output = source.replace("let sum: i64 = handle.await;", "let sum: i64 = handle;");
+        let err = check_expanded(&as_output).unwrap_err();
+        assert!(matches!(err, CompileError::TypeMismatch { .. }), "{}", err);
+
+        // Aborting borrows the handle
+        let aborted = source.replace(
+            "let sum: i64 = handle.await;",
+            "JoinHandle::abort(&handle);\n            let sum: i64 = handle.await;",
+        );
+        assert!(check_expanded(&aborted).is_ok());
+        for bad in ["JoinHandle::abort(handle);", "JoinHandle::abort(&sum);"] {
+            let bad = source.replace("print_int(sum);", bad);
+            let err = check_expanded(&bad).unwrap_err();
+            assert!(matches!(err, CompileError::TypeMismatch { .. }), "{}", err);
+        }
     }
 
     #[test]
-    fn test_string_type_errors() {
+    fn test_async_scope_rules() {
         let source = r#"
-        fn main() {
-            let n = 42;
-            let len = string_len(n); // Error: expects string
+        async fn add(a: i64, b: i64) -> i64 {
+            sleep_ms(1).await;
+            return a + b;
         }
-        "#;
 
-        let mut lexer = Lexer::new(source);
-        let tokens = lexer.collect_tokens().unwrap();
-        let mut parser = Parser::new(tokens);
-        let ast = parser.parse().unwrap();
+        async fn main() {
+            let mut i = 0;
+            while i < 3 {
+                async scope {
+                    spawn(add(i, 1));
+                }
+                i = i + 1;
+            }
+        }
+        "#;
+        assert!(check_expanded(source).is_ok());
+
+        // Control can't leave the scope before its tasks are awaited
+        for exit in ["break;", "return;"] {
+            let leaving = source.replace("spawn(add(i, 1));", exit);
+            let err = check_expanded(&leaving).unwrap_err();
+            assert!(
+                err.to_string().contains("cannot leave an async scope"),
+                "{}",
+                err
+            );
+        }
 
-        let mut type_checker = TypeChecker::new();
-        assert!(type_checker.check(&ast).is_err());
+        let sync = source.replace("async fn main", "fn main");
+        let err = check_expanded(&sync).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("'async scope' is only allowed inside an async fn"),
+            "{}",
+            err
+        );
     }
 
     #[test]
-    fn test_file_io_typecheck() {
+    fn test_effect_handlers() {
         let source = r#"
-        fn main() {
-            let path = "test.txt";
-            let exists = file_exists(path);
-            if exists {
-                let handle = file_open(path);
-                let content = file_read_all(handle);
-                file_close(handle);
-            }
+        effect Log {
+            fn log(msg: String);
+            fn level() -> i64;
         }
-        "#;
 
-        let mut lexer = Lexer::new(source);
-        let tokens = lexer.collect_tokens().unwrap();
-        let mut parser = Parser::new(tokens);
-        let ast = parser.parse().unwrap();
+        fn greet(name: String) {
+            if Log::level() > 0 {
+                Log::log(name);
+            }
+        }
 
-        let mut type_checker = TypeChecker::new();
-        assert!(type_checker.check(&ast).is_ok());
+        fn main() {
+            handle {
+                greet("world");
+            } with Log {
+                fn log(msg: String) {
+                    print(msg);
+                }
+                fn level() -> i64 {
+                    return 1;
+                }
+            }
+        }
+        "#;
+        assert!(check_expanded(source).is_ok());
+
+        let cases = [
+            ("Log::log(name);", "Log::log(1);", "Type mismatch"),
+            ("Log::log(name);", "Log::warn(name);", "Effect 'Log' has no operation 'warn'"),
+            ("} with Log {", "} with Trace {", "Undefined effect 'Trace'"),
+            ("fn log(msg: String) {", "fn log(msg: i64) {", "Type mismatch"),
+            (
+                "fn level() -> i64 {\n                    return 1;\n                }",
+                "",
+                "Handler of 'Log' is missing operation 'level'",
+            ),
+        ];
+        for (from, to, expected) in cases {
+            let err = check_expanded(&source.replace(from, to)).unwrap_err();
+            assert!(err.to_string().contains(expected), "{}", err);
+        }
     }
 
     #[test]
-    fn test_file_write_typecheck() {
+    fn test_clone_requires_impl() {
         let source = r#"
+        struct Named {
+            name: String,
+        }
+
         fn main() {
-            let handle = file_open("output.txt");
-            let success = file_write(handle, "test content");
-            let closed = file_close(handle);
+            let a = Named { name: "a" };
+            let s = a.name.clone();
+            let b = a.clone();
         }
         "#;
-
-        let mut lexer = Lexer::new(source);
-        let tokens = lexer.collect_tokens().unwrap();
-        let mut parser = Parser::new(tokens);
-        let ast = parser.parse().unwrap();
-
-        let mut type_checker = TypeChecker::new();
-        assert!(type_checker.check(&ast).is_ok());
+        let err = check_expanded(source).unwrap_err();
+        assert!(
+            err.to_string().contains("doesn't implement Clone"),
+            "{}",
+            err
+        );
     }
 
     #[test]
-    fn test_file_io_type_errors() {
+    fn test_user_types_named_like_type_params() {
         let source = r#"
-        fn main() {
-            let handle = file_open(123); // Error: expects string
+        struct T {
+            x: i64,
         }
-        "#;
 
-        let mut lexer = Lexer::new(source);
-        let tokens = lexer.collect_tokens().unwrap();
-        let mut parser = Parser::new(tokens);
-        let ast = parser.parse().unwrap();
+        struct U {
+            v: i64,
+        }
 
-        let mut type_checker = TypeChecker::new();
-        assert!(type_checker.check(&ast).is_err());
-    }
+        struct E {
+            code: i64,
+        }
 
-    #[test]
-    fn test_result_enum_definition() {
-        let source = r#"
-        enum Result {
-            Ok(String),
-            Err(String),
+        fn none() -> Option<U> {
+            return Option::None;
         }
-        
-        fn main() {
-            let ok = Result::Ok("success");
-            let err = Result::Err("failure");
-            
-            match ok {
-                Result::Ok(_) => print("ok"),
-                Result::Err(_) => print("err"),
-            }
+
+        fn fail(code: i64) -> Result<i64, E> {
+            return Result::Err(E { code: code });
         }
-        "#;
 
-        let mut lexer = Lexer::new(source);
-        let tokens = lexer.collect_tokens().unwrap();
-        let mut parser = Parser::new(tokens);
-        let ast = parser.parse().unwrap();
+        fn wrap(t: T) -> Option<T> {
+            let wrapped: Option<T> = Option::Some(t);
+            return wrapped;
+        }
 
-        let mut type_checker = TypeChecker::new();
-        match type_checker.check(&ast) {
-            Ok(_) => {}
-            Err(e) => panic!("Type check failed: {}", e),
+        fn main() {
+            let u = none();
+            let e = fail(1);
+            let t = wrap(T { x: 1 });
+        }
+        "#;
+        assert!(check_expanded(source).is_ok());
+
+        // A real mismatch points at the returned value
+        let wrong = source.replace("return Option::None;", "return Option::Some(1);");
+        match check_expanded(&wrong).unwrap_err() {
+            CompileError::TypeMismatch {
+                expected,
+                found,
+                span,
+            } => {
+                assert_eq!(expected, "Option<U>");
+                assert_eq!(found, "Option<Int>");
+                assert_eq!(span.unwrap().line, 15);
+            }
+            other => panic!("Expected TypeMismatch, got {:?}", other),
         }
     }
 
     #[test]
-    fn test_result_pattern_matching() {
+    fn test_clone_borrows_receiver() {
         let source = r#"
-        enum IntResult {
-            Ok(i64),
-            Err(String),
+        struct Named {
+            name: String,
         }
-        
-        fn main() {
-            let result = IntResult::Ok(42);
-            
-            match result {
-                IntResult::Ok(_) => print("Success"),
-                IntResult::Err(_) => print("Error"),
+
+        impl Clone for Named {
+            fn clone(&self) -> Named {
+                return Named { name: self.name.clone() };
             }
         }
-        "#;
-
-        let mut lexer = Lexer::new(source);
-        let tokens = lexer.collect_tokens().unwrap();
-        let mut parser = Parser::new(tokens);
-        let ast = parser.parse().unwrap();
 
-        let mut type_checker = TypeChecker::new();
-        assert!(type_checker.check(&ast).is_ok());
+        fn main() {
+            let a = Named { name: "a" };
+            let b = a.clone();
+        }
+        "#;
+        assert!(check_expanded(source).is_ok());
+
+        let by_value = source.replace("fn clone(&self)", "fn clone(self)");
+        let err = check_expanded(&by_value).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("'clone' of 'Named' must take '&self'"),
+            "{}",
+            err
+        );
     }
 
     #[test]
-    fn test_multiple_result_types() {
+    fn test_trait_methods_take_self_as_declared() {
         let source = r#"
-        enum StringResult {
-            Ok(String),
-            Err(String),
+        struct P {
+            x: i64,
         }
-        
-        enum FileResult {
-            Ok(i64),
-            Err(String),
+
+        impl Display for P {
+            fn fmt(self) -> String {
+                return int_to_string(self.x);
+            }
         }
-        
-        fn main() {
-            let s_result = StringResult::Ok("test");
-            let f_result = FileResult::Err("not found");
-            
-            match s_result {
-                StringResult::Ok(_) => {
-                    print("string ok");
-                }
-                StringResult::Err(_) => {
-                    print("string err");
-                }
+
+        impl Debug for P {
+            fn fmt_debug(self) -> String {
+                return int_to_string(self.x);
             }
-            
-            match f_result {
-                FileResult::Ok(_) => {
-                    print("file ok");
-                }
-                FileResult::Err(_) => {
-                    print("file err");
-                }
+        }
+
+        trait Reset {
+            fn reset(&mut self);
+        }
+
+        impl Reset for P {
+            fn reset(&mut self) {
+                self.x = 0;
             }
         }
-        "#;
 
-        let mut lexer = Lexer::new(source);
-        let tokens = lexer.collect_tokens().unwrap();
-        let mut parser = Parser::new(tokens);
-        let ast = parser.parse().unwrap();
+        fn main() {
+            let p = P { x: 3 };
+            println!("{} {:?}", p, p);
+        }
+        "#;
+        assert!(check_expanded(source).is_ok());
 
-        let mut type_checker = TypeChecker::new();
-        assert!(type_checker.check(&ast).is_ok());
+        for (from, to, expected) in [
+            (
+                "fn fmt(self)",
+                "fn fmt(&self)",
+                "'fmt' of 'P' must take 'self', as trait Display",
+            ),
+            (
+                "fn fmt_debug(self)",
+                "fn fmt_debug(&mut self)",
+                "'fmt_debug' of 'P' must take 'self', as trait Debug",
+            ),
+            (
+                "fn reset(&mut self) {",
+                "fn reset(&self) {",
+                "'reset' of 'P' must take '&mut self', as trait Reset",
+            ),
+        ] {
+            let err = check_expanded(&source.replace(from, to)).unwrap_err();
+            assert!(err.to_string().contains(expected), "{}", err);
+        }
     }
 
     #[test]
-    fn test_exhaustive_enum_match() {
+    fn test_infer_type_args_from_expected_type() {
         let source = r#"
-        enum Color {
-            Red,
-            Green,
-            Blue,
+        struct Vec<T> {
+            items: [T; 4],
+            len: i64,
         }
-        
-        fn main() {
-            let c = Color::Red;
-            
-            match c {
-                Color::Red => print("red"),
-                Color::Green => print("green"),
-                Color::Blue => print("blue"),
+
+        impl<T> Vec<T> {
+            fn new() -> Self {
+                return Vec { items: [], len: 0 };
             }
         }
-        "#;
 
-        let mut lexer = Lexer::new(source);
-        let tokens = lexer.collect_tokens().unwrap();
-        let mut parser = Parser::new(tokens);
-        let ast = parser.parse().unwrap();
+        fn none_of<T>() -> Option<T> {
+            return Option::None;
+        }
 
-        let mut type_checker = TypeChecker::new();
-        assert!(type_checker.check(&ast).is_ok());
-    }
+        fn take(o: Option<bool>) {}
 
-    #[test]
-    fn test_non_exhaustive_enum_match() {
-        let source = r#"
-        enum Color {
-            Red,
-            Green,
-            Blue,
+        fn first() -> Option<String> {
+            return none_of();
         }
-        
+
         fn main() {
-            let c = Color::Red;
-            
-            match c {
-                Color::Red => print("red"),
-                Color::Green => print("green"),
-                // Missing Blue!
-            }
+            let v: Vec<i64> = Vec::new();
+            let a: Option<i64> = none_of();
+            let b = none_of::<String>();
+            let mut c = none_of();
+            c = a;
+            take(none_of());
         }
         "#;
+        assert!(check_expanded(source).is_ok());
 
-        let mut lexer = Lexer::new(source);
-        let tokens = lexer.collect_tokens().unwrap();
-        let mut parser = Parser::new(tokens);
-        let ast = parser.parse().unwrap();
-
-        let mut type_checker = TypeChecker::new();
-        let result = type_checker.check(&ast);
-        assert!(result.is_err());
+        let source = r#"
+        fn none_of<T>() -> Option<T> {
+            return Option::None;
+        }
 
-        if let Err(CompileError::NonExhaustiveMatch {
-            missing_patterns, ..
-        }) = result
-        {
-            assert!(missing_patterns.contains(&"Color::Blue".to_string()));
-        } else {
-            panic!("Expected NonExhaustiveMatch error");
+        fn main() {
+            let a: Option<bool> = none_of::<i64>();
         }
+        "#;
+        let err = check_expanded(source).unwrap_err();
+        assert!(matches!(err, CompileError::TypeMismatch { .. }));
     }
 
     #[test]
-    fn test_wildcard_makes_match_exhaustive() {
+    fn test_type_annotations_needed() {
         let source = r#"
-        enum Color {
-            Red,
-            Green,
-            Blue,
+        fn none_of<T>() -> Option<T> {
+            return Option::None;
         }
-        
+
         fn main() {
-            let c = Color::Red;
-            
-            match c {
-                Color::Red => print("red"),
-                _ => print("other"),
-            }
+            let a = none_of();
         }
         "#;
+        let err = check_expanded(source).unwrap_err();
+        assert!(matches!(
+            &err,
+            CompileError::TypeAnnotationsNeeded { name, param, span: Some(_) }
+                if name == "none_of" && param == "T"
+        ));
+    }
 
-        let mut lexer = Lexer::new(source);
-        let tokens = lexer.collect_tokens().unwrap();
-        let mut parser = Parser::new(tokens);
-        let ast = parser.parse().unwrap();
+    #[test]
+    fn test_argument_mismatches_report_their_span() {
+        let header = r#"
+        enum Shape {
+            Circle(Option<i64>),
+        }
 
-        let mut type_checker = TypeChecker::new();
-        assert!(type_checker.check(&ast).is_ok());
+        fn none_of<T>() -> Option<T> {
+            return Option::None;
+        }
+
+        fn take(o: Option<bool>) {}
+
+        fn same<T>(a: T, b: T) {}
+        "#;
+        // Each argument is reported where it's written, on main's only line
+        let line = header.lines().count() + 2;
+        for call in [
+            "take(none_of::<i64>());",
+            "let s = Shape::Circle(none_of::<bool>());",
+            "same(Option::Some(1), none_of::<bool>());",
+        ] {
+            let source = format!("{}\nfn main() {{\n{}\n}}", header, call);
+            match check_expanded(&source) {
+                Err(CompileError::TypeMismatch { span, .. }) => {
+                    assert_eq!(span.map(|span| span.line), Some(line), "{}", call)
+                }
+                other => panic!("Expected TypeMismatch for `{}`, got {:?}", call, other),
+            }
+        }
     }
 
     #[test]
-    fn test_unreachable_pattern_after_wildcard() {
+    fn test_str_views() {
         let source = r#"
-        enum Color {
-            Red,
-            Green,
-            Blue,
+        fn shout(s: ref str) -> i64 {
+            print(s);
+            let t = s;
+            if t == "hi" {
+                println!("{}!", s + "?");
+            }
+            return string_len(t);
         }
-        
+
         fn main() {
-            let c = Color::Red;
-            
-            match c {
-                Color::Red => print("red"),
-                _ => print("any"),
-                Color::Blue => print("blue"), // Unreachable!
-            }
+            let mut s = "hi";
+            let n = shout(s);
+            string_push_str(s, "!");
+            let v: ref str = s;
         }
         "#;
-
-        let mut lexer = Lexer::new(source);
-        let tokens = lexer.collect_tokens().unwrap();
-        let mut parser = Parser::new(tokens);
-        let ast = parser.parse().unwrap();
-
-        let mut type_checker = TypeChecker::new();
-        let result = type_checker.check(&ast);
-        assert!(result.is_err());
-
-        if let Err(CompileError::UnreachablePattern { .. }) = result {
-            // Expected
-        } else {
-            panic!("Expected UnreachablePattern error");
+        assert!(check_expanded(source).is_ok());
+
+        // A view owns nothing, so it can't stand in for a `String`
+        for body in [
+            "return s;",
+            "let u: String = s; return u;",
+            "return own(s);",
+        ] {
+            let source = format!(
+                "fn own(s: String) -> String {{ return s; }}
+                fn view(s: ref str) -> String {{ {} }}
+                fn main() {{}}",
+                body
+            );
+            match check_expanded(&source) {
+                Err(CompileError::TypeMismatch {
+                    expected, found, ..
+                }) => assert_eq!((expected.as_str(), found.as_str()), ("String", "ref str")),
+                other => panic!("Expected TypeMismatch for `{}`, got {:?}", body, other),
+            }
         }
     }
+
+    fn check_expanded(source: &str) -> Result<()> {
+        let tokens = Lexer::new(source).collect_tokens()?;
+        let mut program = Parser::new(tokens).parse()?;
+        crate::macros::MacroExpander::new().expand_program(&mut program)?;
+        TypeChecker::new().check(&program)
+    }
 }
@@ -0,0 +1,196 @@
+// Prelude for Palladium
+// "The legends every program is born knowing"
+
+use crate::ast::{Item, Program, Type};
+use crate::lexer::Lexer;
+use crate::parser::Parser;
+
+/// Items implicitly available to every program
+pub const PRELUDE_SOURCE: &str = r#"
+pub enum Option<T> {
+    Some(T),
+    None,
+}
+
+pub enum Result<T, E> {
+    Ok(T),
+    Err(E),
+}
+
+pub trait From<T> {
+    fn from(value: T) -> Self;
+}
+
+pub trait Display {
+    fn fmt(self) -> String;
+}
+
+pub trait Debug {
+    fn fmt_debug(self) -> String;
+}
+
+pub trait Clone {
+    fn clone(&self) -> Self;
+}
+
+pub trait Copy {}
+
+// An empty `impl Hash for T {}` or `impl Eq for T {}` derives hashing or
+// equality field by field
+pub trait Hash {}
+
+pub trait Eq {}
+
+pub trait Drop {
+    fn drop(&mut self);
+}
+
+// `for x in value` calls `next` until it returns `None`; a value that isn't an
+// iterator itself is first turned into one with `into_iter`
+pub trait Iterator<T> {
+    fn next(&mut self) -> Option<T>;
+}
+
+pub trait IntoIterator<I> {
+    fn into_iter(self) -> I;
+}
+
+// Signatures of the built-in `Vec<T>`; code generation provides the bodies
+impl<T> Vec<T> {
+    fn new() -> Vec<T> {}
+    fn with_capacity(capacity: i64) -> Vec<T> {}
+    fn push(v: &mut Vec<T>, value: T) {}
+    fn pop(v: &mut Vec<T>) -> Option<T> {}
+    fn insert(v: &mut Vec<T>, index: i64, value: T) {}
+    fn remove(v: &mut Vec<T>, index: i64) -> T {}
+    fn len(v: &Vec<T>) -> i64 {}
+    fn capacity(v: &Vec<T>) -> i64 {}
+}
+
+// Signatures of the built-in `HashMap<K, V>` and `HashSet<T>`, keyed by any
+// `Hash + Eq` type
+impl<K, V> HashMap<K, V> {
+    fn new() -> HashMap<K, V> {}
+    fn insert(m: &mut HashMap<K, V>, key: K, value: V) -> Option<V> {}
+    fn get(m: &HashMap<K, V>, key: &K) -> Option<&V> {}
+    fn get_mut(m: &mut HashMap<K, V>, key: &K) -> Option<&mut V> {}
+    fn remove(m: &mut HashMap<K, V>, key: &K) -> Option<V> {}
+    fn contains_key(m: &HashMap<K, V>, key: &K) -> bool {}
+    fn len(m: &HashMap<K, V>) -> i64 {}
+    fn entry(m: &mut HashMap<K, V>, key: K) -> Entry<K, V> {}
+    fn keys(m: &HashMap<K, V>) -> Vec<K> {}
+    fn values(m: &HashMap<K, V>) -> Vec<V> {}
+}
+
+impl<K, V> Entry<K, V> {
+    fn or_insert(e: Entry<K, V>, default: V) -> &mut V {}
+}
+
+impl<T> HashSet<T> {
+    fn new() -> HashSet<T> {}
+    fn insert(s: &mut HashSet<T>, value: T) -> bool {}
+    fn contains(s: &HashSet<T>, value: &T) -> bool {}
+    fn remove(s: &mut HashSet<T>, value: &T) -> bool {}
+    fn len(s: &HashSet<T>) -> i64 {}
+}
+"#;
+
+/// Parse the prelude into AST items
+pub fn items() -> Vec<Item> {
+    let tokens = Lexer::new(PRELUDE_SOURCE)
+        .collect_tokens()
+        .expect("prelude must lex");
+    Parser::new(tokens)
+        .parse()
+        .expect("prelude must parse")
+        .items
+}
+
+/// Name of a prelude item
+pub fn item_name(item: &Item) -> Option<&str> {
+    match item {
+        Item::Enum(enum_def) => Some(&enum_def.name),
+        Item::Trait(trait_def) => Some(&trait_def.name),
+        Item::Struct(struct_def) => Some(&struct_def.name),
+        _ => None,
+    }
+}
+
+/// Prelude items not shadowed by a definition of the same name in the program.
+/// The signatures of a built-in type go with it.
+pub fn visible_items(program: &Program) -> Vec<Item> {
+    items()
+        .into_iter()
+        .filter(|item| {
+            let name = match item {
+                Item::Impl(impl_block) => match &impl_block.for_type {
+                    Type::Generic { name, .. } | Type::Custom(name) => Some(name.as_str()),
+                    _ => None,
+                },
+                _ => item_name(item),
+            };
+            !program
+                .items
+                .iter()
+                .any(|own| item_name(own).is_some() && item_name(own) == name)
+        })
+        .collect()
+}
+
+
+/// C symbol for the `From::from` conversion from `source` into `target`
+pub fn from_impl_symbol(target: &str, source: &str) -> String {
+    format!("__pd_{}_from_{}", target, mangle_type_arg(source))
+}
+
+/// Turn a type argument like `Option<i64>` into an identifier fragment (`Option_i64`)
+pub fn mangle_type_arg(arg: &str) -> String {
+    arg.chars()
+        .filter(|c| !c.is_whitespace() && *c != '>')
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// C name of a monomorphized generic enum, e.g. `Result_i64_String`
+pub fn mangle_enum_name(name: &str, type_args: &[String]) -> String {
+    let args: Vec<String> = type_args.iter().map(|arg| mangle_type_arg(arg)).collect();
+    format!("{}_{}", name, args.join("_"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prelude_parses() {
+        let names: Vec<String> = items()
+            .iter()
+            .filter_map(|item| item_name(item).map(String::from))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "Option",
+                "Result",
+                "From",
+                "Display",
+                "Debug",
+                "Clone",
+                "Copy",
+                "Hash",
+                "Eq",
+                "Drop",
+                "Iterator",
+                "IntoIterator"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nested_enum_names_mangle_consistently() {
+        let inner = mangle_enum_name("Result", &["i64".to_string(), "String".to_string()]);
+        let outer = mangle_enum_name("Option", &["Result<i64, String>".to_string()]);
+        assert_eq!(inner, "Result_i64_String");
+        assert_eq!(outer, format!("Option_{}", inner));
+    }
+}
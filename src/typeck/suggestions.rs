@@ -138,14 +138,282 @@ impl TypeErrorHelper {
         )
     }
 
+    /// Create error for `?` applied to something that is neither Result nor Option
+    pub fn question_on_non_try_type(&self, found: &str, span: Span) -> CompileError {
+        CompileError::InvalidTryOperator {
+            message: format!("cannot be applied to a value of type '{}'", found),
+            help: "'?' only works on Result<T, E> and Option<T> values".to_string(),
+            span: Some(span),
+        }
+    }
+
+    /// Create error for `?` used in a function whose return type cannot carry the failure
+    pub fn question_in_incompatible_function(
+        &self,
+        operand_kind: &str,
+        function_return: &str,
+        span: Span,
+    ) -> CompileError {
+        let suggested = if operand_kind == "Option" {
+            "Option<...>"
+        } else {
+            "Result<..., E>"
+        };
+        CompileError::InvalidTryOperator {
+            message: format!(
+                "used on {} in a function that returns '{}'",
+                operand_kind, function_return
+            ),
+            help: format!(
+                "Change the function's return type to {} or handle the {} with a match",
+                suggested, operand_kind
+            ),
+            span: Some(span),
+        }
+    }
+
+    /// Create error for `.await` in a function that can't suspend
+    pub fn await_outside_async(&self, span: Span) -> CompileError {
+        CompileError::SyntaxError {
+            message:
+                "'.await' is only allowed inside an async fn; mark the enclosing function async"
+                    .to_string(),
+            span: Some(span),
+        }
+    }
+
+    /// Create error for an `async scope` outside an async fn
+    pub fn scope_outside_async(&self, span: Span) -> CompileError {
+        CompileError::SyntaxError {
+            message: "'async scope' is only allowed inside an async fn; mark the enclosing function async".to_string(),
+            span: Some(span),
+        }
+    }
+
+    /// Create error for a handler or operation of an effect never declared
+    pub fn undefined_effect(&self, name: &str, span: Span) -> CompileError {
+        CompileError::SyntaxError {
+            message: format!(
+                "Undefined effect '{}'; declare it with effect {} {{ fn operation(...); }}",
+                name, name
+            ),
+            span: Some(span),
+        }
+    }
+
+    /// Create error for an operation its effect doesn't declare
+    pub fn unknown_effect_operation(&self, effect: &str, operation: &str, span: Span) -> CompileError {
+        CompileError::SyntaxError {
+            message: format!("Effect '{}' has no operation '{}'", effect, operation),
+            span: Some(span),
+        }
+    }
+
+    /// Create error for a handler leaving out an operation of its effect
+    pub fn missing_handler_operation(&self, effect: &str, operation: &str, span: Span) -> CompileError {
+        CompileError::SyntaxError {
+            message: format!(
+                "Handler of '{}' is missing operation '{}'; every operation of an effect needs a handler",
+                effect, operation
+            ),
+            span: Some(span),
+        }
+    }
+
+    /// Create error for control flow that would leave an `async scope` before
+    /// its tasks are awaited
+    pub fn leave_async_scope(&self, what: &str) -> CompileError {
+        CompileError::Generic(format!(
+            "'{}' cannot leave an async scope, whose tasks must be awaited first; move it after the scope",
+            what
+        ))
+    }
+
+    /// Create error for `?` whose error type cannot be converted into the function's
+    pub fn question_error_conversion(&self, from: &str, into: &str, span: Span) -> CompileError {
+        CompileError::InvalidTryOperator {
+            message: format!(
+                "cannot convert error type '{}' into '{}'",
+                from, into
+            ),
+            help: format!(
+                "Implement the conversion: impl From<{}> for {} {{ fn from(value: {}) -> Self {{ ... }} }}",
+                from, into, from
+            ),
+            span: Some(span),
+        }
+    }
+
+    /// Create error for a formatted value whose type lacks the required trait
+    pub fn format_trait_missing(
+        &self,
+        type_name: &str,
+        trait_name: &str,
+        span: Span,
+    ) -> CompileError {
+        let method = if trait_name == "Debug" {
+            "fmt_debug"
+        } else {
+            "fmt"
+        };
+        CompileError::FormatError {
+            message: format!(
+                "'{}' doesn't implement {}; add impl {} for {} {{ fn {}(self) -> String {{ ... }} }}",
+                type_name, trait_name, trait_name, type_name, method
+            ),
+            span: Some(span),
+        }
+    }
+
+    /// Create error for an `impl Copy` on a type with a field that isn't Copy
+    pub fn copy_field_not_copy(
+        &self,
+        type_name: &str,
+        field: &str,
+        field_type: &crate::ast::Type,
+    ) -> CompileError {
+        CompileError::Generic(format!(
+            "'{}' cannot implement Copy: field '{}' has type '{}', which is not Copy; implement Clone instead",
+            type_name, field, field_type
+        ))
+    }
+
+    /// Create error for a derived `Hash` or `Eq` on a type with a field lacking it
+    pub fn derived_field_missing(
+        &self,
+        type_name: &str,
+        trait_name: &str,
+        field: &str,
+        field_type: &crate::ast::Type,
+    ) -> CompileError {
+        CompileError::Generic(format!(
+            "'{}' cannot derive {}: field '{}' has type '{}', which doesn't implement {}",
+            type_name, trait_name, field, field_type, trait_name
+        ))
+    }
+
+    /// Create error for a hash collection keyed by a type that isn't `Hash + Eq`
+    pub fn hash_key_missing(&self, type_name: &str) -> CompileError {
+        CompileError::Generic(format!(
+            "'{}' can't key a HashMap or HashSet: it must implement Hash and Eq; add impl Hash for {} {{}} and impl Eq for {} {{}}",
+            type_name, type_name, type_name
+        ))
+    }
+
+    /// Create error for a struct or enum that contains itself without indirection
+    pub fn recursive_type(
+        &self,
+        type_name: &str,
+        field: &str,
+        field_type: &crate::ast::Type,
+    ) -> CompileError {
+        CompileError::Generic(format!(
+            "recursive type '{}' has infinite size: field '{}' holds a '{}' inline; consider boxing it as 'Box<{}>'",
+            type_name, field, field_type, field_type
+        ))
+    }
+
+    /// Create error for a type implementing both Copy and Drop
+    pub fn copy_and_drop(&self, type_name: &str) -> CompileError {
+        CompileError::Generic(format!(
+            "'{}' cannot implement both Copy and Drop: copies would run the destructor twice; implement Clone instead of Copy",
+            type_name
+        ))
+    }
+
+    /// Create error for `.clone()` on a type without Clone
+    pub fn clone_missing(&self, type_name: &str) -> CompileError {
+        CompileError::Generic(format!(
+            "'{}' doesn't implement Clone; add impl Clone for {} {{ fn clone(&self) -> {} {{ ... }} }}",
+            type_name, type_name, type_name
+        ))
+    }
+
+    /// Create error for a trait method taking `self` differently than its trait
+    /// declares: `declared` is how it should, as `TypeChecker::receiver_kind` gives it
+    pub fn trait_receiver(
+        &self,
+        trait_name: &str,
+        method: &str,
+        type_name: &str,
+        declared: Option<Option<bool>>,
+    ) -> CompileError {
+        let receiver = match declared {
+            None => "no 'self'",
+            Some(None) => "'self'",
+            Some(Some(false)) => "'&self'",
+            Some(Some(true)) => "'&mut self'",
+        };
+        CompileError::Generic(format!(
+            "'{}' of '{}' must take {}, as trait {} declares it",
+            method, type_name, receiver, trait_name
+        ))
+    }
+
+    /// Create error for an integer-only format spec applied to a non-integer
+    pub fn format_expects_integer(
+        &self,
+        placeholder: &str,
+        found: &str,
+        span: Span,
+    ) -> CompileError {
+        CompileError::FormatError {
+            message: format!("{} expects an integer, found '{}'", placeholder, found),
+            span: Some(span),
+        }
+    }
+
     /// Create for loop non-array error
-    #[allow(dead_code)]
     pub fn for_loop_non_array(&self, found_type: &str) -> CompileError {
+        CompileError::Generic(format!(
+            "For loop requires an array, range, collection or iterator, found '{}'. Iterate a range (1..10), or add impl Iterator<T> for {} {{ fn next(&mut self) -> Option<T> {{ ... }} }}",
+            found_type, found_type
+        ))
+    }
+
+    /// Create error for a for loop pattern that doesn't fit the items it walks
+    pub fn for_loop_pattern_mismatch(&self, item_type: &str, paired: bool) -> CompileError {
+        if paired {
+            CompileError::Generic(format!(
+                "Items of type '{}' aren't pairs; for (a, b) in ... only fits enumerate(), zip() or a HashMap",
+                item_type
+            ))
+        } else {
+            CompileError::Generic(format!(
+                "This for loop yields pairs {}; bind both halves with for (a, b) in ...",
+                item_type
+            ))
+        }
+    }
+
+    /// Create error for an iterator method given something other than a function name
+    pub fn iterator_function_expected(&self, method: &str) -> CompileError {
+        CompileError::Generic(format!(
+            "{}() takes the name of a non-generic function of one parameter, such as .{}(f)",
+            method, method
+        ))
+    }
+
+    /// Create error for an iterator chain that is neither looped over nor consumed
+    pub fn iterator_not_consumed(&self, method: &str) -> CompileError {
+        CompileError::Generic(format!(
+            "The iterator from {}() must be consumed where it is built: loop over it with for, or end the chain with sum() or collect()",
+            method
+        ))
+    }
+
+    /// Create error for pairs given to an iterator method that takes single items
+    pub fn iterator_pairs_unsupported(&self, method: &str, item_type: &str) -> CompileError {
+        CompileError::Generic(format!(
+            "{}() takes single items, found pairs '{}'; only a for loop can bind pairs",
+            method, item_type
+        ))
+    }
+
+    /// Create error for `rev()` on an iterator that can't be walked backwards
+    pub fn iterator_not_reversible(&self) -> CompileError {
         CompileError::Generic(
-            format!(
-                "For loops require an array or range to iterate over. Found '{}'. Use an array literal [1, 2, 3] or a range (1..10).",
-                found_type
-            )
+            "rev() must come right after a range, array, slice or Vec".to_string(),
         )
     }
 
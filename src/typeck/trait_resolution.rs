@@ -25,6 +25,8 @@ pub struct TraitMethodInfo {
     #[allow(dead_code)]
     pub return_type: Option<Type>,
     pub has_default: bool,
+    /// Type of the `self` parameter, if the method takes one
+    pub receiver: Option<Type>,
 }
 
 /// Implementation information
@@ -59,6 +61,12 @@ pub struct TraitResolver {
     type_impls: HashMap<String, Vec<usize>>, // Type name -> impl indices
 }
 
+impl Default for TraitResolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl TraitResolver {
     pub fn new() -> Self {
         Self {
@@ -84,6 +92,11 @@ impl TraitResolver {
                 params: method.params.iter().map(|p| p.ty.clone()).collect(),
                 return_type: method.return_type.clone(),
                 has_default: method.has_body,
+                receiver: method
+                    .params
+                    .first()
+                    .filter(|param| param.name == "self")
+                    .map(|param| param.ty.clone()),
             };
             methods.insert(method.name.clone(), method_info);
         }
@@ -102,7 +115,7 @@ impl TraitResolver {
     pub fn register_impl(&mut self, impl_block: &ImplBlock) -> Result<()> {
         let trait_name = if let Some(trait_type) = &impl_block.trait_type {
             match trait_type {
-                Type::Custom(name) => Some(name.clone()),
+                Type::Custom(name) | Type::Generic { name, .. } => Some(name.clone()),
                 _ => return Err(CompileError::Generic("Invalid trait type".to_string())),
             }
         } else {
@@ -150,7 +163,6 @@ impl TraitResolver {
     }
 
     /// Check if a type implements a trait
-    #[allow(dead_code)]
     pub fn type_implements_trait(&self, ty: &Type, trait_name: &str) -> bool {
         if let Some(type_name) = self.get_type_name(ty) {
             if let Some(impl_indices) = self.type_impls.get(&type_name) {
@@ -165,6 +177,55 @@ impl TraitResolver {
         false
     }
 
+    /// Whether values of a type are copied rather than moved: scalars, shared
+    /// references, arrays and tuples of `Copy` types, and types with an `impl Copy`.
+    /// A `&mut` is moved, or reborrowed where a `&mut` is expected
+    pub fn is_copy(&self, ty: &Type) -> bool {
+        match ty {
+            Type::I32 | Type::I64 | Type::U32 | Type::U64 | Type::Bool | Type::Unit => true,
+            Type::Reference { mutable: false, .. } | Type::Function { .. } => true,
+            Type::Array(elem, _) => self.is_copy(elem),
+            Type::Tuple(types) => types.iter().all(|t| self.is_copy(t)),
+            Type::Custom(_) | Type::Generic { .. } => self.type_implements_trait(ty, "Copy"),
+            Type::String
+            | Type::Str
+            | Type::Reference { mutable: true, .. }
+            | Type::TypeParam(_)
+            | Type::Future { .. } => false,
+        }
+    }
+
+    /// Whether `.clone()` is available: `Copy` types, strings, arrays and tuples of
+    /// `Clone` values, and types with an `impl Clone`
+    pub fn is_clone(&self, ty: &Type) -> bool {
+        match ty {
+            Type::String | Type::Str => true,
+            Type::Array(elem, _) => self.is_clone(elem),
+            Type::Tuple(types) => types.iter().all(|t| self.is_clone(t)),
+            _ => self.is_copy(ty) || self.type_implements_trait(ty, "Clone"),
+        }
+    }
+
+    /// Whether values of a type can be hashed: integers, booleans, strings, and
+    /// types with an `impl Hash`
+    pub fn is_hash(&self, ty: &Type) -> bool {
+        match ty {
+            Type::I32 | Type::I64 | Type::U32 | Type::U64 | Type::Bool | Type::String => true,
+            Type::Custom(_) => self.type_implements_trait(ty, "Hash"),
+            _ => false,
+        }
+    }
+
+    /// Whether values of a type can be compared for equality as hash keys:
+    /// integers, booleans, strings, and types with an `impl Eq`
+    pub fn is_eq(&self, ty: &Type) -> bool {
+        match ty {
+            Type::I32 | Type::I64 | Type::U32 | Type::U64 | Type::Bool | Type::String => true,
+            Type::Custom(_) => self.type_implements_trait(ty, "Eq"),
+            _ => false,
+        }
+    }
+
     /// Find method implementation for a type
     #[allow(dead_code)]
     pub fn find_method(&self, ty: &Type, method_name: &str) -> Option<MethodResolution> {
@@ -221,6 +282,13 @@ impl TraitResolver {
         traits
     }
 
+    /// The `self` parameter a trait declares for one of its methods: `None` if
+    /// there's no such method, `Some(None)` if it takes no `self`
+    pub fn method_receiver(&self, trait_name: &str, method_name: &str) -> Option<Option<&Type>> {
+        let method = self.traits.get(trait_name)?.methods.get(method_name)?;
+        Some(method.receiver.as_ref())
+    }
+
     /// Check if all required trait methods are implemented
     pub fn check_trait_impl_complete(
         &self,
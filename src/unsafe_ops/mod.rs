@@ -1,7 +1,7 @@
 // Unsafe operations for Palladium
 // "With great power comes great responsibility"
 
-use crate::ast::{Expr, Function, Stmt};
+use crate::ast::{Expr, Function, Stmt, StructBase};
 use crate::errors::{CompileError, Result};
 
 /// Unsafe operation checker
@@ -148,7 +148,7 @@ impl UnsafeChecker {
             Expr::Call {
                 func, args, span, ..
             } => {
-                if let Expr::Ident(func_name) = func.as_ref() {
+                if let Some(func_name) = func.callee_name() {
                     if self.is_unsafe_function(func_name) && !self.is_unsafe_context() {
                         return Err(CompileError::UnsafeOperation {
                             operation: format!("call to unsafe function '{}'", func_name),
@@ -182,10 +182,13 @@ impl UnsafeChecker {
 
             Expr::FieldAccess { object, .. } => self.check_expression(object),
 
-            Expr::StructLiteral { fields, .. } => {
+            Expr::StructLiteral { fields, base, .. } => {
                 for (_, field_expr) in fields {
                     self.check_expression(field_expr)?;
                 }
+                if let Some(StructBase::Expr(base)) = base {
+                    self.check_expression(base)?;
+                }
                 Ok(())
             }
 
@@ -233,7 +236,11 @@ impl UnsafeChecker {
             Expr::Await { expr, .. } => self.check_expression(expr),
 
             // Literals and identifiers are safe
-            Expr::Integer(_) | Expr::String(_) | Expr::Bool(_) | Expr::Ident(_) => Ok(()),
+            Expr::Integer(_)
+            | Expr::String(_)
+            | Expr::Bool(_)
+            | Expr::Ident(_)
+            | Expr::Turbofish { .. } => Ok(()),
 
             Expr::MacroInvocation { .. } => Ok(()), // Macros are expanded before this phase
         }
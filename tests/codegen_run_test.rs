@@ -0,0 +1,445 @@
+// End-to-end tests that compile Palladium programs to C, build them with gcc
+// and check what they print
+
+use palladium::Driver;
+use std::fs;
+use std::process::Command;
+use tempfile::TempDir;
+
+/// Compile `source` to an executable and run it, returning its output
+///
+/// The C output lands in `build_output/{name}.c`, so every test picks its own name.
+/// File I/O wrappers reference the Rust runtime's `pd_*` functions, which
+/// these programs never call, so the link leaves them unresolved; that only
+/// works for a position-dependent executable.
+fn compile_and_run(name: &str, source: &str) -> Result<String, String> {
+    let temp_dir = TempDir::new().unwrap();
+    let source_path = temp_dir.path().join(format!("{}.pd", name));
+    let exe_path = temp_dir.path().join(name);
+    fs::write(&source_path, source).unwrap();
+
+    let c_path = Driver::new()
+        .compile_file(&source_path)
+        .map_err(|e| format!("Compilation failed: {}", e))?;
+
+    let cc_output = Command::new("gcc")
+        .arg("-o")
+        .arg(&exe_path)
+        .arg(&c_path)
+        .arg("-no-pie")
+        .arg("-Wl,--unresolved-symbols=ignore-in-object-files")
+        .output()
+        .map_err(|e| format!("Failed to run gcc: {}", e))?;
+    if !cc_output.status.success() {
+        return Err(String::from_utf8_lossy(&cc_output.stderr).to_string());
+    }
+
+    let run_output = Command::new(&exe_path)
+        .output()
+        .map_err(|e| format!("Failed to run executable: {}", e))?;
+    if !run_output.status.success() {
+        return Err(String::from_utf8_lossy(&run_output.stderr).to_string());
+    }
+    Ok(String::from_utf8_lossy(&run_output.stdout).to_string())
+}
+
+#[test]
+fn test_clone_user_struct() {
+    let source = r#"
+struct Named {
+    name: String,
+    n: i64,
+}
+
+impl Clone for Named {
+    fn clone(&self) -> Named {
+        return Named { name: self.name.clone() + "'", n: self.n + 1 };
+    }
+}
+
+fn main() {
+    let a = Named { name: "a", n: 1 };
+    let b = a.clone();
+    print(b.name.clone());
+    print_int(b.n);
+    print(a.name.clone());
+
+    let mut v: Vec<Named> = Vec::new();
+    Vec::push(&mut v, a);
+    Vec::push(&mut v, b);
+    let w = v.clone();
+    print(w[1].name.clone());
+    print_int(w[0].n);
+}
+"#;
+    let output = compile_and_run("clone_user_struct", source).unwrap();
+    assert_eq!(output, "a'\n2\na\na''\n2\n");
+}
+
+#[test]
+fn test_user_types_named_like_type_params() {
+    let source = r#"
+struct T {
+    x: i64,
+}
+
+struct U {
+    v: i64,
+}
+
+struct E {
+    code: i64,
+}
+
+fn none() -> Option<U> {
+    return Option::None;
+}
+
+fn fail(code: i64) -> Result<i64, E> {
+    return Result::Err(E { code: code });
+}
+
+fn wrap(t: T) -> Option<T> {
+    return Option::Some(t);
+}
+
+fn main() {
+    match none() {
+        Option::Some(u) => print_int(u.v),
+        Option::None => print("none"),
+    }
+    match fail(3) {
+        Result::Ok(n) => print_int(n),
+        Result::Err(e) => print_int(e.code),
+    }
+    match wrap(T { x: 7 }) {
+        Option::Some(t) => print_int(t.x),
+        Option::None => print("none"),
+    }
+}
+"#;
+    let output = compile_and_run("user_types_named_like_type_params", source).unwrap();
+    assert_eq!(output, "none\n3\n7\n");
+}
+
+#[test]
+fn test_user_types_named_like_type_params_in_fields() {
+    // Struct fields and enum payloads name one-letter types of the program
+    let source = r#"
+enum E {
+    Parse(String),
+}
+
+impl From<String> for E {
+    fn from(value: String) -> Self {
+        return E::Parse(value);
+    }
+}
+
+struct W {
+    e: Option<E>,
+}
+
+enum Outcome {
+    Done(Result<i64, E>),
+}
+
+fn parse(n: i64) -> Result<i64, String> {
+    if n < 0 {
+        return Result::Err("negative");
+    }
+    return Result::Ok(n);
+}
+
+fn run(n: i64) -> Result<i64, E> {
+    let v = parse(n)?;
+    return Result::Ok(v + 1);
+}
+
+fn show(e: E) {
+    match e {
+        E::Parse(msg) => print(msg),
+    }
+}
+
+fn report(outcome: Outcome) {
+    match outcome {
+        Outcome::Done(result) => {
+            match result {
+                Result::Ok(n) => print_int(n),
+                Result::Err(e) => show(e),
+            }
+        }
+    }
+}
+
+fn main() {
+    let w = W { e: Option::Some(E::Parse("bad")) };
+    match w.e {
+        Option::Some(e) => show(e),
+        Option::None => print("none"),
+    }
+    report(Outcome::Done(run(1)));
+    report(Outcome::Done(run(-1)));
+}
+"#;
+    let output = compile_and_run("user_types_named_like_type_params_in_fields", source).unwrap();
+    assert_eq!(output, "bad\n2\nnegative\n");
+}
+
+#[test]
+fn test_cancelled_tasks_let_go() {
+    // `fail` cancels `sleeper` while it sleeps and `waiter` while it awaits
+    // `slow`'s handle. Both are freed, so `slow` finishing later mustn't wake `waiter`
+    let source = r#"
+async fn fail(n: i64) -> Result<i64, String> {
+    sleep_ms(n).await;
+    return Result::Err("failed");
+}
+
+async fn slow(n: i64) -> Result<i64, String> {
+    sleep_ms(n).await;
+    print_int(n);
+    return Result::Ok(n);
+}
+
+async fn waiter(n: i64) -> Result<i64, String> {
+    let handle = spawn(slow(n));
+    return handle.await;
+}
+
+async fn sleeper(n: i64) -> Result<i64, String> {
+    sleep_ms(n).await;
+    print_int(n);
+    return Result::Ok(n);
+}
+
+async fn main() {
+    async scope {
+        spawn(waiter(50));
+        spawn(sleeper(60000));
+        spawn(fail(10));
+    }
+    print_int(1);
+    sleep_ms(100).await;
+    print_int(2);
+}
+"#;
+    let output = compile_and_run("cancelled_tasks_let_go", source).unwrap();
+    assert_eq!(output, "1\n50\n2\n");
+}
+
+#[test]
+fn test_aborted_tasks() {
+    // Aborting stops `slow` and `never` at their next turn; `quick` had
+    // finished already, so its output is still there to await
+    let source = r#"
+async fn tick(n: i64) -> i64 {
+    sleep_ms(n).await;
+    print_int(n);
+    return n;
+}
+
+async fn main() {
+    let slow = spawn(tick(60000));
+    let quick = spawn(tick(10));
+    sleep_ms(50).await;
+    JoinHandle::abort(&slow);
+    JoinHandle::abort(&quick);
+    print_int(quick.await + 1);
+    let never = spawn(tick(20));
+    JoinHandle::abort(&never);
+    sleep_ms(50).await;
+    print_int(0);
+}
+"#;
+    let output = compile_and_run("aborted_tasks", source).unwrap();
+    assert_eq!(output, "10\n11\n0\n");
+
+    let awaited = source.replace("print_int(quick.await + 1);", "print_int(slow.await);");
+    let err = compile_and_run("awaited_aborted_task", &awaited).unwrap_err();
+    assert!(err.contains("panic: awaited a cancelled task"), "{}", err);
+}
+
+#[test]
+fn test_vec_calls_in_generic_functions() {
+    // Each instantiation calls the runtime of its own element type
+    let source = r#"
+fn make<T>() -> Vec<T> {
+    return Vec::new();
+}
+
+fn first_len<T>(v: Vec<T>) -> i64 {
+    return Vec::len(&v);
+}
+
+fn count<T>(x: T, y: T) -> i64 {
+    let mut v: Vec<T> = Vec::new();
+    Vec::push(&mut v, x);
+    Vec::push(&mut v, y);
+    return Vec::len(&v);
+}
+
+fn main() {
+    let mut a: Vec<i64> = make();
+    Vec::push(&mut a, 4);
+    let mut b: Vec<String> = make();
+    Vec::push(&mut b, "x");
+    Vec::push(&mut b, "y");
+    print_int(first_len(a));
+    print_int(first_len(b));
+    print_int(count(1, 2));
+    print_int(count("a", "b"));
+}
+"#;
+    let output = compile_and_run("vec_calls_in_generic_functions", source).unwrap();
+    assert_eq!(output, "1\n2\n2\n2\n");
+}
+
+#[test]
+fn test_copy_values_by_let() {
+    // Both bindings stay usable after copying a `Copy` struct or array
+    let source = r#"
+struct P {
+    x: i64,
+    y: i64,
+}
+
+impl Copy for P {}
+
+fn main() {
+    let a = P { x: 1, y: 2 };
+    let b = a;
+    let c = [1, 2, 3];
+    let d = c;
+    print_int(a.x + b.y);
+    print_int(c[0] + d[2]);
+}
+"#;
+    let output = compile_and_run("copy_values_by_let", source).unwrap();
+    assert_eq!(output, "3\n4\n");
+}
+
+#[test]
+fn test_boxed_recursive_tree() {
+    // A recursive enum through Box, read through references and boxes alike
+    let source = r#"
+enum Expr {
+    Num(i64),
+    Add(Box<Expr>, Box<Expr>),
+    Mul(Box<Expr>, Box<Expr>),
+}
+
+enum L {
+    Nil,
+    Cons(i64, Box<L>),
+}
+
+fn eval(e: &Expr) -> i64 {
+    match e {
+        Expr::Num(n) => {
+            return n;
+        }
+        Expr::Add(a, b) => {
+            return eval(a) + eval(b);
+        }
+        Expr::Mul(a, b) => {
+            return eval(a) * eval(b);
+        }
+    }
+}
+
+fn sum(l: &L) -> i64 {
+    match l {
+        L::Nil => {
+            return 0;
+        }
+        L::Cons(n, rest) => {
+            return n + sum(rest);
+        }
+    }
+}
+
+fn main() {
+    let product = Expr::Mul(Box::new(Expr::Num(3)), Box::new(Expr::Num(4)));
+    let tree = Expr::Add(Box::new(Expr::Num(2)), Box::new(product));
+    print_int(eval(&tree));
+    let boxed = Box::new(Expr::Num(5));
+    print_int(eval(boxed));
+    print_int(eval(boxed) + 1);
+    let list = L::Cons(1, Box::new(L::Cons(2, Box::new(L::Nil))));
+    print_int(sum(&list));
+}
+"#;
+    let output = compile_and_run("boxed_recursive_tree", source).unwrap();
+    assert_eq!(output, "14\n5\n6\n3\n");
+}
+
+#[test]
+fn test_boxed_expression_tree_by_value() {
+    // Boxes named by a single-letter enum, moved out of while evaluating; the
+    // tree that is never evaluated is dropped with all of its boxes
+    let source = r#"
+enum E {
+    Num(i64),
+    Neg(Box<E>),
+    Add(Box<E>, Box<E>),
+    Scale { by: i64, inner: Box<E> },
+}
+
+fn eval(e: E) -> i64 {
+    match e {
+        E::Num(n) => {
+            return n;
+        }
+        E::Neg(inner) => {
+            return 0 - eval(*inner);
+        }
+        E::Add(a, b) => {
+            return eval(*a) + eval(*b);
+        }
+        E::Scale { by: by, inner: inner } => {
+            return by * eval(*inner);
+        }
+    }
+}
+
+fn main() {
+    let kept = E::Add(Box::new(E::Num(4)), Box::new(E::Neg(Box::new(E::Num(5)))));
+    let e = E::Scale { by: 2, inner: Box::new(E::Add(Box::new(E::Num(1)), Box::new(E::Neg(Box::new(E::Num(7)))))) };
+    print_int(eval(e));
+}
+"#;
+    let output = compile_and_run("boxed_expression_tree_by_value", source).unwrap();
+    assert_eq!(output, "-12\n");
+}
+
+#[test]
+fn test_iterator_chains() {
+    let source = r#"
+fn twice(x: i64) -> i64 {
+    return x * 2;
+}
+
+fn is_odd(x: &i64) -> bool {
+    return x % 2 == 1;
+}
+
+fn main() {
+    let total = (1..10).step_by(3).map(twice).sum();
+    print_int(total);
+    let odds: Vec<i64> = (0..10).filter(is_odd).take(3).collect();
+    for n in &odds {
+        print_int(n);
+    }
+    for (a, b) in (1..4).rev().zip(10..20) {
+        print_int(a * b);
+    }
+    for (i, c) in "hi".chars().enumerate() {
+        print_int(i + c);
+    }
+}
+"#;
+    let output = compile_and_run("iterator_chains", source).unwrap();
+    assert_eq!(output, "24\n1\n3\n5\n30\n22\n12\n104\n106\n");
+}